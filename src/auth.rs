@@ -0,0 +1,62 @@
+use crate::errors::AuthError;
+use crate::structs::user::User;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, TimeDelta, Utc};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+/// How long a token from `issue_token` is accepted for.
+pub const TOKEN_LIFETIME: TimeDelta = TimeDelta::hours(12);
+
+/// What a token vouches for: the user, until `exp` (seconds since the Unix
+/// epoch).
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    #[serde(flatten)]
+    user: User,
+    exp: i64,
+}
+
+/// Builds the HMAC key used to sign and verify client tokens.
+pub fn auth_key(secret: &[u8]) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret)
+}
+
+/// Issues a token for `user` that expires `TOKEN_LIFETIME` from now.
+pub fn issue_token(key: &hmac::Key, user: &User) -> String {
+    issue_token_until(key, user, Utc::now() + TOKEN_LIFETIME)
+}
+
+/// Issues a `<payload>.<signature>` token where the payload is the
+/// base64-encoded user with its expiry and the signature is an HMAC-SHA256
+/// over it.
+pub fn issue_token_until(key: &hmac::Key, user: &User, expires_at: DateTime<Utc>) -> String {
+    let claims = Claims {
+        user: user.clone(),
+        exp: expires_at.timestamp(),
+    };
+    let payload = URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(&claims).expect("Claims always serialize to JSON"));
+    let tag = hmac::sign(key, payload.as_bytes());
+    format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(tag.as_ref()))
+}
+
+/// The user a token was issued for. Tokens without an expiry are malformed.
+pub fn verify_token(key: &hmac::Key, token: &str) -> Result<User, AuthError> {
+    let (payload, signature) = token.split_once('.').ok_or(AuthError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AuthError::Malformed)?;
+    hmac::verify(key, payload.as_bytes(), &signature).map_err(|_| AuthError::InvalidSignature)?;
+
+    let claims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| AuthError::Malformed)?;
+    let claims: Claims = serde_json::from_slice(&claims).map_err(|_| AuthError::Malformed)?;
+    if claims.exp <= Utc::now().timestamp() {
+        return Err(AuthError::Expired);
+    }
+
+    Ok(claims.user)
+}
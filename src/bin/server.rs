@@ -0,0 +1,127 @@
+use messaging::blobs::{BlobStore, FsBlobStore, GridFsBlobStore};
+use messaging::messaging::{ensure_reaction_index, migrate_image_blobs, normalize_timestamps};
+use messaging::scanning::{ClamdAddress, ClamdScanner, ScanPolicy};
+use messaging::server::{AppState, http, scheduler, websocket};
+use messaging::store::MongoMessageStore;
+use messaging::structs::envvars::ServerEnvVars;
+use messaging::transfers::SolanaVerifier;
+use messaging::unfurl::{LinkPreviewer, UnfurlOptions, Unfurler};
+use messaging::uploads::{UploadLimits, UploadManager};
+use messaging::validate_and_get_env_vars::{
+    validate_and_get_file_options, validate_and_get_server_env_vars,
+    validate_and_get_validation_options,
+};
+use messaging::webhooks::{RetryPolicy, WebhookDispatcher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const UPLOAD_SWEEP_INTERVAL_SECS: u64 = 10 * 60;
+const CLAMD_TIMEOUT_SECS: u64 = 60;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let ServerEnvVars {
+        websocket_addr,
+        http_addr,
+        auth_secret,
+        blob_dir,
+        upload_dir,
+        clamd_address,
+        clamd_stream_max,
+        on_infected,
+        edit_window,
+        link_previews,
+        solana_rpc_url,
+    } = validate_and_get_server_env_vars();
+
+    match normalize_timestamps().await {
+        Ok(0) => {}
+        Ok(rewritten) => println!(
+            "Rewrote {} stored timestamps to the fixed-width format",
+            rewritten
+        ),
+        Err(e) => eprintln!("Could not normalize stored timestamps: {}", e),
+    }
+
+    if let Err(e) = ensure_reaction_index().await {
+        eprintln!("Could not index reactions: {}", e);
+    }
+
+    let blobs: Arc<dyn BlobStore> = match blob_dir {
+        Some(blob_dir) => Arc::new(FsBlobStore::new(blob_dir)),
+        None => Arc::new(
+            GridFsBlobStore::new()
+                .await
+                .map_err(std::io::Error::other)?,
+        ),
+    };
+    match migrate_image_blobs(&*blobs).await {
+        Ok(0) => {}
+        Ok(migrated) => println!(
+            "Moved the images of {} messages into the blob store",
+            migrated
+        ),
+        Err(e) => eprintln!("Could not move shared images into the blob store: {}", e),
+    }
+
+    let mut state = AppState::new(Arc::new(MongoMessageStore), blobs, auth_secret.as_bytes())
+        .with_file_options(validate_and_get_file_options())
+        .with_validation(validate_and_get_validation_options());
+    if let Some(clamd_address) = clamd_address {
+        let mut scanner = ClamdScanner::new(
+            ClamdAddress::parse(&clamd_address),
+            Duration::from_secs(CLAMD_TIMEOUT_SECS),
+        );
+        if let Some(clamd_stream_max) = clamd_stream_max {
+            scanner = scanner.with_max_stream_size(clamd_stream_max);
+        }
+        state = state.with_scanning(ScanPolicy::new(Arc::new(scanner), on_infected));
+    }
+    if let Some(solana_rpc_url) = solana_rpc_url {
+        state = state.with_transfer_verifier(Arc::new(SolanaVerifier::new(solana_rpc_url)));
+    }
+    if let Some(edit_window) = edit_window {
+        state = state.with_edit_window(edit_window);
+    }
+    if let Some(upload_dir) = upload_dir {
+        state = state.with_uploads(UploadManager::new(upload_dir, UploadLimits::default()));
+    }
+    tokio::spawn(
+        state
+            .uploads
+            .clone()
+            .run_sweeper(Duration::from_secs(UPLOAD_SWEEP_INTERVAL_SECS)),
+    );
+
+    tokio::spawn(scheduler::run(
+        state.clone(),
+        scheduler::DEFAULT_POLL_INTERVAL,
+    ));
+
+    let webhooks = WebhookDispatcher::new(state.store.clone(), RetryPolicy::default());
+    tokio::spawn(webhooks.run(state.hub.subscribe()));
+    if link_previews {
+        let previewer = LinkPreviewer::new(
+            state.store.clone(),
+            state.hub.clone(),
+            Unfurler::new(UnfurlOptions::default()),
+        );
+        tokio::spawn(previewer.run(state.hub.subscribe()));
+    }
+
+    let websocket_listener = TcpListener::bind(&websocket_addr).await?;
+    println!(
+        "WebSocket server listening on {}",
+        websocket_listener.local_addr()?
+    );
+    let http_listener = TcpListener::bind(&http_addr).await?;
+    println!("HTTP API listening on {}", http_listener.local_addr()?);
+
+    tokio::try_join!(
+        websocket::serve(websocket_listener, state.clone()),
+        http::serve(http_listener, state),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,499 @@
+use crate::db::get_database;
+use crate::enums::BlobCodec;
+use crate::errors::BlobError;
+use crate::structs::blob::BlobRef;
+use crate::utils::{gen_message_id, sha256_hex, to_hex};
+use async_compression::Level;
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use async_trait::async_trait;
+use futures::io::AsyncWriteExt;
+use mongodb::Collection;
+use mongodb::bson::{Bson, Document, doc};
+use mongodb::gridfs::{GridFsBucket, GridFsUploadStream};
+use mongodb::options::{GridFsBucketOptions, ReturnDocument};
+use ring::digest;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt as _, BufReader};
+use tokio::sync::Mutex;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+/// zstd's own default, which already gets most of the gain on logs and JSON.
+const ZSTD_LEVEL: i32 = 3;
+
+/// A blob being read a buffer at a time.
+pub type BlobReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Content-addressed storage for attachment bytes. Message documents only
+/// hold a `BlobRef`, which keeps them well under MongoDB's 16 MB limit and
+/// history queries light.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` under its SHA-256 and takes a reference to it, which
+    /// `release` gives back. Storing bytes that are already there just
+    /// counts another reference to the existing blob.
+    async fn put(&self, data: &[u8]) -> Result<BlobRef, BlobError>;
+
+    /// Like `put`, but streams the file at `path` in, so a large attachment
+    /// never has to sit in memory whole.
+    async fn put_file(&self, path: &Path) -> Result<BlobRef, BlobError>;
+
+    async fn get(&self, sha256: &str) -> Result<Option<Vec<u8>>, BlobError>;
+
+    /// Streams a blob out. Unlike `get` the digest isn't checked, since
+    /// that is only possible once the whole blob has been read.
+    async fn open(&self, sha256: &str) -> Result<Option<BlobReader>, BlobError>;
+
+    /// Gives back a reference `put` took, and deletes the blob once none are
+    /// left. Returns whether it was deleted. Blobs stored before references
+    /// were counted are kept for good.
+    async fn release(&self, sha256: &str) -> Result<bool, BlobError>;
+}
+
+/// The bytes behind `blob`, decompressed, failing when the store no longer
+/// has them.
+pub async fn load_blob(blobs: &dyn BlobStore, blob: &BlobRef) -> Result<Vec<u8>, BlobError> {
+    let data = blobs
+        .get(blob.sha256())
+        .await?
+        .ok_or_else(|| BlobError::Missing(blob.sha256().to_string()))?;
+
+    match blob.codec() {
+        BlobCodec::Identity => Ok(data),
+        BlobCodec::Zstd => zstd::decode_all(data.as_slice())
+            .map_err(|_| BlobError::Corrupt(blob.sha256().to_string())),
+    }
+}
+
+/// Streams the decompressed bytes behind `blob`, so a large file never has
+/// to sit in memory whole.
+pub async fn open_blob(blobs: &dyn BlobStore, blob: &BlobRef) -> Result<BlobReader, BlobError> {
+    let reader = blobs
+        .open(blob.sha256())
+        .await?
+        .ok_or_else(|| BlobError::Missing(blob.sha256().to_string()))?;
+
+    Ok(match blob.codec() {
+        BlobCodec::Identity => reader,
+        BlobCodec::Zstd => Box::new(ZstdDecoder::new(BufReader::new(reader))),
+    })
+}
+
+/// Moves `data` into the store and points `blob` at it. Empty data has
+/// already been stored, or never had any bytes, and is left alone.
+pub async fn store_bytes(
+    blobs: &dyn BlobStore,
+    data: &mut Vec<u8>,
+    blob: &mut Option<BlobRef>,
+) -> Result<(), BlobError> {
+    if !data.is_empty() {
+        *blob = Some(blobs.put(data).await?);
+        *data = Vec::new();
+    }
+
+    Ok(())
+}
+
+/// Like `store_bytes`, but stores `data` zstd compressed when that makes it
+/// smaller.
+pub async fn store_compressed(
+    blobs: &dyn BlobStore,
+    data: &mut Vec<u8>,
+    blob: &mut Option<BlobRef>,
+) -> Result<(), BlobError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let compressed = zstd::bulk::compress(data, ZSTD_LEVEL)?;
+    let stored = if compressed.len() < data.len() {
+        let mut stored = blobs.put(&compressed).await?;
+        stored.set_codec(BlobCodec::Zstd);
+        stored
+    } else {
+        blobs.put(data).await?
+    };
+    *blob = Some(stored);
+    *data = Vec::new();
+
+    Ok(())
+}
+
+/// Like `BlobStore::put_file`, but stores the file zstd compressed when that
+/// makes it smaller. The compressed copy is streamed to a file next to
+/// `path` first, to compare the sizes.
+pub async fn put_file_compressed(blobs: &dyn BlobStore, path: &Path) -> Result<BlobRef, BlobError> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".zst");
+    let compressed_path = PathBuf::from(compressed_path);
+
+    let source = BufReader::new(tokio::fs::File::open(path).await?);
+    let mut encoder = ZstdEncoder::with_quality(source, Level::Precise(ZSTD_LEVEL));
+    let mut compressed = tokio::fs::File::create(&compressed_path).await?;
+    tokio::io::copy(&mut encoder, &mut compressed).await?;
+    compressed.sync_all().await?;
+    drop(compressed);
+
+    let original_size = tokio::fs::metadata(path).await?.len();
+    let compressed_size = tokio::fs::metadata(&compressed_path).await?.len();
+    let stored = if compressed_size < original_size {
+        let mut stored = blobs.put_file(&compressed_path).await?;
+        stored.set_codec(BlobCodec::Zstd);
+        Ok(stored)
+    } else {
+        blobs.put_file(path).await
+    };
+    tokio::fs::remove_file(&compressed_path).await?;
+
+    stored
+}
+
+/// Reads the bytes behind `blob` back into `data`, unless they're already there.
+pub async fn load_bytes(
+    blobs: &dyn BlobStore,
+    data: &mut Vec<u8>,
+    blob: Option<&BlobRef>,
+) -> Result<(), BlobError> {
+    if data.is_empty()
+        && let Some(blob) = blob
+    {
+        *data = load_blob(blobs, blob).await?;
+    }
+
+    Ok(())
+}
+
+/// SHA-256 and length of the file at `path`, read a buffer at a time.
+async fn file_digest(path: &Path) -> Result<BlobRef, BlobError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    let mut size = 0u64;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok(BlobRef::new(to_hex(context.finish().as_ref()), size))
+}
+
+fn check_digest(sha256: &str) -> Result<(), BlobError> {
+    let is_digest = sha256.len() == 64
+        && sha256
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+    if is_digest {
+        Ok(())
+    } else {
+        Err(BlobError::InvalidDigest(sha256.to_string()))
+    }
+}
+
+fn verified(sha256: &str, data: Vec<u8>) -> Result<Vec<u8>, BlobError> {
+    if sha256_hex(&data) == sha256 {
+        Ok(data)
+    } else {
+        Err(BlobError::Corrupt(sha256.to_string()))
+    }
+}
+
+/// Blobs kept in a MongoDB GridFS bucket, with the digest as the file name.
+/// Every upload gets its own file id: the driver removes the chunks of a
+/// failed upload by id, so racing uploads of the same bytes under one id
+/// would delete each other's. Each copy counts its references in its
+/// metadata, and the oldest copy that has any is the one read. A copy whose
+/// count reaches zero is never counted again, so deleting it can't race a
+/// `put` that found it.
+#[derive(Debug, Clone)]
+pub struct GridFsBlobStore {
+    bucket: GridFsBucket,
+    files: Collection<Document>,
+}
+
+impl GridFsBlobStore {
+    pub async fn new() -> Result<Self, mongodb::error::Error> {
+        let database = get_database().await?;
+        let options = GridFsBucketOptions::builder()
+            .bucket_name("attachments".to_string())
+            .build();
+
+        Ok(Self {
+            bucket: database.gridfs_bucket(options),
+            files: database.collection("attachments.files"),
+        })
+    }
+
+    /// The id of the copy of `sha256` that is read. Copies stored before
+    /// references were counted have no count at all.
+    async fn find(&self, sha256: &str) -> Result<Option<Bson>, BlobError> {
+        let file = self
+            .bucket
+            .find_one(doc! { "filename": sha256, "metadata.refs": { "$ne": 0 } })
+            .sort(doc! { "uploadDate": 1, "_id": 1 })
+            .await?;
+
+        Ok(file.map(|file| file.id))
+    }
+
+    /// Counts another reference to a copy of `sha256`, if one that is still
+    /// referenced exists.
+    async fn take_reference(&self, sha256: &str) -> Result<bool, BlobError> {
+        let file = self
+            .files
+            .find_one_and_update(
+                doc! { "filename": sha256, "metadata.refs": { "$gte": 1 } },
+                doc! { "$inc": { "metadata.refs": 1 } },
+            )
+            .sort(doc! { "uploadDate": 1, "_id": 1 })
+            .await?;
+
+        Ok(file.is_some())
+    }
+
+    async fn open_upload(&self, sha256: &str) -> Result<GridFsUploadStream, BlobError> {
+        Ok(self
+            .bucket
+            .open_upload_stream(sha256)
+            .metadata(doc! { "refs": 1 })
+            .await?)
+    }
+
+    async fn delete_copy(&self, id: Bson) -> Result<bool, BlobError> {
+        match self.bucket.delete(id).await {
+            Ok(()) => Ok(true),
+            // Already deleted, e.g. by an earlier release that failed
+            // partway.
+            Err(e) if matches!(*e.kind, mongodb::error::ErrorKind::GridFs(_)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for GridFsBlobStore {
+    async fn put(&self, data: &[u8]) -> Result<BlobRef, BlobError> {
+        let sha256 = sha256_hex(data);
+        let blob = BlobRef::new(sha256.clone(), data.len() as u64);
+        if self.take_reference(&sha256).await? {
+            return Ok(blob);
+        }
+
+        let mut upload = self.open_upload(&sha256).await?;
+        upload.write_all(data).await?;
+        upload.close().await?;
+
+        Ok(blob)
+    }
+
+    async fn put_file(&self, path: &Path) -> Result<BlobRef, BlobError> {
+        let blob = file_digest(path).await?;
+        if self.take_reference(blob.sha256()).await? {
+            return Ok(blob);
+        }
+
+        let mut upload = self.open_upload(blob.sha256()).await?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            upload.write_all(&buffer[..read]).await?;
+        }
+        upload.close().await?;
+
+        Ok(blob)
+    }
+
+    async fn get(&self, sha256: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        let Some(mut download) = self.open(sha256).await? else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        download.read_to_end(&mut data).await?;
+
+        verified(sha256, data).map(Some)
+    }
+
+    async fn open(&self, sha256: &str) -> Result<Option<BlobReader>, BlobError> {
+        check_digest(sha256)?;
+        let Some(id) = self.find(sha256).await? else {
+            return Ok(None);
+        };
+        let download = self.bucket.open_download_stream(id).await?;
+
+        Ok(Some(Box::new(download.compat())))
+    }
+
+    async fn release(&self, sha256: &str) -> Result<bool, BlobError> {
+        check_digest(sha256)?;
+        // The newest copy gives its references back first, so racing
+        // uploads settle on the oldest.
+        let Some(file) = self
+            .files
+            .find_one_and_update(
+                doc! { "filename": sha256, "metadata.refs": { "$gte": 1 } },
+                doc! { "$inc": { "metadata.refs": -1 } },
+            )
+            .sort(doc! { "uploadDate": -1, "_id": -1 })
+            .return_document(ReturnDocument::After)
+            .await?
+        else {
+            return Ok(false);
+        };
+        let unreferenced = matches!(
+            file.get_document("metadata")
+                .map(|metadata| metadata.get("refs")),
+            Ok(Some(Bson::Int32(0) | Bson::Int64(0)))
+        );
+        match file.get("_id") {
+            Some(id) if unreferenced => self.delete_copy(id.clone()).await,
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Blobs kept as files under `root`, fanned out by the first two digits of
+/// the digest. Each blob's references are counted in a `.refs` file next to
+/// it; counts only change under a lock, so one server process may use the
+/// directory at a time.
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+    references: Arc<Mutex<()>>,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            references: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn path(&self, sha256: &str) -> PathBuf {
+        self.root.join(&sha256[..2]).join(sha256)
+    }
+
+    fn references_path(&self, sha256: &str) -> PathBuf {
+        self.path(sha256).with_extension("refs")
+    }
+
+    /// How many references the blob has, or `None` when they aren't
+    /// counted because it is missing or was stored before they were.
+    async fn references(&self, sha256: &str) -> Result<Option<u64>, BlobError> {
+        match tokio::fs::read_to_string(self.references_path(sha256)).await {
+            Ok(count) => count
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| BlobError::Corrupt(sha256.to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set_references(&self, sha256: &str, count: u64) -> Result<(), BlobError> {
+        let path = self.references_path(sha256);
+        let temporary = path.with_extension(format!("refs.{}", gen_message_id()));
+        tokio::fs::write(&temporary, count.to_string()).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+
+        Ok(())
+    }
+
+    /// Counts another reference to the blob, if it is already stored.
+    async fn take_reference(&self, sha256: &str) -> Result<bool, BlobError> {
+        match self.references(sha256).await? {
+            Some(count) => self.set_references(sha256, count + 1).await?,
+            None if tokio::fs::try_exists(self.path(sha256)).await? => {}
+            None => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, data: &[u8]) -> Result<BlobRef, BlobError> {
+        let sha256 = sha256_hex(data);
+        let path = self.path(&sha256);
+        let blob = BlobRef::new(sha256, data.len() as u64);
+        let _references = self.references.lock().await;
+        if self.take_reference(blob.sha256()).await? {
+            return Ok(blob);
+        }
+
+        // Written under a temporary name and renamed, so readers never see a
+        // partially written blob.
+        let directory = path.parent().expect("blob paths have a parent");
+        tokio::fs::create_dir_all(directory).await?;
+        let temporary = directory.join(format!(".{}.{}", blob.sha256(), gen_message_id()));
+        tokio::fs::write(&temporary, data).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+        self.set_references(blob.sha256(), 1).await?;
+
+        Ok(blob)
+    }
+
+    async fn put_file(&self, source: &Path) -> Result<BlobRef, BlobError> {
+        let blob = file_digest(source).await?;
+        let path = self.path(blob.sha256());
+        let _references = self.references.lock().await;
+        if self.take_reference(blob.sha256()).await? {
+            return Ok(blob);
+        }
+
+        let directory = path.parent().expect("blob paths have a parent");
+        tokio::fs::create_dir_all(directory).await?;
+        let temporary = directory.join(format!(".{}.{}", blob.sha256(), gen_message_id()));
+        tokio::fs::copy(source, &temporary).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+        self.set_references(blob.sha256(), 1).await?;
+
+        Ok(blob)
+    }
+
+    async fn get(&self, sha256: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        check_digest(sha256)?;
+        match tokio::fs::read(self.path(sha256)).await {
+            Ok(data) => verified(sha256, data).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn open(&self, sha256: &str) -> Result<Option<BlobReader>, BlobError> {
+        check_digest(sha256)?;
+        match tokio::fs::File::open(self.path(sha256)).await {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn release(&self, sha256: &str) -> Result<bool, BlobError> {
+        check_digest(sha256)?;
+        let _references = self.references.lock().await;
+        match self.references(sha256).await? {
+            Some(count) if count > 1 => {
+                self.set_references(sha256, count - 1).await?;
+                Ok(false)
+            }
+            Some(_) => {
+                tokio::fs::remove_file(self.path(sha256)).await?;
+                tokio::fs::remove_file(self.references_path(sha256)).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
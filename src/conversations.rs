@@ -0,0 +1,208 @@
+use crate::db::get_collection;
+use crate::enums::NotifyLevel;
+use crate::structs::conversation::ConversationSettings;
+use crate::structs::draft::Draft;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{Document, doc, from_bson, from_document, to_bson, to_document};
+
+/// The stored settings of a conversation, or the defaults when it has none.
+pub async fn get_conversation_settings(
+    conversation_id: &str,
+) -> Result<ConversationSettings, mongodb::error::Error> {
+    let collection = get_collection("conversation_settings").await?;
+    let document = collection
+        .find_one(doc! { "conversation_id": conversation_id })
+        .await?;
+
+    Ok(match document {
+        Some(document) => from_document(document)?,
+        None => ConversationSettings::new(conversation_id.to_string()),
+    })
+}
+
+pub async fn save_conversation_settings(
+    settings: &ConversationSettings,
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("conversation_settings").await?;
+    collection
+        .replace_one(
+            doc! { "conversation_id": settings.conversation_id() },
+            to_document(settings)?,
+        )
+        .upsert(true)
+        .await?;
+
+    Ok(())
+}
+
+/// Which new messages `sender_id` is pushed in a conversation. Users who
+/// never chose get everything.
+pub async fn get_notify_level(
+    conversation_id: &str,
+    sender_id: u32,
+) -> Result<NotifyLevel, mongodb::error::Error> {
+    let collection = get_collection("notification_settings").await?;
+    let document = collection
+        .find_one(doc! { "conversation_id": conversation_id, "sender_id": sender_id })
+        .await?;
+
+    Ok(
+        match document.and_then(|mut document| document.remove("level")) {
+            Some(level) => from_bson(level)?,
+            None => NotifyLevel::default(),
+        },
+    )
+}
+
+pub async fn save_notify_level(
+    conversation_id: &str,
+    sender_id: u32,
+    level: NotifyLevel,
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("notification_settings").await?;
+    collection
+        .update_one(
+            doc! { "conversation_id": conversation_id, "sender_id": sender_id },
+            doc! { "$set": { "level": to_bson(&level)? } },
+        )
+        .upsert(true)
+        .await?;
+
+    Ok(())
+}
+
+/// Whether `sender_id` is a member of the conversation. A conversation
+/// nobody has joined yet is claimed by whoever asks first, who becomes its
+/// only member. Members are kept in one document per conversation, keyed by
+/// its id, so two users claiming at once can't both win.
+pub async fn claim_membership(
+    conversation_id: &str,
+    sender_id: u32,
+) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("conversation_members").await?;
+    collection
+        .update_one(
+            doc! { "_id": conversation_id },
+            doc! { "$setOnInsert": { "members": [sender_id] } },
+        )
+        .upsert(true)
+        .await?;
+    let document = collection
+        .find_one(doc! { "_id": conversation_id, "members": sender_id })
+        .await?;
+
+    Ok(document.is_some())
+}
+
+/// The members of a conversation, in the order they joined.
+pub async fn get_members(conversation_id: &str) -> Result<Vec<u32>, mongodb::error::Error> {
+    let collection = get_collection("conversation_members").await?;
+    let document = collection.find_one(doc! { "_id": conversation_id }).await?;
+
+    Ok(
+        match document.and_then(|mut document| document.remove("members")) {
+            Some(members) => from_bson(members)?,
+            None => Vec::new(),
+        },
+    )
+}
+
+pub async fn add_member(
+    conversation_id: &str,
+    sender_id: u32,
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("conversation_members").await?;
+    collection
+        .update_one(
+            doc! { "_id": conversation_id },
+            doc! { "$addToSet": { "members": sender_id } },
+        )
+        .upsert(true)
+        .await?;
+
+    Ok(())
+}
+
+/// Says whether `sender_id` was a member. A conversation whose last member
+/// leaves stays claimed, so its history can't be taken over.
+pub async fn remove_member(
+    conversation_id: &str,
+    sender_id: u32,
+) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("conversation_members").await?;
+    let update_result = collection
+        .update_one(
+            doc! { "_id": conversation_id },
+            doc! { "$pull": { "members": sender_id } },
+        )
+        .await?;
+
+    Ok(update_result.modified_count > 0)
+}
+
+/// Saves `draft` unless the stored one is at least as new, and returns
+/// whichever is stored afterwards. The stored draft is only replaced if it
+/// is still the one that was compared against, so of two devices saving at
+/// once the later `updated_at` wins either way.
+pub async fn save_draft(draft: &Draft) -> Result<Draft, mongodb::error::Error> {
+    let collection = get_collection("message_drafts").await?;
+    let key = doc! { "sender_id": draft.sender_id(), "conversation_id": draft.conversation_id() };
+    loop {
+        let Some(document) = collection.find_one(key.clone()).await? else {
+            let update_result = collection
+                .update_one(key.clone(), doc! { "$setOnInsert": to_document(draft)? })
+                .upsert(true)
+                .await?;
+            if update_result.upserted_id.is_some() {
+                return Ok(draft.clone());
+            }
+            continue;
+        };
+
+        let current: Draft = from_document(document.clone())?;
+        if !draft.supersedes(&current) {
+            return Ok(current);
+        }
+        let mut unchanged = key.clone();
+        if let Some(updated_at) = document.get("updated_at") {
+            unchanged.insert("updated_at", updated_at.clone());
+        }
+        let update_result = collection
+            .replace_one(unchanged, to_document(draft)?)
+            .await?;
+        if update_result.matched_count > 0 {
+            return Ok(draft.clone());
+        }
+    }
+}
+
+pub async fn get_draft(
+    sender_id: u32,
+    conversation_id: &str,
+) -> Result<Option<Draft>, mongodb::error::Error> {
+    let collection = get_collection("message_drafts").await?;
+    collection
+        .find_one(doc! { "sender_id": sender_id, "conversation_id": conversation_id })
+        .await?
+        .map(|document| from_document(document).map_err(Into::into))
+        .transpose()
+}
+
+/// A user's drafts that aren't empty, most recently saved first.
+pub async fn get_drafts(sender_id: u32) -> Result<Vec<Draft>, mongodb::error::Error> {
+    let collection = get_collection("message_drafts").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! { "sender_id": sender_id })
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut drafts = documents
+        .into_iter()
+        .map(from_document)
+        .collect::<Result<Vec<Draft>, _>>()?;
+    drafts.retain(|draft| !draft.is_empty());
+    drafts.sort_by_key(|draft| std::cmp::Reverse(draft.updated_at()));
+
+    Ok(drafts)
+}
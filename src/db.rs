@@ -5,7 +5,7 @@
 // };
 use mongodb::{
     Client, Collection, Database,
-    bson::{Document, doc},
+    bson::Document,
     options::{ClientOptions, ResolverConfig, ServerApi, ServerApiVersion},
 };
 
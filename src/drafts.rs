@@ -0,0 +1,26 @@
+use crate::enums::Message;
+use crate::errors::StoreError;
+use crate::store::MessageStore;
+use crate::structs::draft::Draft;
+
+/// Clears the sender's draft in the conversation `message` was sent to, as
+/// of when it was sent, and returns the cleared draft for their other
+/// devices. A draft saved after that is a new one and stays.
+pub async fn clear_sent_draft(
+    store: &dyn MessageStore,
+    message: &Message<'_>,
+) -> Result<Option<Draft>, StoreError> {
+    let Some(conversation_id) = message.conversation_id() else {
+        return Ok(None);
+    };
+    let sender_id = message.sender_id();
+    match store.find_draft(sender_id, conversation_id).await? {
+        Some(draft) if !draft.is_empty() => {}
+        _ => return Ok(None),
+    }
+
+    let cleared = Draft::empty(sender_id, conversation_id.to_string(), message.created_at());
+    let stored = store.save_draft(&cleared).await?;
+
+    Ok((stored == cleared).then_some(cleared))
+}
@@ -1,23 +1,276 @@
+use crate::blobs::BlobStore;
+use crate::errors::BlobError;
+use crate::structs::blob::BlobRef;
+use crate::structs::draft::Draft;
+use crate::structs::mention::{InboxMention, Mention};
 use crate::structs::messages::{
-    crypto::CryptoTransferMessagePackage, file::FileMessagePackage, image::ImageMessagePackage,
-    text::TextMessagePackage,
+    crypto::CryptoTransferMessagePackage, deleted::DeletedMessagePackage, file::FileMessagePackage,
+    image::ImageMessagePackage, text::TextMessagePackage,
 };
-use serde::{Deserialize, Serialize};
+use crate::structs::reaction::Reaction;
+use crate::structs::receipt::Receipt;
+use crate::structs::scan::ScanVerdict;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::fmt;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MessageType {
     Text,
     Image,
     File,
     CryptoTransfer,
+    /// A tombstone, see `DeletedMessagePackage`.
+    Deleted,
+}
+
+/// Reads a package's `type` field, failing unless it is `expected`. Packages
+/// whose other fields all have defaults need this, or `Message` would read a
+/// document of another type as one of them.
+pub(crate) fn expect_type<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    expected: MessageType,
+) -> Result<MessageType, D::Error> {
+    let found = MessageType::deserialize(deserializer)?;
+    if found != expected {
+        return Err(de::Error::custom(format!(
+            "expected a {:?} message, found {:?}",
+            expected, found
+        )));
+    }
+
+    Ok(found)
 }
 
-#[derive(Debug, Clone)]
+// Every package carries its own `type` field, so the enum is stored and sent
+// over the wire as the bare package document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum Message<'a> {
     Text(TextMessagePackage<'a>),
     Image(ImageMessagePackage),
     File(FileMessagePackage),
     CryptoTransfer(CryptoTransferMessagePackage<'a>),
+    Deleted(DeletedMessagePackage),
+}
+
+impl<'a> Message<'a> {
+    pub fn message_id(&self) -> u32 {
+        match self {
+            Message::Text(message) => message.message_id(),
+            Message::Image(message) => message.message_id(),
+            Message::File(message) => message.message_id(),
+            Message::CryptoTransfer(message) => message.message_id(),
+            Message::Deleted(message) => message.message_id(),
+        }
+    }
+
+    pub fn r#type(&self) -> MessageType {
+        match self {
+            Message::Text(message) => message.r#type(),
+            Message::Image(message) => message.r#type(),
+            Message::File(message) => message.r#type(),
+            Message::CryptoTransfer(message) => message.r#type(),
+            Message::Deleted(message) => message.r#type(),
+        }
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        match self {
+            Message::Text(message) => *message.sender().sender_id(),
+            Message::Image(message) => message.sender_id(),
+            Message::File(message) => message.sender_id(),
+            Message::CryptoTransfer(message) => message.sender_id(),
+            Message::Deleted(message) => message.sender_id(),
+        }
+    }
+
+    pub fn from(&self) -> MessageFromType {
+        match self {
+            Message::Text(message) => message.sender().sender_type().clone(),
+            Message::Image(message) => message.from(),
+            Message::File(message) => message.from(),
+            Message::CryptoTransfer(message) => message.from(),
+            Message::Deleted(message) => message.from(),
+        }
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        match self {
+            Message::Text(message) => message.created_at(),
+            Message::Image(message) => message.created_at(),
+            Message::File(message) => message.created_at(),
+            Message::CryptoTransfer(message) => message.created_at(),
+            Message::Deleted(message) => message.created_at(),
+        }
+    }
+
+    /// Moves the message to `created_at`, as when a scheduled message is
+    /// delivered.
+    pub fn set_created_at(&mut self, created_at: DateTime<Utc>) {
+        match self {
+            Message::Text(message) => message.set_created_at(created_at),
+            Message::Image(message) => message.set_created_at(created_at),
+            Message::File(message) => message.set_created_at(created_at),
+            Message::CryptoTransfer(message) => message.set_created_at(created_at),
+            Message::Deleted(message) => message.set_created_at(created_at),
+        }
+    }
+
+    pub fn pretty_timestamp(&self) -> String {
+        match self {
+            Message::Text(message) => message.pretty_timestamp(),
+            Message::Image(message) => message.pretty_timestamp(),
+            Message::File(message) => message.pretty_timestamp(),
+            Message::CryptoTransfer(message) => message.pretty_timestamp(),
+            Message::Deleted(message) => message.pretty_timestamp(),
+        }
+    }
+
+    pub fn conversation_id(&self) -> Option<&str> {
+        match self {
+            Message::Text(message) => message.conversation_id(),
+            Message::Image(message) => message.conversation_id(),
+            Message::File(message) => message.conversation_id(),
+            Message::CryptoTransfer(message) => message.conversation_id(),
+            Message::Deleted(message) => message.conversation_id(),
+        }
+    }
+
+    pub fn set_conversation_id(&mut self, conversation_id: String) {
+        match self {
+            Message::Text(message) => message.set_conversation_id(conversation_id),
+            Message::Image(message) => message.set_conversation_id(conversation_id),
+            Message::File(message) => message.set_conversation_id(conversation_id),
+            Message::CryptoTransfer(message) => message.set_conversation_id(conversation_id),
+            Message::Deleted(message) => message.set_conversation_id(conversation_id),
+        }
+    }
+
+    pub fn reply_to(&self) -> Option<u32> {
+        match self {
+            Message::Text(message) => message.reply_to(),
+            Message::Image(message) => message.reply_to(),
+            Message::File(message) => message.reply_to(),
+            Message::CryptoTransfer(message) => message.reply_to(),
+            Message::Deleted(message) => message.reply_to(),
+        }
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: u32) {
+        match self {
+            Message::Text(message) => message.set_reply_to(reply_to),
+            Message::Image(message) => message.set_reply_to(reply_to),
+            Message::File(message) => message.set_reply_to(reply_to),
+            Message::CryptoTransfer(message) => message.set_reply_to(reply_to),
+            Message::Deleted(message) => message.set_reply_to(reply_to),
+        }
+    }
+
+    /// Unset on messages that start a thread, or aren't in one.
+    pub fn thread_root(&self) -> Option<u32> {
+        match self {
+            Message::Text(message) => message.thread_root(),
+            Message::Image(message) => message.thread_root(),
+            Message::File(message) => message.thread_root(),
+            Message::CryptoTransfer(message) => message.thread_root(),
+            Message::Deleted(message) => message.thread_root(),
+        }
+    }
+
+    pub fn set_thread_root(&mut self, thread_root: u32) {
+        match self {
+            Message::Text(message) => message.set_thread_root(thread_root),
+            Message::Image(message) => message.set_thread_root(thread_root),
+            Message::File(message) => message.set_thread_root(thread_root),
+            Message::CryptoTransfer(message) => message.set_thread_root(thread_root),
+            Message::Deleted(message) => message.set_thread_root(thread_root),
+        }
+    }
+
+    /// Moves attachment bytes into `blobs`, so the message only references
+    /// them. Done before a message is stored or broadcast.
+    pub async fn store_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        match self {
+            Message::Image(message) => message.store_attachments(blobs).await,
+            Message::File(message) => message.store_attachments(blobs).await,
+            Message::Text(_) | Message::CryptoTransfer(_) | Message::Deleted(_) => Ok(()),
+        }
+    }
+
+    pub async fn load_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        match self {
+            Message::Image(message) => message.load_attachments(blobs).await,
+            Message::File(message) => message.load_attachments(blobs).await,
+            Message::Text(_) | Message::CryptoTransfer(_) | Message::Deleted(_) => Ok(()),
+        }
+    }
+
+    /// The scanner's verdict on the message's attachment, if it was scanned.
+    pub fn scan(&self) -> Option<&ScanVerdict> {
+        match self {
+            Message::Image(message) => message.scan(),
+            Message::File(message) => message.scan(),
+            Message::Text(_) | Message::CryptoTransfer(_) | Message::Deleted(_) => None,
+        }
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.scan().is_some_and(ScanVerdict::is_infected)
+    }
+
+    /// Every blob the message references, so they can be released when it
+    /// goes away.
+    pub fn blobs(&self) -> Vec<&BlobRef> {
+        match self {
+            Message::Image(message) => message.blobs(),
+            Message::File(message) => message.file_blob().into_iter().collect(),
+            Message::Text(_) | Message::CryptoTransfer(_) | Message::Deleted(_) => Vec::new(),
+        }
+    }
+
+    /// The users a text message mentions. Other messages mention nobody.
+    pub fn mentions(&self) -> &[Mention] {
+        match self {
+            Message::Text(message) => message.mentions(),
+            _ => &[],
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        matches!(self, Message::Deleted(_))
+    }
+
+    /// The tombstone that replaces this message once `deleted_by` deletes
+    /// it for everyone. It keeps the message's place in its thread.
+    pub fn tombstone(&self, deleted_by: u32) -> Message<'static> {
+        let mut tombstone = Message::Deleted(DeletedMessagePackage::new(
+            self.message_id(),
+            self.sender_id(),
+            self.from(),
+            self.created_at(),
+            self.conversation_id().map(str::to_string),
+            deleted_by,
+        ));
+        if let Some(reply_to) = self.reply_to() {
+            tombstone.set_reply_to(reply_to);
+        }
+        if let Some(thread_root) = self.thread_root() {
+            tombstone.set_thread_root(thread_root);
+        }
+
+        tombstone
+    }
+
+    pub fn into_owned(self) -> Message<'static> {
+        match self {
+            Message::Text(message) => Message::Text(message.into_owned()),
+            Message::Image(message) => Message::Image(message),
+            Message::File(message) => Message::File(message),
+            Message::CryptoTransfer(message) => Message::CryptoTransfer(message.into_owned()),
+            Message::Deleted(message) => Message::Deleted(message),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -30,11 +283,344 @@ impl MessageFromType {
     pub fn is_agent(&self) -> bool {
         matches!(self, MessageFromType::Agent)
     }
+}
 
-    pub fn to_string(&self) -> String {
+impl fmt::Display for MessageFromType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            MessageFromType::Agent => "Agent".to_string(),
-            MessageFromType::User => "User".to_string(),
+            MessageFromType::Agent => write!(f, "Agent"),
+            MessageFromType::User => write!(f, "User"),
+        }
+    }
+}
+
+/// How the text of a text message is to be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFormat {
+    #[default]
+    Plain,
+    /// The subset `markdown::parse` understands.
+    Markdown,
+}
+
+/// Which new messages in a conversation a user is pushed live.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    /// Only messages that mention them.
+    Mentions,
+}
+
+/// What a user may do beyond sending messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Member,
+    /// May edit and delete other people's messages.
+    Moderator,
+    /// A moderator who may also purge messages for good.
+    Admin,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+/// How far deleting a message goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteScope {
+    /// Hide it from the requester's history only.
+    Me,
+    /// Replace it with a tombstone, so it disappears for everyone.
+    #[default]
+    Everyone,
+    /// Remove every trace of it. Admins only.
+    Purge,
+}
+
+/// What happens to the EXIF, XMP and ICC metadata of an uploaded image.
+/// Stripping is the default since phone photos carry GPS coordinates and
+/// device serial numbers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum MetadataPolicy {
+    #[default]
+    Strip,
+    Keep,
+}
+
+/// How the bytes in a blob are encoded. Only file attachments are ever
+/// compressed; images and thumbnails are compressed formats already.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobCodec {
+    #[default]
+    Identity,
+    Zstd,
+}
+
+impl BlobCodec {
+    pub fn is_identity(&self) -> bool {
+        matches!(self, BlobCodec::Identity)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScanStatus {
+    Clean,
+    Infected,
+}
+
+/// What happens to a message whose attachment the scanner flags. A
+/// quarantined message is kept, so agents can see what was sent, but its
+/// attachment is never served.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InfectedAction {
+    #[default]
+    Reject,
+    Quarantine,
+}
+
+/// Frames a WebSocket client sends to the server. The first frame on every
+/// connection must be `Authenticate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Authenticate {
+        token: String,
+    },
+    Subscribe {
+        conversation_id: String,
+    },
+    Unsubscribe {
+        conversation_id: String,
+    },
+    Send {
+        conversation_id: String,
+        message: Box<Message<'static>>,
+    },
+    Receipt {
+        conversation_id: String,
+        message_id: u32,
+        status: ReceiptStatus,
+    },
+    Typing {
+        conversation_id: String,
+        is_typing: bool,
+    },
+}
+
+impl ClientFrame {
+    /// The conversation the frame acts on. Unsubscribing isn't counted, since
+    /// it only ever narrows what the connection gets.
+    pub fn conversation_id(&self) -> Option<&str> {
+        match self {
+            ClientFrame::Subscribe { conversation_id }
+            | ClientFrame::Send {
+                conversation_id, ..
+            }
+            | ClientFrame::Receipt {
+                conversation_id, ..
+            }
+            | ClientFrame::Typing {
+                conversation_id, ..
+            } => Some(conversation_id),
+            ClientFrame::Authenticate { .. } | ClientFrame::Unsubscribe { .. } => None,
+        }
+    }
+}
+
+/// Frames the server pushes to WebSocket clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Authenticated {
+        sender_id: u32,
+    },
+    Subscribed {
+        conversation_id: String,
+    },
+    Unsubscribed {
+        conversation_id: String,
+    },
+    Sent {
+        conversation_id: String,
+        message_id: u32,
+    },
+    Message {
+        conversation_id: String,
+        message: Message<'static>,
+    },
+    Receipt {
+        receipt: Receipt,
+    },
+    Typing {
+        conversation_id: String,
+        sender_id: u32,
+        is_typing: bool,
+    },
+    MessageUpdated {
+        conversation_id: String,
+        message: Message<'static>,
+    },
+    /// A crypto transfer sent earlier was found on chain. Sent once per
+    /// transfer; one confirmed when it was sent only comes as `Message`.
+    TransferConfirmed {
+        conversation_id: String,
+        message: Message<'static>,
+    },
+    MessageDeleted {
+        conversation_id: String,
+        message_id: u32,
+    },
+    ReactionAdded {
+        reaction: Reaction,
+    },
+    ReactionRemoved {
+        conversation_id: String,
+        message_id: u32,
+        sender_id: u32,
+        emoji: String,
+    },
+    /// Sent only to the user who was mentioned, whatever they subscribed to.
+    Mentioned {
+        mention: InboxMention,
+    },
+    /// Sent to every connection of the draft's owner, so their other
+    /// devices show what was typed on this one.
+    DraftUpdated {
+        draft: Draft,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl ServerFrame {
+    /// The conversation a broadcast frame belongs to. Frames without one are
+    /// only ever sent directly to a single connection, or to the
+    /// `recipient`'s.
+    pub fn conversation_id(&self) -> Option<&str> {
+        match self {
+            ServerFrame::Message {
+                conversation_id, ..
+            }
+            | ServerFrame::Typing {
+                conversation_id, ..
+            }
+            | ServerFrame::MessageUpdated {
+                conversation_id, ..
+            }
+            | ServerFrame::TransferConfirmed {
+                conversation_id, ..
+            }
+            | ServerFrame::MessageDeleted {
+                conversation_id, ..
+            }
+            | ServerFrame::ReactionRemoved {
+                conversation_id, ..
+            } => Some(conversation_id),
+            ServerFrame::Receipt { receipt } => Some(receipt.conversation_id()),
+            ServerFrame::ReactionAdded { reaction } => Some(reaction.conversation_id()),
+            _ => None,
+        }
+    }
+
+    /// The one user a targeted frame is meant for.
+    pub fn recipient(&self) -> Option<u32> {
+        match self {
+            ServerFrame::Mentioned { mention } => Some(mention.sender_id()),
+            ServerFrame::DraftUpdated { draft } => Some(draft.sender_id()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventType {
+    MessageCreated,
+    TransferConfirmed,
+    ReceiptRead,
+    Mentioned,
+}
+
+/// The body POSTed to webhook subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    MessageCreated { message: Message<'static> },
+    TransferConfirmed { message: Message<'static> },
+    ReceiptRead { receipt: Receipt },
+    Mentioned { mention: InboxMention },
+}
+
+impl WebhookEvent {
+    pub fn event_type(&self) -> WebhookEventType {
+        match self {
+            WebhookEvent::MessageCreated { .. } => WebhookEventType::MessageCreated,
+            WebhookEvent::TransferConfirmed { .. } => WebhookEventType::TransferConfirmed,
+            WebhookEvent::ReceiptRead { .. } => WebhookEventType::ReceiptRead,
+            WebhookEvent::Mentioned { .. } => WebhookEventType::Mentioned,
+        }
+    }
+
+    /// The one user an event is meant for. Only their subscriptions are told.
+    pub fn recipient(&self) -> Option<u32> {
+        match self {
+            WebhookEvent::Mentioned { mention } => Some(mention.sender_id()),
+            _ => None,
+        }
+    }
+
+    /// The conversation the event happened in. Unless the event has a
+    /// `recipient`, only subscribers who are members of it are told.
+    pub fn conversation_id(&self) -> Option<&str> {
+        match self {
+            WebhookEvent::MessageCreated { message }
+            | WebhookEvent::TransferConfirmed { message } => message.conversation_id(),
+            WebhookEvent::ReceiptRead { receipt } => Some(receipt.conversation_id()),
+            WebhookEvent::Mentioned { mention } => Some(mention.conversation_id()),
+        }
+    }
+
+    /// The webhook events a frame published on the hub gives rise to. A
+    /// transfer is confirmed once the server found it on chain, which it
+    /// announces either with the message or later on its own.
+    pub fn from_frame(frame: &ServerFrame) -> Vec<WebhookEvent> {
+        match frame {
+            ServerFrame::Message { message, .. } => {
+                let mut events = vec![WebhookEvent::MessageCreated {
+                    message: message.clone(),
+                }];
+                if matches!(message, Message::CryptoTransfer(transfer) if transfer.is_confirmed()) {
+                    events.push(WebhookEvent::TransferConfirmed {
+                        message: message.clone(),
+                    });
+                }
+                events
+            }
+            ServerFrame::TransferConfirmed { message, .. } => {
+                vec![WebhookEvent::TransferConfirmed {
+                    message: message.clone(),
+                }]
+            }
+            ServerFrame::Receipt { receipt } if receipt.status() == ReceiptStatus::Read => {
+                vec![WebhookEvent::ReceiptRead {
+                    receipt: receipt.clone(),
+                }]
+            }
+            ServerFrame::Mentioned { mention } => vec![WebhookEvent::Mentioned {
+                mention: mention.clone(),
+            }],
+            _ => Vec::new(),
         }
     }
 }
@@ -0,0 +1,267 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("token is malformed")]
+    Malformed,
+    #[error("token signature is invalid")]
+    InvalidSignature,
+    #[error("token has expired")]
+    Expired,
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("database error: {0}")]
+    Database(#[from] mongodb::error::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum BlobError {
+    #[error("blob storage error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("database error: {0}")]
+    Database(#[from] mongodb::error::Error),
+    #[error("{0} is not a SHA-256 digest")]
+    InvalidDigest(String),
+    #[error("blob {0} does not match its digest")]
+    Corrupt(String),
+    #[error("blob {0} was not found")]
+    Missing(String),
+}
+
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("upload {0} was not found")]
+    NotFound(String),
+    #[error("uploads must be between 1 and {max} bytes, not {size}")]
+    InvalidSize { size: u64, max: u64 },
+    #[error("chunks must be between 1 and {max} bytes, not {size}")]
+    InvalidChunkSize { size: u64, max: u64 },
+    #[error("uploads may have at most {max} chunks, not {chunks}")]
+    TooManyChunks { chunks: u64, max: u32 },
+    #[error("chunk {index} is out of range, the upload has {total} chunks")]
+    ChunkOutOfRange { index: u32, total: u32 },
+    #[error("chunk {index} should be {expected} bytes but was {actual}")]
+    ChunkLength {
+        index: u32,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("chunk {0} does not match its checksum")]
+    ChecksumMismatch(u32),
+    #[error("upload is missing {missing} chunks, starting with chunk {first}")]
+    Incomplete { missing: usize, first: u32 },
+    #[error("upload {0} is already being completed")]
+    Completing(String),
+    #[error("no more than {max} uploads may be open at once")]
+    TooManySessions { max: usize },
+    #[error("open uploads already reserve {reserved} of the {max} bytes allowed")]
+    QuotaExceeded { reserved: u64, max: u64 },
+    #[error("upload storage error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Blob(#[from] BlobError),
+}
+
+#[derive(Debug, Error)]
+pub enum ImageProcessingError {
+    #[error("image format is not supported")]
+    UnsupportedFormat,
+    #[error("image is corrupt: {0}")]
+    Corrupt(String),
+    #[error("image is {width}x{height}, more than the {max_pixels} pixel limit")]
+    TooManyPixels {
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+    #[error("image could not be re-encoded: {0}")]
+    Encode(String),
+}
+
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("file is {size} bytes, more than the {max} byte limit")]
+    TooLarge { size: u64, max: u64 },
+    #[error("files of type {0} are not allowed")]
+    TypeNotAllowed(String),
+    #[error("{0:?} is not a valid filename")]
+    InvalidFilename(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ScanError {
+    #[error("attachment is infected with {0}")]
+    Infected(String),
+    #[error("scanner could not be reached: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("scanner did not answer in time")]
+    Timeout,
+    #[error("attachment is larger than the {max} bytes the scanner accepts")]
+    TooLarge { max: u64 },
+    #[error("scanner replied {0:?}")]
+    Protocol(String),
+}
+
+/// Why a crypto transfer could not be confirmed.
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("transfers can't be checked on this server")]
+    Unverifiable,
+    #[error("{0:?} is not a transaction signature")]
+    InvalidSignature(String),
+    #[error("transaction {0} is not a confirmed payment to the recipient")]
+    NotConfirmed(String),
+    #[error("chain could not be reached: {0}")]
+    Rpc(String),
+}
+
+/// Why a link could not be previewed.
+#[derive(Debug, Error)]
+pub enum UnfurlError {
+    #[error("only http and https links are previewed, not {0:?}")]
+    UnsupportedUrl(String),
+    #[error("{0} resolves to an address that may not be fetched")]
+    Blocked(String),
+    #[error("could not resolve {0}")]
+    Resolve(String),
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("page answered with status {0}")]
+    Status(u16),
+    #[error("page is {0:?}, not HTML")]
+    NotHtml(String),
+    #[error("response is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("more than {0} redirects")]
+    TooManyRedirects(usize),
+    #[error("page did not answer in time")]
+    Timeout,
+    #[error("page has no title, description or image")]
+    NoMetadata,
+    #[error("oEmbed answer is not valid JSON")]
+    InvalidOEmbed,
+}
+
+/// Why the Markdown of a text message was refused.
+#[derive(Debug, Error, PartialEq)]
+pub enum MarkdownError {
+    #[error("links may only use http, https or mailto URLs, not {0:?}")]
+    UnsafeLink(String),
+    #[error("formatting may be nested at most {0} levels deep")]
+    TooDeep(usize),
+}
+
+/// Why the text of a message, or a file name, was refused. See
+/// `validation::ContentRules`.
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("text is empty or only whitespace")]
+    Blank,
+    #[error("text contains the control character U+{:04X}", *.0 as u32)]
+    ControlCharacter(char),
+    #[error("text opens a bidirectional override or isolate it does not close")]
+    UnbalancedBidi,
+    #[error("text is {length} characters long, more than the {max} character limit")]
+    TooLong { length: usize, max: usize },
+    #[error("text is {size} bytes, more than the {max} byte limit")]
+    TooLarge { size: usize, max: usize },
+    #[error(transparent)]
+    Markdown(#[from] MarkdownError),
+}
+
+/// Why a reply could not be placed in a thread.
+#[derive(Debug, Error)]
+pub enum ThreadError {
+    /// Also used for messages in other conversations, so their existence
+    /// isn't given away.
+    #[error("message {0} can't be replied to because it was not found")]
+    ParentNotFound(u32),
+    #[error("message {0} can't be replied to because it was deleted")]
+    ParentDeleted(u32),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// Why a message was refused when it was built or rebuilt from client input.
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Image(#[from] ImageProcessingError),
+    #[error(transparent)]
+    File(#[from] FileError),
+    #[error(transparent)]
+    Content(#[from] ValidationError),
+}
+
+impl From<String> for MessageError {
+    fn from(reason: String) -> Self {
+        MessageError::Invalid(reason)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("missing or invalid bearer token")]
+    Unauthorized,
+    #[error("only the sender may change this message")]
+    Forbidden,
+    #[error("you are not a member of conversation {0}")]
+    NotMember(String),
+    #[error("message {0} can no longer be edited")]
+    EditWindowClosed(u32),
+    #[error("only agents may change conversation settings")]
+    AgentOnly,
+    #[error("only admins may purge messages")]
+    AdminOnly,
+    #[error("only moderators may search every conversation")]
+    ModeratorOnly,
+    #[error("message {0} was not found")]
+    NotFound(u32),
+    #[error("webhook {0} was not found")]
+    WebhookNotFound(u32),
+    #[error("there is no draft in conversation {0}")]
+    DraftNotFound(String),
+    #[error("message {message_id} has no {size}px thumbnail")]
+    ThumbnailNotFound { message_id: u32, size: u32 },
+    #[error("a message takes at most {0} different reactions from one user")]
+    TooManyReactions(usize),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error(transparent)]
+    Content(#[from] ValidationError),
+    #[error(transparent)]
+    Image(#[from] ImageProcessingError),
+    #[error(transparent)]
+    File(#[from] FileError),
+    #[error(transparent)]
+    Blob(#[from] BlobError),
+    #[error(transparent)]
+    Scan(#[from] ScanError),
+    #[error(transparent)]
+    Transfer(#[from] TransferError),
+    #[error("message {0}'s attachment is quarantined")]
+    Quarantined(u32),
+    #[error(transparent)]
+    Upload(#[from] UploadError),
+    #[error(transparent)]
+    Thread(#[from] ThreadError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl From<MessageError> for ApiError {
+    fn from(error: MessageError) -> Self {
+        match error {
+            MessageError::Invalid(reason) => ApiError::Validation(reason),
+            MessageError::Image(error) => ApiError::Image(error),
+            MessageError::File(error) => ApiError::File(error),
+            MessageError::Content(error) => ApiError::Content(error),
+        }
+    }
+}
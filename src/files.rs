@@ -0,0 +1,139 @@
+use crate::errors::FileError;
+use crate::structs::messages::file::FileMetadata;
+use crate::utils::sha256_hex;
+
+/// How much of a file is looked at to work out its type. Every signature
+/// `infer` knows sits well inside this.
+pub const SNIFF_LENGTH: usize = 8 * 1024;
+const MAX_FILENAME_BYTES: usize = 255;
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// What a deployment accepts as a file attachment. Types are matched against
+/// the type sniffed from the content, never the one a client claims, and
+/// may end in `/*` to cover a whole family such as `image/*`.
+#[derive(Debug, Clone)]
+pub struct FileOptions {
+    pub max_size: u64,
+    /// When not empty, only these types are accepted.
+    pub allowed_types: Vec<String>,
+    /// Refused even when they are also allowed.
+    pub denied_types: Vec<String>,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 100 * 1024 * 1024,
+            allowed_types: Vec::new(),
+            denied_types: vec![
+                "application/x-executable".into(),
+                "application/x-mach-binary".into(),
+                "application/vnd.microsoft.portable-executable".into(),
+            ],
+        }
+    }
+}
+
+impl FileOptions {
+    pub fn check_size(&self, size: u64) -> Result<(), FileError> {
+        if size > self.max_size {
+            return Err(FileError::TooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn check_type(&self, mime_type: &str) -> Result<(), FileError> {
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(family) => mime_type
+                .split_once('/')
+                .is_some_and(|(top, _)| top.eq_ignore_ascii_case(family)),
+            None => pattern.eq_ignore_ascii_case(mime_type),
+        };
+        let allowed = self.allowed_types.is_empty() || self.allowed_types.iter().any(matches);
+        if !allowed || self.denied_types.iter().any(matches) {
+            return Err(FileError::TypeNotAllowed(mime_type.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks an in-memory file against `options` and describes it.
+pub fn inspect_file(
+    filename: &str,
+    data: &[u8],
+    options: &FileOptions,
+) -> Result<FileMetadata, FileError> {
+    let filename = sanitize_filename(filename)?;
+    options.check_size(data.len() as u64)?;
+    let mime_type = sniff_mime_type(data);
+    options.check_type(&mime_type)?;
+
+    Ok(FileMetadata::new(
+        filename,
+        mime_type,
+        data.len() as u64,
+        sha256_hex(data),
+    ))
+}
+
+/// The type of a file judged by its first bytes. Content without a known
+/// signature is `text/plain` when it reads as UTF-8 and
+/// `application/octet-stream` otherwise.
+pub fn sniff_mime_type(head: &[u8]) -> String {
+    let head = &head[..head.len().min(SNIFF_LENGTH)];
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+
+    // The head may end part way through a character.
+    let is_text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if is_text && !head.is_empty() && !head.contains(&0) {
+        "text/plain".to_string()
+    } else {
+        OCTET_STREAM.to_string()
+    }
+}
+
+/// The last component of a client supplied path, so a name like
+/// `..\..\boot.ini` can't be mistaken for a path later on.
+pub fn sanitize_filename(filename: &str) -> Result<String, FileError> {
+    let name = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+    let is_valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.len() <= MAX_FILENAME_BYTES
+        && !name.chars().any(char::is_control);
+    if !is_valid {
+        return Err(FileError::InvalidFilename(filename.to_string()));
+    }
+
+    Ok(name.to_string())
+}
+
+/// A `Content-Disposition` value that makes browsers download the file
+/// under its original name, percent-encoded per RFC 6266.
+pub fn content_disposition(filename: &str) -> String {
+    let encoded: String = filename
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+
+    format!("attachment; filename*=UTF-8''{}", encoded)
+}
@@ -0,0 +1,262 @@
+use crate::enums::MetadataPolicy;
+use crate::errors::ImageProcessingError;
+use crate::structs::messages::image::{ImageMetadata, Thumbnail};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits, Rgba, RgbaImage,
+};
+use std::io::Cursor;
+
+const JPEG_QUALITY: u8 = 85;
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+/// Long edge of the copy placeholders are computed from. BlurHash only keeps
+/// a handful of components, so more pixels would just cost time.
+const PLACEHOLDER_SIZE: u32 = 32;
+
+/// Bounds on the images we are willing to decode. Uploaded images are
+/// untrusted, so the pixel count is checked from the header before anything
+/// is decompressed.
+#[derive(Debug, Clone)]
+pub struct ImageProcessingOptions {
+    pub max_pixels: u64,
+    pub max_dimension: u32,
+    pub max_alloc: u64,
+    /// Long-edge sizes, in pixels, of the thumbnails generated for each image.
+    pub thumbnail_sizes: Vec<u32>,
+    /// Whether the uploaded original, with its EXIF, XMP and ICC data, is
+    /// kept. Every generated copy is stripped regardless.
+    pub metadata: MetadataPolicy,
+}
+
+impl Default for ImageProcessingOptions {
+    fn default() -> Self {
+        Self {
+            max_pixels: 40_000_000,
+            max_dimension: 16_384,
+            max_alloc: 256 * 1024 * 1024,
+            thumbnail_sizes: vec![64, 256, 1024],
+            metadata: MetadataPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    pub metadata: ImageMetadata,
+    pub normalized_data: Vec<u8>,
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// Decodes `data` to check that it really is an image, and re-encodes it as
+/// 8-bit sRGB: JPEG for JPEG sources, PNG for everything else. The EXIF
+/// orientation is baked into the pixels, and no metadata survives the
+/// re-encode.
+pub fn process_image(
+    data: &[u8],
+    options: &ImageProcessingOptions,
+) -> Result<ProcessedImage, ImageProcessingError> {
+    let format = image::guess_format(data).map_err(|_| ImageProcessingError::UnsupportedFormat)?;
+
+    let (width, height) = ImageReader::with_format(Cursor::new(data), format)
+        .into_dimensions()
+        .map_err(decode_error)?;
+    let too_many_pixels = ImageProcessingError::TooManyPixels {
+        width,
+        height,
+        max_pixels: options.max_pixels,
+    };
+    if u64::from(width) * u64::from(height) > options.max_pixels
+        || width > options.max_dimension
+        || height > options.max_dimension
+    {
+        return Err(too_many_pixels);
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(options.max_dimension);
+    limits.max_image_height = Some(options.max_dimension);
+    limits.max_alloc = Some(options.max_alloc);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    let image = reader
+        .into_decoder()
+        .and_then(|mut decoder| {
+            let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+            let mut image = DynamicImage::from_decoder(decoder)?;
+            image.apply_orientation(orientation);
+            Ok(image)
+        })
+        .map_err(|e| match e {
+            ImageError::Limits(_) => too_many_pixels,
+            e => decode_error(e),
+        })?;
+
+    let (oriented_width, oriented_height) = (image.width(), image.height());
+    let thumbnails = options
+        .thumbnail_sizes
+        .iter()
+        .map(|&size| thumbnail(&image, size))
+        .collect::<Result<_, _>>()?;
+    let preview = image
+        .thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+        .into_rgba8();
+    let blurhash = blurhash(&preview)?;
+    let dominant_color = dominant_color(&preview);
+    let perceptual_hash = perceptual_hash(&image);
+    let (normalized_data, normalized_format) = normalize(image, format)?;
+
+    let mut metadata = ImageMetadata::new(
+        oriented_width,
+        oriented_height,
+        format,
+        normalized_format,
+        options.metadata.clone(),
+    );
+    metadata.set_placeholder(blurhash, dominant_color);
+    metadata.set_perceptual_hash(format!("{:016x}", perceptual_hash));
+
+    Ok(ProcessedImage {
+        metadata,
+        normalized_data,
+        thumbnails,
+    })
+}
+
+/// Number of bits two perceptual hashes differ in, or `None` when either is
+/// not a hash. Images up to about 10 bits apart look the same to people.
+pub fn hash_distance(a: &str, b: &str) -> Option<u32> {
+    let a = u64::from_str_radix(a, 16).ok()?;
+    let b = u64::from_str_radix(b, 16).ok()?;
+    Some((a ^ b).count_ones())
+}
+
+/// 64-bit difference hash: each bit says whether a pixel of a 9x8 grayscale
+/// copy is darker than its right-hand neighbour. Survives re-encoding,
+/// resizing and small edits, unlike a hash of the bytes.
+fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// A BlurHash with more components along the image's long edge.
+fn blurhash(preview: &RgbaImage) -> Result<String, ImageProcessingError> {
+    let (components_x, components_y) = if preview.width() >= preview.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+
+    blurhash::encode(
+        components_x,
+        components_y,
+        preview.width(),
+        preview.height(),
+        preview.as_raw(),
+    )
+    .map_err(|e| ImageProcessingError::Encode(e.to_string()))
+}
+
+/// The most common colour as `#rrggbb`. Pixels are bucketed by the top four
+/// bits of each channel, and the winning bucket's pixels are averaged.
+/// Mostly transparent pixels don't count.
+fn dominant_color(preview: &RgbaImage) -> String {
+    let bucket = |pixel: &Rgba<u8>| {
+        let [r, g, b, _] = pixel.0;
+        (usize::from(r >> 4) << 8) | (usize::from(g >> 4) << 4) | usize::from(b >> 4)
+    };
+    let visible = || preview.pixels().filter(|pixel| pixel.0[3] >= 128);
+
+    let mut counts = vec![0u32; 4096];
+    for pixel in visible() {
+        counts[bucket(pixel)] += 1;
+    }
+    let winner = (0..counts.len())
+        .max_by_key(|&bucket| counts[bucket])
+        .unwrap_or_default();
+    if counts[winner] == 0 {
+        return "#000000".to_string();
+    }
+
+    let mut sums = [0u32; 3];
+    for pixel in visible().filter(|pixel| bucket(pixel) == winner) {
+        for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+            *sum += u32::from(channel);
+        }
+    }
+    let [r, g, b] = sums.map(|sum| sum / counts[winner]);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Scales `image` down so its long edge is at most `size` pixels, never up.
+/// Opaque thumbnails are JPEG; ones with transparency are lossless WebP.
+fn thumbnail(image: &DynamicImage, size: u32) -> Result<Thumbnail, ImageProcessingError> {
+    let resized = if image.width().max(image.height()) > size {
+        image.thumbnail(size, size)
+    } else {
+        image.clone()
+    };
+
+    let (width, height) = (resized.width(), resized.height());
+    let mut data = Vec::new();
+    let result = if resized.color().has_alpha() {
+        resized
+            .into_rgba8()
+            .write_with_encoder(WebPEncoder::new_lossless(&mut data))
+            .map(|_| ImageFormat::WebP)
+    } else {
+        resized
+            .into_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(
+                &mut data,
+                THUMBNAIL_JPEG_QUALITY,
+            ))
+            .map(|_| ImageFormat::Jpeg)
+    };
+    let format = result.map_err(|e| ImageProcessingError::Encode(e.to_string()))?;
+
+    Ok(Thumbnail::new(size, width, height, format, data))
+}
+
+fn normalize(
+    image: DynamicImage,
+    format: ImageFormat,
+) -> Result<(Vec<u8>, ImageFormat), ImageProcessingError> {
+    let mut normalized = Vec::new();
+    let result = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.into_rgba8())
+            .write_to(&mut Cursor::new(&mut normalized), ImageFormat::Png)
+            .map(|_| ImageFormat::Png)
+    } else if format == ImageFormat::Jpeg {
+        image
+            .into_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut normalized, JPEG_QUALITY))
+            .map(|_| ImageFormat::Jpeg)
+    } else {
+        DynamicImage::ImageRgb8(image.into_rgb8())
+            .write_to(&mut Cursor::new(&mut normalized), ImageFormat::Png)
+            .map(|_| ImageFormat::Png)
+    };
+
+    let normalized_format = result.map_err(|e| ImageProcessingError::Encode(e.to_string()))?;
+    Ok((normalized, normalized_format))
+}
+
+fn decode_error(error: ImageError) -> ImageProcessingError {
+    match error {
+        ImageError::Unsupported(_) => ImageProcessingError::UnsupportedFormat,
+        error => ImageProcessingError::Corrupt(error.to_string()),
+    }
+}
@@ -1,5 +1,26 @@
+pub mod auth;
+pub mod blobs;
+pub mod conversations;
 pub mod db;
+pub mod drafts;
 pub mod enums;
+pub mod errors;
+pub mod files;
+pub mod images;
+pub mod markdown;
+pub mod mentions;
 pub mod messaging;
+pub mod scanning;
+pub mod server;
+pub mod store;
 pub mod structs;
+pub mod subscriptions;
+pub mod threads;
+pub mod transfers;
+pub mod unfurl;
+pub mod uploads;
+pub mod users;
 pub mod utils;
+pub mod validate_and_get_env_vars;
+pub mod validation;
+pub mod webhooks;
@@ -1,32 +1,303 @@
-mod db;
-mod enums;
-mod messaging;
-mod structs;
-mod utils;
-mod validate_and_get_env_vars;
-
-use dotenv::dotenv;
-use enums::Message::{CryptoTransfer, File, Image, Text};
-use enums::{Message, MessageFromType, MessageType};
-use futures::stream::{StreamExt, TryStreamExt};
-use image::ImageReader;
-use messaging::{debug_saved_message, get_messages, insert_text_message};
-use mongodb::{
-    Client, Collection, Database,
-    bson::{Document, doc},
-    options::{ClientOptions, ServerApi, ServerApiVersion},
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use messaging::blobs::GridFsBlobStore;
+use messaging::conversations::get_conversation_settings;
+use messaging::enums::{Message, MessageFromType, MessageType, Role, TextFormat};
+use messaging::mentions::{record_mentions, resolve_mentions};
+use messaging::messaging::{
+    debug_saved_message, find_message_by_id, find_similar_images, get_messages_since,
+    insert_message,
 };
-use std::env;
-use structs::envvars::EnvVars;
-use structs::messages::{
+use messaging::store::MongoMessageStore;
+use messaging::structs::envvars::EnvVars;
+use messaging::structs::messages::{
     crypto::CryptoTransferMessagePackage, file::FileMessagePackage, image::ImageMessagePackage,
     text::TextMessagePackage,
 };
-use structs::user::User;
-use validate_and_get_env_vars::validate_and_get_env_vars;
+use messaging::structs::user::User;
+use messaging::subscriptions::{SubscriptionFilter, subscribe};
+use messaging::threads::resolve_reply;
+use messaging::users::{
+    delete_user, find_user_by_id, find_users_by_handle, get_users, insert_user,
+};
+use messaging::validate_and_get_env_vars::{
+    validate_and_get_env_vars, validate_and_get_file_options, validate_and_get_validation_options,
+};
+use mongodb::bson::to_document;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+#[command(name = "messaging", about = "Send and browse messages")]
+struct Cli {
+    /// How to print messages and users
+    #[arg(long, value_enum, global = true, default_value_t = Format::Human)]
+    format: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Human,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Send a message to a conversation
+    Send {
+        #[command(subcommand)]
+        message: SendCommand,
+    },
+    /// List a conversation's messages, oldest first
+    History {
+        #[arg(long)]
+        conversation: String,
+        /// Only show messages sent after this RFC 3339 timestamp
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Show a single message
+    Show { message_id: u32 },
+    /// List image messages that look like the given one, closest first
+    Similar {
+        message_id: u32,
+        /// Only search this conversation
+        #[arg(long)]
+        conversation: Option<String>,
+        /// Largest perceptual-hash distance, in bits, that still counts
+        #[arg(long, default_value_t = 10)]
+        max_distance: u32,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Follow new messages as they arrive
+    Tail {
+        #[arg(long)]
+        conversation: Option<String>,
+        #[arg(long = "type", value_enum)]
+        message_type: Option<MessageKind>,
+        #[arg(long, value_enum)]
+        from: Option<SenderType>,
+        /// Remember the position under this name and resume from it next time
+        #[arg(long)]
+        resume: Option<String>,
+        /// Seconds between polls when the server has no change streams
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Manage registered users
+    Users {
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+}
+
+#[derive(Debug, Args)]
+struct SendArgs {
+    #[arg(long)]
+    conversation: String,
+    /// Sender id of a registered user, see `users add`
+    #[arg(long)]
+    sender: u32,
+    /// Id of the message this one answers
+    #[arg(long)]
+    reply_to: Option<u32>,
+}
+
+#[derive(Debug, Subcommand)]
+enum SendCommand {
+    Text {
+        #[command(flatten)]
+        args: SendArgs,
+        /// Read the text as Markdown
+        #[arg(long)]
+        markdown: bool,
+        text: String,
+    },
+    Image {
+        #[command(flatten)]
+        args: SendArgs,
+        path: PathBuf,
+    },
+    File {
+        #[command(flatten)]
+        args: SendArgs,
+        path: PathBuf,
+    },
+    Crypto {
+        #[command(flatten)]
+        args: SendArgs,
+        #[arg(long)]
+        recipient: String,
+        #[arg(long)]
+        amount: f64,
+        #[arg(long, default_value = "SOL")]
+        token: String,
+        /// Signature of an already confirmed transaction
+        #[arg(long)]
+        signature: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum UsersCommand {
+    /// Register a new user and print its sender id
+    Add {
+        #[arg(long = "type", value_enum)]
+        sender_type: SenderType,
+        /// Moderators may edit and delete other people's messages, and
+        /// admins may also purge them
+        #[arg(long, value_enum, default_value_t = UserRole::Member)]
+        role: UserRole,
+        /// What others write after `@` to mention the user
+        #[arg(long)]
+        handle: Option<String>,
+    },
+    List,
+    Remove {
+        sender_id: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SenderType {
+    User,
+    Agent,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum UserRole {
+    Member,
+    Moderator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum MessageKind {
+    Text,
+    Image,
+    File,
+    Crypto,
+}
+
+impl From<MessageKind> for MessageType {
+    fn from(kind: MessageKind) -> Self {
+        match kind {
+            MessageKind::Text => MessageType::Text,
+            MessageKind::Image => MessageType::Image,
+            MessageKind::File => MessageType::File,
+            MessageKind::Crypto => MessageType::CryptoTransfer,
+        }
+    }
+}
+
+impl From<SenderType> for MessageFromType {
+    fn from(sender_type: SenderType) -> Self {
+        match sender_type {
+            SenderType::User => MessageFromType::User,
+            SenderType::Agent => MessageFromType::Agent,
+        }
+    }
+}
+
+impl From<UserRole> for Role {
+    fn from(role: UserRole) -> Self {
+        match role {
+            UserRole::Member => Role::Member,
+            UserRole::Moderator => Role::Moderator,
+            UserRole::Admin => Role::Admin,
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() -> mongodb::error::Result<()> {
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Send { message } => send(message, cli.format).await?,
+        Command::History {
+            conversation,
+            since,
+            limit,
+        } => {
+            let messages = get_messages_since(Some(&conversation), since, limit).await?;
+            for message in &messages {
+                print_message(message, cli.format)?;
+            }
+        }
+        Command::Show { message_id } => match find_message_by_id(message_id).await? {
+            Some(message) => print_message(&message, cli.format)?,
+            None => return Err(format!("Message {} was not found", message_id).into()),
+        },
+        Command::Similar {
+            message_id,
+            conversation,
+            max_distance,
+            limit,
+        } => {
+            let perceptual_hash = match find_message_by_id(message_id).await? {
+                Some(Message::Image(image)) => image
+                    .metadata()
+                    .perceptual_hash()
+                    .ok_or_else(|| format!("Message {} has no perceptual hash", message_id))?
+                    .to_string(),
+                Some(_) => return Err(format!("Message {} is not an image", message_id).into()),
+                None => return Err(format!("Message {} was not found", message_id).into()),
+            };
+            let similar = find_similar_images(
+                &perceptual_hash,
+                conversation.as_deref(),
+                max_distance,
+                limit + 1,
+            )
+            .await?;
+            for similar in similar
+                .iter()
+                .filter(|similar| similar.message.message_id() != message_id)
+                .take(limit)
+            {
+                match cli.format {
+                    Format::Human => {
+                        println!("Distance: {}", similar.distance);
+                        print_message(&similar.message, cli.format)?;
+                    }
+                    Format::Json => println!("{}", serde_json::to_string(similar)?),
+                }
+            }
+        }
+        Command::Tail {
+            conversation,
+            message_type,
+            from,
+            resume,
+            interval,
+        } => {
+            let filter = SubscriptionFilter {
+                conversation_id: conversation,
+                message_type: message_type.map(Into::into),
+                from: from.map(Into::into),
+            };
+            let mut messages =
+                subscribe(filter, resume.as_deref(), Duration::from_secs(interval)).await?;
+            while let Some(message) = messages.next().await {
+                print_message(&message, cli.format)?;
+            }
+        }
+        Command::Users { command } => users(command, cli.format).await?,
+    }
+
+    Ok(())
+}
+
+async fn send(command: SendCommand, format: Format) -> Result<(), Box<dyn Error>> {
     let EnvVars {
         send_text_messages,
         send_file_messages,
@@ -34,91 +305,157 @@ async fn main() -> mongodb::error::Result<()> {
         send_crypto_transfer_messages,
     } = validate_and_get_env_vars();
 
-    // ===== Get all messages from the database =====
-    let messages = get_messages().await?;
-    println!("Messages {:#?}", messages);
+    let (args, enabled) = match &command {
+        SendCommand::Text { args, .. } => (args, send_text_messages),
+        SendCommand::Image { args, .. } => (args, send_image_messages),
+        SendCommand::File { args, .. } => (args, send_file_messages),
+        SendCommand::Crypto { args, .. } => (args, send_crypto_transfer_messages),
+    };
+    if enabled != "true" {
+        return Err(
+            "Sending this type of message is disabled by its SEND_* environment variable".into(),
+        );
+    }
 
-    // ===== Send Text Messages =====
-    if !send_text_messages.is_empty() {
-        let user = User::new(MessageFromType::User);
-        let agent = User::new(MessageFromType::Agent);
+    let sender = find_user_by_id(args.sender)
+        .await?
+        .ok_or_else(|| format!("User {} is not registered", args.sender))?;
+    let sender_id = *sender.sender_id();
+    let sender_type = sender.sender_type().clone();
+    let conversation_id = args.conversation.clone();
+    let reply_to = args.reply_to;
 
-        // ===== Create a User text message =====
-        let text = "USER text message";
-        let user_text_message: Result<TextMessagePackage<'_>, String> =
-            TextMessagePackage::new(user, &text);
+    let mut message = match command {
+        SendCommand::Text { text, markdown, .. } => {
+            let format = if markdown {
+                TextFormat::Markdown
+            } else {
+                TextFormat::Plain
+            };
+            Message::Text(TextMessagePackage::with_options(
+                sender,
+                text,
+                format,
+                &validate_and_get_validation_options(),
+            )?)
+        }
+        SendCommand::Image { path, .. } => {
+            let settings = get_conversation_settings(&conversation_id).await?;
+            Message::Image(ImageMessagePackage::with_options(
+                sender_id,
+                sender_type,
+                std::fs::read(path)?,
+                &settings.image_processing_options(),
+            )?)
+        }
+        SendCommand::File { path, .. } => {
+            let filename = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("{} has no usable filename", path.display()))?;
+            validate_and_get_validation_options()
+                .file_name
+                .check(filename)?;
+            Message::File(FileMessagePackage::with_options(
+                sender_id,
+                sender_type,
+                filename,
+                std::fs::read(&path)?,
+                &validate_and_get_file_options(),
+            )?)
+        }
+        SendCommand::Crypto {
+            recipient,
+            amount,
+            token,
+            signature,
+            ..
+        } => {
+            let mut transfer = CryptoTransferMessagePackage::new(
+                sender_id,
+                sender_type,
+                recipient,
+                amount,
+                token,
+            )?;
+            if let Some(signature) = signature {
+                transfer.set_transaction_signature(signature);
+            }
+            Message::CryptoTransfer(transfer)
+        }
+    };
+    message.set_conversation_id(conversation_id);
+    if let Some(reply_to) = reply_to {
+        message.set_reply_to(reply_to);
+        resolve_reply(&MongoMessageStore, &mut message).await?;
+    }
+    resolve_mentions(&MongoMessageStore, &mut message).await?;
+    message
+        .store_attachments(&GridFsBlobStore::new().await?)
+        .await?;
 
-        match user_text_message {
-            Ok(message) => {
-                println!("Message {:?}", message);
-                let result = insert_text_message(message).await?;
+    insert_message(&message).await?;
+    record_mentions(&MongoMessageStore, &message, &[]).await?;
+    print_message(&message, format)
+}
 
-                if let Some(document) = result {
-                    debug_saved_message(document, true);
-                }
+async fn users(command: UsersCommand, format: Format) -> Result<(), Box<dyn Error>> {
+    match command {
+        UsersCommand::Add {
+            sender_type,
+            role,
+            handle,
+        } => {
+            let mut user = User::new(sender_type.into());
+            user.set_role(role.into());
+            if let Some(handle) = handle {
+                user.set_handle(handle.trim_start_matches('@'))?;
             }
-            Err(e) => {
-                println!("Error creating a message to insert {:?}", e);
+            if let Some(handle) = user.handle()
+                && !find_users_by_handle(&[handle.to_string()])
+                    .await?
+                    .is_empty()
+            {
+                return Err(format!("The handle @{} is taken", handle).into());
             }
+            insert_user(&user).await?;
+            print_user(&user, format)?;
         }
-
-        // =============================
-        // Insert an Agent text message
-        // =============================
-        let text = "AGENT text message";
-        let agent_text_message: Result<TextMessagePackage<'_>, String> =
-            TextMessagePackage::new(agent, &text);
-
-        match agent_text_message {
-            Ok(message) => {
-                println!("Message {:?}", message);
-                let result = insert_text_message(message).await?;
-                if let Some(document) = result {
-                    debug_saved_message(document, true);
-                }
+        UsersCommand::List => {
+            for user in get_users().await? {
+                print_user(&user, format)?;
             }
-            Err(e) => {
-                println!("Error creating a message to insert {:?}", e);
+        }
+        UsersCommand::Remove { sender_id } => {
+            if !delete_user(sender_id).await? {
+                return Err(format!("User {} is not registered", sender_id).into());
             }
         }
     }
 
-    // if send_file_text {
-    //     // =============================================
-    //     // Image Message Handling Section
-    //     // This section demonstrates creating and managing image messages
-    //     // File message handling is implemented in the File variant of Message enum
-    //     // =============================================
-
-    //     // Create sample file messages
-
-    //     const FILE_PATH: &str = "src/files/file.txt";
-    //     let file_data = std::fs::read(FILE_PATH);
-    //     match file_data {
-    //         Ok(data) => {
-    //             let agent_message: Result<FileMessagePackage, String> = FileMessagePackage::new(
-    //                 MessageFrom::Agent,
-    //                 data.clone()
-    //             );
-    //             let user_message: Result<FileMessagePackage, String> = FileMessagePackage::new(
-    //                 MessageFrom::User,
-    //                 data
-    //             );
-
-    //             match agent_message {
-    //                 Ok(message) => messaging::add_to_history(File(message)),
-    //                 Err(error) => println!("Could not add File message to history: {}", error)
-    //             }
-
-    //             match user_message {
-    //                 Ok(message) => messaging::add_to_history(File(message)),
-    //                 Err(error) => println!("Could not add File message to history: {}", error)
-    //             }
-    //         },
-    //         Err(error) => println!("Error reading FILE: {:?}", error)
-    //     }
-
-    // }
+    Ok(())
+}
+
+fn print_message(message: &Message<'_>, format: Format) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Human => {
+            debug_saved_message(to_document(message)?, true);
+            println!();
+        }
+        Format::Json => println!("{}", serde_json::to_string(message)?),
+    }
+
+    Ok(())
+}
+
+fn print_user(user: &User, format: Format) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Human => match user.handle() {
+            Some(handle) => println!("{} @{} ({})", user.sender_id(), handle, user.sender_type()),
+            None => println!("{} ({})", user.sender_id(), user.sender_type()),
+        },
+        Format::Json => println!("{}", serde_json::to_string(user)?),
+    }
 
     Ok(())
 }
@@ -0,0 +1,379 @@
+use crate::errors::MarkdownError;
+use crate::structs::markdown::{Block, Inline};
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag, TagEnd};
+use std::ops::Range;
+
+/// How deeply lists, emphasis and links may be nested in one another.
+pub const MAX_NESTING: usize = 8;
+
+const LINK_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// What is being built while the parser's events are walked.
+enum Frame {
+    Blocks(Vec<Block>),
+    /// Headings, quotes and raw HTML end up as plain paragraphs.
+    Paragraph(Vec<Inline>),
+    List {
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+    CodeBlock {
+        language: Option<String>,
+        code: String,
+    },
+    Strong(Vec<Inline>),
+    Emphasis(Vec<Inline>),
+    Link {
+        url: String,
+        content: Vec<Inline>,
+    },
+}
+
+/// Parses `text` into the supported subset: paragraphs, bold, italics, code
+/// spans, code blocks, links and lists. Anything else is kept as plain
+/// text. Links to anything but web pages and email addresses are refused.
+pub fn parse(text: &str) -> Result<Vec<Block>, MarkdownError> {
+    let mut stack = vec![Frame::Blocks(Vec::new())];
+    for event in Parser::new_ext(text, Options::empty()) {
+        match event {
+            Event::Start(tag) => start(&mut stack, tag)?,
+            Event::End(tag) => end(&mut stack, tag),
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => {
+                if let Some(Frame::CodeBlock { code, .. }) = stack.last_mut() {
+                    code.push_str(&text);
+                } else {
+                    push_inline(&mut stack, Inline::Text { text: text.into() });
+                }
+            }
+            Event::Code(code) => push_inline(&mut stack, Inline::Code { code: code.into() }),
+            Event::SoftBreak | Event::HardBreak => push_inline(&mut stack, Inline::LineBreak),
+            _ => {}
+        }
+        if nesting(&stack) > MAX_NESTING {
+            return Err(MarkdownError::TooDeep(MAX_NESTING));
+        }
+    }
+
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    match stack.pop() {
+        Some(Frame::Blocks(blocks)) => Ok(blocks),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Byte ranges of `text` taken up by code spans and code blocks, where
+/// nothing else is markup.
+pub fn code_ranges(text: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(text, Options::empty())
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect()
+}
+
+fn nesting(stack: &[Frame]) -> usize {
+    stack
+        .iter()
+        .filter(|frame| {
+            matches!(
+                frame,
+                Frame::List { .. } | Frame::Strong(_) | Frame::Emphasis(_) | Frame::Link { .. }
+            )
+        })
+        .count()
+}
+
+fn start(stack: &mut Vec<Frame>, tag: Tag<'_>) -> Result<(), MarkdownError> {
+    let frame = match tag {
+        Tag::Paragraph | Tag::Heading { .. } | Tag::HtmlBlock => {
+            close_paragraph(stack);
+            Frame::Paragraph(Vec::new())
+        }
+        Tag::CodeBlock(kind) => {
+            close_paragraph(stack);
+            let language = match kind {
+                CodeBlockKind::Fenced(info) => info
+                    .split_whitespace()
+                    .next()
+                    .filter(|language| {
+                        language
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "+-_.#".contains(c))
+                    })
+                    .map(str::to_ascii_lowercase),
+                CodeBlockKind::Indented => None,
+            };
+            Frame::CodeBlock {
+                language,
+                code: String::new(),
+            }
+        }
+        Tag::List(start) => {
+            close_paragraph(stack);
+            Frame::List {
+                start,
+                items: Vec::new(),
+            }
+        }
+        Tag::Item => Frame::Blocks(Vec::new()),
+        Tag::Strong => open_inline(stack, Frame::Strong(Vec::new())),
+        Tag::Emphasis => open_inline(stack, Frame::Emphasis(Vec::new())),
+        Tag::Link {
+            link_type,
+            dest_url,
+            ..
+        }
+        | Tag::Image {
+            link_type,
+            dest_url,
+            ..
+        } => {
+            let url = match link_type {
+                LinkType::Email => format!("mailto:{}", dest_url),
+                _ => dest_url.to_string(),
+            };
+            if !is_safe_url(&url) {
+                return Err(MarkdownError::UnsafeLink(url));
+            }
+            open_inline(
+                stack,
+                Frame::Link {
+                    url,
+                    content: Vec::new(),
+                },
+            )
+        }
+        // Quotes and anything else only contribute their text.
+        _ => return Ok(()),
+    };
+    stack.push(frame);
+
+    Ok(())
+}
+
+fn end(stack: &mut Vec<Frame>, tag: TagEnd) {
+    match tag {
+        TagEnd::Paragraph
+        | TagEnd::Heading(_)
+        | TagEnd::HtmlBlock
+        | TagEnd::CodeBlock
+        | TagEnd::Strong
+        | TagEnd::Emphasis
+        | TagEnd::Link
+        | TagEnd::Image => close(stack),
+        TagEnd::Item => {
+            close_paragraph(stack);
+            close(stack);
+        }
+        TagEnd::List(_) => {
+            close_paragraph(stack);
+            close(stack);
+        }
+        _ => {}
+    }
+}
+
+/// Pops the innermost frame into the one around it.
+fn close(stack: &mut Vec<Frame>) {
+    if stack.len() < 2 {
+        return;
+    }
+    let Some(frame) = stack.pop() else {
+        return;
+    };
+    match frame {
+        Frame::Blocks(blocks) => {
+            if let Some(Frame::List { items, .. }) = stack.last_mut() {
+                items.push(blocks);
+            } else {
+                for block in blocks {
+                    push_block(stack, block);
+                }
+            }
+        }
+        Frame::Paragraph(content) => {
+            if !content.is_empty() {
+                push_block(stack, Block::Paragraph { content });
+            }
+        }
+        Frame::List { start, items } => push_block(stack, Block::List { start, items }),
+        Frame::CodeBlock { language, code } => {
+            push_block(stack, Block::CodeBlock { language, code })
+        }
+        Frame::Strong(content) => push_inline(stack, Inline::Strong { content }),
+        Frame::Emphasis(content) => push_inline(stack, Inline::Emphasis { content }),
+        Frame::Link { url, content } => push_inline(stack, Inline::Link { url, content }),
+    }
+}
+
+/// Ends the paragraph tight list items put their text in, so a block can
+/// follow it.
+fn close_paragraph(stack: &mut Vec<Frame>) {
+    if matches!(stack.last(), Some(Frame::Paragraph(_))) {
+        close(stack);
+    }
+}
+
+fn open_inline(stack: &mut Vec<Frame>, frame: Frame) -> Frame {
+    if matches!(stack.last(), Some(Frame::Blocks(_))) {
+        stack.push(Frame::Paragraph(Vec::new()));
+    }
+    frame
+}
+
+/// Blocks always close straight into a list item or the top level, since
+/// `close_paragraph` runs before any block starts.
+fn push_block(stack: &mut [Frame], block: Block) {
+    if let Some(Frame::Blocks(blocks)) = stack.last_mut() {
+        blocks.push(block);
+    }
+}
+
+fn push_inline(stack: &mut Vec<Frame>, inline: Inline) {
+    if matches!(stack.last(), Some(Frame::Blocks(_))) {
+        stack.push(Frame::Paragraph(Vec::new()));
+    }
+    let content = match stack.last_mut() {
+        Some(
+            Frame::Paragraph(content)
+            | Frame::Strong(content)
+            | Frame::Emphasis(content)
+            | Frame::Link { content, .. },
+        ) => content,
+        _ => return,
+    };
+    // The parser splits text at every character it might have treated as
+    // markup; join it back up.
+    if let (Some(Inline::Text { text }), Inline::Text { text: more }) =
+        (content.last_mut(), &inline)
+    {
+        text.push_str(more);
+        return;
+    }
+    content.push(inline);
+}
+
+fn is_safe_url(url: &str) -> bool {
+    let Some((scheme, _)) = url.split_once(':') else {
+        return false;
+    };
+    LINK_SCHEMES
+        .iter()
+        .any(|allowed| scheme.eq_ignore_ascii_case(allowed))
+}
+
+/// Renders parsed Markdown as an HTML fragment. Every piece of text is
+/// escaped and only a fixed set of tags is produced, so the result is safe
+/// to insert into a page as is.
+pub fn render_html(blocks: &[Block]) -> String {
+    let mut html = String::new();
+    for block in blocks {
+        render_block(&mut html, block);
+    }
+    html
+}
+
+/// Renders plain text the way a Markdown paragraph would be.
+pub fn render_plain_html(text: &str) -> String {
+    let content = text
+        .split('\n')
+        .enumerate()
+        .flat_map(|(index, line)| {
+            let text = Inline::Text { text: line.into() };
+            if index == 0 {
+                vec![text]
+            } else {
+                vec![Inline::LineBreak, text]
+            }
+        })
+        .collect();
+    render_html(&[Block::Paragraph { content }])
+}
+
+fn render_block(html: &mut String, block: &Block) {
+    match block {
+        Block::Paragraph { content } => {
+            html.push_str("<p>");
+            render_inlines(html, content);
+            html.push_str("</p>");
+        }
+        Block::CodeBlock { language, code } => {
+            html.push_str("<pre><code");
+            if let Some(language) = language {
+                html.push_str(" class=\"language-");
+                push_escaped(html, language);
+                html.push('"');
+            }
+            html.push('>');
+            push_escaped(html, code);
+            html.push_str("</code></pre>");
+        }
+        Block::List { start, items } => {
+            match start {
+                Some(1) => html.push_str("<ol>"),
+                Some(start) => html.push_str(&format!("<ol start=\"{}\">", start)),
+                None => html.push_str("<ul>"),
+            }
+            for item in items {
+                html.push_str("<li>");
+                for block in item {
+                    render_block(html, block);
+                }
+                html.push_str("</li>");
+            }
+            html.push_str(if start.is_some() { "</ol>" } else { "</ul>" });
+        }
+    }
+}
+
+fn render_inlines(html: &mut String, inlines: &[Inline]) {
+    for inline in inlines {
+        match inline {
+            Inline::Text { text } => push_escaped(html, text),
+            Inline::Strong { content } => {
+                html.push_str("<strong>");
+                render_inlines(html, content);
+                html.push_str("</strong>");
+            }
+            Inline::Emphasis { content } => {
+                html.push_str("<em>");
+                render_inlines(html, content);
+                html.push_str("</em>");
+            }
+            Inline::Code { code } => {
+                html.push_str("<code>");
+                push_escaped(html, code);
+                html.push_str("</code>");
+            }
+            Inline::Link { url, content } => {
+                // The AST may have come from a stored document rather than
+                // from `parse`, so the scheme is checked again.
+                if !is_safe_url(url) {
+                    render_inlines(html, content);
+                    continue;
+                }
+                html.push_str("<a href=\"");
+                push_escaped(html, url);
+                html.push_str("\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">");
+                render_inlines(html, content);
+                html.push_str("</a>");
+            }
+            Inline::LineBreak => html.push_str("<br>"),
+        }
+    }
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
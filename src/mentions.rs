@@ -0,0 +1,112 @@
+use crate::enums::Message;
+use crate::enums::TextFormat;
+use crate::errors::StoreError;
+use crate::markdown::code_ranges;
+use crate::store::MessageStore;
+use crate::structs::mention::{InboxMention, Mention};
+use crate::structs::user::{MAX_HANDLE_LENGTH, MIN_HANDLE_LENGTH, is_handle_char};
+use std::collections::BTreeSet;
+
+/// The `@handle`s in `text`, lowercased, with the byte range each one takes
+/// up including its `@`. An `@` right after a letter or digit, as in an
+/// email address, doesn't start a handle.
+pub fn parse_handles(text: &str) -> Vec<(usize, usize, String)> {
+    let mut handles = Vec::new();
+    let mut previous = None;
+    for (start, c) in text.char_indices() {
+        let after_word =
+            previous.is_some_and(|previous| is_handle_char(previous) || previous == '@');
+        previous = Some(c);
+        if c != '@' || after_word {
+            continue;
+        }
+
+        let handle: String = text[start + 1..]
+            .chars()
+            .take_while(|c| is_handle_char(*c))
+            .collect();
+        if (MIN_HANDLE_LENGTH..=MAX_HANDLE_LENGTH).contains(&handle.len()) {
+            let end = start + 1 + handle.len();
+            handles.push((start, end, handle.to_ascii_lowercase()));
+        }
+    }
+
+    handles
+}
+
+/// Fills in the mentions of a text message from its text, replacing
+/// whatever the client sent. Handles nobody is registered under, and those
+/// inside Markdown code, are left as plain text.
+pub async fn resolve_mentions(
+    store: &dyn MessageStore,
+    message: &mut Message<'_>,
+) -> Result<(), StoreError> {
+    let Message::Text(text) = message else {
+        return Ok(());
+    };
+    let mut handles = parse_handles(text.message());
+    if text.format() == TextFormat::Markdown {
+        let code = code_ranges(text.message());
+        handles.retain(|(start, _, _)| !code.iter().any(|range| range.contains(start)));
+    }
+    if handles.is_empty() {
+        text.set_mentions(Vec::new());
+        return Ok(());
+    }
+
+    let wanted: Vec<String> = handles
+        .iter()
+        .map(|(_, _, handle)| handle.clone())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let users = store.users_by_handle(&wanted).await?;
+    let mentions = handles
+        .into_iter()
+        .filter_map(|(start, end, handle)| {
+            let user = users.iter().find(|user| user.handle() == Some(&handle))?;
+            Some(Mention::new(*user.sender_id(), handle, start, end))
+        })
+        .collect();
+    text.set_mentions(mentions);
+
+    Ok(())
+}
+
+/// Puts a stored message in the inbox of everyone it mentions, other than
+/// its sender, and returns the entries for those not already in
+/// `previous`, the mentions before an edit.
+pub async fn record_mentions(
+    store: &dyn MessageStore,
+    message: &Message<'_>,
+    previous: &[Mention],
+) -> Result<Vec<InboxMention>, StoreError> {
+    let conversation_id = message.conversation_id().unwrap_or_default();
+    let mentioned: BTreeSet<u32> = message
+        .mentions()
+        .iter()
+        .map(Mention::sender_id)
+        .filter(|sender_id| *sender_id != message.sender_id())
+        .collect();
+    let entries: Vec<InboxMention> = mentioned
+        .into_iter()
+        .map(|sender_id| {
+            InboxMention::new(
+                message.message_id(),
+                conversation_id.to_string(),
+                sender_id,
+                message.sender_id(),
+            )
+        })
+        .collect();
+    store.save_mentions(message.message_id(), &entries).await?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| {
+            !previous
+                .iter()
+                .any(|mention| mention.sender_id() == entry.sender_id())
+        })
+        .collect())
+}
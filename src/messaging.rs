@@ -1,15 +1,34 @@
+use crate::blobs::BlobStore;
 use crate::db::get_collection;
 use crate::enums::Message;
-use crate::enums::Message::{File, Image};
-use crate::enums::MessageFromType;
-use crate::enums::MessageType;
-use crate::structs::messages::text::TextMessagePackage;
+use crate::errors::{BlobError, MessageError};
+use crate::files::FileOptions;
+use crate::images::{ImageProcessingOptions, hash_distance};
+use crate::structs::history::HistoryCursor;
+use crate::structs::mention::InboxMention;
+use crate::structs::messages::{
+    crypto::CryptoTransferMessagePackage,
+    file::FileMessagePackage,
+    image::{ImageMessagePackage, SimilarImage},
+    text::TextMessagePackage,
+};
+use crate::structs::preview::LinkPreview;
+use crate::structs::reaction::Reaction;
+use crate::structs::receipt::Receipt;
+use crate::structs::revision::MessageRevision;
+use crate::structs::scheduled::ScheduledMessage;
+use crate::structs::user::User;
+use crate::utils::sortable_timestamp;
+use crate::validation::ValidationOptions;
+use chrono::{DateTime, SecondsFormat, Utc};
 use futures::stream::{StreamExt, TryStreamExt};
-use mongodb::bson::{doc, to_document, Bson, DateTime, Document};
-use once_cell::sync::Lazy;
-use std::sync::Mutex;
+use mongodb::IndexModel;
+use mongodb::bson::{Bson, Document, Regex, doc, from_bson, from_document, to_bson, to_document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use std::collections::BTreeMap;
 
-pub async fn get_messages<'a>()
+pub async fn get_messages()
 -> Result<Vec<Result<Document, mongodb::error::Error>>, mongodb::error::Error> {
     let collection = get_collection("message_history").await?;
     let cursor = collection.find(doc! { "type": "text" }).await?;
@@ -18,24 +37,865 @@ pub async fn get_messages<'a>()
     Ok(v)
 }
 
-// pub async fn insert_message<'a>(message: Message<'a>) {
-//     println!("db::saveMessage(): {:?}", message);
-//     let collection = get_collection("message_history").await?;
-//     let cursor = collection.insert_one(message).await?;
-//     let v: Vec<Result<Document, mongodb::error::Error>> = cursor.collect().await;
+pub async fn insert_message(
+    message: &Message<'_>,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let insert_one_result = collection.insert_one(to_document(message)?).await?;
+    let document = collection
+        .find_one(doc! { "_id": insert_one_result.inserted_id })
+        .await?;
 
-//     Ok(())
-// }
+    Ok(document)
+}
 
-pub async fn insert_text_message<'a>(
-    message: TextMessagePackage<'a>,
-) -> Result<Option<Document>, mongodb::error::Error> {
-    println!("db::saveMessage(): {:?}", message);
+pub async fn find_message_by_id(
+    message_id: u32,
+) -> Result<Option<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let document = collection
+        .find_one(doc! { "message_id": message_id })
+        .await?;
+
+    Ok(document.map(from_document).transpose()?)
+}
+
+/// Lists a conversation's messages newest first. When `before` is given the
+/// page starts right after that cursor, and when `viewer` is given the
+/// messages they deleted for themselves are left out.
+pub async fn get_conversation_messages(
+    conversation_id: &str,
+    before: Option<&HistoryCursor>,
+    viewer: Option<u32>,
+    limit: i64,
+) -> Result<Vec<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let mut filter = doc! { "conversation_id": conversation_id };
+    if let Some(viewer) = viewer {
+        let hidden = hidden_message_ids(conversation_id, viewer).await?;
+        if !hidden.is_empty() {
+            filter.insert("message_id", doc! { "$nin": hidden });
+        }
+    }
+    if let Some(cursor) = before {
+        let timestamp = sortable_timestamp::format(&cursor.timestamp());
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "timestamp": { "$lt": &timestamp } },
+                doc! { "timestamp": &timestamp, "message_id": { "$lt": cursor.message_id() } },
+            ],
+        );
+    }
+
+    let documents: Vec<Document> = collection
+        .find(filter)
+        .sort(doc! { "timestamp": -1, "message_id": -1 })
+        .limit(limit)
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+/// Lists messages oldest first, optionally limited to one conversation and to
+/// messages sent after `since`.
+pub async fn get_messages_since(
+    conversation_id: Option<&str>,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+) -> Result<Vec<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let mut filter = doc! {};
+    if let Some(conversation_id) = conversation_id {
+        filter.insert("conversation_id", conversation_id);
+    }
+    if let Some(since) = since {
+        filter.insert(
+            "timestamp",
+            doc! { "$gt": sortable_timestamp::format(&since) },
+        );
+    }
+
+    let documents: Vec<Document> = collection
+        .find(filter)
+        .sort(doc! { "timestamp": 1, "message_id": 1 })
+        .limit(limit)
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+/// Replaces the content of a stored message with the content of `message`,
+/// keeping its id, sender, type, timestamp and conversation. Returns the
+/// message as it was and as it is now.
+pub async fn update_message(
+    message_id: u32,
+    message: &Message<'_>,
+) -> Result<Option<(Message<'static>, Message<'static>)>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let content = content_fields(message)?;
+    let Some(previous) = collection
+        .find_one_and_update(doc! { "message_id": message_id }, doc! { "$set": content })
+        .await?
+    else {
+        return Ok(None);
+    };
+    let Some(updated) = find_message_by_id(message_id).await? else {
+        return Ok(None);
+    };
+
+    Ok(Some((from_document(previous)?, updated)))
+}
+
+/// The replies in the thread `thread_root` started, oldest first, leaving
+/// out those `viewer` deleted for themselves.
+pub async fn get_thread_replies(
+    conversation_id: &str,
+    thread_root: u32,
+    viewer: Option<u32>,
+) -> Result<Vec<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let mut filter = doc! { "conversation_id": conversation_id, "thread_root": thread_root };
+    if let Some(viewer) = viewer {
+        let hidden = hidden_message_ids(conversation_id, viewer).await?;
+        if !hidden.is_empty() {
+            filter.insert("message_id", doc! { "$nin": hidden });
+        }
+    }
+
+    let documents: Vec<Document> = collection
+        .find(filter)
+        .sort(doc! { "timestamp": 1, "message_id": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+/// How many replies, not counting deleted ones, each of `thread_roots` has.
+/// Threads without replies are left out.
+pub async fn count_replies(
+    thread_roots: &[u32],
+) -> Result<BTreeMap<u32, u64>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let mut cursor = collection
+        .aggregate(vec![
+            doc! { "$match": { "thread_root": { "$in": thread_roots }, "type": { "$ne": "Deleted" } } },
+            doc! { "$group": { "_id": "$thread_root", "count": { "$sum": 1 } } },
+        ])
+        .await?;
+
+    let mut counts = BTreeMap::new();
+    while let Some(mut document) = cursor.try_next().await? {
+        if let (Some(thread_root), Some(count)) = (document.remove("_id"), document.remove("count"))
+        {
+            counts.insert(from_bson(thread_root)?, from_bson(count)?);
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Swaps a stored message for `message` whole, rather than updating its
+/// content like `update_message`. Returns the message that was replaced.
+pub async fn replace_message(
+    message_id: u32,
+    message: &Message<'_>,
+) -> Result<Option<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let previous = collection
+        .find_one_and_replace(doc! { "message_id": message_id }, to_document(message)?)
+        .await?;
+
+    Ok(previous.map(from_document).transpose()?)
+}
+
+/// Marks a crypto transfer confirmed with `signature`, unless it already
+/// was. Returns the message if this call confirmed it.
+pub async fn confirm_transfer(
+    message_id: u32,
+    signature: &str,
+) -> Result<Option<Message<'static>>, mongodb::error::Error> {
     let collection = get_collection("message_history").await?;
-    let insert_one_result = collection
-        .insert_one(to_document(&message).unwrap())
+    let confirmed = collection
+        .find_one_and_update(
+            doc! { "message_id": message_id, "type": "CryptoTransfer", "confirmed": { "$ne": true } },
+            doc! { "$set": { "transaction_signature": signature, "confirmed": true } },
+        )
+        .return_document(ReturnDocument::After)
         .await?;
-    // println!("db::saveMessage result: {:?}", result);
+
+    Ok(confirmed.map(from_document).transpose()?)
+}
+
+/// Sets the link previews of a text message, as long as its text is still
+/// `text`. Returns the message if they were attached.
+pub async fn attach_previews(
+    message_id: u32,
+    text: &str,
+    previews: &[LinkPreview],
+) -> Result<Option<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let update_result = collection
+        .update_one(
+            doc! { "message_id": message_id, "type": "Text", "message": text },
+            doc! { "$set": { "previews": to_bson(previews)? } },
+        )
+        .await?;
+    if update_result.matched_count == 0 {
+        return Ok(None);
+    }
+
+    find_message_by_id(message_id).await
+}
+
+/// Removes a message along with its revisions, its reactions, the mentions
+/// it put in inboxes and everyone's record of having hidden it. Returns the
+/// message that was removed.
+pub async fn delete_message(
+    message_id: u32,
+) -> Result<Option<Message<'static>>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let deleted = collection
+        .find_one_and_delete(doc! { "message_id": message_id })
+        .await?;
+    delete_revisions(message_id).await?;
+    delete_reactions(message_id).await?;
+    save_mentions(message_id, &[]).await?;
+    get_collection("hidden_messages")
+        .await?
+        .delete_many(doc! { "message_id": message_id })
+        .await?;
+
+    Ok(deleted.map(from_document).transpose()?)
+}
+
+/// Hides a message from one user's history, leaving it for everyone else.
+pub async fn hide_message(
+    message_id: u32,
+    conversation_id: &str,
+    sender_id: u32,
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("hidden_messages").await?;
+    let hidden = doc! {
+        "message_id": message_id,
+        "conversation_id": conversation_id,
+        "sender_id": sender_id,
+    };
+    collection
+        .update_one(hidden.clone(), doc! { "$setOnInsert": hidden })
+        .upsert(true)
+        .await?;
+
+    Ok(())
+}
+
+async fn hidden_message_ids(
+    conversation_id: &str,
+    sender_id: u32,
+) -> Result<Vec<u32>, mongodb::error::Error> {
+    let collection = get_collection("hidden_messages").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! { "conversation_id": conversation_id, "sender_id": sender_id })
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(documents
+        .into_iter()
+        .filter_map(|mut document| document.remove("message_id"))
+        .filter_map(|message_id| from_bson(message_id).ok())
+        .collect())
+}
+
+/// The fields of a message that an update is allowed to change.
+pub fn content_fields(message: &Message<'_>) -> Result<Document, mongodb::bson::ser::Error> {
+    const IDENTITY_FIELDS: [&str; 10] = [
+        "_id",
+        "message_id",
+        "type",
+        "sender",
+        "sender_id",
+        "from",
+        "timestamp",
+        "conversation_id",
+        "reply_to",
+        "thread_root",
+    ];
+
+    let mut document = to_document(message)?;
+    for field in IDENTITY_FIELDS {
+        document.remove(field);
+    }
+
+    Ok(document)
+}
+
+/// Image messages whose perceptual hash is within `max_distance` bits of
+/// `perceptual_hash`, closest first, optionally limited to one conversation.
+pub async fn find_similar_images(
+    perceptual_hash: &str,
+    conversation_id: Option<&str>,
+    max_distance: u32,
+    limit: usize,
+) -> Result<Vec<SimilarImage>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let mut filter = doc! { "type": "Image", "metadata.perceptual_hash": { "$exists": true } };
+    if let Some(conversation_id) = conversation_id {
+        filter.insert("conversation_id", conversation_id);
+    }
+
+    let documents: Vec<Document> = collection
+        .find(filter)
+        .projection(doc! { "message_id": 1, "metadata.perceptual_hash": 1 })
+        .await?
+        .try_collect()
+        .await?;
+    let mut matches: Vec<(u32, u32)> = documents
+        .iter()
+        .filter_map(|document| {
+            let message_id = match document.get("message_id")? {
+                Bson::Int64(message_id) => u32::try_from(*message_id).ok()?,
+                Bson::Int32(message_id) => u32::try_from(*message_id).ok()?,
+                _ => return None,
+            };
+            let hash = document
+                .get_document("metadata")
+                .and_then(|metadata| metadata.get_str("perceptual_hash"))
+                .ok()?;
+            let distance = hash_distance(perceptual_hash, hash)?;
+            (distance <= max_distance).then_some((distance, message_id))
+        })
+        .collect();
+    matches.sort();
+    matches.truncate(limit);
+
+    let mut similar = Vec::with_capacity(matches.len());
+    for (distance, message_id) in matches {
+        if let Some(message) = find_message_by_id(message_id).await? {
+            similar.push(SimilarImage { message, distance });
+        }
+    }
+
+    Ok(similar)
+}
+
+pub async fn insert_receipt(receipt: &Receipt) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("message_receipts").await?;
+    collection.insert_one(to_document(receipt)?).await?;
+
+    Ok(())
+}
+
+/// Makes `mentions` the inbox entries for `message_id`: entries for users
+/// no longer mentioned are removed, and those already there keep whether
+/// they were read.
+pub async fn save_mentions(
+    message_id: u32,
+    mentions: &[InboxMention],
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("mention_inbox").await?;
+    let sender_ids: Vec<u32> = mentions.iter().map(InboxMention::sender_id).collect();
+    collection
+        .delete_many(doc! { "message_id": message_id, "sender_id": { "$nin": sender_ids } })
+        .await?;
+    for mention in mentions {
+        collection
+            .update_one(
+                doc! { "message_id": message_id, "sender_id": mention.sender_id() },
+                doc! { "$setOnInsert": to_document(mention)? },
+            )
+            .upsert(true)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// A user's mentions, newest first.
+pub async fn find_mention_inbox(
+    sender_id: u32,
+    unread_only: bool,
+    limit: usize,
+) -> Result<Vec<InboxMention>, mongodb::error::Error> {
+    let collection = get_collection("mention_inbox").await?;
+    let mut filter = doc! { "sender_id": sender_id };
+    if unread_only {
+        filter.insert("read_at", doc! { "$exists": false });
+    }
+    let documents: Vec<Document> = collection
+        .find(filter)
+        .sort(doc! { "timestamp": -1, "message_id": -1 })
+        .limit(limit as i64)
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+/// Says whether the user had such a mention.
+pub async fn mark_mention_read(
+    sender_id: u32,
+    message_id: u32,
+) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("mention_inbox").await?;
+    let read_at = Utc::now().to_rfc3339_opts(SecondsFormat::AutoSi, true);
+    let update_result = collection
+        .update_one(
+            doc! { "message_id": message_id, "sender_id": sender_id, "read_at": { "$exists": false } },
+            doc! { "$set": { "read_at": read_at } },
+        )
+        .await?;
+    if update_result.matched_count > 0 {
+        return Ok(true);
+    }
+
+    Ok(collection
+        .count_documents(doc! { "message_id": message_id, "sender_id": sender_id })
+        .await?
+        > 0)
+}
+
+/// Stores `reaction` unless the user already reacted with that emoji, and
+/// says whether it was new.
+pub async fn insert_reaction(reaction: &Reaction) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("message_reactions").await?;
+    let update_result = collection
+        .update_one(
+            doc! {
+                "message_id": reaction.message_id(),
+                "sender_id": reaction.sender_id(),
+                "emoji": reaction.emoji(),
+            },
+            doc! { "$setOnInsert": to_document(reaction)? },
+        )
+        .upsert(true)
+        .await;
+
+    match update_result {
+        Ok(update_result) => Ok(update_result.upserted_id.is_some()),
+        // A racing upsert of the same reaction won the unique index.
+        Err(e) if is_duplicate_key(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Makes sure a user can't react to a message with the same emoji twice,
+/// even when the requests race.
+pub async fn ensure_reaction_index() -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("message_reactions").await?;
+    let index = IndexModel::builder()
+        .keys(doc! { "message_id": 1, "sender_id": 1, "emoji": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    collection.create_index(index).await?;
+
+    Ok(())
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        &*error.kind,
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+pub async fn delete_reaction(
+    message_id: u32,
+    sender_id: u32,
+    emoji: &str,
+) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("message_reactions").await?;
+    let delete_result = collection
+        .delete_one(doc! { "message_id": message_id, "sender_id": sender_id, "emoji": emoji })
+        .await?;
+
+    Ok(delete_result.deleted_count > 0)
+}
+
+pub async fn delete_reactions(message_id: u32) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("message_reactions").await?;
+    collection
+        .delete_many(doc! { "message_id": message_id })
+        .await?;
+
+    Ok(())
+}
+
+/// A message's reactions, oldest first.
+pub async fn find_reactions(message_id: u32) -> Result<Vec<Reaction>, mongodb::error::Error> {
+    let collection = get_collection("message_reactions").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! { "message_id": message_id })
+        .sort(doc! { "timestamp": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+/// How often each emoji was used on each of `message_ids`. Messages without
+/// reactions are left out.
+pub async fn count_reactions(
+    message_ids: &[u32],
+) -> Result<BTreeMap<u32, BTreeMap<String, u64>>, mongodb::error::Error> {
+    let collection = get_collection("message_reactions").await?;
+    let mut cursor = collection
+        .aggregate(vec![
+            doc! { "$match": { "message_id": { "$in": message_ids } } },
+            doc! { "$group": {
+                "_id": { "message_id": "$message_id", "emoji": "$emoji" },
+                "count": { "$sum": 1 },
+            } },
+        ])
+        .await?;
+
+    let mut counts: BTreeMap<u32, BTreeMap<String, u64>> = BTreeMap::new();
+    while let Some(mut document) = cursor.try_next().await? {
+        let Some(count) = document.remove("count") else {
+            continue;
+        };
+        let Ok(group) = document.get_document_mut("_id") else {
+            continue;
+        };
+        if let (Some(message_id), Some(emoji)) = (group.remove("message_id"), group.remove("emoji"))
+        {
+            counts
+                .entry(from_bson(message_id)?)
+                .or_default()
+                .insert(from_bson(emoji)?, from_bson(count)?);
+        }
+    }
+
+    Ok(counts)
+}
+
+pub async fn insert_revision(revision: &MessageRevision) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("message_revisions").await?;
+    collection.insert_one(to_document(revision)?).await?;
+
+    Ok(())
+}
+
+pub async fn delete_revisions(message_id: u32) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("message_revisions").await?;
+    collection
+        .delete_many(doc! { "message_id": message_id })
+        .await?;
+
+    Ok(())
+}
+
+/// Earlier versions of a message, oldest first.
+pub async fn find_revisions(
+    message_id: u32,
+) -> Result<Vec<MessageRevision>, mongodb::error::Error> {
+    let collection = get_collection("message_revisions").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! { "message_id": message_id })
+        .sort(doc! { "edited_at": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+pub async fn insert_scheduled(scheduled: &ScheduledMessage) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    collection.insert_one(to_document(scheduled)?).await?;
+
+    Ok(())
+}
+
+pub async fn find_scheduled(
+    message_id: u32,
+) -> Result<Option<ScheduledMessage>, mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    collection
+        .find_one(doc! { "message_id": message_id })
+        .await?
+        .map(|document| from_document(document).map_err(Into::into))
+        .transpose()
+}
+
+/// A user's messages that are still waiting to be sent, soonest first.
+pub async fn find_scheduled_by_sender(
+    sender_id: u32,
+) -> Result<Vec<ScheduledMessage>, mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! { "sender_id": sender_id })
+        .sort(doc! { "send_at": 1, "message_id": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+/// Scheduled messages whose time has come by `now`, longest due first.
+pub async fn find_due_scheduled(
+    now: DateTime<Utc>,
+    limit: usize,
+) -> Result<Vec<ScheduledMessage>, mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    let now = sortable_timestamp::format(&now);
+    let documents: Vec<Document> = collection
+        .find(doc! { "send_at": { "$lte": now } })
+        .sort(doc! { "send_at": 1, "message_id": 1 })
+        .limit(limit as i64)
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+pub async fn reschedule(
+    message_id: u32,
+    send_at: DateTime<Utc>,
+) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    let send_at = sortable_timestamp::format(&send_at);
+    let update_result = collection
+        .update_one(
+            doc! { "message_id": message_id },
+            doc! { "$set": { "send_at": send_at } },
+        )
+        .await?;
+
+    Ok(update_result.matched_count > 0)
+}
+
+/// Where timestamps are sorted or compared in queries, by collection.
+const SORTABLE_TIMESTAMPS: [(&str, &str); 4] = [
+    ("message_history", "timestamp"),
+    ("mention_inbox", "timestamp"),
+    ("message_reactions", "timestamp"),
+    ("scheduled_messages", "send_at"),
+];
+
+/// Rewrites timestamps stored before they were kept at a fixed width, see
+/// `utils::sortable_timestamp`, and says how many were rewritten. Safe to
+/// run on every start; timestamps already in the fixed format are skipped.
+pub async fn normalize_timestamps() -> Result<u64, mongodb::error::Error> {
+    let fixed_width = Regex {
+        pattern: r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{9}Z$".to_string(),
+        options: String::new(),
+    };
+    let mut rewritten = 0;
+    for (collection_name, field) in SORTABLE_TIMESTAMPS {
+        let collection = get_collection(collection_name).await?;
+        let documents: Vec<Document> = collection
+            .find(doc! { field: { "$type": "string", "$not": fixed_width.clone() } })
+            .await?
+            .try_collect()
+            .await?;
+        for document in documents {
+            let (Some(id), Ok(timestamp)) = (document.get("_id"), document.get_str(field)) else {
+                continue;
+            };
+            let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) else {
+                eprintln!(
+                    "Skipping unreadable {} {:?} in {}",
+                    field, timestamp, collection_name
+                );
+                continue;
+            };
+            let timestamp = sortable_timestamp::format(&timestamp.with_timezone(&Utc));
+            collection
+                .update_one(doc! { "_id": id }, doc! { "$set": { field: timestamp } })
+                .await?;
+            rewritten += 1;
+        }
+    }
+
+    Ok(rewritten)
+}
+
+/// Fields of an image message that were kept in `image_blobs`, keyed by the
+/// image's content hash, before attachments moved to the blob store.
+const IMAGE_BLOB_FIELDS: [&str; 3] = ["image_data", "normalized_data", "thumbnails"];
+
+/// Moves the bytes of image messages stored in `image_blobs` into `blobs`,
+/// and says how many messages were moved. Safe to run on every start;
+/// messages that already reference a blob, or carry their own bytes, are
+/// skipped.
+pub async fn migrate_image_blobs(blobs: &dyn BlobStore) -> Result<u64, BlobError> {
+    let collection = get_collection("message_history").await?;
+    let image_blobs = get_collection("image_blobs").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! {
+            "type": "Image",
+            "image_data": { "$exists": false },
+            "image_blob": null,
+            "metadata.content_hash": { "$type": "string" },
+        })
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut migrated = 0;
+    for mut document in documents {
+        let content_hash = document
+            .get_document("metadata")
+            .and_then(|metadata| metadata.get_str("content_hash"))
+            .map(str::to_string)
+            .unwrap_or_default();
+        let Some(shared) = image_blobs.find_one(doc! { "_id": &content_hash }).await? else {
+            eprintln!("No shared image bytes for content hash {}", content_hash);
+            continue;
+        };
+        for field in IMAGE_BLOB_FIELDS {
+            if let Some(value) = shared.get(field) {
+                document.insert(field, value.clone());
+            }
+        }
+        let mut message: Message = match from_document(document) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Skipping unreadable image message: {}", e);
+                continue;
+            }
+        };
+        if let Message::Image(image) = &mut message {
+            image.store_attachments(blobs).await?;
+        }
+        replace_message(message.message_id(), &message).await?;
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+/// Removes a scheduled message if it is due by `now`, so that of several
+/// servers polling at once only one delivers it.
+pub async fn claim_scheduled(
+    message_id: u32,
+    now: DateTime<Utc>,
+) -> Result<Option<ScheduledMessage>, mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    let now = sortable_timestamp::format(&now);
+    let claimed = collection
+        .find_one_and_delete(doc! { "message_id": message_id, "send_at": { "$lte": now } })
+        .await?;
+
+    Ok(claimed.map(from_document).transpose()?)
+}
+
+pub async fn delete_scheduled(message_id: u32) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("scheduled_messages").await?;
+    let delete_result = collection
+        .delete_one(doc! { "message_id": message_id })
+        .await?;
+
+    Ok(delete_result.deleted_count > 0)
+}
+
+/// Rebuilds a message received from a client through the package
+/// constructors, so it gets a fresh id and timestamp, is attributed to the
+/// authenticated `sender` and goes through the same validation as
+/// locally-created messages.
+pub fn accept_message(
+    message: &Message<'_>,
+    sender: &User,
+    conversation_id: &str,
+    image_options: &ImageProcessingOptions,
+    file_options: &FileOptions,
+    validation: &ValidationOptions,
+) -> Result<Message<'static>, MessageError> {
+    let sender_id = *sender.sender_id();
+    let sender_type = sender.sender_type().clone();
+
+    let mut accepted = match message {
+        Message::Text(message) => Message::Text(TextMessagePackage::with_options(
+            sender.clone(),
+            message.message().to_string(),
+            message.format(),
+            validation,
+        )?),
+        Message::Image(message) => Message::Image(ImageMessagePackage::with_options(
+            sender_id,
+            sender_type,
+            message.image_data(),
+            image_options,
+        )?),
+        Message::File(message) => {
+            validation.file_name.check(message.metadata().filename())?;
+            Message::File(FileMessagePackage::with_options(
+                sender_id,
+                sender_type,
+                message.metadata().filename(),
+                message.inline_data().to_vec(),
+                file_options,
+            )?)
+        }
+        Message::CryptoTransfer(message) => {
+            let mut transfer = CryptoTransferMessagePackage::new(
+                sender_id,
+                sender_type,
+                message.recipient_address().to_string(),
+                message.amount(),
+                message.token_symbol().to_string(),
+            )?;
+            if let Some(signature) = message.transaction_signature() {
+                transfer.set_transaction_signature(signature.to_string());
+            }
+            Message::CryptoTransfer(transfer)
+        }
+        Message::Deleted(_) => {
+            return Err(MessageError::Invalid(
+                "A deleted message can't be sent".into(),
+            ));
+        }
+    };
+    accepted.set_conversation_id(conversation_id.to_string());
+    // The thread root is looked up from the parent, see
+    // `threads::resolve_reply`, never taken from the client.
+    if let Some(reply_to) = message.reply_to() {
+        accepted.set_reply_to(reply_to);
+    }
+
+    Ok(accepted)
+}
+
+pub async fn insert_text_message(
+    message: TextMessagePackage<'_>,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    let collection = get_collection("message_history").await?;
+    let insert_one_result = collection.insert_one(to_document(&message)?).await?;
     let document = collection
         .find_one(doc! { "_id": insert_one_result.inserted_id })
         .await?;
@@ -98,7 +958,7 @@ pub async fn insert_text_message<'a>(
 //     }
 // }
 
-pub fn debug_saved_message(message_package: Document, pretty_print:bool) {
+pub fn debug_saved_message(message_package: Document, pretty_print: bool) {
     if let Some(id) = message_package.get("_id") {
         println!("Document ID: {:?}", id);
     }
@@ -111,20 +971,84 @@ pub fn debug_saved_message(message_package: Document, pretty_print:bool) {
     if let Some(message) = message_package.get("message") {
         println!("Message Content: {:?}", message);
     }
-    if let Some(sender) = message_package.get("sender") {
-        if let Some(sender_doc) = sender.as_document() {
-            if let Some(sender_id) = sender_doc.get("sender_id") {
-                println!("Sender ID: {:?}", sender_id);
-            }
-            if let Some(sender_type) = sender_doc.get("sender_type") {
-                println!("Sender Type: {:?}", sender_type);
-            }
+    if let Some(edited_at) = message_package.get("edited_at") {
+        println!("Edited: {}", edited_at);
+    }
+    if let Some(deleted_at) = message_package.get("deleted_at") {
+        println!("Deleted: {}", deleted_at);
+    }
+    if let Ok(image_data) = message_package.get_array("image_data") {
+        println!("Image Data Length: {:?}", image_data.len());
+    }
+    if let Ok(metadata) = message_package.get_document("metadata") {
+        println!("Metadata: {}", metadata);
+    }
+    if let Ok(file_data) = message_package.get_array("file_data") {
+        println!("File Data Length: {:?}", file_data.len());
+    }
+    if let Ok(blob) = message_package.get_document("image_blob") {
+        println!("Image Blob: {}", blob);
+    }
+    if let Ok(blob) = message_package.get_document("file_blob") {
+        println!("File Blob: {}", blob);
+    }
+    if let Ok(scan) = message_package.get_document("scan") {
+        println!("Scan: {}", scan);
+    }
+    if let (Ok(amount), Ok(token_symbol), Ok(recipient_address)) = (
+        message_package.get_f64("amount"),
+        message_package.get_str("token_symbol"),
+        message_package.get_str("recipient_address"),
+    ) {
+        println!(
+            "Transfer: {} {} to {}",
+            amount, token_symbol, recipient_address
+        );
+    }
+    if let Ok(signature) = message_package.get_str("transaction_signature") {
+        println!("Transaction Signature: {}", signature);
+    }
+    if let Some(conversation_id) = message_package.get("conversation_id") {
+        println!("Conversation ID: {:?}", conversation_id);
+    }
+    if let Some(reply_to) = message_package.get("reply_to") {
+        println!("Reply To: {}", reply_to);
+    }
+    if let Some(thread_root) = message_package.get("thread_root") {
+        println!("Thread: {}", thread_root);
+    }
+    if let Ok(mentions) = message_package.get_array("mentions") {
+        let handles: Vec<String> = mentions
+            .iter()
+            .filter_map(|mention| mention.as_document()?.get_str("handle").ok())
+            .map(|handle| format!("@{}", handle))
+            .collect();
+        if !handles.is_empty() {
+            println!("Mentions: {}", handles.join(", "));
+        }
+    }
+    if let Some(sender_doc) = message_package.get("sender").and_then(|s| s.as_document()) {
+        if let Some(sender_id) = sender_doc.get("sender_id") {
+            println!("Sender ID: {:?}", sender_id);
+        }
+        if let Some(sender_type) = sender_doc.get("sender_type") {
+            println!("Sender Type: {:?}", sender_type);
+        }
+    } else {
+        if let Some(sender_id) = message_package.get("sender_id") {
+            println!("Sender ID: {:?}", sender_id);
+        }
+        if let Some(sender_type) = message_package.get("from") {
+            println!("Sender Type: {:?}", sender_type);
         }
     }
     if let Some(timestamp) = message_package.get("timestamp") {
-        if pretty_print {
-            // IMPLEMENT THIS
-            println!("Timestamp: {:?}", timestamp);
+        let pretty = pretty_print
+            .then(|| from_document::<Message>(message_package.clone()).ok())
+            .flatten();
+        match pretty {
+            Some(message) => println!("Timestamp: {}", message.pretty_timestamp()),
+            None => println!("Timestamp: {:?}", timestamp),
         }
     }
     // match message_package {
@@ -0,0 +1,232 @@
+use crate::enums::{InfectedAction, Message};
+use crate::errors::ScanError;
+use crate::structs::scan::ScanVerdict;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Chunks just have to be smaller than clamd's `StreamMaxLength`.
+const INSTREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// clamd's own default `StreamMaxLength`.
+pub const DEFAULT_CLAMD_STREAM_MAX: u64 = 25 * 1024 * 1024;
+const MAX_REPLY_BYTES: u64 = 4096;
+
+/// Something that can look through attachment bytes for malware.
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// Recorded on every verdict, so it is clear which scanner passed a file.
+    fn name(&self) -> &str;
+
+    /// The most bytes `scan` accepts, when it has a limit.
+    fn max_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// The name of the signature `data` matched, or `None` when it is clean.
+    async fn scan(
+        &self,
+        data: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<Option<String>, ScanError>;
+}
+
+/// Which scanner a deployment uses and what it does with infected files.
+#[derive(Clone)]
+pub struct ScanPolicy {
+    pub scanner: Arc<dyn Scanner>,
+    pub on_infected: InfectedAction,
+}
+
+impl ScanPolicy {
+    pub fn new(scanner: Arc<dyn Scanner>, on_infected: InfectedAction) -> Self {
+        Self {
+            scanner,
+            on_infected,
+        }
+    }
+
+    /// Scans the `size` bytes of `data`. Infected data is an error when the
+    /// policy is to reject it, and an infected verdict when it is to
+    /// quarantine it. Data the scanner can't take is refused unread.
+    pub async fn check(
+        &self,
+        data: &mut (dyn AsyncRead + Unpin + Send),
+        size: u64,
+    ) -> Result<ScanVerdict, ScanError> {
+        if let Some(max) = self.scanner.max_size()
+            && size > max
+        {
+            return Err(ScanError::TooLarge { max });
+        }
+        let signature = self.scanner.scan(data).await?;
+        match signature {
+            Some(signature) if self.on_infected == InfectedAction::Reject => {
+                Err(ScanError::Infected(signature))
+            }
+            signature => Ok(ScanVerdict::new(self.scanner.name().to_string(), signature)),
+        }
+    }
+
+    /// Scans the attachment of an image or file message and records the
+    /// verdict on it. Must run before the bytes are moved to the blob store;
+    /// messages that already carry a verdict are left alone.
+    pub async fn check_message(&self, message: &mut Message<'_>) -> Result<(), ScanError> {
+        if message.scan().is_some() {
+            return Ok(());
+        }
+
+        match message {
+            Message::Image(image) => {
+                let data = image.image_data();
+                let verdict = self.check(&mut data.as_slice(), data.len() as u64).await?;
+                image.set_scan(verdict);
+            }
+            Message::File(file) => {
+                let data = file.inline_data();
+                let verdict = self.check(&mut &data[..], data.len() as u64).await?;
+                file.set_scan(verdict);
+            }
+            Message::Text(_) | Message::CryptoTransfer(_) | Message::Deleted(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClamdAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl ClamdAddress {
+    /// `unix:/run/clamav/clamd.ctl`, `tcp:127.0.0.1:3310`, or either without
+    /// its prefix: anything starting with `/` is a socket path.
+    pub fn parse(address: &str) -> Self {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return ClamdAddress::Unix(path.into());
+        }
+        #[cfg(unix)]
+        if address.starts_with('/') {
+            return ClamdAddress::Unix(address.into());
+        }
+
+        ClamdAddress::Tcp(address.strip_prefix("tcp:").unwrap_or(address).to_string())
+    }
+}
+
+/// Talks to a ClamAV daemon using its INSTREAM command, so the daemon needs
+/// no access to our files.
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    address: ClamdAddress,
+    timeout: Duration,
+    max_stream_size: u64,
+}
+
+impl ClamdScanner {
+    pub fn new(address: ClamdAddress, timeout: Duration) -> Self {
+        Self {
+            address,
+            timeout,
+            max_stream_size: DEFAULT_CLAMD_STREAM_MAX,
+        }
+    }
+
+    /// Matches the daemon's `StreamMaxLength`, when it isn't the default.
+    /// Anything longer is refused before it is sent.
+    pub fn with_max_stream_size(mut self, max_stream_size: u64) -> Self {
+        self.max_stream_size = max_stream_size;
+        self
+    }
+
+    async fn scan_stream<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        data: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<Option<String>, ScanError> {
+        let too_large = ScanError::TooLarge {
+            max: self.max_stream_size,
+        };
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut buffer = vec![0u8; INSTREAM_CHUNK_SIZE];
+        let mut sent = 0;
+        loop {
+            let read = data.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            sent += read as u64;
+            if sent > self.max_stream_size {
+                return Err(too_large);
+            }
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            stream.write_all(&buffer[..read]).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+
+        let mut reply = Vec::new();
+        BufReader::new(stream)
+            .take(MAX_REPLY_BYTES)
+            .read_until(b'\0', &mut reply)
+            .await?;
+        match parse_reply(&String::from_utf8_lossy(&reply)) {
+            // The daemon's limit is lower than ours.
+            Err(ScanError::Protocol(reply)) if reply.contains("size limit exceeded") => {
+                Err(too_large)
+            }
+            result => result,
+        }
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamdScanner {
+    fn name(&self) -> &str {
+        "clamd"
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        Some(self.max_stream_size)
+    }
+
+    async fn scan(
+        &self,
+        data: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<Option<String>, ScanError> {
+        let scan = async {
+            match &self.address {
+                ClamdAddress::Tcp(address) => {
+                    let stream = tokio::net::TcpStream::connect(address).await?;
+                    self.scan_stream(stream, data).await
+                }
+                #[cfg(unix)]
+                ClamdAddress::Unix(path) => {
+                    let stream = tokio::net::UnixStream::connect(path).await?;
+                    self.scan_stream(stream, data).await
+                }
+            }
+        };
+
+        tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| ScanError::Timeout)?
+    }
+}
+
+/// clamd answers `stream: OK`, `stream: <signature> FOUND` or
+/// `<reason> ERROR`, terminated by a NUL in the `z` command form.
+fn parse_reply(reply: &str) -> Result<Option<String>, ScanError> {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").map(str::trim);
+    match result {
+        Some("OK") => Ok(None),
+        Some(result) if result.ends_with(" FOUND") => {
+            Ok(Some(result.trim_end_matches(" FOUND").trim().to_string()))
+        }
+        _ => Err(ScanError::Protocol(reply.to_string())),
+    }
+}
@@ -0,0 +1,1668 @@
+use crate::auth::verify_token;
+use crate::blobs::{load_blob, open_blob};
+use crate::drafts::clear_sent_draft;
+use crate::enums::{
+    DeleteScope, Message, MetadataPolicy, ServerFrame, TextFormat, WebhookEventType,
+};
+use crate::errors::{
+    ApiError, FileError, ImageProcessingError, ScanError, StoreError, ThreadError, TransferError,
+    UploadError, ValidationError,
+};
+use crate::files::{SNIFF_LENGTH, content_disposition, sanitize_filename, sniff_mime_type};
+use crate::mentions::{record_mentions, resolve_mentions};
+use crate::messaging::accept_message;
+use crate::server::AppState;
+use crate::structs::blob::BlobRef;
+use crate::structs::conversation::{ConversationSettings, NotificationSettings};
+use crate::structs::draft::{Draft, MAX_DRAFT_ATTACHMENTS};
+use crate::structs::history::{HistoryCursor, HistoryPage};
+use crate::structs::mention::InboxMention;
+use crate::structs::messages::{
+    file::{FileMessagePackage, FileMetadata},
+    image::{ImageMessagePackage, SimilarImage},
+};
+use crate::structs::reaction::{MAX_REACTIONS_PER_USER, Reaction, ReactionGroup};
+use crate::structs::revision::MessageRevision;
+use crate::structs::scheduled::ScheduledMessage;
+use crate::structs::thread::ThreadPage;
+use crate::structs::upload::UploadSession;
+use crate::structs::user::User;
+use crate::structs::webhook::WebhookSubscription;
+use crate::threads::{quotes_for, resolve_reply, thread_root_for};
+use crate::unfurl::resolve_public;
+use crate::uploads::CHUNK_CHECKSUM_HEADER;
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio_util::io::ReaderStream;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+const MAX_ATTACHMENT_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_SIMILARITY_DISTANCE: u32 = 10;
+
+/// Serves the JSON API on `listener` until the listener fails.
+pub async fn serve(listener: TcpListener, state: AppState) -> std::io::Result<()> {
+    axum::serve(listener, router(state)).await
+}
+
+pub fn router(state: AppState) -> Router {
+    let max_chunk_size = state.uploads.limits().max_chunk_size as usize;
+
+    Router::new()
+        .route(
+            "/conversations/{conversation_id}/messages",
+            get(list_history).post(create_message),
+        )
+        .route(
+            "/conversations/{conversation_id}/attachments",
+            post(upload_attachment).layer(DefaultBodyLimit::max(MAX_ATTACHMENT_BYTES)),
+        )
+        .route(
+            "/conversations/{conversation_id}/uploads",
+            post(start_upload),
+        )
+        .route(
+            "/uploads/{upload_id}",
+            get(upload_status).delete(cancel_upload),
+        )
+        .route(
+            "/uploads/{upload_id}/chunks/{index}",
+            put(upload_chunk).layer(DefaultBodyLimit::max(max_chunk_size)),
+        )
+        .route("/uploads/{upload_id}/complete", post(complete_upload))
+        .route(
+            "/conversations/{conversation_id}/members",
+            get(list_members),
+        )
+        .route(
+            "/conversations/{conversation_id}/members/{sender_id}",
+            put(add_member).delete(remove_member),
+        )
+        .route(
+            "/conversations/{conversation_id}/settings",
+            get(get_settings).put(update_settings),
+        )
+        .route(
+            "/conversations/{conversation_id}/notifications",
+            get(get_notifications).put(update_notifications),
+        )
+        .route(
+            "/conversations/{conversation_id}/draft",
+            get(get_draft).put(save_draft).delete(delete_draft),
+        )
+        .route("/drafts", get(list_drafts))
+        .route(
+            "/conversations/{conversation_id}/scheduled",
+            post(schedule_message),
+        )
+        .route("/scheduled", get(list_scheduled))
+        .route(
+            "/scheduled/{message_id}",
+            put(reschedule_message).delete(cancel_scheduled),
+        )
+        .route("/mentions", get(list_mentions))
+        .route("/mentions/{message_id}/read", put(read_mention))
+        .route(
+            "/messages/{message_id}",
+            get(get_message).put(update_message).delete(delete_message),
+        )
+        .route(
+            "/messages/{message_id}/confirmation",
+            post(confirm_transfer),
+        )
+        .route("/messages/{message_id}/revisions", get(list_revisions))
+        .route("/messages/{message_id}/thread", get(get_thread))
+        .route("/messages/{message_id}/reactions", get(list_reactions))
+        .route(
+            "/messages/{message_id}/reactions/{emoji}",
+            put(add_reaction).delete(remove_reaction),
+        )
+        .route("/messages/{message_id}/attachment", get(get_attachment))
+        .route("/messages/{message_id}/html", get(get_html))
+        .route("/messages/{message_id}/similar", get(similar_images))
+        .route(
+            "/messages/{message_id}/thumbnails/{size}",
+            get(get_thumbnail),
+        )
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/{subscription_id}", delete(delete_webhook))
+        .with_state(state)
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code) = match &self {
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ApiError::Forbidden
+            | ApiError::AgentOnly
+            | ApiError::AdminOnly
+            | ApiError::ModeratorOnly => (StatusCode::FORBIDDEN, "forbidden"),
+            ApiError::NotMember(_) => (StatusCode::FORBIDDEN, "not_member"),
+            ApiError::EditWindowClosed(_) => (StatusCode::FORBIDDEN, "edit_window_closed"),
+            ApiError::NotFound(_)
+            | ApiError::WebhookNotFound(_)
+            | ApiError::DraftNotFound(_)
+            | ApiError::ThumbnailNotFound { .. } => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            ApiError::Validation(_) => (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+            ApiError::TooManyReactions(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "too_many_reactions")
+            }
+            ApiError::Content(ValidationError::Blank) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "blank_text")
+            }
+            ApiError::Content(ValidationError::ControlCharacter(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "control_character")
+            }
+            ApiError::Content(ValidationError::UnbalancedBidi) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "unbalanced_bidi")
+            }
+            ApiError::Content(
+                ValidationError::TooLong { .. } | ValidationError::TooLarge { .. },
+            ) => (StatusCode::UNPROCESSABLE_ENTITY, "text_too_long"),
+            ApiError::Content(ValidationError::Markdown(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed")
+            }
+            ApiError::Image(ImageProcessingError::UnsupportedFormat) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_image")
+            }
+            ApiError::Image(ImageProcessingError::TooManyPixels { .. }) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "image_too_large")
+            }
+            ApiError::Image(ImageProcessingError::Corrupt(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "corrupt_image")
+            }
+            ApiError::Image(ImageProcessingError::Encode(_)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "image_error")
+            }
+            ApiError::File(FileError::TooLarge { .. }) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "file_too_large")
+            }
+            ApiError::File(FileError::TypeNotAllowed(_)) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, "file_type_not_allowed")
+            }
+            ApiError::File(FileError::InvalidFilename(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed")
+            }
+            ApiError::Scan(ScanError::Infected(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "infected_attachment")
+            }
+            ApiError::Scan(ScanError::TooLarge { .. }) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "too_large_to_scan")
+            }
+            ApiError::Scan(e) => {
+                eprintln!("Attachment scanner failed while handling request: {}", e);
+                (StatusCode::SERVICE_UNAVAILABLE, "scanner_unavailable")
+            }
+            ApiError::Transfer(TransferError::InvalidSignature(_)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "invalid_signature")
+            }
+            ApiError::Transfer(TransferError::NotConfirmed(_)) => {
+                (StatusCode::CONFLICT, "transfer_not_confirmed")
+            }
+            ApiError::Transfer(e) => {
+                eprintln!(
+                    "Transfer could not be checked while handling request: {}",
+                    e
+                );
+                (StatusCode::SERVICE_UNAVAILABLE, "chain_unavailable")
+            }
+            ApiError::Quarantined(_) => (StatusCode::FORBIDDEN, "quarantined"),
+            ApiError::Upload(UploadError::NotFound(_)) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::Upload(
+                UploadError::InvalidSize { .. }
+                | UploadError::InvalidChunkSize { .. }
+                | UploadError::TooManyChunks { .. }
+                | UploadError::ChunkOutOfRange { .. },
+            ) => (StatusCode::BAD_REQUEST, "bad_request"),
+            ApiError::Upload(
+                UploadError::ChunkLength { .. } | UploadError::ChecksumMismatch(_),
+            ) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_chunk"),
+            ApiError::Upload(UploadError::Incomplete { .. }) => {
+                (StatusCode::CONFLICT, "upload_incomplete")
+            }
+            ApiError::Upload(UploadError::Completing(_)) => {
+                (StatusCode::CONFLICT, "upload_completing")
+            }
+            ApiError::Upload(
+                UploadError::TooManySessions { .. } | UploadError::QuotaExceeded { .. },
+            ) => (StatusCode::TOO_MANY_REQUESTS, "upload_quota_exceeded"),
+            ApiError::Thread(ThreadError::Store(e)) => {
+                eprintln!("Store error while handling request: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "store_error")
+            }
+            ApiError::Thread(_) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_reply"),
+            ApiError::Upload(e @ (UploadError::Io(_) | UploadError::Blob(_))) => {
+                eprintln!("Upload error while handling request: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "upload_error")
+            }
+            ApiError::Blob(e) => {
+                eprintln!("Blob store error while handling request: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "blob_error")
+            }
+            ApiError::Store(e) => {
+                eprintln!("Store error while handling request: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "store_error")
+            }
+        };
+
+        (
+            status,
+            Json(json!({ "error": code, "message": self.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+/// The user behind the request's `Authorization: Bearer <token>` header.
+pub struct AuthUser(pub User);
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(ApiError::Unauthorized)?;
+
+        let user = verify_token(&state.auth_key, token).map_err(|_| ApiError::Unauthorized)?;
+        Ok(AuthUser(with_stored_role(state, user).await?))
+    }
+}
+
+/// Gives `user` the role the users store has for them. Tokens outlive role
+/// changes, so the role they carry is never trusted; users who aren't
+/// registered get the default role.
+pub(crate) async fn with_stored_role(state: &AppState, mut user: User) -> Result<User, StoreError> {
+    let role = state
+        .store
+        .find_user(*user.sender_id())
+        .await?
+        .map(|stored| stored.role())
+        .unwrap_or_default();
+    user.set_role(role);
+    Ok(user)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn list_history(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let before = query
+        .cursor
+        .as_deref()
+        .map(HistoryCursor::decode)
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+    require_member(&state, &conversation_id, &user).await?;
+
+    let messages = state
+        .store
+        .conversation_history(
+            &conversation_id,
+            before.as_ref(),
+            Some(*user.sender_id()),
+            limit,
+        )
+        .await?;
+    let next_cursor = match messages.last() {
+        Some(last) if messages.len() == limit => Some(HistoryCursor::after(last).encode()),
+        _ => None,
+    };
+    let thread_roots: Vec<u32> = messages
+        .iter()
+        .filter(|message| message.thread_root().is_none())
+        .map(Message::message_id)
+        .collect();
+    let reply_counts = state.store.reply_counts(&thread_roots).await?;
+    let quoted = quotes_for(&*state.store, &messages).await?;
+    let message_ids: Vec<u32> = messages.iter().map(Message::message_id).collect();
+    let reactions = state.store.reaction_counts(&message_ids).await?;
+
+    Ok(Json(HistoryPage {
+        messages,
+        next_cursor,
+        quoted,
+        reply_counts,
+        reactions,
+    }))
+}
+
+async fn create_message(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(message): Json<Message<'static>>,
+) -> Result<(StatusCode, Json<Message<'static>>), ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    let settings = state.store.conversation_settings(&conversation_id).await?;
+    let message = accept_message(
+        &message,
+        &user,
+        &conversation_id,
+        &settings.image_processing_options(),
+        &state.files,
+        &state.validation,
+    )?;
+    publish_new_message(&state, message).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttachmentQuery {
+    /// Required for anything that isn't an image.
+    filename: Option<String>,
+    reply_to: Option<u32>,
+}
+
+/// Stores the request body as an image or file message. Which one is decided
+/// by sniffing the body; the request's `Content-Type` is not trusted.
+async fn upload_attachment(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Query(query): Query<AttachmentQuery>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Message<'static>>), ApiError> {
+    if body.is_empty() {
+        return Err(ApiError::BadRequest("Attachment body is empty".into()));
+    }
+    require_member(&state, &conversation_id, &user).await?;
+
+    let is_image = sniff_mime_type(&body).starts_with("image/");
+    let sender_id = *user.sender_id();
+    let sender_type = user.sender_type().clone();
+
+    let mut message = if is_image {
+        let settings = state.store.conversation_settings(&conversation_id).await?;
+        Message::Image(ImageMessagePackage::with_options(
+            sender_id,
+            sender_type,
+            body.to_vec(),
+            &settings.image_processing_options(),
+        )?)
+    } else {
+        let filename = query.filename.ok_or_else(|| {
+            ApiError::BadRequest("The filename query parameter is required for files".into())
+        })?;
+        state.validation.file_name.check(&filename)?;
+        Message::File(FileMessagePackage::with_options(
+            sender_id,
+            sender_type,
+            &filename,
+            body.to_vec(),
+            &state.files,
+        )?)
+    };
+    message.set_conversation_id(conversation_id);
+    if let Some(reply_to) = query.reply_to {
+        message.set_reply_to(reply_to);
+    }
+
+    publish_new_message(&state, message).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewUpload {
+    filename: String,
+    total_size: u64,
+    chunk_size: u64,
+}
+
+/// Starts a chunked upload. The client then PUTs each chunk, can ask which
+/// ones are still missing after a dropped connection, and finally completes
+/// the upload to turn it into a file message.
+async fn start_upload(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(upload): Json<NewUpload>,
+) -> Result<(StatusCode, Json<UploadSession>), ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    let filename = sanitize_filename(&upload.filename)?;
+    state.validation.file_name.check(&filename)?;
+    state.files.check_size(upload.total_size)?;
+    let session = state
+        .uploads
+        .start(
+            *user.sender_id(),
+            conversation_id,
+            filename,
+            upload.total_size,
+            upload.chunk_size,
+        )
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+async fn upload_status(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(upload_id): Path<String>,
+) -> Result<Json<UploadSession>, ApiError> {
+    Ok(Json(
+        state.uploads.status(&upload_id, *user.sender_id()).await?,
+    ))
+}
+
+async fn upload_chunk(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path((upload_id, index)): Path<(String, u32)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<UploadSession>, ApiError> {
+    let checksum = headers
+        .get(CHUNK_CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!("The {} header is required", CHUNK_CHECKSUM_HEADER))
+        })?;
+    let session = state
+        .uploads
+        .put_chunk(&upload_id, *user.sender_id(), index, checksum, &body)
+        .await?;
+
+    Ok(Json(session))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteQuery {
+    reply_to: Option<u32>,
+}
+
+async fn complete_upload(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(upload_id): Path<String>,
+    Query(query): Query<CompleteQuery>,
+) -> Result<(StatusCode, Json<Message<'static>>), ApiError> {
+    let sender_id = *user.sender_id();
+    // Check the parent before the upload is used up.
+    if let Some(reply_to) = query.reply_to {
+        let session = state.uploads.status(&upload_id, sender_id).await?;
+        thread_root_for(&*state.store, session.conversation_id(), reply_to).await?;
+    }
+    let upload = state.uploads.assemble(&upload_id, sender_id).await?;
+    let mut head = Vec::new();
+    upload
+        .open()
+        .await?
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut head)
+        .await
+        .map_err(UploadError::from)?;
+    let mime_type = sniff_mime_type(&head);
+
+    // Refuse the file before it reaches the blob store. It can never be
+    // completed, so the upload goes too.
+    let checked = async {
+        state.files.check_type(&mime_type)?;
+        match &state.scanning {
+            Some(scanning) => {
+                let mut data = upload.open().await?;
+                Ok(Some(scanning.check(&mut data, upload.size()).await?))
+            }
+            None => Ok(None),
+        }
+    }
+    .await;
+    let scan = match checked {
+        Ok(scan) => scan,
+        Err(
+            e @ (ApiError::File(_)
+            | ApiError::Scan(ScanError::Infected(_) | ScanError::TooLarge { .. })),
+        ) => {
+            state.uploads.cancel(&upload_id, sender_id).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // The blob may be compressed, so its digest isn't the file's.
+    let (size, sha256) = (upload.size(), upload.sha256().to_string());
+    let (session, blob) = state.uploads.finish(upload, &*state.blobs).await?;
+    let metadata = FileMetadata::new(session.filename().to_string(), mime_type, size, sha256);
+    let mut file =
+        FileMessagePackage::from_blob(sender_id, user.sender_type().clone(), metadata, blob);
+    if let Some(scan) = scan {
+        file.set_scan(scan);
+    }
+    let mut message = Message::File(file);
+    message.set_conversation_id(session.conversation_id().to_string());
+    if let Some(reply_to) = query.reply_to {
+        message.set_reply_to(reply_to);
+    }
+
+    publish_new_message(&state, message).await
+}
+
+async fn cancel_upload(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(upload_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    state.uploads.cancel(&upload_id, *user.sender_id()).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_members(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+) -> Result<Json<Vec<u32>>, ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    Ok(Json(state.store.members(&conversation_id).await?))
+}
+
+/// Members may add anyone to their conversation.
+async fn add_member(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path((conversation_id, sender_id)): Path<(String, u32)>,
+) -> Result<StatusCode, ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    state.store.add_member(&conversation_id, sender_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Members may leave; only moderators may remove someone else.
+async fn remove_member(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path((conversation_id, sender_id)): Path<(String, u32)>,
+) -> Result<StatusCode, ApiError> {
+    if sender_id != *user.sender_id() && !user.is_moderator() {
+        return Err(ApiError::Forbidden);
+    }
+    if !state
+        .store
+        .remove_member(&conversation_id, sender_id)
+        .await?
+    {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not a member of conversation {}",
+            sender_id, conversation_id
+        )));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_settings(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+) -> Result<Json<ConversationSettings>, ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    Ok(Json(
+        state.store.conversation_settings(&conversation_id).await?,
+    ))
+}
+
+/// Fields left out of the request body keep their current value.
+#[derive(Debug, Deserialize)]
+pub struct SettingsUpdate {
+    image_metadata: Option<MetadataPolicy>,
+}
+
+async fn update_settings(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(update): Json<SettingsUpdate>,
+) -> Result<Json<ConversationSettings>, ApiError> {
+    if !user.sender_type().is_agent() {
+        return Err(ApiError::AgentOnly);
+    }
+    require_member(&state, &conversation_id, &user).await?;
+
+    let mut settings = state.store.conversation_settings(&conversation_id).await?;
+    if let Some(image_metadata) = update.image_metadata {
+        settings.set_image_metadata(image_metadata);
+    }
+    state.store.save_conversation_settings(&settings).await?;
+
+    Ok(Json(settings))
+}
+
+async fn get_notifications(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+) -> Result<Json<NotificationSettings>, ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    let level = state
+        .store
+        .notify_level(&conversation_id, *user.sender_id())
+        .await?;
+
+    Ok(Json(NotificationSettings { level }))
+}
+
+/// Chooses whether the requester is pushed every new message in the
+/// conversation or only those that mention them. Connections read it when
+/// they subscribe.
+async fn update_notifications(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(settings): Json<NotificationSettings>,
+) -> Result<Json<NotificationSettings>, ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    state
+        .store
+        .set_notify_level(&conversation_id, *user.sender_id(), settings.level)
+        .await?;
+
+    Ok(Json(settings))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MentionQuery {
+    #[serde(default)]
+    unread: bool,
+    limit: Option<usize>,
+}
+
+/// The requester's mentions, newest first.
+async fn list_mentions(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Query(query): Query<MentionQuery>,
+) -> Result<Json<Vec<InboxMention>>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    Ok(Json(
+        state
+            .store
+            .mention_inbox(*user.sender_id(), query.unread, limit)
+            .await?,
+    ))
+}
+
+async fn read_mention(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<StatusCode, ApiError> {
+    if !state
+        .store
+        .mark_mention_read(*user.sender_id(), message_id)
+        .await?
+    {
+        return Err(ApiError::NotFound(message_id));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_message(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<Json<Message<'static>>, ApiError> {
+    Ok(Json(
+        find_readable_message(&state, &user, message_id).await?,
+    ))
+}
+
+/// The raw bytes of one of an image message's thumbnails.
+async fn get_thumbnail(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path((message_id, size)): Path<(u32, u32)>,
+) -> Result<Response, ApiError> {
+    let message = find_readable_message(&state, &user, message_id).await?;
+    if message.is_quarantined() {
+        return Err(ApiError::Quarantined(message_id));
+    }
+    let Message::Image(image) = message else {
+        return Err(ApiError::ThumbnailNotFound { message_id, size });
+    };
+    let thumbnail = image
+        .thumbnail(size)
+        .ok_or(ApiError::ThumbnailNotFound { message_id, size })?;
+    let data = match thumbnail.blob() {
+        Some(blob) => load_blob(&*state.blobs, blob).await?,
+        None => thumbnail.data().to_vec(),
+    };
+
+    Ok(immutable_bytes(thumbnail.mime_type(), data))
+}
+
+/// The bytes of an image or file message. Message documents only reference
+/// their attachments, so this is how clients download them.
+async fn get_attachment(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<Response, ApiError> {
+    let message = find_readable_message(&state, &user, message_id).await?;
+    if message.is_quarantined() {
+        return Err(ApiError::Quarantined(message_id));
+    }
+
+    match message {
+        Message::Image(mut image) => {
+            image.load_attachments(&*state.blobs).await?;
+            Ok(immutable_bytes(
+                image.metadata().stored_mime_type(),
+                image.image_data(),
+            ))
+        }
+        Message::File(file) => {
+            let metadata = file.metadata();
+            let content_type = match metadata.mime_type() {
+                "" => "application/octet-stream",
+                mime_type => mime_type,
+            };
+            // Files can be large, so stored ones are streamed out and
+            // decompressed on the way rather than loaded whole.
+            let mut response = match file.file_blob() {
+                Some(blob) => {
+                    let reader = open_blob(&*state.blobs, blob).await?;
+                    let mut response =
+                        immutable_bytes(content_type, Body::from_stream(ReaderStream::new(reader)));
+                    if metadata.size() > 0 {
+                        response
+                            .headers_mut()
+                            .insert(header::CONTENT_LENGTH, HeaderValue::from(metadata.size()));
+                    }
+                    response
+                }
+                None => immutable_bytes(content_type, file.inline_data().to_vec()),
+            };
+            let headers = response.headers_mut();
+            headers.insert(
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            );
+            if !metadata.filename().is_empty() {
+                let disposition = content_disposition(metadata.filename());
+                if let Ok(value) = HeaderValue::from_str(&disposition) {
+                    headers.insert(header::CONTENT_DISPOSITION, value);
+                }
+            }
+            Ok(response)
+        }
+        _ => Err(ApiError::BadRequest(format!(
+            "Message {} has no attachment",
+            message_id
+        ))),
+    }
+}
+
+/// A text message as a sanitized HTML fragment, so every client renders
+/// Markdown the same way.
+async fn get_html(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<Response, ApiError> {
+    let message = find_readable_message(&state, &user, message_id).await?;
+    let Message::Text(text) = message else {
+        return Err(ApiError::BadRequest(format!(
+            "Message {} is not a text message",
+            message_id
+        )));
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::CONTENT_SECURITY_POLICY, "default-src 'none'"),
+        ],
+        text.html(),
+    )
+        .into_response())
+}
+
+/// Attachments never change once stored, so clients may cache them forever.
+fn immutable_bytes(content_type: &str, data: impl Into<Body>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CACHE_CONTROL,
+                "private, max-age=31536000, immutable".to_string(),
+            ),
+        ],
+        data.into(),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarQuery {
+    max_distance: Option<u32>,
+    limit: Option<usize>,
+    /// Search every conversation instead of just the message's own.
+    /// Moderators only, since it reaches conversations the requester is not in.
+    #[serde(default)]
+    all_conversations: bool,
+}
+
+/// Other images that look like the given image message, closest first. A
+/// distance of 0 means the images are identical or nearly so.
+async fn similar_images(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+    Query(query): Query<SimilarQuery>,
+) -> Result<Json<Vec<SimilarImage>>, ApiError> {
+    if query.all_conversations && !user.is_moderator() {
+        return Err(ApiError::ModeratorOnly);
+    }
+    let message = find_readable_message(&state, &user, message_id).await?;
+    let Message::Image(image) = &message else {
+        return Err(ApiError::BadRequest(format!(
+            "Message {} is not an image",
+            message_id
+        )));
+    };
+    let perceptual_hash = image.metadata().perceptual_hash().ok_or_else(|| {
+        ApiError::BadRequest(format!("Message {} has no perceptual hash", message_id))
+    })?;
+    let conversation_id = message
+        .conversation_id()
+        .filter(|_| !query.all_conversations);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // Ask for one more, since the image itself is always among the results.
+    let similar = state
+        .store
+        .similar_images(
+            perceptual_hash,
+            conversation_id,
+            query.max_distance.unwrap_or(DEFAULT_SIMILARITY_DISTANCE),
+            limit + 1,
+        )
+        .await?
+        .into_iter()
+        .filter(|similar| similar.message.message_id() != message_id)
+        .take(limit)
+        .collect();
+
+    Ok(Json(similar))
+}
+
+/// Text messages are edited: the sender or a moderator may change them
+/// within the edit window, and the text they replace is kept as a revision.
+/// Other messages can't be changed.
+async fn update_message(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+    Json(update): Json<Message<'static>>,
+) -> Result<Json<Message<'static>>, ApiError> {
+    let existing = match state.store.find_message(message_id).await? {
+        Some(Message::Text(text)) => text,
+        Some(_) => {
+            return Err(ApiError::BadRequest(
+                "Only text messages can be edited".into(),
+            ));
+        }
+        None => return Err(ApiError::NotFound(message_id)),
+    };
+    if existing.sender().sender_id() != user.sender_id() && !user.is_moderator() {
+        return Err(ApiError::Forbidden);
+    }
+    if existing.created_at() + state.edit_window < Utc::now() {
+        return Err(ApiError::EditWindowClosed(message_id));
+    }
+    if !matches!(update, Message::Text(_)) {
+        return Err(ApiError::BadRequest(
+            "A message can't change its type".into(),
+        ));
+    }
+    let conversation_id = existing.conversation_id().unwrap_or_default().to_string();
+    let settings = state.store.conversation_settings(&conversation_id).await?;
+    let mut update = accept_message(
+        &update,
+        &user,
+        &conversation_id,
+        &settings.image_processing_options(),
+        &state.files,
+        &state.validation,
+    )?;
+    resolve_mentions(&*state.store, &mut update).await?;
+    let edited_at = Utc::now();
+    if let Message::Text(edited) = &mut update {
+        edited.set_edited_at(edited_at);
+    }
+
+    // The revision is written once the edit is saved, from the text the
+    // edit actually replaced.
+    let (previous, updated) = state
+        .store
+        .update_message(message_id, &update)
+        .await?
+        .ok_or(ApiError::NotFound(message_id))?;
+    if let Message::Text(previous) = &previous {
+        let revision = MessageRevision::new(
+            message_id,
+            previous.message().to_string(),
+            *user.sender_id(),
+        )
+        .with_edited_at(edited_at);
+        state.store.insert_revision(&revision).await?;
+    }
+    let mentions = record_mentions(&*state.store, &updated, previous.mentions()).await?;
+    state.hub.publish(ServerFrame::MessageUpdated {
+        conversation_id,
+        message: updated.clone(),
+    });
+    for mention in mentions {
+        state.hub.publish(ServerFrame::Mentioned { mention });
+    }
+
+    Ok(Json(updated))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferConfirmation {
+    transaction_signature: String,
+}
+
+/// Confirms a crypto transfer once its transaction is on chain. Only its
+/// sender may, and subscribers are told the first time it succeeds.
+async fn confirm_transfer(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+    Json(confirmation): Json<TransferConfirmation>,
+) -> Result<Json<Message<'static>>, ApiError> {
+    let Message::CryptoTransfer(transfer) = find_own_message(&state, &user, message_id).await?
+    else {
+        return Err(ApiError::BadRequest(
+            "Only crypto transfers can be confirmed".into(),
+        ));
+    };
+    if transfer.is_confirmed() {
+        return Ok(Json(Message::CryptoTransfer(transfer)));
+    }
+    let verifier = state
+        .transfers
+        .as_ref()
+        .ok_or(TransferError::Unverifiable)?;
+    let signature = confirmation.transaction_signature;
+    if !verifier.is_confirmed(&transfer, &signature).await? {
+        return Err(TransferError::NotConfirmed(signature).into());
+    }
+
+    let Some(confirmed) = state.store.confirm_transfer(message_id, &signature).await? else {
+        // A request racing this one confirmed it first.
+        return Ok(Json(find_own_message(&state, &user, message_id).await?));
+    };
+    state.hub.publish(ServerFrame::TransferConfirmed {
+        conversation_id: confirmed.conversation_id().unwrap_or_default().to_string(),
+        message: confirmed.clone(),
+    });
+
+    Ok(Json(confirmed))
+}
+
+/// The thread a message belongs to, whether it is the first message or one
+/// of the replies.
+async fn get_thread(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<Json<ThreadPage>, ApiError> {
+    let message = find_readable_message(&state, &user, message_id).await?;
+    let root = match message.thread_root() {
+        Some(thread_root) => state
+            .store
+            .find_message(thread_root)
+            .await?
+            .ok_or(ApiError::NotFound(thread_root))?,
+        None => message,
+    };
+    let replies = state
+        .store
+        .thread_replies(
+            root.conversation_id().unwrap_or_default(),
+            root.message_id(),
+            Some(*user.sender_id()),
+        )
+        .await?;
+    let quoted = quotes_for(&*state.store, &replies).await?;
+
+    Ok(Json(ThreadPage {
+        root,
+        replies,
+        quoted,
+    }))
+}
+
+/// The earlier versions of an edited text message, oldest first.
+async fn list_revisions(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<Json<Vec<MessageRevision>>, ApiError> {
+    find_readable_message(&state, &user, message_id).await?;
+
+    Ok(Json(state.store.message_revisions(message_id).await?))
+}
+
+/// Who reacted to a message with what, most used emoji first.
+async fn list_reactions(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<Json<Vec<ReactionGroup>>, ApiError> {
+    find_readable_message(&state, &user, message_id).await?;
+    let reactions = state.store.reactions(message_id).await?;
+
+    Ok(Json(ReactionGroup::group(&reactions)))
+}
+
+/// The requester's drafts in every conversation, most recently saved first.
+async fn list_drafts(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<Draft>>, ApiError> {
+    Ok(Json(state.store.drafts(*user.sender_id()).await?))
+}
+
+async fn get_draft(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+) -> Result<Json<Draft>, ApiError> {
+    let Some(mut draft) = state
+        .store
+        .find_draft(*user.sender_id(), &conversation_id)
+        .await?
+    else {
+        return Err(ApiError::DraftNotFound(conversation_id));
+    };
+    drop_expired_uploads(&state, &mut draft).await?;
+    if draft.is_empty() {
+        return Err(ApiError::DraftNotFound(conversation_id));
+    }
+
+    Ok(Json(draft))
+}
+
+/// Leaves out the uploads of `draft` that expired while it waited. The
+/// upload sweeper removes idle sessions whether or not a draft holds them.
+async fn drop_expired_uploads(state: &AppState, draft: &mut Draft) -> Result<(), ApiError> {
+    let mut live = Vec::new();
+    for upload_id in draft.upload_ids() {
+        match state.uploads.status(upload_id, draft.sender_id()).await {
+            Ok(_) => live.push(upload_id.clone()),
+            Err(UploadError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    draft.set_upload_ids(live);
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DraftUpdate {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    format: TextFormat,
+    #[serde(default)]
+    upload_ids: Vec<String>,
+    /// When the draft was last changed on the client.
+    updated_at: DateTime<Utc>,
+}
+
+/// Saves the requester's draft unless another device saved a newer one,
+/// and returns whichever is kept so the client can catch up. A time ahead
+/// of the server's counts as now, so a clock running fast can't win over
+/// every later save. Saving one with no text or uploads clears it.
+async fn save_draft(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(update): Json<DraftUpdate>,
+) -> Result<Json<Draft>, ApiError> {
+    // Unfinished text may break the other rules for a while, not the size.
+    let max_bytes = state.validation.text_rules(update.format).max_bytes;
+    if update.text.len() > max_bytes {
+        return Err(ValidationError::TooLarge {
+            size: update.text.len(),
+            max: max_bytes,
+        }
+        .into());
+    }
+    let mut upload_ids = update.upload_ids;
+    let mut seen = HashSet::new();
+    upload_ids.retain(|upload_id| seen.insert(upload_id.clone()));
+    if upload_ids.len() > MAX_DRAFT_ATTACHMENTS {
+        return Err(ApiError::Validation(format!(
+            "A draft can hold at most {} attachments",
+            MAX_DRAFT_ATTACHMENTS
+        )));
+    }
+    for upload_id in &upload_ids {
+        let session = state.uploads.status(upload_id, *user.sender_id()).await?;
+        if session.conversation_id() != conversation_id {
+            return Err(ApiError::BadRequest(format!(
+                "Upload {} belongs to another conversation",
+                upload_id
+            )));
+        }
+    }
+
+    let draft = Draft::new(
+        *user.sender_id(),
+        conversation_id,
+        update.text,
+        update.format,
+        upload_ids,
+        update.updated_at.min(Utc::now()),
+    );
+    Ok(Json(store_draft(&state, draft).await?))
+}
+
+async fn delete_draft(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let draft = Draft::empty(*user.sender_id(), conversation_id, Utc::now());
+    store_draft(&state, draft).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Saves `draft` and, if it was newer than the stored one, passes it on to
+/// the owner's other devices.
+async fn store_draft(state: &AppState, draft: Draft) -> Result<Draft, ApiError> {
+    let mut stored = state.store.save_draft(&draft).await?;
+    if stored == draft {
+        state.hub.publish(ServerFrame::DraftUpdated {
+            draft: stored.clone(),
+        });
+    } else {
+        // The newer draft that was kept may have waited a while.
+        drop_expired_uploads(state, &mut stored).await?;
+    }
+
+    Ok(stored)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewScheduledMessage {
+    message: Message<'static>,
+    send_at: DateTime<Utc>,
+}
+
+/// Accepts a message now and holds it back until `send_at`, see
+/// `scheduler::run`. It is checked like any other message, so one that
+/// can't be sent is refused here rather than when it is due.
+async fn schedule_message(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(new): Json<NewScheduledMessage>,
+) -> Result<(StatusCode, Json<ScheduledMessage>), ApiError> {
+    require_member(&state, &conversation_id, &user).await?;
+    let settings = state.store.conversation_settings(&conversation_id).await?;
+    let mut message = accept_message(
+        &new.message,
+        &user,
+        &conversation_id,
+        &settings.image_processing_options(),
+        &state.files,
+        &state.validation,
+    )?;
+    resolve_reply(&*state.store, &mut message).await?;
+    if let Some(scanning) = &state.scanning {
+        scanning.check_message(&mut message).await?;
+    }
+    let mut scheduled =
+        ScheduledMessage::new(message, new.send_at).map_err(ApiError::Validation)?;
+    scheduled.store_attachments(&*state.blobs).await?;
+    if let Err(e) = state.store.insert_scheduled(&scheduled).await {
+        release_blobs(&state, scheduled.message().blobs()).await?;
+        return Err(e.into());
+    }
+    clear_draft(&state, scheduled.message()).await;
+
+    Ok((StatusCode::CREATED, Json(scheduled)))
+}
+
+/// The user's messages that are still waiting to be sent, soonest first.
+async fn list_scheduled(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<ScheduledMessage>>, ApiError> {
+    Ok(Json(
+        state.store.scheduled_messages(*user.sender_id()).await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Reschedule {
+    send_at: DateTime<Utc>,
+}
+
+async fn reschedule_message(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+    Json(reschedule): Json<Reschedule>,
+) -> Result<Json<ScheduledMessage>, ApiError> {
+    let mut scheduled = find_own_scheduled(&state, &user, message_id).await?;
+    scheduled
+        .set_send_at(reschedule.send_at)
+        .map_err(ApiError::Validation)?;
+    if !state.store.reschedule(&scheduled).await? {
+        // Delivered or cancelled in the meantime.
+        return Err(ApiError::NotFound(message_id));
+    }
+
+    Ok(Json(scheduled))
+}
+
+async fn cancel_scheduled(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+) -> Result<StatusCode, ApiError> {
+    let scheduled = find_own_scheduled(&state, &user, message_id).await?;
+    if !state.store.delete_scheduled(message_id).await? {
+        return Err(ApiError::NotFound(message_id));
+    }
+    release_blobs(&state, scheduled.message().blobs()).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find_own_scheduled(
+    state: &AppState,
+    user: &User,
+    message_id: u32,
+) -> Result<ScheduledMessage, ApiError> {
+    let scheduled = state
+        .store
+        .find_scheduled(message_id)
+        .await?
+        .ok_or(ApiError::NotFound(message_id))?;
+    if scheduled.sender_id() != *user.sender_id() {
+        return Err(ApiError::Forbidden);
+    }
+
+    Ok(scheduled)
+}
+
+/// Reacts to a message. Reacting twice with the same emoji changes nothing,
+/// and each user may only use so many different emoji on one message.
+async fn add_reaction(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path((message_id, emoji)): Path<(u32, String)>,
+) -> Result<Response, ApiError> {
+    let message = find_readable_message(&state, &user, message_id).await?;
+    if message.is_deleted() {
+        return Err(ApiError::Validation(
+            "Deleted messages can't be reacted to".into(),
+        ));
+    }
+    let reaction = Reaction::new(
+        message_id,
+        message.conversation_id().unwrap_or_default().to_string(),
+        *user.sender_id(),
+        emoji,
+    )
+    .map_err(ApiError::Validation)?;
+    let own: Vec<Reaction> = state
+        .store
+        .reactions(message_id)
+        .await?
+        .into_iter()
+        .filter(|stored| stored.sender_id() == *user.sender_id())
+        .collect();
+    if own.iter().any(|stored| stored.emoji() == reaction.emoji()) {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    if own.len() >= MAX_REACTIONS_PER_USER {
+        return Err(ApiError::TooManyReactions(MAX_REACTIONS_PER_USER));
+    }
+
+    if !state.store.add_reaction(&reaction).await? {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    state.hub.publish(ServerFrame::ReactionAdded {
+        reaction: reaction.clone(),
+    });
+
+    Ok((StatusCode::CREATED, Json(reaction)).into_response())
+}
+
+async fn remove_reaction(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path((message_id, emoji)): Path<(u32, String)>,
+) -> Result<StatusCode, ApiError> {
+    let message = find_readable_message(&state, &user, message_id).await?;
+    let removed = state
+        .store
+        .remove_reaction(message_id, *user.sender_id(), &emoji)
+        .await?;
+    if let (true, Some(conversation_id)) = (removed, message.conversation_id()) {
+        state.hub.publish(ServerFrame::ReactionRemoved {
+            conversation_id: conversation_id.to_string(),
+            message_id,
+            sender_id: *user.sender_id(),
+            emoji,
+        });
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    #[serde(default)]
+    scope: DeleteScope,
+}
+
+/// Deletes a message for the requester, for everyone, or for good. Only the
+/// last two release its attachments and tell the conversation; a tombstone
+/// stays in the history so clients that sync later remove it too.
+async fn delete_message(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(message_id): Path<u32>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<StatusCode, ApiError> {
+    let existing = find_readable_message(&state, &user, message_id).await?;
+    // Released are the blobs of the message as the store gave it up, so a
+    // racing edit or delete can't have them released twice.
+    let removed = match query.scope {
+        DeleteScope::Me => {
+            let conversation_id = existing.conversation_id().unwrap_or_default();
+            state
+                .store
+                .hide_message(message_id, conversation_id, *user.sender_id())
+                .await?;
+            return Ok(StatusCode::NO_CONTENT);
+        }
+        DeleteScope::Everyone => {
+            if existing.sender_id() != *user.sender_id() && !user.is_moderator() {
+                return Err(ApiError::Forbidden);
+            }
+            if existing.is_deleted() {
+                return Ok(StatusCode::NO_CONTENT);
+            }
+            let tombstone = existing.tombstone(*user.sender_id());
+            let replaced = state
+                .store
+                .replace_message(message_id, &tombstone)
+                .await?
+                .ok_or(ApiError::NotFound(message_id))?;
+            state.store.delete_revisions(message_id).await?;
+            state.store.delete_reactions(message_id).await?;
+            state.store.save_mentions(message_id, &[]).await?;
+            replaced
+        }
+        DeleteScope::Purge => {
+            if !user.is_admin() {
+                return Err(ApiError::AdminOnly);
+            }
+            state
+                .store
+                .delete_message(message_id)
+                .await?
+                .ok_or(ApiError::NotFound(message_id))?
+        }
+    };
+
+    release_blobs(&state, removed.blobs()).await?;
+    if let Some(conversation_id) = existing.conversation_id() {
+        state.hub.publish(ServerFrame::MessageDeleted {
+            conversation_id: conversation_id.to_string(),
+            message_id,
+        });
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    events: Vec<WebhookEventType>,
+    secret: String,
+}
+
+async fn create_webhook(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Json(webhook): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<WebhookSubscription>), ApiError> {
+    let subscription = WebhookSubscription::new(
+        *user.sender_id(),
+        webhook.url,
+        webhook.events,
+        webhook.secret,
+    )
+    .map_err(ApiError::Validation)?;
+    // Deliveries check again, since where the name points may change.
+    let url = reqwest::Url::parse(subscription.url())
+        .map_err(|e| ApiError::Validation(format!("Webhook url is invalid: {}", e)))?;
+    resolve_public(&url, false)
+        .await
+        .map_err(|e| ApiError::Validation(format!("Webhook url is refused: {}", e)))?;
+    state.store.insert_webhook(&subscription).await?;
+
+    Ok((StatusCode::CREATED, Json(subscription)))
+}
+
+async fn list_webhooks(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<WebhookSubscription>>, ApiError> {
+    let subscriptions = state
+        .store
+        .webhooks()
+        .await?
+        .into_iter()
+        .filter(|subscription| subscription.owner_id() == *user.sender_id())
+        .collect();
+
+    Ok(Json(subscriptions))
+}
+
+async fn delete_webhook(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(subscription_id): Path<u32>,
+) -> Result<StatusCode, ApiError> {
+    if !state
+        .store
+        .delete_webhook(subscription_id, *user.sender_id())
+        .await?
+    {
+        return Err(ApiError::WebhookNotFound(subscription_id));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Refuses users who aren't members of the conversation. Whoever first uses
+/// a conversation nobody belongs to becomes its member; moderators may use
+/// any conversation without joining it.
+pub(crate) async fn require_member(
+    state: &AppState,
+    conversation_id: &str,
+    user: &User,
+) -> Result<(), ApiError> {
+    if user.is_moderator()
+        || state
+            .store
+            .claim_membership(conversation_id, *user.sender_id())
+            .await?
+    {
+        Ok(())
+    } else {
+        Err(ApiError::NotMember(conversation_id.to_string()))
+    }
+}
+
+/// A message the user may read, which is one in a conversation they are a
+/// member of. Other messages are reported as not found, so their existence
+/// isn't given away.
+async fn find_readable_message(
+    state: &AppState,
+    user: &User,
+    message_id: u32,
+) -> Result<Message<'static>, ApiError> {
+    let message = state
+        .store
+        .find_message(message_id)
+        .await?
+        .ok_or(ApiError::NotFound(message_id))?;
+    if let Some(conversation_id) = message.conversation_id() {
+        match require_member(state, conversation_id, user).await {
+            Err(ApiError::NotMember(_)) => return Err(ApiError::NotFound(message_id)),
+            result => result?,
+        }
+    }
+
+    Ok(message)
+}
+
+async fn find_own_message(
+    state: &AppState,
+    user: &User,
+    message_id: u32,
+) -> Result<Message<'static>, ApiError> {
+    let message = state
+        .store
+        .find_message(message_id)
+        .await?
+        .ok_or(ApiError::NotFound(message_id))?;
+    if message.sender_id() != *user.sender_id() {
+        return Err(ApiError::Forbidden);
+    }
+
+    Ok(message)
+}
+
+/// Gives back the references a message held on its blobs. Identical
+/// attachments share a blob, which is only deleted once the last message
+/// using it lets go.
+pub(crate) async fn release_blobs(state: &AppState, blobs: Vec<&BlobRef>) -> Result<(), ApiError> {
+    for blob in blobs {
+        state.blobs.release(blob.sha256()).await?;
+    }
+
+    Ok(())
+}
+
+async fn publish_new_message(
+    state: &AppState,
+    message: Message<'static>,
+) -> Result<(StatusCode, Json<Message<'static>>), ApiError> {
+    let message = publish_message(state, message).await?;
+    clear_draft(state, &message).await;
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+/// Clears the draft a message was written in once it is sent, and tells
+/// the sender's other devices. The message is out by then, so this failing
+/// doesn't fail the request.
+pub(crate) async fn clear_draft(state: &AppState, message: &Message<'_>) {
+    match clear_sent_draft(&*state.store, message).await {
+        Ok(Some(draft)) => state.hub.publish(ServerFrame::DraftUpdated { draft }),
+        Ok(None) => {}
+        Err(e) => eprintln!(
+            "Could not clear the draft of message {}: {}",
+            message.message_id(),
+            e
+        ),
+    }
+}
+
+/// Confirms a crypto transfer that comes with its signature, when the
+/// server can check transfers. One that isn't on chain yet is sent all the
+/// same, and its sender confirms it later.
+pub(crate) async fn confirm_on_arrival(state: &AppState, message: &mut Message<'static>) {
+    let (Some(verifier), Message::CryptoTransfer(transfer)) = (&state.transfers, message) else {
+        return;
+    };
+    let Some(signature) = transfer.transaction_signature() else {
+        return;
+    };
+    match verifier.is_confirmed(transfer, signature).await {
+        Ok(true) => transfer.set_confirmed(),
+        Ok(false) => {}
+        Err(e) => eprintln!("Could not check transfer {}: {}", transfer.message_id(), e),
+    }
+}
+
+/// Stores a message that has been accepted and tells the conversation, and
+/// anyone it mentions, about it. Scheduled messages are delivered through
+/// here too.
+pub(crate) async fn publish_message(
+    state: &AppState,
+    mut message: Message<'static>,
+) -> Result<Message<'static>, ApiError> {
+    resolve_reply(&*state.store, &mut message).await?;
+    resolve_mentions(&*state.store, &mut message).await?;
+    if let Some(scanning) = &state.scanning {
+        scanning.check_message(&mut message).await?;
+    }
+    message.store_attachments(&*state.blobs).await?;
+    confirm_on_arrival(state, &mut message).await;
+    state.store.insert_message(&message).await?;
+    let mentions = record_mentions(&*state.store, &message, &[]).await?;
+    if let Some(conversation_id) = message.conversation_id() {
+        state.hub.publish(ServerFrame::Message {
+            conversation_id: conversation_id.to_string(),
+            message: message.clone(),
+        });
+    }
+    for mention in mentions {
+        state.hub.publish(ServerFrame::Mentioned { mention });
+    }
+
+    Ok(message)
+}
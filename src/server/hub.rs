@@ -0,0 +1,33 @@
+use crate::enums::ServerFrame;
+use tokio::sync::broadcast;
+
+const HUB_CAPACITY: usize = 1024;
+
+/// Fans frames out to every connected client. Each connection keeps its own
+/// set of subscribed conversations and drops frames for the others.
+#[derive(Debug, Clone)]
+pub struct Hub {
+    sender: broadcast::Sender<ServerFrame>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(HUB_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, frame: ServerFrame) {
+        // An error only means nobody is connected right now.
+        let _ = self.sender.send(frame);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerFrame> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
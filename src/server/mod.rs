@@ -0,0 +1,98 @@
+pub mod http;
+pub mod hub;
+pub mod scheduler;
+pub mod websocket;
+
+use crate::auth::auth_key;
+use crate::blobs::BlobStore;
+use crate::files::FileOptions;
+use crate::scanning::ScanPolicy;
+use crate::store::MessageStore;
+use crate::transfers::TransferVerifier;
+use crate::uploads::{UploadLimits, UploadManager};
+use crate::validation::ValidationOptions;
+use hub::Hub;
+use ring::hmac;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long after sending a text message it may still be edited, unless
+/// `with_edit_window` says otherwise.
+pub const DEFAULT_EDIT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// State shared by every connection the server accepts.
+#[derive(Clone)]
+pub struct AppState {
+    pub store: Arc<dyn MessageStore>,
+    pub blobs: Arc<dyn BlobStore>,
+    pub uploads: Arc<UploadManager>,
+    pub files: FileOptions,
+    /// What the text of messages and the names of files are held to.
+    pub validation: ValidationOptions,
+    /// Attachments are only scanned when this is set.
+    pub scanning: Option<ScanPolicy>,
+    /// Crypto transfers are only confirmed when this is set.
+    pub transfers: Option<Arc<dyn TransferVerifier>>,
+    /// How long after sending a text message it may still be edited.
+    pub edit_window: Duration,
+    pub hub: Hub,
+    pub auth_key: hmac::Key,
+}
+
+impl AppState {
+    /// Files and text get the default limits, text messages the default
+    /// edit window, and chunked uploads are kept under the system temp
+    /// directory, until `with_file_options`, `with_validation`,
+    /// `with_edit_window` and `with_uploads` say otherwise.
+    pub fn new(
+        store: Arc<dyn MessageStore>,
+        blobs: Arc<dyn BlobStore>,
+        auth_secret: &[u8],
+    ) -> Self {
+        Self {
+            store,
+            blobs,
+            uploads: Arc::new(UploadManager::new(
+                std::env::temp_dir().join("messaging-uploads"),
+                UploadLimits::default(),
+            )),
+            files: FileOptions::default(),
+            validation: ValidationOptions::default(),
+            scanning: None,
+            transfers: None,
+            edit_window: DEFAULT_EDIT_WINDOW,
+            hub: Hub::new(),
+            auth_key: auth_key(auth_secret),
+        }
+    }
+
+    pub fn with_file_options(mut self, files: FileOptions) -> Self {
+        self.files = files;
+        self
+    }
+
+    pub fn with_validation(mut self, validation: ValidationOptions) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    pub fn with_scanning(mut self, scanning: ScanPolicy) -> Self {
+        self.scanning = Some(scanning);
+        self
+    }
+
+    pub fn with_transfer_verifier(mut self, transfers: Arc<dyn TransferVerifier>) -> Self {
+        self.transfers = Some(transfers);
+        self
+    }
+
+    pub fn with_edit_window(mut self, edit_window: Duration) -> Self {
+        self.edit_window = edit_window;
+        self
+    }
+
+    pub fn with_uploads(mut self, uploads: UploadManager) -> Self {
+        self.uploads = Arc::new(uploads);
+        self
+    }
+}
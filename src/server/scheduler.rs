@@ -0,0 +1,123 @@
+use super::AppState;
+use super::http::{publish_message, release_blobs};
+use crate::errors::{ApiError, BlobError, ScanError, ThreadError};
+use crate::structs::blob::BlobRef;
+use crate::structs::scheduled::ScheduledMessage;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// How often the scheduler looks for messages that are due.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most messages delivered in one poll; the rest wait for the next.
+const BATCH_SIZE: usize = 100;
+
+/// Delivers scheduled messages once they are due. They stay in the store
+/// until then, so a restart loses none. A due message is taken out of the
+/// store before it is sent: a cancel or reschedule that succeeded always
+/// stops it, and of several servers polling only one sends it.
+pub async fn run(state: AppState, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        deliver_due(&state, Utc::now()).await;
+    }
+}
+
+/// Delivers what is due by `now` and says how many messages were sent.
+pub async fn deliver_due(state: &AppState, now: DateTime<Utc>) -> usize {
+    let due = match state.store.due_scheduled(now, BATCH_SIZE).await {
+        Ok(due) => due,
+        Err(e) => {
+            eprintln!("Could not load scheduled messages: {}", e);
+            return 0;
+        }
+    };
+
+    let mut sent = 0;
+    for listed in due {
+        let message_id = listed.message_id();
+        // Gone if it was cancelled or moved to later since it was listed.
+        let scheduled = match state.store.claim_scheduled(message_id, now).await {
+            Ok(Some(scheduled)) => scheduled,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Could not claim scheduled message {}: {}", message_id, e);
+                continue;
+            }
+        };
+        let blobs: Vec<BlobRef> = scheduled.message().blobs().into_iter().cloned().collect();
+        match deliver(state, scheduled.clone()).await {
+            Ok(true) => {
+                sent += 1;
+                continue;
+            }
+            // Sent before, and that message holds the blobs.
+            Ok(false) => continue,
+            Err(e) if is_transient(&e) => {
+                eprintln!("Will retry scheduled message {}: {}", message_id, e);
+                match state.store.insert_scheduled(&scheduled).await {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        eprintln!("Could not put back scheduled message {}: {}", message_id, e)
+                    }
+                }
+            }
+            Err(e) => eprintln!("Dropping scheduled message {}: {}", message_id, e),
+        }
+        if let Err(e) = release_blobs(state, blobs.iter().collect()).await {
+            eprintln!("Could not release scheduled message {}: {}", message_id, e);
+        }
+    }
+
+    sent
+}
+
+/// Sends a scheduled message as if it had just been written, unless it was
+/// sent already. The delivered message keeps the scheduled one's blobs.
+async fn deliver(state: &AppState, scheduled: ScheduledMessage) -> Result<bool, ApiError> {
+    if state
+        .store
+        .find_message(scheduled.message_id())
+        .await?
+        .is_some()
+    {
+        return Ok(false);
+    }
+    if !sender_may_post(state, &scheduled).await? {
+        return Err(ApiError::NotMember(scheduled.conversation_id().to_string()));
+    }
+
+    let mut message = scheduled.into_message();
+    message.set_created_at(Utc::now());
+    publish_message(state, message).await?;
+
+    Ok(true)
+}
+
+/// Whether the sender may still post in the conversation, which they may
+/// have left since they scheduled the message.
+async fn sender_may_post(state: &AppState, scheduled: &ScheduledMessage) -> Result<bool, ApiError> {
+    let sender_id = scheduled.sender_id();
+    if let Some(sender) = state.store.find_user(sender_id).await?
+        && sender.is_moderator()
+    {
+        return Ok(true);
+    }
+
+    Ok(state
+        .store
+        .claim_membership(scheduled.conversation_id(), sender_id)
+        .await?)
+}
+
+/// Whether trying again later could succeed. Anything else, such as a
+/// reply whose parent has since been deleted, never will.
+fn is_transient(error: &ApiError) -> bool {
+    match error {
+        ApiError::Blob(e) => !matches!(e, BlobError::Missing(_)),
+        ApiError::Store(_) | ApiError::Thread(ThreadError::Store(_)) => true,
+        ApiError::Scan(e) => !matches!(e, ScanError::Infected(_)),
+        _ => false,
+    }
+}
@@ -0,0 +1,287 @@
+use crate::auth::verify_token;
+use crate::enums::{ClientFrame, NotifyLevel, ServerFrame};
+use crate::mentions::{record_mentions, resolve_mentions};
+use crate::messaging::accept_message;
+use crate::server::AppState;
+use crate::server::http::{clear_draft, confirm_on_arrival, require_member, with_stored_role};
+use crate::structs::receipt::Receipt;
+use crate::structs::user::User;
+use crate::threads::resolve_reply;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{self, Message as WsMessage};
+use tokio_tungstenite::{WebSocketStream, accept_async};
+
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+type WsSink = SplitSink<WebSocketStream<TcpStream>, WsMessage>;
+type WsSource = SplitStream<WebSocketStream<TcpStream>>;
+
+/// Accepts WebSocket connections on `listener` until the listener fails.
+pub async fn serve(listener: TcpListener, state: AppState) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state).await {
+                eprintln!("WebSocket connection {} closed with error: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: AppState) -> Result<(), tungstenite::Error> {
+    let websocket = accept_async(stream).await?;
+    let (mut sink, mut source) = websocket.split();
+
+    let user = match authenticate(&mut source, &state).await {
+        Ok(user) => user,
+        Err(error) => {
+            send_frame(&mut sink, &ServerFrame::Error { error }).await?;
+            sink.close().await?;
+            return Ok(());
+        }
+    };
+    send_frame(
+        &mut sink,
+        &ServerFrame::Authenticated {
+            sender_id: *user.sender_id(),
+        },
+    )
+    .await?;
+
+    let mut events = state.hub.subscribe();
+    let mut subscriptions: HashMap<String, NotifyLevel> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            incoming = source.next() => {
+                let Some(incoming) = incoming else { break };
+                match incoming? {
+                    WsMessage::Text(text) => {
+                        let reply = match serde_json::from_str::<ClientFrame>(&text) {
+                            // Roles may change while the connection is open.
+                            Ok(frame) => match with_stored_role(&state, user.clone()).await {
+                                Ok(user) => handle_frame(frame, &user, &state, &mut subscriptions).await,
+                                Err(e) => Some(ServerFrame::Error { error: e.to_string() }),
+                            },
+                            Err(e) => Some(ServerFrame::Error {
+                                error: format!("Invalid frame: {}", e),
+                            }),
+                        };
+                        if let Some(reply) = reply {
+                            send_frame(&mut sink, &reply).await?;
+                        }
+                    }
+                    WsMessage::Close(_) => break,
+                    _ => {}
+                }
+            }
+            event = events.recv() => match event {
+                Ok(frame) => {
+                    if wants(&frame, &user, &subscriptions) {
+                        send_frame(&mut sink, &frame).await?;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("WebSocket client {} lagged, skipped {} frames", user.sender_id(), skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a frame from the hub goes to this connection. Frames for one
+/// user go to them alone; in conversations where they only want mentions,
+/// new messages that don't mention them are held back.
+fn wants(frame: &ServerFrame, user: &User, subscriptions: &HashMap<String, NotifyLevel>) -> bool {
+    if let Some(recipient) = frame.recipient() {
+        return recipient == *user.sender_id();
+    }
+    let Some(level) = frame.conversation_id().and_then(|id| subscriptions.get(id)) else {
+        return false;
+    };
+
+    match frame {
+        ServerFrame::Message { message, .. } if *level == NotifyLevel::Mentions => message
+            .mentions()
+            .iter()
+            .any(|mention| mention.sender_id() == *user.sender_id()),
+        _ => true,
+    }
+}
+
+async fn authenticate(source: &mut WsSource, state: &AppState) -> Result<User, String> {
+    let first = timeout(AUTH_TIMEOUT, source.next())
+        .await
+        .map_err(|_| "Timed out waiting for an authenticate frame".to_string())?;
+
+    let token = match first {
+        Some(Ok(WsMessage::Text(text))) => match serde_json::from_str::<ClientFrame>(&text) {
+            Ok(ClientFrame::Authenticate { token }) => token,
+            _ => return Err("The first frame must be an authenticate frame".into()),
+        },
+        _ => return Err("The first frame must be an authenticate frame".into()),
+    };
+
+    verify_token(&state.auth_key, &token).map_err(|e| format!("Authentication failed: {}", e))
+}
+
+async fn handle_frame(
+    frame: ClientFrame,
+    user: &User,
+    state: &AppState,
+    subscriptions: &mut HashMap<String, NotifyLevel>,
+) -> Option<ServerFrame> {
+    if let Some(conversation_id) = frame.conversation_id()
+        && let Err(e) = require_member(state, conversation_id, user).await
+    {
+        return Some(ServerFrame::Error {
+            error: e.to_string(),
+        });
+    }
+
+    match frame {
+        ClientFrame::Authenticate { .. } => Some(ServerFrame::Error {
+            error: "Connection is already authenticated".into(),
+        }),
+        ClientFrame::Subscribe { conversation_id } => {
+            let level = match state
+                .store
+                .notify_level(&conversation_id, *user.sender_id())
+                .await
+            {
+                Ok(level) => level,
+                Err(e) => {
+                    return Some(ServerFrame::Error {
+                        error: format!("Could not load notification settings: {}", e),
+                    });
+                }
+            };
+            subscriptions.insert(conversation_id.clone(), level);
+            Some(ServerFrame::Subscribed { conversation_id })
+        }
+        ClientFrame::Unsubscribe { conversation_id } => {
+            subscriptions.remove(&conversation_id);
+            Some(ServerFrame::Unsubscribed { conversation_id })
+        }
+        ClientFrame::Send {
+            conversation_id,
+            message,
+        } => {
+            let settings = match state.store.conversation_settings(&conversation_id).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    return Some(ServerFrame::Error {
+                        error: format!("Could not load conversation settings: {}", e),
+                    });
+                }
+            };
+            let image_options = settings.image_processing_options();
+            let mut message = match accept_message(
+                &message,
+                user,
+                &conversation_id,
+                &image_options,
+                &state.files,
+                &state.validation,
+            ) {
+                Ok(message) => message,
+                Err(error) => {
+                    return Some(ServerFrame::Error {
+                        error: error.to_string(),
+                    });
+                }
+            };
+            if let Err(e) = resolve_reply(&*state.store, &mut message).await {
+                return Some(ServerFrame::Error {
+                    error: e.to_string(),
+                });
+            }
+            if let Err(e) = resolve_mentions(&*state.store, &mut message).await {
+                return Some(ServerFrame::Error {
+                    error: format!("Could not resolve mentions: {}", e),
+                });
+            }
+            if let Some(scanning) = &state.scanning
+                && let Err(e) = scanning.check_message(&mut message).await
+            {
+                return Some(ServerFrame::Error {
+                    error: e.to_string(),
+                });
+            }
+            if let Err(e) = message.store_attachments(&*state.blobs).await {
+                return Some(ServerFrame::Error {
+                    error: format!("Could not store attachment: {}", e),
+                });
+            }
+            confirm_on_arrival(state, &mut message).await;
+            if let Err(e) = state.store.insert_message(&message).await {
+                return Some(ServerFrame::Error {
+                    error: format!("Could not save message: {}", e),
+                });
+            }
+
+            let mentions = match record_mentions(&*state.store, &message, &[]).await {
+                Ok(mentions) => mentions,
+                Err(e) => {
+                    return Some(ServerFrame::Error {
+                        error: format!("Could not save mentions: {}", e),
+                    });
+                }
+            };
+
+            clear_draft(state, &message).await;
+            let message_id = message.message_id();
+            state.hub.publish(ServerFrame::Message {
+                conversation_id: conversation_id.clone(),
+                message,
+            });
+            for mention in mentions {
+                state.hub.publish(ServerFrame::Mentioned { mention });
+            }
+            Some(ServerFrame::Sent {
+                conversation_id,
+                message_id,
+            })
+        }
+        ClientFrame::Receipt {
+            conversation_id,
+            message_id,
+            status,
+        } => {
+            let receipt = Receipt::new(message_id, conversation_id, *user.sender_id(), status);
+            if let Err(e) = state.store.insert_receipt(&receipt).await {
+                return Some(ServerFrame::Error {
+                    error: format!("Could not save receipt: {}", e),
+                });
+            }
+            state.hub.publish(ServerFrame::Receipt { receipt });
+            None
+        }
+        ClientFrame::Typing {
+            conversation_id,
+            is_typing,
+        } => {
+            state.hub.publish(ServerFrame::Typing {
+                conversation_id,
+                sender_id: *user.sender_id(),
+                is_typing,
+            });
+            None
+        }
+    }
+}
+
+async fn send_frame(sink: &mut WsSink, frame: &ServerFrame) -> Result<(), tungstenite::Error> {
+    let json = serde_json::to_string(frame).expect("ServerFrame always serializes to JSON");
+    sink.send(WsMessage::text(json)).await
+}
@@ -0,0 +1,1300 @@
+use crate::conversations;
+use crate::enums::{Message, NotifyLevel};
+use crate::errors::StoreError;
+use crate::images::hash_distance;
+use crate::messaging;
+use crate::structs::conversation::ConversationSettings;
+use crate::structs::draft::Draft;
+use crate::structs::history::HistoryCursor;
+use crate::structs::mention::InboxMention;
+use crate::structs::messages::image::SimilarImage;
+use crate::structs::preview::LinkPreview;
+use crate::structs::reaction::Reaction;
+use crate::structs::receipt::Receipt;
+use crate::structs::revision::MessageRevision;
+use crate::structs::scheduled::ScheduledMessage;
+use crate::structs::user::User;
+use crate::structs::webhook::{WebhookDeadLetter, WebhookSubscription};
+use crate::users;
+use crate::webhooks;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{from_document, to_document};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Persistence used by the servers. `MongoMessageStore` is backed by the
+/// functions in `messaging.rs`; `MemoryMessageStore` keeps everything in
+/// process and is what the integration tests run against.
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    async fn insert_message(&self, message: &Message<'static>) -> Result<(), StoreError>;
+
+    async fn find_message(&self, message_id: u32) -> Result<Option<Message<'static>>, StoreError>;
+
+    /// Newest first, starting after `before` when it is given. Messages
+    /// `viewer` deleted for themselves are left out.
+    async fn conversation_history(
+        &self,
+        conversation_id: &str,
+        before: Option<&HistoryCursor>,
+        viewer: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<Message<'static>>, StoreError>;
+
+    /// Replaces the content of a stored message, see `messaging::content_fields`.
+    /// Returns the message as it was and as it is now.
+    async fn update_message(
+        &self,
+        message_id: u32,
+        message: &Message<'static>,
+    ) -> Result<Option<(Message<'static>, Message<'static>)>, StoreError>;
+
+    /// The replies in the thread `thread_root` started, oldest first. Replies
+    /// `viewer` deleted for themselves are left out.
+    async fn thread_replies(
+        &self,
+        conversation_id: &str,
+        thread_root: u32,
+        viewer: Option<u32>,
+    ) -> Result<Vec<Message<'static>>, StoreError>;
+
+    /// How many replies, not counting deleted ones, each of `thread_roots`
+    /// has. Threads without replies are left out.
+    async fn reply_counts(&self, thread_roots: &[u32]) -> Result<BTreeMap<u32, u64>, StoreError>;
+
+    /// Swaps a stored message for `message` whole, as when it is replaced
+    /// by a tombstone. Returns the message that was replaced.
+    async fn replace_message(
+        &self,
+        message_id: u32,
+        message: &Message<'static>,
+    ) -> Result<Option<Message<'static>>, StoreError>;
+
+    /// Attaches link previews to a text message, unless its text is no
+    /// longer `text` because it was edited or deleted meanwhile.
+    async fn attach_previews(
+        &self,
+        message_id: u32,
+        text: &str,
+        previews: &[LinkPreview],
+    ) -> Result<Option<Message<'static>>, StoreError>;
+
+    /// Marks a crypto transfer confirmed with `signature`. Returns the
+    /// message only to the one caller that confirmed it, so it is announced
+    /// once.
+    async fn confirm_transfer(
+        &self,
+        message_id: u32,
+        signature: &str,
+    ) -> Result<Option<Message<'static>>, StoreError>;
+
+    /// Removes a message for good, with its revisions, reactions and the
+    /// mentions it put in inboxes.
+    async fn delete_message(&self, message_id: u32)
+    -> Result<Option<Message<'static>>, StoreError>;
+
+    /// Hides a message from `sender_id`'s history only.
+    async fn hide_message(
+        &self,
+        message_id: u32,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<(), StoreError>;
+
+    /// Image messages within `max_distance` bits of `perceptual_hash`, closest
+    /// first, optionally limited to one conversation.
+    async fn similar_images(
+        &self,
+        perceptual_hash: &str,
+        conversation_id: Option<&str>,
+        max_distance: u32,
+        limit: usize,
+    ) -> Result<Vec<SimilarImage>, StoreError>;
+
+    async fn insert_receipt(&self, receipt: &Receipt) -> Result<(), StoreError>;
+
+    async fn insert_revision(&self, revision: &MessageRevision) -> Result<(), StoreError>;
+
+    /// Earlier versions of a message, oldest first.
+    async fn message_revisions(&self, message_id: u32) -> Result<Vec<MessageRevision>, StoreError>;
+
+    async fn delete_revisions(&self, message_id: u32) -> Result<(), StoreError>;
+
+    /// Records a reaction unless the user already reacted to the message
+    /// with the same emoji. Says whether it was new.
+    async fn add_reaction(&self, reaction: &Reaction) -> Result<bool, StoreError>;
+
+    /// Says whether there was such a reaction to remove.
+    async fn remove_reaction(
+        &self,
+        message_id: u32,
+        sender_id: u32,
+        emoji: &str,
+    ) -> Result<bool, StoreError>;
+
+    /// Reactions to a message, oldest first.
+    async fn reactions(&self, message_id: u32) -> Result<Vec<Reaction>, StoreError>;
+
+    /// How often each emoji was used on each of `message_ids`. Messages
+    /// without reactions are left out.
+    async fn reaction_counts(
+        &self,
+        message_ids: &[u32],
+    ) -> Result<BTreeMap<u32, BTreeMap<String, u64>>, StoreError>;
+
+    async fn delete_reactions(&self, message_id: u32) -> Result<(), StoreError>;
+
+    async fn insert_user(&self, user: &User) -> Result<(), StoreError>;
+
+    async fn find_user(&self, sender_id: u32) -> Result<Option<User>, StoreError>;
+
+    /// The registered users with any of `handles`, which must be lowercase.
+    async fn users_by_handle(&self, handles: &[String]) -> Result<Vec<User>, StoreError>;
+
+    /// Makes `mentions` the inbox entries for `message_id`. Entries that are
+    /// already there keep whether they were read; an empty list clears them.
+    async fn save_mentions(
+        &self,
+        message_id: u32,
+        mentions: &[InboxMention],
+    ) -> Result<(), StoreError>;
+
+    /// A user's mentions, newest first.
+    async fn mention_inbox(
+        &self,
+        sender_id: u32,
+        unread_only: bool,
+        limit: usize,
+    ) -> Result<Vec<InboxMention>, StoreError>;
+
+    /// Says whether the user had such a mention.
+    async fn mark_mention_read(&self, sender_id: u32, message_id: u32) -> Result<bool, StoreError>;
+
+    async fn notify_level(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<NotifyLevel, StoreError>;
+
+    async fn set_notify_level(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+        level: NotifyLevel,
+    ) -> Result<(), StoreError>;
+
+    /// Whether `sender_id` is a member of the conversation. The first user
+    /// to ask about a conversation nobody has joined becomes its member.
+    async fn claim_membership(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<bool, StoreError>;
+
+    /// The members of a conversation, in the order they joined.
+    async fn members(&self, conversation_id: &str) -> Result<Vec<u32>, StoreError>;
+
+    async fn add_member(&self, conversation_id: &str, sender_id: u32) -> Result<(), StoreError>;
+
+    /// Says whether `sender_id` was a member.
+    async fn remove_member(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<bool, StoreError>;
+
+    /// Saves `draft` unless the stored one is at least as new, and returns
+    /// whichever is stored afterwards.
+    async fn save_draft(&self, draft: &Draft) -> Result<Draft, StoreError>;
+
+    async fn find_draft(
+        &self,
+        sender_id: u32,
+        conversation_id: &str,
+    ) -> Result<Option<Draft>, StoreError>;
+
+    /// `sender_id`'s drafts that aren't empty, most recently saved first.
+    async fn drafts(&self, sender_id: u32) -> Result<Vec<Draft>, StoreError>;
+
+    async fn insert_scheduled(&self, scheduled: &ScheduledMessage) -> Result<(), StoreError>;
+
+    async fn find_scheduled(&self, message_id: u32)
+    -> Result<Option<ScheduledMessage>, StoreError>;
+
+    /// `sender_id`'s messages still waiting to be sent, soonest first.
+    async fn scheduled_messages(&self, sender_id: u32)
+    -> Result<Vec<ScheduledMessage>, StoreError>;
+
+    /// Up to `limit` scheduled messages due by `now`, longest due first.
+    async fn due_scheduled(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<ScheduledMessage>, StoreError>;
+
+    /// Saves the new `send_at` of a scheduled message.
+    async fn reschedule(&self, scheduled: &ScheduledMessage) -> Result<bool, StoreError>;
+
+    async fn delete_scheduled(&self, message_id: u32) -> Result<bool, StoreError>;
+
+    /// Removes a scheduled message if it is still due by `now`, and returns
+    /// it. Only one caller gets it, and none once it was cancelled or moved
+    /// to later.
+    async fn claim_scheduled(
+        &self,
+        message_id: u32,
+        now: DateTime<Utc>,
+    ) -> Result<Option<ScheduledMessage>, StoreError>;
+
+    async fn insert_webhook(&self, subscription: &WebhookSubscription) -> Result<(), StoreError>;
+
+    async fn webhooks(&self) -> Result<Vec<WebhookSubscription>, StoreError>;
+
+    async fn delete_webhook(&self, subscription_id: u32, owner_id: u32)
+    -> Result<bool, StoreError>;
+
+    async fn insert_dead_letter(&self, dead_letter: &WebhookDeadLetter) -> Result<(), StoreError>;
+
+    /// The conversation's settings, or the defaults when it has none.
+    async fn conversation_settings(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationSettings, StoreError>;
+
+    async fn save_conversation_settings(
+        &self,
+        settings: &ConversationSettings,
+    ) -> Result<(), StoreError>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MongoMessageStore;
+
+#[async_trait]
+impl MessageStore for MongoMessageStore {
+    async fn insert_message(&self, message: &Message<'static>) -> Result<(), StoreError> {
+        messaging::insert_message(message).await?;
+        Ok(())
+    }
+
+    async fn find_message(&self, message_id: u32) -> Result<Option<Message<'static>>, StoreError> {
+        Ok(messaging::find_message_by_id(message_id).await?)
+    }
+
+    async fn conversation_history(
+        &self,
+        conversation_id: &str,
+        before: Option<&HistoryCursor>,
+        viewer: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<Message<'static>>, StoreError> {
+        Ok(
+            messaging::get_conversation_messages(conversation_id, before, viewer, limit as i64)
+                .await?,
+        )
+    }
+
+    async fn update_message(
+        &self,
+        message_id: u32,
+        message: &Message<'static>,
+    ) -> Result<Option<(Message<'static>, Message<'static>)>, StoreError> {
+        Ok(messaging::update_message(message_id, message).await?)
+    }
+
+    async fn thread_replies(
+        &self,
+        conversation_id: &str,
+        thread_root: u32,
+        viewer: Option<u32>,
+    ) -> Result<Vec<Message<'static>>, StoreError> {
+        Ok(messaging::get_thread_replies(conversation_id, thread_root, viewer).await?)
+    }
+
+    async fn reply_counts(&self, thread_roots: &[u32]) -> Result<BTreeMap<u32, u64>, StoreError> {
+        Ok(messaging::count_replies(thread_roots).await?)
+    }
+
+    async fn replace_message(
+        &self,
+        message_id: u32,
+        message: &Message<'static>,
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        Ok(messaging::replace_message(message_id, message).await?)
+    }
+
+    async fn attach_previews(
+        &self,
+        message_id: u32,
+        text: &str,
+        previews: &[LinkPreview],
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        Ok(messaging::attach_previews(message_id, text, previews).await?)
+    }
+
+    async fn confirm_transfer(
+        &self,
+        message_id: u32,
+        signature: &str,
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        Ok(messaging::confirm_transfer(message_id, signature).await?)
+    }
+
+    async fn delete_message(
+        &self,
+        message_id: u32,
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        Ok(messaging::delete_message(message_id).await?)
+    }
+
+    async fn hide_message(
+        &self,
+        message_id: u32,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<(), StoreError> {
+        messaging::hide_message(message_id, conversation_id, sender_id).await?;
+        Ok(())
+    }
+
+    async fn similar_images(
+        &self,
+        perceptual_hash: &str,
+        conversation_id: Option<&str>,
+        max_distance: u32,
+        limit: usize,
+    ) -> Result<Vec<SimilarImage>, StoreError> {
+        Ok(
+            messaging::find_similar_images(perceptual_hash, conversation_id, max_distance, limit)
+                .await?,
+        )
+    }
+
+    async fn insert_receipt(&self, receipt: &Receipt) -> Result<(), StoreError> {
+        messaging::insert_receipt(receipt).await?;
+        Ok(())
+    }
+
+    async fn insert_revision(&self, revision: &MessageRevision) -> Result<(), StoreError> {
+        messaging::insert_revision(revision).await?;
+        Ok(())
+    }
+
+    async fn message_revisions(&self, message_id: u32) -> Result<Vec<MessageRevision>, StoreError> {
+        Ok(messaging::find_revisions(message_id).await?)
+    }
+
+    async fn delete_revisions(&self, message_id: u32) -> Result<(), StoreError> {
+        messaging::delete_revisions(message_id).await?;
+        Ok(())
+    }
+
+    async fn add_reaction(&self, reaction: &Reaction) -> Result<bool, StoreError> {
+        Ok(messaging::insert_reaction(reaction).await?)
+    }
+
+    async fn remove_reaction(
+        &self,
+        message_id: u32,
+        sender_id: u32,
+        emoji: &str,
+    ) -> Result<bool, StoreError> {
+        Ok(messaging::delete_reaction(message_id, sender_id, emoji).await?)
+    }
+
+    async fn reactions(&self, message_id: u32) -> Result<Vec<Reaction>, StoreError> {
+        Ok(messaging::find_reactions(message_id).await?)
+    }
+
+    async fn reaction_counts(
+        &self,
+        message_ids: &[u32],
+    ) -> Result<BTreeMap<u32, BTreeMap<String, u64>>, StoreError> {
+        Ok(messaging::count_reactions(message_ids).await?)
+    }
+
+    async fn delete_reactions(&self, message_id: u32) -> Result<(), StoreError> {
+        messaging::delete_reactions(message_id).await?;
+        Ok(())
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), StoreError> {
+        users::insert_user(user).await?;
+        Ok(())
+    }
+
+    async fn find_user(&self, sender_id: u32) -> Result<Option<User>, StoreError> {
+        Ok(users::find_user_by_id(sender_id).await?)
+    }
+
+    async fn users_by_handle(&self, handles: &[String]) -> Result<Vec<User>, StoreError> {
+        Ok(users::find_users_by_handle(handles).await?)
+    }
+
+    async fn save_mentions(
+        &self,
+        message_id: u32,
+        mentions: &[InboxMention],
+    ) -> Result<(), StoreError> {
+        messaging::save_mentions(message_id, mentions).await?;
+        Ok(())
+    }
+
+    async fn mention_inbox(
+        &self,
+        sender_id: u32,
+        unread_only: bool,
+        limit: usize,
+    ) -> Result<Vec<InboxMention>, StoreError> {
+        Ok(messaging::find_mention_inbox(sender_id, unread_only, limit).await?)
+    }
+
+    async fn mark_mention_read(&self, sender_id: u32, message_id: u32) -> Result<bool, StoreError> {
+        Ok(messaging::mark_mention_read(sender_id, message_id).await?)
+    }
+
+    async fn notify_level(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<NotifyLevel, StoreError> {
+        Ok(conversations::get_notify_level(conversation_id, sender_id).await?)
+    }
+
+    async fn set_notify_level(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+        level: NotifyLevel,
+    ) -> Result<(), StoreError> {
+        conversations::save_notify_level(conversation_id, sender_id, level).await?;
+        Ok(())
+    }
+
+    async fn claim_membership(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<bool, StoreError> {
+        Ok(conversations::claim_membership(conversation_id, sender_id).await?)
+    }
+
+    async fn members(&self, conversation_id: &str) -> Result<Vec<u32>, StoreError> {
+        Ok(conversations::get_members(conversation_id).await?)
+    }
+
+    async fn add_member(&self, conversation_id: &str, sender_id: u32) -> Result<(), StoreError> {
+        conversations::add_member(conversation_id, sender_id).await?;
+        Ok(())
+    }
+
+    async fn remove_member(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<bool, StoreError> {
+        Ok(conversations::remove_member(conversation_id, sender_id).await?)
+    }
+
+    async fn save_draft(&self, draft: &Draft) -> Result<Draft, StoreError> {
+        Ok(conversations::save_draft(draft).await?)
+    }
+
+    async fn find_draft(
+        &self,
+        sender_id: u32,
+        conversation_id: &str,
+    ) -> Result<Option<Draft>, StoreError> {
+        Ok(conversations::get_draft(sender_id, conversation_id).await?)
+    }
+
+    async fn drafts(&self, sender_id: u32) -> Result<Vec<Draft>, StoreError> {
+        Ok(conversations::get_drafts(sender_id).await?)
+    }
+
+    async fn insert_scheduled(&self, scheduled: &ScheduledMessage) -> Result<(), StoreError> {
+        messaging::insert_scheduled(scheduled).await?;
+        Ok(())
+    }
+
+    async fn find_scheduled(
+        &self,
+        message_id: u32,
+    ) -> Result<Option<ScheduledMessage>, StoreError> {
+        Ok(messaging::find_scheduled(message_id).await?)
+    }
+
+    async fn scheduled_messages(
+        &self,
+        sender_id: u32,
+    ) -> Result<Vec<ScheduledMessage>, StoreError> {
+        Ok(messaging::find_scheduled_by_sender(sender_id).await?)
+    }
+
+    async fn due_scheduled(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<ScheduledMessage>, StoreError> {
+        Ok(messaging::find_due_scheduled(now, limit).await?)
+    }
+
+    async fn reschedule(&self, scheduled: &ScheduledMessage) -> Result<bool, StoreError> {
+        Ok(messaging::reschedule(scheduled.message_id(), scheduled.send_at()).await?)
+    }
+
+    async fn delete_scheduled(&self, message_id: u32) -> Result<bool, StoreError> {
+        Ok(messaging::delete_scheduled(message_id).await?)
+    }
+
+    async fn claim_scheduled(
+        &self,
+        message_id: u32,
+        now: DateTime<Utc>,
+    ) -> Result<Option<ScheduledMessage>, StoreError> {
+        Ok(messaging::claim_scheduled(message_id, now).await?)
+    }
+
+    async fn insert_webhook(&self, subscription: &WebhookSubscription) -> Result<(), StoreError> {
+        webhooks::insert_webhook(subscription).await?;
+        Ok(())
+    }
+
+    async fn webhooks(&self) -> Result<Vec<WebhookSubscription>, StoreError> {
+        Ok(webhooks::get_webhooks().await?)
+    }
+
+    async fn delete_webhook(
+        &self,
+        subscription_id: u32,
+        owner_id: u32,
+    ) -> Result<bool, StoreError> {
+        Ok(webhooks::delete_webhook(subscription_id, owner_id).await?)
+    }
+
+    async fn insert_dead_letter(&self, dead_letter: &WebhookDeadLetter) -> Result<(), StoreError> {
+        webhooks::insert_dead_letter(dead_letter).await?;
+        Ok(())
+    }
+
+    async fn conversation_settings(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationSettings, StoreError> {
+        Ok(conversations::get_conversation_settings(conversation_id).await?)
+    }
+
+    async fn save_conversation_settings(
+        &self,
+        settings: &ConversationSettings,
+    ) -> Result<(), StoreError> {
+        conversations::save_conversation_settings(settings).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryMessageStore {
+    messages: Mutex<Vec<Message<'static>>>,
+    receipts: Mutex<Vec<Receipt>>,
+    revisions: Mutex<Vec<MessageRevision>>,
+    reactions: Mutex<Vec<Reaction>>,
+    users: Mutex<Vec<User>>,
+    mentions: Mutex<Vec<InboxMention>>,
+    notify_levels: Mutex<Vec<(String, u32, NotifyLevel)>>,
+    /// The members of each conversation that has been claimed.
+    members: Mutex<BTreeMap<String, Vec<u32>>>,
+    drafts: Mutex<Vec<Draft>>,
+    /// `(message_id, sender_id)` of messages deleted for one user.
+    hidden: Mutex<Vec<(u32, u32)>>,
+    webhooks: Mutex<Vec<WebhookSubscription>>,
+    dead_letters: Mutex<Vec<WebhookDeadLetter>>,
+    conversation_settings: Mutex<Vec<ConversationSettings>>,
+    scheduled: Mutex<Vec<ScheduledMessage>>,
+}
+
+impl MemoryMessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages(&self) -> Vec<Message<'static>> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    pub fn receipts(&self) -> Vec<Receipt> {
+        self.receipts.lock().unwrap().clone()
+    }
+
+    pub fn dead_letters(&self) -> Vec<WebhookDeadLetter> {
+        self.dead_letters.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryMessageStore {
+    async fn insert_message(&self, message: &Message<'static>) -> Result<(), StoreError> {
+        self.messages.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+
+    async fn find_message(&self, message_id: u32) -> Result<Option<Message<'static>>, StoreError> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .iter()
+            .find(|message| message.message_id() == message_id)
+            .cloned())
+    }
+
+    async fn conversation_history(
+        &self,
+        conversation_id: &str,
+        before: Option<&HistoryCursor>,
+        viewer: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<Message<'static>>, StoreError> {
+        let hidden = self.hidden.lock().unwrap().clone();
+        let mut history: Vec<Message<'static>> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.conversation_id() == Some(conversation_id))
+            .filter(|message| {
+                viewer.is_none_or(|viewer| !hidden.contains(&(message.message_id(), viewer)))
+            })
+            .filter(|message| {
+                before.is_none_or(|cursor| {
+                    (message.created_at(), message.message_id())
+                        < (cursor.timestamp(), cursor.message_id())
+                })
+            })
+            .cloned()
+            .collect();
+        history
+            .sort_by_key(|message| std::cmp::Reverse((message.created_at(), message.message_id())));
+        history.truncate(limit);
+
+        Ok(history)
+    }
+
+    async fn update_message(
+        &self,
+        message_id: u32,
+        message: &Message<'static>,
+    ) -> Result<Option<(Message<'static>, Message<'static>)>, StoreError> {
+        let content = messaging::content_fields(message).map_err(mongodb::error::Error::from)?;
+        let mut messages = self.messages.lock().unwrap();
+        let Some(stored) = messages
+            .iter_mut()
+            .find(|stored| stored.message_id() == message_id)
+        else {
+            return Ok(None);
+        };
+
+        let mut document = to_document(stored).map_err(mongodb::error::Error::from)?;
+        document.extend(content);
+        let updated = from_document(document).map_err(mongodb::error::Error::from)?;
+        let previous = std::mem::replace(stored, updated);
+
+        Ok(Some((previous, stored.clone())))
+    }
+
+    async fn thread_replies(
+        &self,
+        conversation_id: &str,
+        thread_root: u32,
+        viewer: Option<u32>,
+    ) -> Result<Vec<Message<'static>>, StoreError> {
+        let hidden = self.hidden.lock().unwrap().clone();
+        let mut replies: Vec<Message<'static>> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.conversation_id() == Some(conversation_id))
+            .filter(|message| message.thread_root() == Some(thread_root))
+            .filter(|message| {
+                viewer.is_none_or(|viewer| !hidden.contains(&(message.message_id(), viewer)))
+            })
+            .cloned()
+            .collect();
+        replies.sort_by_key(|message| (message.created_at(), message.message_id()));
+
+        Ok(replies)
+    }
+
+    async fn reply_counts(&self, thread_roots: &[u32]) -> Result<BTreeMap<u32, u64>, StoreError> {
+        let mut counts = BTreeMap::new();
+        for message in self.messages.lock().unwrap().iter() {
+            if let Some(thread_root) = message.thread_root()
+                && thread_roots.contains(&thread_root)
+                && !message.is_deleted()
+            {
+                *counts.entry(thread_root).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    async fn replace_message(
+        &self,
+        message_id: u32,
+        message: &Message<'static>,
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some(stored) = messages
+            .iter_mut()
+            .find(|stored| stored.message_id() == message_id)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(std::mem::replace(stored, message.clone())))
+    }
+
+    async fn attach_previews(
+        &self,
+        message_id: u32,
+        text: &str,
+        previews: &[LinkPreview],
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some(Message::Text(stored)) = messages
+            .iter_mut()
+            .find(|stored| stored.message_id() == message_id)
+        else {
+            return Ok(None);
+        };
+        if stored.message() != text {
+            return Ok(None);
+        }
+        stored.set_previews(previews.to_vec());
+
+        Ok(Some(Message::Text(stored.clone())))
+    }
+
+    async fn confirm_transfer(
+        &self,
+        message_id: u32,
+        signature: &str,
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some(Message::CryptoTransfer(stored)) = messages
+            .iter_mut()
+            .find(|stored| stored.message_id() == message_id)
+        else {
+            return Ok(None);
+        };
+        if stored.is_confirmed() {
+            return Ok(None);
+        }
+        stored.set_transaction_signature(signature.to_string());
+        stored.set_confirmed();
+
+        Ok(Some(Message::CryptoTransfer(stored.clone())))
+    }
+
+    async fn delete_message(
+        &self,
+        message_id: u32,
+    ) -> Result<Option<Message<'static>>, StoreError> {
+        self.revisions
+            .lock()
+            .unwrap()
+            .retain(|revision| revision.message_id() != message_id);
+        self.reactions
+            .lock()
+            .unwrap()
+            .retain(|reaction| reaction.message_id() != message_id);
+        self.mentions
+            .lock()
+            .unwrap()
+            .retain(|mention| mention.message_id() != message_id);
+        self.hidden
+            .lock()
+            .unwrap()
+            .retain(|(hidden_id, _)| *hidden_id != message_id);
+        let mut messages = self.messages.lock().unwrap();
+        let Some(index) = messages
+            .iter()
+            .position(|message| message.message_id() == message_id)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(messages.remove(index)))
+    }
+
+    async fn hide_message(
+        &self,
+        message_id: u32,
+        _conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<(), StoreError> {
+        let mut hidden = self.hidden.lock().unwrap();
+        if !hidden.contains(&(message_id, sender_id)) {
+            hidden.push((message_id, sender_id));
+        }
+        Ok(())
+    }
+
+    async fn similar_images(
+        &self,
+        perceptual_hash: &str,
+        conversation_id: Option<&str>,
+        max_distance: u32,
+        limit: usize,
+    ) -> Result<Vec<SimilarImage>, StoreError> {
+        let mut similar: Vec<SimilarImage> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| {
+                conversation_id.is_none_or(|id| message.conversation_id() == Some(id))
+            })
+            .filter_map(|message| {
+                let Message::Image(image) = message else {
+                    return None;
+                };
+                let distance = hash_distance(perceptual_hash, image.metadata().perceptual_hash()?)?;
+                (distance <= max_distance).then(|| SimilarImage {
+                    message: message.clone(),
+                    distance,
+                })
+            })
+            .collect();
+        similar.sort_by_key(|similar| (similar.distance, similar.message.message_id()));
+        similar.truncate(limit);
+
+        Ok(similar)
+    }
+
+    async fn insert_receipt(&self, receipt: &Receipt) -> Result<(), StoreError> {
+        self.receipts.lock().unwrap().push(receipt.clone());
+        Ok(())
+    }
+
+    async fn insert_revision(&self, revision: &MessageRevision) -> Result<(), StoreError> {
+        self.revisions.lock().unwrap().push(revision.clone());
+        Ok(())
+    }
+
+    async fn message_revisions(&self, message_id: u32) -> Result<Vec<MessageRevision>, StoreError> {
+        let revisions = self.revisions.lock().unwrap();
+        Ok(revisions
+            .iter()
+            .filter(|revision| revision.message_id() == message_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_revisions(&self, message_id: u32) -> Result<(), StoreError> {
+        self.revisions
+            .lock()
+            .unwrap()
+            .retain(|revision| revision.message_id() != message_id);
+        Ok(())
+    }
+
+    async fn add_reaction(&self, reaction: &Reaction) -> Result<bool, StoreError> {
+        let mut reactions = self.reactions.lock().unwrap();
+        if reactions.iter().any(|stored| {
+            stored.message_id() == reaction.message_id()
+                && stored.sender_id() == reaction.sender_id()
+                && stored.emoji() == reaction.emoji()
+        }) {
+            return Ok(false);
+        }
+        reactions.push(reaction.clone());
+        Ok(true)
+    }
+
+    async fn remove_reaction(
+        &self,
+        message_id: u32,
+        sender_id: u32,
+        emoji: &str,
+    ) -> Result<bool, StoreError> {
+        let mut reactions = self.reactions.lock().unwrap();
+        let count = reactions.len();
+        reactions.retain(|reaction| {
+            reaction.message_id() != message_id
+                || reaction.sender_id() != sender_id
+                || reaction.emoji() != emoji
+        });
+        Ok(reactions.len() < count)
+    }
+
+    async fn reactions(&self, message_id: u32) -> Result<Vec<Reaction>, StoreError> {
+        let reactions = self.reactions.lock().unwrap();
+        Ok(reactions
+            .iter()
+            .filter(|reaction| reaction.message_id() == message_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn reaction_counts(
+        &self,
+        message_ids: &[u32],
+    ) -> Result<BTreeMap<u32, BTreeMap<String, u64>>, StoreError> {
+        let mut counts: BTreeMap<u32, BTreeMap<String, u64>> = BTreeMap::new();
+        for reaction in self.reactions.lock().unwrap().iter() {
+            if message_ids.contains(&reaction.message_id()) {
+                *counts
+                    .entry(reaction.message_id())
+                    .or_default()
+                    .entry(reaction.emoji().to_string())
+                    .or_default() += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn delete_reactions(&self, message_id: u32) -> Result<(), StoreError> {
+        self.reactions
+            .lock()
+            .unwrap()
+            .retain(|reaction| reaction.message_id() != message_id);
+        Ok(())
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), StoreError> {
+        self.users.lock().unwrap().push(user.clone());
+        Ok(())
+    }
+
+    async fn find_user(&self, sender_id: u32) -> Result<Option<User>, StoreError> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .find(|user| *user.sender_id() == sender_id)
+            .cloned())
+    }
+
+    async fn users_by_handle(&self, handles: &[String]) -> Result<Vec<User>, StoreError> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .filter(|user| {
+                user.handle()
+                    .is_some_and(|handle| handles.iter().any(|wanted| wanted == handle))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn save_mentions(
+        &self,
+        message_id: u32,
+        mentions: &[InboxMention],
+    ) -> Result<(), StoreError> {
+        let mut stored = self.mentions.lock().unwrap();
+        stored.retain(|stored| {
+            stored.message_id() != message_id
+                || mentions
+                    .iter()
+                    .any(|mention| mention.sender_id() == stored.sender_id())
+        });
+        for mention in mentions {
+            if !stored.iter().any(|stored| {
+                stored.message_id() == message_id && stored.sender_id() == mention.sender_id()
+            }) {
+                stored.push(mention.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn mention_inbox(
+        &self,
+        sender_id: u32,
+        unread_only: bool,
+        limit: usize,
+    ) -> Result<Vec<InboxMention>, StoreError> {
+        let mut inbox: Vec<InboxMention> = self
+            .mentions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|mention| mention.sender_id() == sender_id)
+            .filter(|mention| !unread_only || !mention.is_read())
+            .cloned()
+            .collect();
+        inbox
+            .sort_by_key(|mention| std::cmp::Reverse((mention.created_at(), mention.message_id())));
+        inbox.truncate(limit);
+        Ok(inbox)
+    }
+
+    async fn mark_mention_read(&self, sender_id: u32, message_id: u32) -> Result<bool, StoreError> {
+        let mut mentions = self.mentions.lock().unwrap();
+        let Some(mention) = mentions
+            .iter_mut()
+            .find(|mention| mention.sender_id() == sender_id && mention.message_id() == message_id)
+        else {
+            return Ok(false);
+        };
+        if !mention.is_read() {
+            mention.set_read_at(Utc::now());
+        }
+        Ok(true)
+    }
+
+    async fn notify_level(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<NotifyLevel, StoreError> {
+        let levels = self.notify_levels.lock().unwrap();
+        Ok(levels
+            .iter()
+            .find(|(conversation, sender, _)| {
+                conversation == conversation_id && *sender == sender_id
+            })
+            .map(|(_, _, level)| *level)
+            .unwrap_or_default())
+    }
+
+    async fn set_notify_level(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+        level: NotifyLevel,
+    ) -> Result<(), StoreError> {
+        let mut levels = self.notify_levels.lock().unwrap();
+        levels.retain(|(conversation, sender, _)| {
+            conversation != conversation_id || *sender != sender_id
+        });
+        levels.push((conversation_id.to_string(), sender_id, level));
+        Ok(())
+    }
+
+    async fn claim_membership(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<bool, StoreError> {
+        let mut members = self.members.lock().unwrap();
+        Ok(members
+            .entry(conversation_id.to_string())
+            .or_insert_with(|| vec![sender_id])
+            .contains(&sender_id))
+    }
+
+    async fn members(&self, conversation_id: &str) -> Result<Vec<u32>, StoreError> {
+        let members = self.members.lock().unwrap();
+        Ok(members.get(conversation_id).cloned().unwrap_or_default())
+    }
+
+    async fn add_member(&self, conversation_id: &str, sender_id: u32) -> Result<(), StoreError> {
+        let mut members = self.members.lock().unwrap();
+        let members = members.entry(conversation_id.to_string()).or_default();
+        if !members.contains(&sender_id) {
+            members.push(sender_id);
+        }
+        Ok(())
+    }
+
+    async fn remove_member(
+        &self,
+        conversation_id: &str,
+        sender_id: u32,
+    ) -> Result<bool, StoreError> {
+        let mut members = self.members.lock().unwrap();
+        let Some(members) = members.get_mut(conversation_id) else {
+            return Ok(false);
+        };
+        let before = members.len();
+        members.retain(|member| *member != sender_id);
+        Ok(members.len() < before)
+    }
+
+    async fn save_draft(&self, draft: &Draft) -> Result<Draft, StoreError> {
+        let mut drafts = self.drafts.lock().unwrap();
+        let current = drafts.iter_mut().find(|current| {
+            current.sender_id() == draft.sender_id()
+                && current.conversation_id() == draft.conversation_id()
+        });
+        match current {
+            Some(current) if !draft.supersedes(current) => return Ok(current.clone()),
+            Some(current) => *current = draft.clone(),
+            None => drafts.push(draft.clone()),
+        }
+
+        Ok(draft.clone())
+    }
+
+    async fn find_draft(
+        &self,
+        sender_id: u32,
+        conversation_id: &str,
+    ) -> Result<Option<Draft>, StoreError> {
+        Ok(self
+            .drafts
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|draft| {
+                draft.sender_id() == sender_id && draft.conversation_id() == conversation_id
+            })
+            .cloned())
+    }
+
+    async fn drafts(&self, sender_id: u32) -> Result<Vec<Draft>, StoreError> {
+        let mut drafts: Vec<Draft> = self
+            .drafts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|draft| draft.sender_id() == sender_id && !draft.is_empty())
+            .cloned()
+            .collect();
+        drafts.sort_by_key(|draft| std::cmp::Reverse(draft.updated_at()));
+
+        Ok(drafts)
+    }
+
+    async fn insert_scheduled(&self, scheduled: &ScheduledMessage) -> Result<(), StoreError> {
+        self.scheduled.lock().unwrap().push(scheduled.clone());
+        Ok(())
+    }
+
+    async fn find_scheduled(
+        &self,
+        message_id: u32,
+    ) -> Result<Option<ScheduledMessage>, StoreError> {
+        Ok(self
+            .scheduled
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|scheduled| scheduled.message_id() == message_id)
+            .cloned())
+    }
+
+    async fn scheduled_messages(
+        &self,
+        sender_id: u32,
+    ) -> Result<Vec<ScheduledMessage>, StoreError> {
+        let mut scheduled: Vec<ScheduledMessage> = self
+            .scheduled
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|scheduled| scheduled.sender_id() == sender_id)
+            .cloned()
+            .collect();
+        scheduled.sort_by_key(|scheduled| (scheduled.send_at(), scheduled.message_id()));
+
+        Ok(scheduled)
+    }
+
+    async fn due_scheduled(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<ScheduledMessage>, StoreError> {
+        let mut due: Vec<ScheduledMessage> = self
+            .scheduled
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|scheduled| scheduled.is_due(now))
+            .cloned()
+            .collect();
+        due.sort_by_key(|scheduled| (scheduled.send_at(), scheduled.message_id()));
+        due.truncate(limit);
+
+        Ok(due)
+    }
+
+    async fn reschedule(&self, scheduled: &ScheduledMessage) -> Result<bool, StoreError> {
+        let mut stored = self.scheduled.lock().unwrap();
+        let Some(stored) = stored
+            .iter_mut()
+            .find(|stored| stored.message_id() == scheduled.message_id())
+        else {
+            return Ok(false);
+        };
+        *stored = scheduled.clone();
+
+        Ok(true)
+    }
+
+    async fn delete_scheduled(&self, message_id: u32) -> Result<bool, StoreError> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let count = scheduled.len();
+        scheduled.retain(|scheduled| scheduled.message_id() != message_id);
+        Ok(scheduled.len() < count)
+    }
+
+    async fn claim_scheduled(
+        &self,
+        message_id: u32,
+        now: DateTime<Utc>,
+    ) -> Result<Option<ScheduledMessage>, StoreError> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let Some(index) = scheduled
+            .iter()
+            .position(|scheduled| scheduled.message_id() == message_id && scheduled.is_due(now))
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(scheduled.remove(index)))
+    }
+
+    async fn insert_webhook(&self, subscription: &WebhookSubscription) -> Result<(), StoreError> {
+        self.webhooks.lock().unwrap().push(subscription.clone());
+        Ok(())
+    }
+
+    async fn webhooks(&self) -> Result<Vec<WebhookSubscription>, StoreError> {
+        Ok(self.webhooks.lock().unwrap().clone())
+    }
+
+    async fn delete_webhook(
+        &self,
+        subscription_id: u32,
+        owner_id: u32,
+    ) -> Result<bool, StoreError> {
+        let mut webhooks = self.webhooks.lock().unwrap();
+        let count = webhooks.len();
+        webhooks.retain(|subscription| {
+            subscription.subscription_id() != subscription_id || subscription.owner_id() != owner_id
+        });
+        Ok(webhooks.len() < count)
+    }
+
+    async fn insert_dead_letter(&self, dead_letter: &WebhookDeadLetter) -> Result<(), StoreError> {
+        self.dead_letters.lock().unwrap().push(dead_letter.clone());
+        Ok(())
+    }
+
+    async fn conversation_settings(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationSettings, StoreError> {
+        let settings = self.conversation_settings.lock().unwrap();
+        Ok(settings
+            .iter()
+            .find(|settings| settings.conversation_id() == conversation_id)
+            .cloned()
+            .unwrap_or_else(|| ConversationSettings::new(conversation_id.to_string())))
+    }
+
+    async fn save_conversation_settings(
+        &self,
+        settings: &ConversationSettings,
+    ) -> Result<(), StoreError> {
+        let mut stored = self.conversation_settings.lock().unwrap();
+        stored.retain(|stored| stored.conversation_id() != settings.conversation_id());
+        stored.push(settings.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use crate::enums::BlobCodec;
+use serde::{Deserialize, Serialize};
+
+/// Where an attachment's bytes live in the blob store. Blobs are addressed by
+/// the SHA-256 of their content, so identical attachments share one blob.
+/// For a compressed blob that is the compressed content, and `size` is its
+/// compressed size.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlobRef {
+    sha256: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "BlobCodec::is_identity")]
+    codec: BlobCodec,
+}
+
+impl BlobRef {
+    pub fn new(sha256: String, size: u64) -> Self {
+        Self {
+            sha256,
+            size,
+            codec: BlobCodec::Identity,
+        }
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn codec(&self) -> BlobCodec {
+        self.codec
+    }
+
+    pub fn set_codec(&mut self, codec: BlobCodec) {
+        self.codec = codec;
+    }
+}
@@ -0,0 +1,46 @@
+use crate::enums::{MetadataPolicy, NotifyLevel};
+use crate::images::ImageProcessingOptions;
+use serde::{Deserialize, Serialize};
+
+/// Per-conversation policies. A conversation nobody has configured uses the
+/// defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConversationSettings {
+    conversation_id: String,
+    #[serde(default)]
+    image_metadata: MetadataPolicy,
+}
+
+impl ConversationSettings {
+    pub fn new(conversation_id: String) -> Self {
+        Self {
+            conversation_id,
+            image_metadata: MetadataPolicy::default(),
+        }
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn image_metadata(&self) -> MetadataPolicy {
+        self.image_metadata.clone()
+    }
+
+    pub fn set_image_metadata(&mut self, image_metadata: MetadataPolicy) {
+        self.image_metadata = image_metadata;
+    }
+
+    pub fn image_processing_options(&self) -> ImageProcessingOptions {
+        ImageProcessingOptions {
+            metadata: self.image_metadata.clone(),
+            ..ImageProcessingOptions::default()
+        }
+    }
+}
+
+/// One user's notification preference for one conversation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotificationSettings {
+    pub level: NotifyLevel,
+}
@@ -0,0 +1,95 @@
+use crate::enums::TextFormat;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Most uploads a draft can hold on to.
+pub const MAX_DRAFT_ATTACHMENTS: usize = 10;
+
+/// What a user has written in a conversation but not sent yet. There is one
+/// per user and conversation, shared by all of their devices, and whichever
+/// device saved it last by `updated_at` wins. A cleared draft is kept empty
+/// so that an older save arriving late can't bring it back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Draft {
+    sender_id: u32,
+    conversation_id: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    format: TextFormat,
+    /// Chunked uploads, see `UploadSession`, to send along with the text.
+    /// Any of the user's devices can finish them.
+    #[serde(default)]
+    upload_ids: Vec<String>,
+    updated_at: DateTime<Utc>,
+}
+
+impl Draft {
+    pub fn new(
+        sender_id: u32,
+        conversation_id: String,
+        text: String,
+        format: TextFormat,
+        upload_ids: Vec<String>,
+        updated_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            sender_id,
+            conversation_id,
+            text,
+            format,
+            upload_ids,
+            updated_at,
+        }
+    }
+
+    /// A cleared draft, as of `updated_at`.
+    pub fn empty(sender_id: u32, conversation_id: String, updated_at: DateTime<Utc>) -> Self {
+        Self::new(
+            sender_id,
+            conversation_id,
+            String::new(),
+            TextFormat::default(),
+            Vec::new(),
+            updated_at,
+        )
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn format(&self) -> TextFormat {
+        self.format
+    }
+
+    pub fn upload_ids(&self) -> &[String] {
+        &self.upload_ids
+    }
+
+    pub fn set_upload_ids(&mut self, upload_ids: Vec<String>) {
+        self.upload_ids = upload_ids;
+    }
+
+    pub fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.upload_ids.is_empty()
+    }
+
+    /// Whether this draft replaces `current` under last-write-wins. On a
+    /// tie the one already stored stays.
+    pub fn supersedes(&self, current: &Draft) -> bool {
+        self.updated_at > current.updated_at
+    }
+}
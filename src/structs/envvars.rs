@@ -1,3 +1,6 @@
+use crate::enums::InfectedAction;
+use std::time::Duration;
+
 #[derive(Debug, Clone)]
 pub struct EnvVars {
     pub send_text_messages: String,
@@ -5,3 +8,26 @@ pub struct EnvVars {
     pub send_image_messages: String,
     pub send_crypto_transfer_messages: String,
 }
+
+#[derive(Debug, Clone)]
+pub struct ServerEnvVars {
+    pub websocket_addr: String,
+    pub http_addr: String,
+    pub auth_secret: String,
+    /// Keep attachments in this directory instead of GridFS.
+    pub blob_dir: Option<String>,
+    /// Where chunked uploads are kept until they are completed.
+    pub upload_dir: Option<String>,
+    /// Scan attachments with the clamd listening here, see
+    /// `ClamdAddress::parse`.
+    pub clamd_address: Option<String>,
+    /// clamd's `StreamMaxLength` in bytes, when it isn't the default.
+    pub clamd_stream_max: Option<u64>,
+    pub on_infected: InfectedAction,
+    /// How long text messages stay editable, when not the default.
+    pub edit_window: Option<Duration>,
+    /// Fetch previews of the links in text messages.
+    pub link_previews: bool,
+    /// Confirm crypto transfers against the Solana node answering here.
+    pub solana_rpc_url: Option<String>,
+}
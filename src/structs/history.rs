@@ -0,0 +1,77 @@
+use crate::enums::Message;
+use crate::structs::thread::QuotedMessage;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Position in a conversation's history. History is listed newest first, so
+/// the next page starts strictly before this timestamp and message id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryCursor {
+    timestamp: DateTime<Utc>,
+    message_id: u32,
+}
+
+impl HistoryCursor {
+    pub fn new(timestamp: DateTime<Utc>, message_id: u32) -> Self {
+        Self {
+            timestamp,
+            message_id,
+        }
+    }
+
+    pub fn after(message: &Message<'_>) -> Self {
+        Self::new(message.created_at(), message.message_id())
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}|{}",
+            self.timestamp.to_rfc3339(),
+            self.message_id
+        ))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, String> {
+        let decoded = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or("Cursor is not valid base64")?;
+        let (timestamp, message_id) = decoded.split_once('|').ok_or("Cursor is malformed")?;
+
+        Ok(Self {
+            timestamp: DateTime::parse_from_rfc3339(timestamp)
+                .map_err(|_| "Cursor timestamp is malformed")?
+                .with_timezone(&Utc),
+            message_id: message_id
+                .parse()
+                .map_err(|_| "Cursor message id is malformed")?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryPage {
+    pub messages: Vec<Message<'static>>,
+    pub next_cursor: Option<String>,
+    /// Previews of the messages replies on this page answer, by message id.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub quoted: BTreeMap<u32, QuotedMessage>,
+    /// How many replies each thread started on this page has.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reply_counts: BTreeMap<u32, u64>,
+    /// How often each emoji was used on the messages on this page.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<u32, BTreeMap<String, u64>>,
+}
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+/// A block of a Markdown text message, see `markdown::parse`. Only the
+/// subset chat clients render is kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Paragraph {
+        content: Vec<Inline>,
+    },
+    CodeBlock {
+        /// The fence's info string up to the first space, when it names a
+        /// language.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+        code: String,
+    },
+    List {
+        /// The number of the first item of an ordered list; `None` for a
+        /// bulleted one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Inline {
+    Text {
+        text: String,
+    },
+    Strong {
+        content: Vec<Inline>,
+    },
+    Emphasis {
+        content: Vec<Inline>,
+    },
+    Code {
+        code: String,
+    },
+    /// Only `http`, `https` and `mailto` URLs get this far.
+    Link {
+        url: String,
+        content: Vec<Inline>,
+    },
+    LineBreak,
+}
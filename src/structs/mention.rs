@@ -0,0 +1,103 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// An `@handle` in a text message that names a registered user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mention {
+    sender_id: u32,
+    handle: String,
+    /// Byte offsets of the `@handle` in the message text.
+    start: usize,
+    end: usize,
+}
+
+impl Mention {
+    pub fn new(sender_id: u32, handle: String, start: usize, end: usize) -> Self {
+        Self {
+            sender_id,
+            handle,
+            start,
+            end,
+        }
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn handle(&self) -> &str {
+        &self.handle
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+}
+
+/// An entry in a user's mention inbox: one message that mentions them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InboxMention {
+    message_id: u32,
+    conversation_id: String,
+    /// The user who was mentioned and whose inbox this is in.
+    sender_id: u32,
+    mentioned_by: u32,
+    #[serde(with = "crate::utils::sortable_timestamp")]
+    timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl InboxMention {
+    pub fn new(
+        message_id: u32,
+        conversation_id: String,
+        sender_id: u32,
+        mentioned_by: u32,
+    ) -> Self {
+        Self {
+            message_id,
+            conversation_id,
+            sender_id,
+            mentioned_by,
+            timestamp: Utc::now(),
+            read_at: None,
+        }
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn mentioned_by(&self) -> u32 {
+        self.mentioned_by
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn read_at(&self) -> Option<DateTime<Utc>> {
+        self.read_at
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.read_at.is_some()
+    }
+
+    pub fn set_read_at(&mut self, read_at: DateTime<Utc>) {
+        self.read_at = Some(read_at);
+    }
+}
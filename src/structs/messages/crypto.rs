@@ -2,27 +2,45 @@ use crate::enums::{MessageFromType, MessageType};
 use crate::utils::gen_message_id;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::any::Any;
+use std::borrow::Cow;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CryptoTransferMessagePackage<'a> {
+    #[serde(default = "gen_message_id")]
     message_id: u32,
     r#type: MessageType,
+    sender_id: u32,
     from: MessageFromType,
-    recipient_address: &'a str,
+    recipient_address: Cow<'a, str>,
     amount: f64,
-    token_symbol: &'a str,
+    token_symbol: Cow<'a, str>,
+    #[serde(default = "Utc::now", with = "crate::utils::sortable_timestamp")]
     timestamp: DateTime<Utc>,
     transaction_signature: Option<String>,
+    /// Set by the server once it has found the transaction on chain, see
+    /// `transfers::TransferVerifier`. Never taken from clients.
+    #[serde(default)]
+    confirmed: bool,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// The message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u32>,
+    /// The first message of the thread this one belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_root: Option<u32>,
 }
 
 impl<'a> CryptoTransferMessagePackage<'a> {
     pub fn new(
-        from: MessageFromType,
-        recipient_address: &'a str,
+        sender_id: u32,
+        sender_type: MessageFromType,
+        recipient_address: impl Into<Cow<'a, str>>,
         amount: f64,
-        token_symbol: &'a str,
+        token_symbol: impl Into<Cow<'a, str>>,
     ) -> Result<Self, String> {
+        let recipient_address = recipient_address.into();
+
         // Validate Solana address format (base58, 32-44 characters)
         if !recipient_address.chars().all(|c| c.is_alphanumeric())
             || recipient_address.len() < 32
@@ -39,12 +57,17 @@ impl<'a> CryptoTransferMessagePackage<'a> {
         Ok(Self {
             message_id: gen_message_id(),
             r#type: MessageType::CryptoTransfer,
-            from,
+            sender_id,
+            from: sender_type,
             recipient_address,
             amount,
-            token_symbol,
+            token_symbol: token_symbol.into(),
             timestamp: Utc::now(),
             transaction_signature: None,
+            confirmed: false,
+            conversation_id: None,
+            reply_to: None,
+            thread_root: None,
         })
     }
 
@@ -56,12 +79,16 @@ impl<'a> CryptoTransferMessagePackage<'a> {
         self.r#type.clone()
     }
 
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
     pub fn from(&self) -> MessageFromType {
         self.from.clone()
     }
 
     pub fn recipient_address(&self) -> &str {
-        self.recipient_address
+        &self.recipient_address
     }
 
     pub fn amount(&self) -> f64 {
@@ -69,13 +96,21 @@ impl<'a> CryptoTransferMessagePackage<'a> {
     }
 
     pub fn token_symbol(&self) -> &str {
-        self.token_symbol
+        &self.token_symbol
     }
 
     pub fn timestamp(&self) -> String {
         self.timestamp.to_string()
     }
 
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn set_created_at(&mut self, created_at: DateTime<Utc>) {
+        self.timestamp = created_at;
+    }
+
     pub fn pretty_timestamp(&self) -> String {
         self.timestamp
             .format("%A, %B, %e, %Y at %I:%M%p")
@@ -89,4 +124,54 @@ impl<'a> CryptoTransferMessagePackage<'a> {
     pub fn transaction_signature(&self) -> Option<&str> {
         self.transaction_signature.as_deref()
     }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    pub fn set_confirmed(&mut self) {
+        self.confirmed = true;
+    }
+
+    pub fn set_conversation_id(&mut self, conversation_id: String) {
+        self.conversation_id = Some(conversation_id);
+    }
+
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
+    pub fn reply_to(&self) -> Option<u32> {
+        self.reply_to
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: u32) {
+        self.reply_to = Some(reply_to);
+    }
+
+    pub fn thread_root(&self) -> Option<u32> {
+        self.thread_root
+    }
+
+    pub fn set_thread_root(&mut self, thread_root: u32) {
+        self.thread_root = Some(thread_root);
+    }
+
+    pub fn into_owned(self) -> CryptoTransferMessagePackage<'static> {
+        CryptoTransferMessagePackage {
+            message_id: self.message_id,
+            r#type: self.r#type,
+            sender_id: self.sender_id,
+            from: self.from,
+            recipient_address: Cow::Owned(self.recipient_address.into_owned()),
+            amount: self.amount,
+            token_symbol: Cow::Owned(self.token_symbol.into_owned()),
+            timestamp: self.timestamp,
+            transaction_signature: self.transaction_signature,
+            confirmed: self.confirmed,
+            conversation_id: self.conversation_id,
+            reply_to: self.reply_to,
+            thread_root: self.thread_root,
+        }
+    }
 }
@@ -0,0 +1,123 @@
+use crate::enums::{MessageFromType, MessageType, expect_type};
+use chrono::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Keeps a tombstone from reading back as another kind of message.
+fn deleted_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MessageType, D::Error> {
+    expect_type(deserializer, MessageType::Deleted)
+}
+
+/// What is left of a message deleted for everyone: enough to keep its place
+/// in the conversation and tell clients to remove it, but none of its
+/// content.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeletedMessagePackage {
+    message_id: u32,
+    #[serde(deserialize_with = "deleted_type")]
+    r#type: MessageType,
+    sender_id: u32,
+    from: MessageFromType,
+    #[serde(with = "crate::utils::sortable_timestamp")]
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// The message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u32>,
+    /// The first message of the thread this one belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_root: Option<u32>,
+    /// The sender, or the moderator who deleted the message for them.
+    deleted_by: u32,
+    deleted_at: DateTime<Utc>,
+}
+
+impl DeletedMessagePackage {
+    pub fn new(
+        message_id: u32,
+        sender_id: u32,
+        sender_type: MessageFromType,
+        timestamp: DateTime<Utc>,
+        conversation_id: Option<String>,
+        deleted_by: u32,
+    ) -> Self {
+        Self {
+            message_id,
+            r#type: MessageType::Deleted,
+            sender_id,
+            from: sender_type,
+            timestamp,
+            conversation_id,
+            reply_to: None,
+            thread_root: None,
+            deleted_by,
+            deleted_at: Utc::now(),
+        }
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn r#type(&self) -> MessageType {
+        self.r#type.clone()
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn from(&self) -> MessageFromType {
+        self.from.clone()
+    }
+
+    pub fn deleted_by(&self) -> u32 {
+        self.deleted_by
+    }
+
+    pub fn deleted_at(&self) -> DateTime<Utc> {
+        self.deleted_at
+    }
+
+    pub fn timestamp(&self) -> String {
+        self.timestamp.to_string()
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn set_created_at(&mut self, created_at: DateTime<Utc>) {
+        self.timestamp = created_at;
+    }
+
+    pub fn pretty_timestamp(&self) -> String {
+        self.timestamp
+            .format("%A, %B, %e, %Y at %I:%M%p")
+            .to_string()
+    }
+
+    pub fn set_conversation_id(&mut self, conversation_id: String) {
+        self.conversation_id = Some(conversation_id);
+    }
+
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
+    pub fn reply_to(&self) -> Option<u32> {
+        self.reply_to
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: u32) {
+        self.reply_to = Some(reply_to);
+    }
+
+    pub fn thread_root(&self) -> Option<u32> {
+        self.thread_root
+    }
+
+    pub fn set_thread_root(&mut self, thread_root: u32) {
+        self.thread_root = Some(thread_root);
+    }
+}
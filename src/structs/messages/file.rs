@@ -1,49 +1,250 @@
-use crate::enums::MessageFromType;
+use crate::blobs::{BlobStore, load_blob, load_bytes, store_compressed};
+use crate::enums::{MessageFromType, MessageType, expect_type};
+use crate::errors::{BlobError, FileError};
+use crate::files::{FileOptions, inspect_file};
+use crate::structs::blob::BlobRef;
+use crate::structs::scan::ScanVerdict;
 use crate::utils::gen_message_id;
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// What the server found out about a file. The type is sniffed from the
+/// content, not taken from the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FileMetadata {
+    filename: String,
+    mime_type: String,
+    size: u64,
+    sha256: String,
+}
+
+impl FileMetadata {
+    pub fn new(filename: String, mime_type: String, size: u64, sha256: String) -> Self {
+        Self {
+            filename,
+            mime_type,
+            size,
+            sha256,
+        }
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+}
+
+/// Every other file field has a default, so without this a crypto transfer
+/// would read back as a file.
+fn file_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MessageType, D::Error> {
+    expect_type(deserializer, MessageType::File)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMessagePackage {
+    #[serde(default = "gen_message_id")]
     message_id: u32,
+    #[serde(deserialize_with = "file_type")]
+    r#type: MessageType,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     file_data: Vec<u8>,
+    #[serde(default)]
+    file_blob: Option<BlobRef>,
+    sender_id: u32,
     from: MessageFromType,
+    #[serde(default = "Utc::now", with = "crate::utils::sortable_timestamp")]
     timestamp: DateTime<Utc>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// The message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u32>,
+    /// The first message of the thread this one belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_root: Option<u32>,
+    /// Missing on files sent before metadata was recorded.
+    #[serde(default)]
+    metadata: FileMetadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scan: Option<ScanVerdict>,
 }
 
 impl FileMessagePackage {
-    pub fn new(from: MessageFromType, file_data: Vec<u8>) -> Result<Self, String> {
+    pub fn new(
+        sender_id: u32,
+        sender_type: MessageFromType,
+        filename: &str,
+        file_data: Vec<u8>,
+    ) -> Result<Self, FileError> {
+        Self::with_options(
+            sender_id,
+            sender_type,
+            filename,
+            file_data,
+            &FileOptions::default(),
+        )
+    }
+
+    /// Checks the file against a deployment's `options` and records its
+    /// metadata.
+    pub fn with_options(
+        sender_id: u32,
+        sender_type: MessageFromType,
+        filename: &str,
+        file_data: Vec<u8>,
+        options: &FileOptions,
+    ) -> Result<Self, FileError> {
+        let metadata = inspect_file(filename, &file_data, options)?;
+
         Ok(Self {
             message_id: gen_message_id(),
-            from,
+            r#type: MessageType::File,
+            sender_id,
+            from: sender_type,
             file_data,
+            file_blob: None,
             timestamp: Utc::now(),
+            conversation_id: None,
+            reply_to: None,
+            thread_root: None,
+            metadata,
+            scan: None,
         })
     }
 
+    /// A file message for bytes already in the blob store, such as a
+    /// finished chunked upload. `metadata` must already have been checked.
+    pub fn from_blob(
+        sender_id: u32,
+        sender_type: MessageFromType,
+        metadata: FileMetadata,
+        file_blob: BlobRef,
+    ) -> Self {
+        Self {
+            message_id: gen_message_id(),
+            r#type: MessageType::File,
+            sender_id,
+            from: sender_type,
+            file_data: Vec::new(),
+            file_blob: Some(file_blob),
+            timestamp: Utc::now(),
+            conversation_id: None,
+            reply_to: None,
+            thread_root: None,
+            metadata,
+            scan: None,
+        }
+    }
+
     pub fn message_id(&self) -> u32 {
         self.message_id
     }
 
+    pub fn r#type(&self) -> MessageType {
+        self.r#type.clone()
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
     pub fn from(&self) -> MessageFromType {
         self.from.clone()
     }
 
-    pub fn file_data(&self) -> Vec<u8> {
-        self.file_data.clone()
+    /// The file's bytes. Once the file has been moved to the blob store
+    /// they are read back from `blobs` and decompressed; `open_blob` streams
+    /// them instead, for files too large to hold in memory.
+    pub async fn file_data(&self, blobs: &dyn BlobStore) -> Result<Vec<u8>, BlobError> {
+        match &self.file_blob {
+            Some(blob) if self.file_data.is_empty() => load_blob(blobs, blob).await,
+            _ => Ok(self.file_data.clone()),
+        }
+    }
+
+    /// The bytes carried in the message itself, as clients send them. Empty
+    /// once `store_attachments` has moved them to the blob store.
+    pub fn inline_data(&self) -> &[u8] {
+        &self.file_data
+    }
+
+    pub fn file_blob(&self) -> Option<&BlobRef> {
+        self.file_blob.as_ref()
+    }
+
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    pub fn scan(&self) -> Option<&ScanVerdict> {
+        self.scan.as_ref()
+    }
+
+    pub fn set_scan(&mut self, scan: ScanVerdict) {
+        self.scan = Some(scan);
+    }
+
+    /// Moves the file into `blobs`, compressed when that saves space, leaving
+    /// only a reference behind.
+    pub async fn store_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        store_compressed(blobs, &mut self.file_data, &mut self.file_blob).await
+    }
+
+    /// Loads the file's bytes back from `blobs`, decompressed.
+    pub async fn load_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        load_bytes(blobs, &mut self.file_data, self.file_blob.as_ref()).await
     }
 
     pub fn timestamp(&self) -> String {
         self.timestamp.to_string()
     }
 
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn set_created_at(&mut self, created_at: DateTime<Utc>) {
+        self.timestamp = created_at;
+    }
+
     pub fn pretty_timestamp(&self) -> String {
         self.timestamp
             .format("%A, %B, %e, %Y at %I:%M%p")
             .to_string()
     }
 
-    // fn as_any(&self) -> &dyn Any {
-    //   self
-    // }
+    pub fn set_conversation_id(&mut self, conversation_id: String) {
+        self.conversation_id = Some(conversation_id);
+    }
+
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
+    pub fn reply_to(&self) -> Option<u32> {
+        self.reply_to
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: u32) {
+        self.reply_to = Some(reply_to);
+    }
+
+    pub fn thread_root(&self) -> Option<u32> {
+        self.thread_root
+    }
+
+    pub fn set_thread_root(&mut self, thread_root: u32) {
+        self.thread_root = Some(thread_root);
+    }
 }
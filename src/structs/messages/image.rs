@@ -1,16 +1,225 @@
-use crate::enums::MessageFromType;
-use crate::utils::gen_message_id;
+use crate::blobs::{BlobStore, load_bytes, store_bytes};
+use crate::enums::{Message, MessageFromType, MessageType, MetadataPolicy, expect_type};
+use crate::errors::{BlobError, ImageProcessingError};
+use crate::images::{ImageProcessingOptions, ProcessedImage, process_image};
+use crate::structs::blob::BlobRef;
+use crate::structs::scan::ScanVerdict;
+use crate::utils::{gen_message_id, sha256_hex};
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::any::Any;
+use image::ImageFormat;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// What decoding the original image found out about it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ImageMetadata {
+    width: u32,
+    height: u32,
+    format: String,
+    mime_type: String,
+    normalized_mime_type: String,
+    #[serde(default = "kept")]
+    metadata_policy: MetadataPolicy,
+    /// Placeholder clients can render before the image itself has loaded.
+    #[serde(default)]
+    blurhash: Option<String>,
+    /// The most common colour, as `#rrggbb`.
+    #[serde(default)]
+    dominant_color: Option<String>,
+    /// SHA-256 of the stored image bytes, which is also the address of its blob.
+    #[serde(default)]
+    content_hash: Option<String>,
+    /// dHash of the pixels as 16 hex digits, see `images::hash_distance`.
+    #[serde(default)]
+    perceptual_hash: Option<String>,
+}
+
+impl ImageMetadata {
+    pub fn new(
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+        normalized: ImageFormat,
+        metadata_policy: MetadataPolicy,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            format: format!("{:?}", format).to_lowercase(),
+            mime_type: format.to_mime_type().to_string(),
+            normalized_mime_type: normalized.to_mime_type().to_string(),
+            metadata_policy,
+            blurhash: None,
+            dominant_color: None,
+            content_hash: None,
+            perceptual_hash: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    pub fn normalized_mime_type(&self) -> &str {
+        &self.normalized_mime_type
+    }
+
+    pub fn metadata_policy(&self) -> MetadataPolicy {
+        self.metadata_policy.clone()
+    }
+
+    /// The type of the stored image bytes: the normalized copy for a stripped
+    /// image, the original otherwise.
+    pub fn stored_mime_type(&self) -> &str {
+        match self.metadata_policy {
+            MetadataPolicy::Strip => &self.normalized_mime_type,
+            MetadataPolicy::Keep => &self.mime_type,
+        }
+    }
+
+    pub fn blurhash(&self) -> Option<&str> {
+        self.blurhash.as_deref()
+    }
+
+    pub fn dominant_color(&self) -> Option<&str> {
+        self.dominant_color.as_deref()
+    }
+
+    pub fn content_hash(&self) -> Option<&str> {
+        self.content_hash.as_deref()
+    }
+
+    pub fn perceptual_hash(&self) -> Option<&str> {
+        self.perceptual_hash.as_deref()
+    }
+
+    pub fn set_content_hash(&mut self, content_hash: String) {
+        self.content_hash = Some(content_hash);
+    }
+
+    pub fn set_perceptual_hash(&mut self, perceptual_hash: String) {
+        self.perceptual_hash = Some(perceptual_hash);
+    }
+
+    pub fn set_placeholder(&mut self, blurhash: String, dominant_color: String) {
+        self.blurhash = Some(blurhash);
+        self.dominant_color = Some(dominant_color);
+    }
+}
+
+/// A scaled-down copy of an image, `size` pixels on its long edge at most.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    size: u32,
+    width: u32,
+    height: u32,
+    mime_type: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    data: Vec<u8>,
+    #[serde(default)]
+    blob: Option<BlobRef>,
+}
+
+impl Thumbnail {
+    pub fn new(size: u32, width: u32, height: u32, format: ImageFormat, data: Vec<u8>) -> Self {
+        Self {
+            size,
+            width,
+            height,
+            mime_type: format.to_mime_type().to_string(),
+            data,
+            blob: None,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    /// Empty once the thumbnail has been moved to the blob store.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn blob(&self) -> Option<&BlobRef> {
+        self.blob.as_ref()
+    }
+}
+
+/// An image message found by a similarity search, `distance` bits away from
+/// the perceptual hash searched for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimilarImage {
+    pub message: Message<'static>,
+    pub distance: u32,
+}
+
+/// Images stored before metadata was stripped are their original bytes.
+fn kept() -> MetadataPolicy {
+    MetadataPolicy::Keep
+}
+
+/// Every other image field has a default, so without this a file message
+/// whose bytes live in the blob store would read back as an image.
+fn image_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MessageType, D::Error> {
+    expect_type(deserializer, MessageType::Image)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ImageMessagePackage {
+    #[serde(default = "gen_message_id")]
     message_id: u32,
+    #[serde(deserialize_with = "image_type")]
+    r#type: MessageType,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     image_data: Vec<u8>,
+    #[serde(default)]
+    image_blob: Option<BlobRef>,
     sender_id: u32,
     from: MessageFromType,
+    #[serde(default = "Utc::now", with = "crate::utils::sortable_timestamp")]
     timestamp: DateTime<Utc>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// The message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u32>,
+    /// The first message of the thread this one belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_root: Option<u32>,
+    #[serde(default)]
+    metadata: Box<ImageMetadata>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    normalized_data: Vec<u8>,
+    #[serde(default)]
+    normalized_blob: Option<BlobRef>,
+    #[serde(default)]
+    thumbnails: Vec<Thumbnail>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scan: Option<ScanVerdict>,
 }
 
 impl ImageMessagePackage {
@@ -18,13 +227,50 @@ impl ImageMessagePackage {
         sender_id: u32,
         sender_type: MessageFromType,
         image_data: Vec<u8>,
-    ) -> Result<Self, String> {
+    ) -> Result<Self, ImageProcessingError> {
+        Self::with_options(
+            sender_id,
+            sender_type,
+            image_data,
+            &ImageProcessingOptions::default(),
+        )
+    }
+
+    pub fn with_options(
+        sender_id: u32,
+        sender_type: MessageFromType,
+        image_data: Vec<u8>,
+        options: &ImageProcessingOptions,
+    ) -> Result<Self, ImageProcessingError> {
+        let ProcessedImage {
+            mut metadata,
+            mut normalized_data,
+            thumbnails,
+        } = process_image(&image_data, options)?;
+        // A stripped original is replaced by the normalized copy, which is then
+        // not stored a second time.
+        let image_data = match options.metadata {
+            MetadataPolicy::Keep => image_data,
+            MetadataPolicy::Strip => std::mem::take(&mut normalized_data),
+        };
+        metadata.set_content_hash(sha256_hex(&image_data));
+
         Ok(Self {
             message_id: gen_message_id(),
+            r#type: MessageType::Image,
             sender_id,
             from: sender_type,
-            image_data: image_data,
+            image_data,
+            image_blob: None,
             timestamp: Utc::now(),
+            conversation_id: None,
+            reply_to: None,
+            thread_root: None,
+            metadata: Box::new(metadata),
+            normalized_data,
+            normalized_blob: None,
+            thumbnails,
+            scan: None,
         })
     }
 
@@ -32,25 +278,139 @@ impl ImageMessagePackage {
         self.message_id
     }
 
+    pub fn r#type(&self) -> MessageType {
+        self.r#type.clone()
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
     pub fn from(&self) -> MessageFromType {
         self.from.clone()
     }
 
+    /// Empty once the image has been moved to the blob store, see
+    /// `load_attachments`.
     pub fn image_data(&self) -> Vec<u8> {
         self.image_data.clone()
     }
 
+    pub fn image_blob(&self) -> Option<&BlobRef> {
+        self.image_blob.as_ref()
+    }
+
+    pub fn scan(&self) -> Option<&ScanVerdict> {
+        self.scan.as_ref()
+    }
+
+    pub fn set_scan(&mut self, scan: ScanVerdict) {
+        self.scan = Some(scan);
+    }
+
+    pub fn metadata(&self) -> &ImageMetadata {
+        &self.metadata
+    }
+
+    pub fn normalized_data(&self) -> &[u8] {
+        if self.normalized_data.is_empty() {
+            &self.image_data
+        } else {
+            &self.normalized_data
+        }
+    }
+
+    /// A stripped image is stored normalized, and shares its blob.
+    pub fn normalized_blob(&self) -> Option<&BlobRef> {
+        self.normalized_blob.as_ref().or(self.image_blob.as_ref())
+    }
+
+    /// Moves the image, its normalized copy and its thumbnails into `blobs`,
+    /// leaving only references behind.
+    pub async fn store_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        store_bytes(blobs, &mut self.image_data, &mut self.image_blob).await?;
+        store_bytes(blobs, &mut self.normalized_data, &mut self.normalized_blob).await?;
+        for thumbnail in &mut self.thumbnails {
+            store_bytes(blobs, &mut thumbnail.data, &mut thumbnail.blob).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the bytes behind every reference back onto the message.
+    pub async fn load_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        load_bytes(blobs, &mut self.image_data, self.image_blob.as_ref()).await?;
+        load_bytes(
+            blobs,
+            &mut self.normalized_data,
+            self.normalized_blob.as_ref(),
+        )
+        .await?;
+        for thumbnail in &mut self.thumbnails {
+            load_bytes(blobs, &mut thumbnail.data, thumbnail.blob.as_ref()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The image's blobs, its normalized copy's and its thumbnails'.
+    pub fn blobs(&self) -> Vec<&BlobRef> {
+        self.image_blob
+            .iter()
+            .chain(&self.normalized_blob)
+            .chain(self.thumbnails.iter().filter_map(Thumbnail::blob))
+            .collect()
+    }
+
+    pub fn thumbnails(&self) -> &[Thumbnail] {
+        &self.thumbnails
+    }
+
+    pub fn thumbnail(&self, size: u32) -> Option<&Thumbnail> {
+        self.thumbnails
+            .iter()
+            .find(|thumbnail| thumbnail.size() == size)
+    }
+
     pub fn timestamp(&self) -> String {
         self.timestamp.to_string()
     }
 
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn set_created_at(&mut self, created_at: DateTime<Utc>) {
+        self.timestamp = created_at;
+    }
+
     pub fn pretty_timestamp(&self) -> String {
         self.timestamp
             .format("%A, %B, %e, %Y at %I:%M%p")
             .to_string()
     }
 
-    // fn as_any(&self) -> &dyn Any {
-    //   self
-    // }
+    pub fn set_conversation_id(&mut self, conversation_id: String) {
+        self.conversation_id = Some(conversation_id);
+    }
+
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
+    pub fn reply_to(&self) -> Option<u32> {
+        self.reply_to
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: u32) {
+        self.reply_to = Some(reply_to);
+    }
+
+    pub fn thread_root(&self) -> Option<u32> {
+        self.thread_root
+    }
+
+    pub fn set_thread_root(&mut self, thread_root: u32) {
+        self.thread_root = Some(thread_root);
+    }
 }
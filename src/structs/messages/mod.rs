@@ -1,4 +1,5 @@
 pub mod crypto;
+pub mod deleted;
 pub mod file;
 pub mod image;
 pub mod text;
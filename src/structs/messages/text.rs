@@ -1,32 +1,97 @@
-use crate::enums::{MessageFromType, MessageType};
+use crate::enums::{MessageType, TextFormat};
+use crate::errors::ValidationError;
+use crate::markdown::{parse, render_html, render_plain_html};
+use crate::structs::markdown::Block;
+use crate::structs::mention::Mention;
+use crate::structs::preview::LinkPreview;
 use crate::structs::user::User;
 use crate::utils::gen_message_id;
+use crate::validation::ValidationOptions;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::any::Any;
+use std::borrow::Cow;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TextMessagePackage<'a> {
+    #[serde(default = "gen_message_id")]
     message_id: u32,
     r#type: MessageType,
-    message: &'a str,
+    message: Cow<'a, str>,
     sender: User,
+    #[serde(default = "Utc::now", with = "crate::utils::sortable_timestamp")]
     timestamp: DateTime<Utc>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    /// The message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u32>,
+    /// The first message of the thread this one belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_root: Option<u32>,
+    /// Set by the latest edit; earlier text is kept as revisions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edited_at: Option<DateTime<Utc>>,
+    /// Filled in by the server from the `@handle`s in the text; always
+    /// written, so an edit that drops a mention clears it.
+    #[serde(default)]
+    mentions: Vec<Mention>,
+    #[serde(default)]
+    format: TextFormat,
+    /// The parsed text of a Markdown message, kept next to the raw text.
+    /// Always written, like `mentions`.
+    #[serde(default)]
+    markdown: Option<Vec<Block>>,
+    /// Attached in the background once the links in the text have been
+    /// fetched. Always written, so an edit drops previews of the old text.
+    #[serde(default)]
+    previews: Vec<LinkPreview>,
 }
 
 impl<'a> TextMessagePackage<'a> {
-    pub fn new(sender: User, message: &'a str) -> Result<Self, String> {
-        if message.len() <= 512 {
-            Ok(Self {
-                message_id: gen_message_id(),
-                r#type: MessageType::Text,
-                sender,
-                message,
-                timestamp: Utc::now(),
-            })
-        } else {
-            Err("Message is too long. Max characters is 512.".into())
-        }
+    pub fn new(sender: User, message: impl Into<Cow<'a, str>>) -> Result<Self, ValidationError> {
+        Self::with_format(sender, message, TextFormat::Plain)
+    }
+
+    /// A message whose text is read as `format`, held to the default
+    /// `ValidationOptions`.
+    pub fn with_format(
+        sender: User,
+        message: impl Into<Cow<'a, str>>,
+        format: TextFormat,
+    ) -> Result<Self, ValidationError> {
+        Self::with_options(sender, message, format, &ValidationOptions::default())
+    }
+
+    /// Checks the text against the rules `options` has for `format`.
+    /// Markdown is parsed and checked here too.
+    pub fn with_options(
+        sender: User,
+        message: impl Into<Cow<'a, str>>,
+        format: TextFormat,
+        options: &ValidationOptions,
+    ) -> Result<Self, ValidationError> {
+        let message = message.into();
+        options.text_rules(format).check(&message)?;
+        let markdown = match format {
+            TextFormat::Plain => None,
+            TextFormat::Markdown => Some(parse(&message)?),
+        };
+
+        Ok(Self {
+            message_id: gen_message_id(),
+            r#type: MessageType::Text,
+            sender,
+            message,
+            timestamp: Utc::now(),
+            conversation_id: None,
+            reply_to: None,
+            thread_root: None,
+            edited_at: None,
+            mentions: Vec::new(),
+            format,
+            markdown,
+            previews: Vec::new(),
+        })
     }
 
     pub fn message_id(&self) -> u32 {
@@ -42,20 +107,118 @@ impl<'a> TextMessagePackage<'a> {
     }
 
     pub fn message(&self) -> &str {
-        self.message
+        &self.message
     }
 
     pub fn timestamp(&self) -> String {
         self.timestamp.to_string()
     }
 
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn set_created_at(&mut self, created_at: DateTime<Utc>) {
+        self.timestamp = created_at;
+    }
+
     pub fn pretty_timestamp(&self) -> String {
         self.timestamp
             .format("%A, %B, %e, %Y at %I:%M%p")
             .to_string()
     }
 
-    // fn as_any(&self) -> &dyn Any {
-    //   self
-    // }
+    pub fn set_conversation_id(&mut self, conversation_id: String) {
+        self.conversation_id = Some(conversation_id);
+    }
+
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
+    pub fn reply_to(&self) -> Option<u32> {
+        self.reply_to
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: u32) {
+        self.reply_to = Some(reply_to);
+    }
+
+    pub fn thread_root(&self) -> Option<u32> {
+        self.thread_root
+    }
+
+    pub fn set_thread_root(&mut self, thread_root: u32) {
+        self.thread_root = Some(thread_root);
+    }
+
+    pub fn edited_at(&self) -> Option<DateTime<Utc>> {
+        self.edited_at
+    }
+
+    pub fn is_edited(&self) -> bool {
+        self.edited_at.is_some()
+    }
+
+    pub fn set_edited_at(&mut self, edited_at: DateTime<Utc>) {
+        self.edited_at = Some(edited_at);
+    }
+
+    pub fn mentions(&self) -> &[Mention] {
+        &self.mentions
+    }
+
+    pub fn set_mentions(&mut self, mentions: Vec<Mention>) {
+        self.mentions = mentions;
+    }
+
+    /// Whether the message mentions `sender_id` by handle.
+    pub fn mentions_user(&self, sender_id: u32) -> bool {
+        self.mentions
+            .iter()
+            .any(|mention| mention.sender_id() == sender_id)
+    }
+
+    pub fn format(&self) -> TextFormat {
+        self.format
+    }
+
+    pub fn markdown(&self) -> Option<&[Block]> {
+        self.markdown.as_deref()
+    }
+
+    /// The message as a sanitized HTML fragment. Plain text is escaped and
+    /// keeps its line breaks.
+    pub fn html(&self) -> String {
+        match &self.markdown {
+            Some(blocks) => render_html(blocks),
+            None => render_plain_html(&self.message),
+        }
+    }
+
+    pub fn previews(&self) -> &[LinkPreview] {
+        &self.previews
+    }
+
+    pub fn set_previews(&mut self, previews: Vec<LinkPreview>) {
+        self.previews = previews;
+    }
+
+    pub fn into_owned(self) -> TextMessagePackage<'static> {
+        TextMessagePackage {
+            message_id: self.message_id,
+            r#type: self.r#type,
+            message: Cow::Owned(self.message.into_owned()),
+            sender: self.sender,
+            timestamp: self.timestamp,
+            conversation_id: self.conversation_id,
+            reply_to: self.reply_to,
+            thread_root: self.thread_root,
+            edited_at: self.edited_at,
+            mentions: self.mentions,
+            format: self.format,
+            markdown: self.markdown,
+            previews: self.previews,
+        }
+    }
 }
@@ -1,4 +1,19 @@
+pub mod blob;
+pub mod conversation;
+pub mod draft;
 pub mod envvars;
+pub mod history;
+pub mod markdown;
+pub mod mention;
 pub mod messages;
+pub mod preview;
+pub mod reaction;
+pub mod receipt;
+pub mod revision;
+pub mod scan;
+pub mod scheduled;
+pub mod thread;
+pub mod upload;
 pub mod user;
+pub mod webhook;
 // pub mod image_messaging_package;
@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// What a page linked from a text message says about itself, taken from its
+/// OpenGraph tags, its oEmbed endpoint or, failing both, its `<title>`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LinkPreview {
+    /// The URL as it appears in the message.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// An absolute `http` or `https` URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+}
+
+impl LinkPreview {
+    /// Whether there is anything to show beyond the URL itself.
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none() && self.image.is_none()
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Longest emoji accepted, in characters. Flags, skin tones and ZWJ
+/// sequences take several.
+const MAX_EMOJI_CHARS: usize = 16;
+
+/// Most different emoji one user may put on one message.
+pub const MAX_REACTIONS_PER_USER: usize = 20;
+
+/// Characters that are emoji on their own, or are one half of a flag.
+fn is_emoji_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00A9}'
+            | '\u{00AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{21AA}'
+            | '\u{231A}'..='\u{23FF}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25FE}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2B05}'..='\u{2B55}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1F000}'..='\u{1FAFF}'
+    )
+}
+
+/// Characters that only change or join the emoji around them: the zero
+/// width joiner, variation selectors, the keycap mark and the tags of
+/// subdivision flags.
+fn is_emoji_component(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200D}' | '\u{FE0E}' | '\u{FE0F}' | '\u{20E3}' | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+/// Whether `text` is made of emoji only. Digits, `#` and `*` count when
+/// they are keycaps, as in 1️⃣.
+fn is_emoji(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    let mut has_emoji = false;
+    for (i, &c) in chars.iter().enumerate() {
+        if is_emoji_char(c) {
+            has_emoji = true;
+        } else if matches!(c, '0'..='9' | '#' | '*') {
+            let keycap = match chars.get(i + 1) {
+                Some('\u{FE0F}') => chars.get(i + 2) == Some(&'\u{20E3}'),
+                next => next == Some(&'\u{20E3}'),
+            };
+            if !keycap {
+                return false;
+            }
+            has_emoji = true;
+        } else if !is_emoji_component(c) {
+            return false;
+        }
+    }
+
+    has_emoji
+}
+
+/// One user's emoji on one message. Reactions are kept apart from the
+/// message documents.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reaction {
+    message_id: u32,
+    conversation_id: String,
+    sender_id: u32,
+    emoji: String,
+    #[serde(with = "crate::utils::sortable_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+impl Reaction {
+    pub fn new(
+        message_id: u32,
+        conversation_id: String,
+        sender_id: u32,
+        emoji: String,
+    ) -> Result<Self, String> {
+        if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_CHARS {
+            return Err(format!(
+                "A reaction must be between 1 and {} characters",
+                MAX_EMOJI_CHARS
+            ));
+        }
+        if !is_emoji(&emoji) {
+            return Err("A reaction must be an emoji".into());
+        }
+
+        Ok(Self {
+            message_id,
+            conversation_id,
+            sender_id,
+            emoji,
+            timestamp: Utc::now(),
+        })
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn emoji(&self) -> &str {
+        &self.emoji
+    }
+
+    pub fn timestamp(&self) -> String {
+        self.timestamp.to_string()
+    }
+}
+
+/// Everyone who reacted to a message with the same emoji.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReactionGroup {
+    pub emoji: String,
+    pub count: u64,
+    /// In the order they reacted.
+    pub sender_ids: Vec<u32>,
+}
+
+impl ReactionGroup {
+    /// Groups `reactions` by emoji, most used first.
+    pub fn group(reactions: &[Reaction]) -> Vec<ReactionGroup> {
+        let mut groups: Vec<ReactionGroup> = Vec::new();
+        for reaction in reactions {
+            match groups
+                .iter_mut()
+                .find(|group| group.emoji == reaction.emoji)
+            {
+                Some(group) => {
+                    group.count += 1;
+                    group.sender_ids.push(reaction.sender_id);
+                }
+                None => groups.push(ReactionGroup {
+                    emoji: reaction.emoji.clone(),
+                    count: 1,
+                    sender_ids: vec![reaction.sender_id],
+                }),
+            }
+        }
+        // Stable, so ties keep the order the emoji were first used in.
+        groups.sort_by_key(|group| std::cmp::Reverse(group.count));
+
+        groups
+    }
+}
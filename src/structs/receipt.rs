@@ -0,0 +1,49 @@
+use crate::enums::ReceiptStatus;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt {
+    message_id: u32,
+    conversation_id: String,
+    sender_id: u32,
+    status: ReceiptStatus,
+    timestamp: DateTime<Utc>,
+}
+
+impl Receipt {
+    pub fn new(
+        message_id: u32,
+        conversation_id: String,
+        sender_id: u32,
+        status: ReceiptStatus,
+    ) -> Self {
+        Self {
+            message_id,
+            conversation_id,
+            sender_id,
+            status,
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn status(&self) -> ReceiptStatus {
+        self.status.clone()
+    }
+
+    pub fn timestamp(&self) -> String {
+        self.timestamp.to_string()
+    }
+}
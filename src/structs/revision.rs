@@ -0,0 +1,45 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The text a message had before an edit replaced it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageRevision {
+    message_id: u32,
+    message: String,
+    /// Who made the edit, which is not always the sender.
+    edited_by: u32,
+    edited_at: DateTime<Utc>,
+}
+
+impl MessageRevision {
+    pub fn new(message_id: u32, message: String, edited_by: u32) -> Self {
+        Self {
+            message_id,
+            message,
+            edited_by,
+            edited_at: Utc::now(),
+        }
+    }
+
+    /// For a revision written after the edit it records was saved.
+    pub fn with_edited_at(mut self, edited_at: DateTime<Utc>) -> Self {
+        self.edited_at = edited_at;
+        self
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn edited_by(&self) -> u32 {
+        self.edited_by
+    }
+
+    pub fn edited_at(&self) -> DateTime<Utc> {
+        self.edited_at
+    }
+}
@@ -0,0 +1,48 @@
+use crate::enums::ScanStatus;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// What the attachment scanner said about a message's attachment.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScanVerdict {
+    status: ScanStatus,
+    /// The name of what was found, such as `Eicar-Test-Signature`.
+    #[serde(default)]
+    signature: Option<String>,
+    scanner: String,
+    scanned_at: DateTime<Utc>,
+}
+
+impl ScanVerdict {
+    pub fn new(scanner: String, signature: Option<String>) -> Self {
+        Self {
+            status: match signature {
+                Some(_) => ScanStatus::Infected,
+                None => ScanStatus::Clean,
+            },
+            signature,
+            scanner,
+            scanned_at: Utc::now(),
+        }
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        self.status.clone()
+    }
+
+    pub fn is_infected(&self) -> bool {
+        self.status == ScanStatus::Infected
+    }
+
+    pub fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    pub fn scanner(&self) -> &str {
+        &self.scanner
+    }
+
+    pub fn scanned_at(&self) -> DateTime<Utc> {
+        self.scanned_at
+    }
+}
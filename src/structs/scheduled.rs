@@ -0,0 +1,105 @@
+use crate::blobs::BlobStore;
+use crate::enums::Message;
+use crate::errors::BlobError;
+use chrono::TimeDelta;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// How far ahead a message may be scheduled.
+pub const MAX_SCHEDULE_DAYS: i64 = 365;
+
+/// A message held back until `send_at`, when the scheduler delivers it like
+/// any other. It keeps its `message_id`, which is also how it is cancelled
+/// or rescheduled until then.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledMessage {
+    message_id: u32,
+    sender_id: u32,
+    conversation_id: String,
+    #[serde(with = "crate::utils::sortable_timestamp")]
+    send_at: DateTime<Utc>,
+    scheduled_at: DateTime<Utc>,
+    message: Message<'static>,
+}
+
+impl ScheduledMessage {
+    /// `message` must already belong to a conversation.
+    pub fn new(message: Message<'static>, send_at: DateTime<Utc>) -> Result<Self, String> {
+        let conversation_id = message
+            .conversation_id()
+            .ok_or("Only messages in a conversation can be scheduled")?
+            .to_string();
+        if message.is_deleted() {
+            return Err("A deleted message can't be scheduled".into());
+        }
+        check_send_at(send_at)?;
+
+        Ok(Self {
+            message_id: message.message_id(),
+            sender_id: message.sender_id(),
+            conversation_id,
+            send_at,
+            scheduled_at: Utc::now(),
+            message,
+        })
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn send_at(&self) -> DateTime<Utc> {
+        self.send_at
+    }
+
+    pub fn set_send_at(&mut self, send_at: DateTime<Utc>) -> Result<(), String> {
+        check_send_at(send_at)?;
+        self.send_at = send_at;
+        Ok(())
+    }
+
+    pub fn scheduled_at(&self) -> DateTime<Utc> {
+        self.scheduled_at
+    }
+
+    pub fn message(&self) -> &Message<'static> {
+        &self.message
+    }
+
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.send_at <= now
+    }
+
+    /// Moves the message's attachment bytes into `blobs` while it waits, as
+    /// for any stored message.
+    pub async fn store_attachments(&mut self, blobs: &dyn BlobStore) -> Result<(), BlobError> {
+        self.message.store_attachments(blobs).await
+    }
+
+    pub fn into_message(self) -> Message<'static> {
+        self.message
+    }
+}
+
+fn check_send_at(send_at: DateTime<Utc>) -> Result<(), String> {
+    let now = Utc::now();
+    if send_at <= now {
+        return Err("Messages can only be scheduled for the future".into());
+    }
+    if send_at > now + TimeDelta::days(MAX_SCHEDULE_DAYS) {
+        return Err(format!(
+            "Messages can be scheduled at most {} days ahead",
+            MAX_SCHEDULE_DAYS
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use crate::enums::{Message, MessageType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A short, read-only rendering of the message a reply answers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuotedMessage {
+    message_id: u32,
+    sender_id: u32,
+    r#type: MessageType,
+    preview: String,
+}
+
+impl QuotedMessage {
+    pub fn new(message_id: u32, sender_id: u32, r#type: MessageType, preview: String) -> Self {
+        Self {
+            message_id,
+            sender_id,
+            r#type,
+            preview,
+        }
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    pub fn sender_id(&self) -> u32 {
+        self.sender_id
+    }
+
+    pub fn r#type(&self) -> MessageType {
+        self.r#type.clone()
+    }
+
+    pub fn preview(&self) -> &str {
+        &self.preview
+    }
+}
+
+/// A thread's first message and its replies, oldest first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThreadPage {
+    pub root: Message<'static>,
+    pub replies: Vec<Message<'static>>,
+    /// Previews of the messages the replies answer, by message id.
+    pub quoted: BTreeMap<u32, QuotedMessage>,
+}
@@ -0,0 +1,104 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A chunked upload in progress. Chunks are numbered from 0 and all are
+/// `chunk_size` bytes except the last, which holds the remainder.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadSession {
+    upload_id: String,
+    owner_id: u32,
+    conversation_id: String,
+    filename: String,
+    total_size: u64,
+    chunk_size: u64,
+    total_chunks: u32,
+    created_at: DateTime<Utc>,
+    #[serde(default)]
+    received_chunks: Vec<u32>,
+    #[serde(default = "Utc::now")]
+    expires_at: DateTime<Utc>,
+}
+
+impl UploadSession {
+    pub fn new(
+        upload_id: String,
+        owner_id: u32,
+        conversation_id: String,
+        filename: String,
+        total_size: u64,
+        chunk_size: u64,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            upload_id,
+            owner_id,
+            conversation_id,
+            filename,
+            total_size,
+            chunk_size,
+            total_chunks: total_size.div_ceil(chunk_size) as u32,
+            created_at: Utc::now(),
+            received_chunks: Vec::new(),
+            expires_at,
+        }
+    }
+
+    pub fn upload_id(&self) -> &str {
+        &self.upload_id
+    }
+
+    pub fn owner_id(&self) -> u32 {
+        self.owner_id
+    }
+
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn total_chunks(&self) -> u32 {
+        self.total_chunks
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn received_chunks(&self) -> &[u32] {
+        &self.received_chunks
+    }
+
+    /// The chunks still to send, which is where a client resumes from.
+    pub fn missing_chunks(&self) -> Vec<u32> {
+        (0..self.total_chunks)
+            .filter(|index| self.received_chunks.binary_search(index).is_err())
+            .collect()
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// How many bytes chunk `index` must hold.
+    pub fn expected_chunk_size(&self, index: u32) -> u64 {
+        let start = u64::from(index) * self.chunk_size;
+        self.chunk_size.min(self.total_size.saturating_sub(start))
+    }
+
+    pub fn set_progress(&mut self, mut received_chunks: Vec<u32>, expires_at: DateTime<Utc>) {
+        received_chunks.sort_unstable();
+        self.received_chunks = received_chunks;
+        self.expires_at = expires_at;
+    }
+}
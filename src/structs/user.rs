@@ -1,11 +1,24 @@
-use crate::enums::MessageFromType;
+use crate::enums::{MessageFromType, Role};
 use crate::utils::gen_sender_id;
 use serde::{Deserialize, Serialize};
 
+pub const MIN_HANDLE_LENGTH: usize = 2;
+pub const MAX_HANDLE_LENGTH: usize = 32;
+
+/// Handles are ASCII letters, digits and underscores, compared without case.
+pub fn is_handle_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     sender_id: u32,
     sender_type: MessageFromType,
+    #[serde(default)]
+    role: Role,
+    /// What others write after `@` to mention this user. Stored lowercase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    handle: Option<String>,
 }
 
 impl User {
@@ -13,6 +26,8 @@ impl User {
         Self {
             sender_id: gen_sender_id(),
             sender_type,
+            role: Role::default(),
+            handle: None,
         }
     }
 
@@ -23,4 +38,42 @@ impl User {
     pub fn sender_type(&self) -> &MessageFromType {
         &self.sender_type
     }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    /// Admins moderate too.
+    pub fn is_moderator(&self) -> bool {
+        matches!(self.role, Role::Moderator | Role::Admin)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    pub fn handle(&self) -> Option<&str> {
+        self.handle.as_deref()
+    }
+
+    /// Sets the handle, without a leading `@`.
+    pub fn set_handle(&mut self, handle: &str) -> Result<(), String> {
+        let length = handle.chars().count();
+        if !(MIN_HANDLE_LENGTH..=MAX_HANDLE_LENGTH).contains(&length) {
+            return Err(format!(
+                "A handle must be between {} and {} characters",
+                MIN_HANDLE_LENGTH, MAX_HANDLE_LENGTH
+            ));
+        }
+        if !handle.chars().all(is_handle_char) {
+            return Err("A handle may only contain letters, digits and underscores".into());
+        }
+
+        self.handle = Some(handle.to_ascii_lowercase());
+        Ok(())
+    }
 }
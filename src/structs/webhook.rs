@@ -0,0 +1,111 @@
+use crate::enums::{WebhookEvent, WebhookEventType};
+use crate::utils::gen_message_id;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookSubscription {
+    subscription_id: u32,
+    owner_id: u32,
+    url: String,
+    events: Vec<WebhookEventType>,
+    secret: String,
+    created_at: DateTime<Utc>,
+}
+
+impl WebhookSubscription {
+    pub fn new(
+        owner_id: u32,
+        url: String,
+        events: Vec<WebhookEventType>,
+        secret: String,
+    ) -> Result<Self, String> {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err("Webhook url must start with http:// or https://".into());
+        }
+        if events.is_empty() {
+            return Err("A webhook must subscribe to at least one event".into());
+        }
+        if secret.len() < 16 {
+            return Err("Webhook secret must be at least 16 characters".into());
+        }
+
+        Ok(Self {
+            subscription_id: gen_message_id(),
+            owner_id,
+            url,
+            events,
+            secret,
+            created_at: Utc::now(),
+        })
+    }
+
+    pub fn subscription_id(&self) -> u32 {
+        self.subscription_id
+    }
+
+    pub fn owner_id(&self) -> u32 {
+        self.owner_id
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn events(&self) -> &[WebhookEventType] {
+        &self.events
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    pub fn wants(&self, event_type: &WebhookEventType) -> bool {
+        self.events.contains(event_type)
+    }
+}
+
+/// A delivery that kept failing after every retry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookDeadLetter {
+    subscription_id: u32,
+    url: String,
+    event: WebhookEvent,
+    attempts: u32,
+    last_error: String,
+    failed_at: DateTime<Utc>,
+}
+
+impl WebhookDeadLetter {
+    pub fn new(
+        subscription: &WebhookSubscription,
+        event: WebhookEvent,
+        attempts: u32,
+        last_error: String,
+    ) -> Self {
+        Self {
+            subscription_id: subscription.subscription_id(),
+            url: subscription.url().to_string(),
+            event,
+            attempts,
+            last_error,
+            failed_at: Utc::now(),
+        }
+    }
+
+    pub fn subscription_id(&self) -> u32 {
+        self.subscription_id
+    }
+
+    pub fn event(&self) -> &WebhookEvent {
+        &self.event
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn last_error(&self) -> &str {
+        &self.last_error
+    }
+}
@@ -0,0 +1,332 @@
+use crate::db::get_collection;
+use crate::enums::{Message, MessageFromType, MessageType};
+use crate::utils::sortable_timestamp;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use mongodb::bson::{Bson, Document, doc, from_document, to_bson, to_document};
+use mongodb::change_stream::ChangeStream;
+use mongodb::change_stream::event::{ChangeStreamEvent, ResumeToken};
+use mongodb::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Server error code for "$changeStream is only supported on replica sets".
+const CHANGE_STREAMS_UNSUPPORTED: i32 = 40573;
+const POLL_BATCH_SIZE: i64 = 100;
+/// How long to wait before reopening a change stream that failed.
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+/// Narrows a subscription down to one conversation, message type or kind of
+/// sender. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionFilter {
+    pub conversation_id: Option<String>,
+    pub message_type: Option<MessageType>,
+    pub from: Option<MessageFromType>,
+}
+
+impl SubscriptionFilter {
+    /// Query conditions on the stored message document, with every field
+    /// prefixed by `prefix` (`fullDocument.` inside a change stream).
+    pub fn conditions(&self, prefix: &str) -> Result<Vec<Document>, mongodb::bson::ser::Error> {
+        let mut conditions = Vec::new();
+        if let Some(conversation_id) = &self.conversation_id {
+            conditions.push(doc! { format!("{}conversation_id", prefix): conversation_id });
+        }
+        if let Some(message_type) = &self.message_type {
+            conditions.push(doc! { format!("{}type", prefix): to_bson(message_type)? });
+        }
+        if let Some(from) = &self.from {
+            // Text messages keep the sender type on the embedded user.
+            let from = to_bson(from)?;
+            conditions.push(doc! { "$or": [
+                { format!("{}from", prefix): from.clone() },
+                { format!("{}sender.sender_type", prefix): from },
+            ] });
+        }
+
+        Ok(conditions)
+    }
+}
+
+/// Where a named subscription left off. Change streams resume from the
+/// server's resume token; the polling fallback from the last message it saw.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    name: String,
+    resume_token: Option<ResumeToken>,
+    last_timestamp: Option<DateTime<Utc>>,
+    last_message_id: Option<u32>,
+}
+
+impl Checkpoint {
+    /// A subscription that has not seen anything yet.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn resume_token(&self) -> Option<&ResumeToken> {
+        self.resume_token.as_ref()
+    }
+
+    pub fn last_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.last_timestamp
+    }
+
+    pub fn last_message_id(&self) -> Option<u32> {
+        self.last_message_id
+    }
+
+    /// Moves the polling position past `message`.
+    pub fn advance(&mut self, message: &Message) {
+        self.last_timestamp = Some(message.created_at());
+        self.last_message_id = Some(message.message_id());
+    }
+}
+
+/// Where the polling fallback reads messages from and where named
+/// subscriptions keep their checkpoints.
+#[async_trait]
+pub trait SubscriptionSource: Send + Sync + 'static {
+    /// The saved checkpoint called `name`, or a fresh one.
+    async fn load_checkpoint(&self, name: &str) -> Result<Checkpoint, mongodb::error::Error>;
+
+    async fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), mongodb::error::Error>;
+
+    /// Up to `limit` messages matching `filter` that are ordered after
+    /// (`timestamp`, `message_id`), oldest first.
+    async fn messages_after(
+        &self,
+        filter: &SubscriptionFilter,
+        timestamp: DateTime<Utc>,
+        message_id: u32,
+        limit: i64,
+    ) -> Result<Vec<Message<'static>>, mongodb::error::Error>;
+}
+
+/// Reads `message_history` and keeps checkpoints in
+/// `subscription_checkpoints`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MongoSubscriptionSource;
+
+#[async_trait]
+impl SubscriptionSource for MongoSubscriptionSource {
+    async fn load_checkpoint(&self, name: &str) -> Result<Checkpoint, mongodb::error::Error> {
+        let collection = get_collection("subscription_checkpoints").await?;
+        let document = collection.find_one(doc! { "name": name }).await?;
+
+        Ok(match document {
+            Some(document) => from_document(document)?,
+            None => Checkpoint::new(name),
+        })
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), mongodb::error::Error> {
+        let collection = get_collection("subscription_checkpoints").await?;
+        collection
+            .replace_one(doc! { "name": &checkpoint.name }, to_document(checkpoint)?)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn messages_after(
+        &self,
+        filter: &SubscriptionFilter,
+        timestamp: DateTime<Utc>,
+        message_id: u32,
+        limit: i64,
+    ) -> Result<Vec<Message<'static>>, mongodb::error::Error> {
+        let collection = get_collection("message_history").await?;
+        let timestamp = Bson::String(sortable_timestamp::format(&timestamp));
+        let mut conditions = filter.conditions("")?;
+        conditions.push(doc! { "$or": [
+            { "timestamp": { "$gt": timestamp.clone() } },
+            { "timestamp": timestamp, "message_id": { "$gt": message_id } },
+        ] });
+
+        let documents: Vec<Document> = collection
+            .find(doc! { "$and": conditions })
+            .sort(doc! { "timestamp": 1, "message_id": 1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?;
+
+        documents
+            .into_iter()
+            .map(|document| from_document(document).map_err(Into::into))
+            .collect()
+    }
+}
+
+/// Streams messages as they are inserted into `message_history` by any
+/// process. Uses a MongoDB change stream, and falls back to polling every
+/// `poll_interval` when the server is a standalone that has no change streams.
+///
+/// When `name` is given the subscription's position is persisted under that
+/// name once the consumer asks for the next message, and a later
+/// subscription with the same name resumes from it.
+pub async fn subscribe(
+    filter: SubscriptionFilter,
+    name: Option<&str>,
+    poll_interval: Duration,
+) -> Result<BoxStream<'static, Message<'static>>, mongodb::error::Error> {
+    let source = Arc::new(MongoSubscriptionSource);
+    let checkpoint = match name {
+        Some(name) => Some(source.load_checkpoint(name).await?),
+        None => None,
+    };
+
+    match watch_messages(&filter, checkpoint.clone()).await {
+        Ok(stream) => Ok(stream),
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == CHANGE_STREAMS_UNSUPPORTED) =>
+        {
+            eprintln!("Change streams are not supported by this server, falling back to polling");
+            Ok(poll_messages(source, filter, checkpoint, poll_interval))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn open_change_stream(
+    conditions: &[Document],
+    resume_token: Option<ResumeToken>,
+) -> Result<ChangeStream<ChangeStreamEvent<Document>>, mongodb::error::Error> {
+    get_collection("message_history")
+        .await?
+        .watch()
+        .pipeline([doc! { "$match": { "$and": conditions } }])
+        .resume_after(resume_token)
+        .await
+}
+
+async fn watch_messages(
+    filter: &SubscriptionFilter,
+    checkpoint: Option<Checkpoint>,
+) -> Result<BoxStream<'static, Message<'static>>, mongodb::error::Error> {
+    let mut conditions = vec![doc! { "operationType": "insert" }];
+    conditions.extend(filter.conditions("fullDocument.")?);
+
+    let mut resume_token = checkpoint
+        .as_ref()
+        .and_then(|checkpoint| checkpoint.resume_token.clone());
+    let change_stream = open_change_stream(&conditions, resume_token.clone()).await?;
+
+    let stream = async_stream::stream! {
+        let source = MongoSubscriptionSource;
+        let mut checkpoint = checkpoint;
+        let mut change_stream = Some(change_stream);
+        loop {
+            let mut events = match change_stream.take() {
+                Some(events) => events,
+                None => match open_change_stream(&conditions, resume_token.clone()).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        eprintln!("Could not reopen change stream: {}", e);
+                        tokio::time::sleep(RESUME_DELAY).await;
+                        continue;
+                    }
+                },
+            };
+
+            loop {
+                let event = match events.try_next().await {
+                    Ok(Some(event)) => event,
+                    // The stream was invalidated, e.g. the collection was dropped.
+                    Ok(None) => return,
+                    Err(e) => {
+                        eprintln!("Change stream failed, resuming: {}", e);
+                        resume_token = events.resume_token().or(resume_token);
+                        break;
+                    }
+                };
+
+                if let Some(document) = event.full_document {
+                    match from_document::<Message>(document) {
+                        Ok(message) => yield message,
+                        Err(e) => eprintln!("Skipping unreadable message document: {}", e),
+                    }
+                }
+
+                // Only once the consumer is done with the message.
+                resume_token = events.resume_token().or(resume_token);
+                if let Some(checkpoint) = checkpoint.as_mut() {
+                    checkpoint.resume_token = resume_token.clone();
+                    if let Err(e) = source.save_checkpoint(checkpoint).await {
+                        eprintln!("Could not save subscription checkpoint: {}", e);
+                    }
+                }
+            }
+            tokio::time::sleep(RESUME_DELAY).await;
+        }
+    };
+
+    Ok(stream.boxed())
+}
+
+/// Polls `source` every `poll_interval` for messages after the checkpoint,
+/// or after now when there is none. A named checkpoint is saved once the
+/// consumer asks for the message after the one it covers.
+pub fn poll_messages<S: SubscriptionSource>(
+    source: Arc<S>,
+    filter: SubscriptionFilter,
+    checkpoint: Option<Checkpoint>,
+    poll_interval: Duration,
+) -> BoxStream<'static, Message<'static>> {
+    let stream = async_stream::stream! {
+        let mut checkpoint = checkpoint;
+        let mut last_timestamp = checkpoint
+            .as_ref()
+            .and_then(|checkpoint| checkpoint.last_timestamp)
+            .unwrap_or_else(Utc::now);
+        let mut last_message_id = checkpoint
+            .as_ref()
+            .and_then(|checkpoint| checkpoint.last_message_id)
+            .unwrap_or(0);
+
+        loop {
+            let messages = match source
+                .messages_after(&filter, last_timestamp, last_message_id, POLL_BATCH_SIZE)
+                .await
+            {
+                Ok(messages) => messages,
+                Err(e) => {
+                    eprintln!("Polling for messages failed: {}", e);
+                    Vec::new()
+                }
+            };
+            let caught_up = (messages.len() as i64) < POLL_BATCH_SIZE;
+
+            for message in messages {
+                last_timestamp = message.created_at();
+                last_message_id = message.message_id();
+                let seen = message.clone();
+                yield message;
+
+                if let Some(checkpoint) = checkpoint.as_mut() {
+                    checkpoint.advance(&seen);
+                    if let Err(e) = source.save_checkpoint(checkpoint).await {
+                        eprintln!("Could not save subscription checkpoint: {}", e);
+                    }
+                }
+            }
+
+            if caught_up {
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    };
+
+    stream.boxed()
+}
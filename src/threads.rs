@@ -0,0 +1,103 @@
+use crate::enums::Message;
+use crate::errors::{StoreError, ThreadError};
+use crate::store::MessageStore;
+use crate::structs::thread::QuotedMessage;
+use std::collections::BTreeMap;
+
+/// Characters of a text message shown when it is quoted.
+const PREVIEW_CHARS: usize = 100;
+
+/// Places a reply in its parent's thread. The parent has to be in the same
+/// conversation and not deleted; replies to replies join the thread of the
+/// message that started it, so threads never nest.
+pub async fn resolve_reply(
+    store: &dyn MessageStore,
+    message: &mut Message<'_>,
+) -> Result<(), ThreadError> {
+    let Some(parent_id) = message.reply_to() else {
+        return Ok(());
+    };
+    let conversation_id = message.conversation_id().unwrap_or_default();
+    let thread_root = thread_root_for(store, conversation_id, parent_id).await?;
+    message.set_thread_root(thread_root);
+
+    Ok(())
+}
+
+/// The thread a reply to `parent_id` in `conversation_id` belongs to.
+pub async fn thread_root_for(
+    store: &dyn MessageStore,
+    conversation_id: &str,
+    parent_id: u32,
+) -> Result<u32, ThreadError> {
+    let parent = store
+        .find_message(parent_id)
+        .await?
+        .filter(|parent| parent.conversation_id() == Some(conversation_id))
+        .ok_or(ThreadError::ParentNotFound(parent_id))?;
+    if parent.is_deleted() {
+        return Err(ThreadError::ParentDeleted(parent_id));
+    }
+
+    Ok(parent.thread_root().unwrap_or(parent_id))
+}
+
+/// How `message` is shown when a reply quotes it.
+pub fn quote(message: &Message<'_>) -> QuotedMessage {
+    let preview = match message {
+        Message::Text(text) => {
+            let mut preview: String = text.message().chars().take(PREVIEW_CHARS).collect();
+            if preview.len() < text.message().len() {
+                preview.push('…');
+            }
+            preview
+        }
+        Message::Image(_) => "[image]".to_string(),
+        Message::File(file) => match file.metadata().filename() {
+            "" => "[file]".to_string(),
+            filename => format!("[file] {}", filename),
+        },
+        Message::CryptoTransfer(transfer) => {
+            format!(
+                "[transfer] {} {}",
+                transfer.amount(),
+                transfer.token_symbol()
+            )
+        }
+        Message::Deleted(_) => "[deleted]".to_string(),
+    };
+
+    QuotedMessage::new(
+        message.message_id(),
+        message.sender_id(),
+        message.r#type(),
+        preview,
+    )
+}
+
+/// Quotes of every message one of `messages` replies to, by message id.
+/// Parents that are among `messages` aren't looked up again, and parents
+/// that have since been purged are left out.
+pub async fn quotes_for(
+    store: &dyn MessageStore,
+    messages: &[Message<'static>],
+) -> Result<BTreeMap<u32, QuotedMessage>, StoreError> {
+    let mut quoted = BTreeMap::new();
+    for parent_id in messages.iter().filter_map(Message::reply_to) {
+        if quoted.contains_key(&parent_id) {
+            continue;
+        }
+        let parent = match messages
+            .iter()
+            .find(|message| message.message_id() == parent_id)
+        {
+            Some(parent) => Some(parent.clone()),
+            None => store.find_message(parent_id).await?,
+        };
+        if let Some(parent) = parent {
+            quoted.insert(parent_id, quote(&parent));
+        }
+    }
+
+    Ok(quoted)
+}
@@ -0,0 +1,107 @@
+use crate::errors::TransferError;
+use crate::structs::messages::crypto::CryptoTransferMessagePackage;
+use async_trait::async_trait;
+use solana_client::client_error::ClientErrorKind;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::native_token::sol_to_lamports;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use std::str::FromStr;
+
+/// Something that can tell whether a transaction really paid a transfer.
+#[async_trait]
+pub trait TransferVerifier: Send + Sync {
+    /// Whether the transaction `signature` names is confirmed and paid the
+    /// transfer's recipient. A transaction that isn't known yet is `false`.
+    async fn is_confirmed(
+        &self,
+        transfer: &CryptoTransferMessagePackage<'_>,
+        signature: &str,
+    ) -> Result<bool, TransferError>;
+}
+
+/// Looks transactions up through a Solana JSON-RPC node.
+pub struct SolanaVerifier {
+    client: RpcClient,
+}
+
+impl SolanaVerifier {
+    pub fn new(rpc_url: impl Into<String>) -> Self {
+        Self {
+            client: RpcClient::new_with_commitment(rpc_url.into(), CommitmentConfig::confirmed()),
+        }
+    }
+}
+
+#[async_trait]
+impl TransferVerifier for SolanaVerifier {
+    async fn is_confirmed(
+        &self,
+        transfer: &CryptoTransferMessagePackage<'_>,
+        signature: &str,
+    ) -> Result<bool, TransferError> {
+        let parsed = Signature::from_str(signature)
+            .map_err(|_| TransferError::InvalidSignature(signature.to_string()))?;
+        let config = RpcTransactionConfig {
+            commitment: Some(CommitmentConfig::confirmed()),
+            max_supported_transaction_version: Some(0),
+            ..RpcTransactionConfig::default()
+        };
+        let found = match self
+            .client
+            .get_transaction_with_config(&parsed, config)
+            .await
+        {
+            Ok(found) => found,
+            // Nodes answer null, which the client reports as an error, for
+            // transactions they don't have (yet).
+            Err(e) if matches!(e.kind(), ClientErrorKind::SerdeJson(_)) => return Ok(false),
+            Err(e) => return Err(TransferError::Rpc(e.to_string())),
+        };
+
+        let Some(meta) = found.transaction.meta else {
+            return Ok(false);
+        };
+        if meta.err.is_some() {
+            return Ok(false);
+        }
+        // Token transfers pay an account the recipient owns, SOL transfers
+        // the recipient itself.
+        if !transfer.token_symbol().eq_ignore_ascii_case("SOL") {
+            let mut received = 0.0;
+            for (balances, sign) in [
+                (meta.pre_token_balances, -1.0),
+                (meta.post_token_balances, 1.0),
+            ] {
+                let balances: Option<Vec<_>> = balances.into();
+                for balance in balances.unwrap_or_default() {
+                    let owner: Option<String> = balance.owner.into();
+                    if owner.as_deref() == Some(transfer.recipient_address()) {
+                        received += sign * balance.ui_token_amount.ui_amount.unwrap_or(0.0);
+                    }
+                }
+            }
+            return Ok(received >= transfer.amount());
+        }
+        let Ok(recipient) = Pubkey::from_str(transfer.recipient_address()) else {
+            return Ok(false);
+        };
+        let Some(transaction) = found.transaction.transaction.decode() else {
+            return Ok(false);
+        };
+        let Some(index) = transaction
+            .message
+            .static_account_keys()
+            .iter()
+            .position(|key| *key == recipient)
+        else {
+            return Ok(false);
+        };
+        let before = meta.pre_balances.get(index).copied().unwrap_or(0);
+        let after = meta.post_balances.get(index).copied().unwrap_or(0);
+
+        Ok(after.saturating_sub(before) >= sol_to_lamports(transfer.amount()))
+    }
+}
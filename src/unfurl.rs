@@ -0,0 +1,653 @@
+use crate::enums::{Message, ServerFrame};
+use crate::errors::UnfurlError;
+use crate::server::hub::Hub;
+use crate::store::MessageStore;
+use crate::structs::messages::text::TextMessagePackage;
+use crate::structs::preview::LinkPreview;
+use reqwest::Url;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+const USER_AGENT: &str = "messaging-link-preview/1.0";
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 500;
+
+#[derive(Debug, Clone)]
+pub struct UnfurlOptions {
+    /// For one link, redirects and oEmbed included.
+    pub timeout: Duration,
+    /// Pages are cut off here, which still leaves their `<head>`; oEmbed
+    /// answers that are larger are refused.
+    pub max_bytes: usize,
+    pub max_redirects: usize,
+    /// Links after these many in a message aren't previewed.
+    pub max_links: usize,
+    /// How many links are fetched at once, across all messages.
+    pub max_concurrent: usize,
+    /// How long a preview, or the failure to make one, is remembered.
+    pub cache_ttl: Duration,
+    pub max_cache_entries: usize,
+    /// Lets links to this machine through, for tests and local development.
+    /// Private, link-local and other internal addresses are always refused.
+    pub allow_loopback: bool,
+}
+
+impl Default for UnfurlOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_bytes: 512 * 1024,
+            max_redirects: 3,
+            max_links: 3,
+            max_concurrent: 8,
+            cache_ttl: Duration::from_secs(60 * 60),
+            max_cache_entries: 1000,
+            allow_loopback: false,
+        }
+    }
+}
+
+struct CacheEntry {
+    fetched_at: Instant,
+    preview: Option<LinkPreview>,
+}
+
+/// Fetches previews for links in messages without letting them reach the
+/// server's own network: every address a link or redirect resolves to is
+/// checked, and the connection goes to the address that was checked.
+pub struct Unfurler {
+    options: UnfurlOptions,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    permits: Semaphore,
+}
+
+impl Unfurler {
+    pub fn new(options: UnfurlOptions) -> Self {
+        Self {
+            permits: Semaphore::new(options.max_concurrent.max(1)),
+            options,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn options(&self) -> &UnfurlOptions {
+        &self.options
+    }
+
+    /// Previews of the links in `text`, in order, leaving out those that
+    /// could not be previewed.
+    pub async fn previews(&self, text: &str) -> Vec<LinkPreview> {
+        let mut previews = Vec::new();
+        for url in extract_urls(text, self.options.max_links) {
+            match self.cached_unfurl(&url).await {
+                Some(preview) => previews.push(preview),
+                None => continue,
+            }
+        }
+        previews
+    }
+
+    /// `unfurl`, remembering the outcome for `cache_ttl`.
+    pub async fn cached_unfurl(&self, url: &str) -> Option<LinkPreview> {
+        if let Some(entry) = self.cache.lock().unwrap().get(url)
+            && entry.fetched_at.elapsed() < self.options.cache_ttl
+        {
+            return entry.preview.clone();
+        }
+
+        let preview = match self.unfurl(url).await {
+            Ok(preview) => Some(preview),
+            Err(e) => {
+                eprintln!("Could not preview {}: {}", url, e);
+                None
+            }
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.options.max_cache_entries {
+            let ttl = self.options.cache_ttl;
+            cache.retain(|_, entry| entry.fetched_at.elapsed() < ttl);
+            if cache.len() >= self.options.max_cache_entries {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            url.to_string(),
+            CacheEntry {
+                fetched_at: Instant::now(),
+                preview: preview.clone(),
+            },
+        );
+
+        preview
+    }
+
+    /// Fetches `url` and reads its OpenGraph tags, falling back to its
+    /// oEmbed endpoint and then its `<title>`.
+    pub async fn unfurl(&self, url: &str) -> Result<LinkPreview, UnfurlError> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the unfurl semaphore is never closed");
+        tokio::time::timeout(self.options.timeout, self.unfurl_now(url))
+            .await
+            .map_err(|_| UnfurlError::Timeout)?
+    }
+
+    async fn unfurl_now(&self, url: &str) -> Result<LinkPreview, UnfurlError> {
+        let parsed = Url::parse(url).map_err(|_| UnfurlError::UnsupportedUrl(url.to_string()))?;
+        let (page_url, content_type, body) = self
+            .fetch(parsed, "text/html,application/xhtml+xml", false)
+            .await?;
+        if !content_type.starts_with("text/html")
+            && !content_type.starts_with("application/xhtml+xml")
+        {
+            return Err(UnfurlError::NotHtml(content_type));
+        }
+
+        let page = parse_head(&String::from_utf8_lossy(&body));
+        let mut preview = LinkPreview {
+            url: url.to_string(),
+            title: page.meta("og:title").or(page.meta("twitter:title")),
+            description: page
+                .meta("og:description")
+                .or(page.meta("twitter:description"))
+                .or(page.meta("description")),
+            image: page
+                .meta("og:image")
+                .or(page.meta("twitter:image"))
+                .and_then(|image| absolute_http_url(&page_url, &image)),
+            site_name: page.meta("og:site_name"),
+        };
+
+        if (preview.title.is_none() || preview.image.is_none())
+            && let Some(endpoint) = page
+                .oembed
+                .as_deref()
+                .and_then(|href| absolute_http_url(&page_url, href))
+        {
+            match self.oembed(&endpoint).await {
+                Ok(oembed) => {
+                    preview.title = preview.title.or(oembed.title);
+                    preview.site_name = preview.site_name.or(oembed.provider_name);
+                    preview.image = preview.image.or(oembed
+                        .thumbnail_url
+                        .and_then(|image| absolute_http_url(&page_url, &image)));
+                }
+                Err(e) => eprintln!("Could not read oEmbed for {}: {}", url, e),
+            }
+        }
+        preview.title = preview.title.or(page.title);
+
+        preview.title = preview.title.map(|title| truncate(&title, MAX_TITLE_CHARS));
+        preview.description = preview
+            .description
+            .map(|description| truncate(&description, MAX_DESCRIPTION_CHARS));
+        if preview.is_empty() {
+            return Err(UnfurlError::NoMetadata);
+        }
+
+        Ok(preview)
+    }
+
+    async fn oembed(&self, endpoint: &str) -> Result<OEmbed, UnfurlError> {
+        let endpoint =
+            Url::parse(endpoint).map_err(|_| UnfurlError::UnsupportedUrl(endpoint.to_string()))?;
+        let (_, _, body) = self.fetch(endpoint, "application/json", true).await?;
+        serde_json::from_slice(&body).map_err(|_| UnfurlError::InvalidOEmbed)
+    }
+
+    /// GETs `url`, following redirects by hand so each hop is checked.
+    /// Returns the final URL, its content type and at most `max_bytes` of
+    /// its body; with `strict` a larger body is an error instead.
+    async fn fetch(
+        &self,
+        mut url: Url,
+        accept: &str,
+        strict: bool,
+    ) -> Result<(Url, String, Vec<u8>), UnfurlError> {
+        for _ in 0..=self.options.max_redirects {
+            let address = resolve_public(&url, self.options.allow_loopback).await?;
+            let mut client = reqwest::Client::builder()
+                .redirect(Policy::none())
+                // A proxy would connect to the host itself, past the check
+                // of the address it resolves to.
+                .no_proxy()
+                .user_agent(USER_AGENT);
+            if let Some(domain) = url.domain() {
+                client = client.resolve(domain, address);
+            }
+            let mut response = client
+                .build()?
+                .get(url.clone())
+                .header(ACCEPT, accept)
+                .send()
+                .await?;
+
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(UnfurlError::Status(status.as_u16()))?;
+                url = url
+                    .join(location)
+                    .map_err(|_| UnfurlError::UnsupportedUrl(location.to_string()))?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(UnfurlError::Status(status.as_u16()));
+            }
+
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                let room = self.options.max_bytes - body.len();
+                if chunk.len() > room {
+                    if strict {
+                        return Err(UnfurlError::TooLarge(self.options.max_bytes));
+                    }
+                    body.extend_from_slice(&chunk[..room]);
+                    break;
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            return Ok((url, content_type, body));
+        }
+
+        Err(UnfurlError::TooManyRedirects(self.options.max_redirects))
+    }
+}
+
+/// Listens to the hub for new and edited text messages with links, and
+/// attaches previews to them once the links have been fetched, publishing
+/// the message again as updated.
+#[derive(Clone)]
+pub struct LinkPreviewer {
+    store: Arc<dyn MessageStore>,
+    hub: Hub,
+    unfurler: Arc<Unfurler>,
+}
+
+impl LinkPreviewer {
+    pub fn new(store: Arc<dyn MessageStore>, hub: Hub, unfurler: Unfurler) -> Self {
+        Self {
+            store,
+            hub,
+            unfurler: Arc::new(unfurler),
+        }
+    }
+
+    pub async fn run(self, mut frames: broadcast::Receiver<ServerFrame>) {
+        loop {
+            match frames.recv().await {
+                Ok(frame) => self.dispatch(frame),
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Link previewer lagged, skipped {} frames", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn dispatch(&self, frame: ServerFrame) {
+        let (conversation_id, message) = match frame {
+            ServerFrame::Message {
+                conversation_id,
+                message: Message::Text(message),
+            }
+            | ServerFrame::MessageUpdated {
+                conversation_id,
+                message: Message::Text(message),
+            } => (conversation_id, message),
+            _ => return,
+        };
+        // Previews are only ever attached to a message without any, which
+        // also keeps the update published below from being previewed again.
+        if !message.previews().is_empty() || extract_urls(message.message(), 1).is_empty() {
+            return;
+        }
+
+        let previewer = self.clone();
+        tokio::spawn(async move { previewer.attach(conversation_id, message).await });
+    }
+
+    /// Fetches previews for the links in `message` and attaches them.
+    pub async fn attach(&self, conversation_id: String, message: TextMessagePackage<'static>) {
+        let previews = self.unfurler.previews(message.message()).await;
+        if previews.is_empty() {
+            return;
+        }
+
+        match self
+            .store
+            .attach_previews(message.message_id(), message.message(), &previews)
+            .await
+        {
+            Ok(Some(updated)) => self.hub.publish(ServerFrame::MessageUpdated {
+                conversation_id,
+                message: updated,
+            }),
+            Ok(None) => {}
+            Err(e) => eprintln!("Could not attach link previews: {}", e),
+        }
+    }
+}
+
+/// The address to connect to for `url`, once every address its host
+/// resolves to has been found public. With `allow_loopback` this machine's
+/// own addresses pass too, for tests and local development.
+pub async fn resolve_public(url: &Url, allow_loopback: bool) -> Result<SocketAddr, UnfurlError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(UnfurlError::UnsupportedUrl(url.to_string()));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| UnfurlError::UnsupportedUrl(url.to_string()))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| UnfurlError::UnsupportedUrl(url.to_string()))?;
+
+    let addresses: Vec<SocketAddr> = match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| UnfurlError::Resolve(host.to_string()))?
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(UnfurlError::Resolve(host.to_string()));
+    }
+    let may_connect = |ip: IpAddr| (allow_loopback && ip.is_loopback()) || is_public(ip);
+    if addresses.iter().any(|address| !may_connect(address.ip())) {
+        return Err(UnfurlError::Blocked(host.to_string()));
+    }
+
+    Ok(addresses[0])
+}
+
+/// Whether `ip` is on the public internet, as opposed to a private,
+/// loopback, link-local, shared, reserved or otherwise special range.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(embedded) => is_public_v4(embedded),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 address leads to: IPv4-mapped `::ffff:a.b.c.d`,
+/// the deprecated IPv4-compatible `::a.b.c.d` and 6to4 `2002:aabb:ccdd::/48`.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return Some(mapped);
+    }
+    if segments[..6] == [0; 6] && !ip.is_loopback() && !ip.is_unspecified() {
+        return ip.to_ipv4();
+    }
+    if segments[0] == 0x2002 {
+        let [a, b] = segments[1].to_be_bytes();
+        let [c, d] = segments[2].to_be_bytes();
+        return Some(Ipv4Addr::new(a, b, c, d));
+    }
+
+    None
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "This network", shared address space (carrier-grade NAT),
+        // protocol assignments, benchmarking and the reserved 240/4.
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local fc00::/7, link-local fe80::/10, documentation
+        // 2001:db8::/32 and NAT64 64:ff9b::/96, which can reach IPv4
+        // addresses that are private.
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+/// The `http` and `https` URLs in `text`, without repeats and without the
+/// punctuation that usually follows a link in a sentence.
+pub fn extract_urls(text: &str, limit: usize) -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+    let mut rest = text;
+    while urls.len() < limit {
+        let Some(start) = ["http://", "https://"]
+            .iter()
+            .filter_map(|scheme| rest.find(scheme))
+            .min()
+        else {
+            break;
+        };
+        let candidate = &rest[start..];
+        let end = candidate
+            .find(|c: char| c.is_whitespace() || "<>\"'`".contains(c))
+            .unwrap_or(candidate.len());
+        let url = candidate[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '}']);
+        rest = &candidate[end..];
+
+        if Url::parse(url).is_ok_and(|parsed| parsed.host_str().is_some())
+            && !urls.iter().any(|seen| seen == url)
+        {
+            urls.push(url.to_string());
+        }
+    }
+
+    urls
+}
+
+fn absolute_http_url(base: &Url, href: &str) -> Option<String> {
+    let url = base.join(href.trim()).ok()?;
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OEmbed {
+    title: Option<String>,
+    provider_name: Option<String>,
+    thumbnail_url: Option<String>,
+}
+
+/// The bits of a page's `<head>` a preview is made from.
+#[derive(Debug, Default)]
+struct PageHead {
+    title: Option<String>,
+    /// `property` or `name` of each `<meta>`, lowercased, with its content.
+    meta: Vec<(String, String)>,
+    /// The JSON oEmbed endpoint the page advertises.
+    oembed: Option<String>,
+}
+
+impl PageHead {
+    fn meta(&self, name: &str) -> Option<String> {
+        self.meta
+            .iter()
+            .find(|(key, value)| key == name && !value.trim().is_empty())
+            .map(|(_, value)| value.clone())
+    }
+}
+
+/// Reads `<title>`, `<meta>` and `<link>` tags up to the end of `<head>`.
+/// This is not a full HTML parser, but previews only need those tags, and
+/// what it finds is only ever shown as text.
+fn parse_head(html: &str) -> PageHead {
+    let mut head = PageHead::default();
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        if rest.starts_with("!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+            continue;
+        }
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        let tag_end = rest.find('>').unwrap_or(rest.len());
+        let attributes = parse_attributes(&rest[name_end..tag_end]);
+        rest = rest.get(tag_end + 1..).unwrap_or("");
+
+        match name.as_str() {
+            "title" if head.title.is_none() => {
+                let end = rest
+                    .to_ascii_lowercase()
+                    .find("</title")
+                    .unwrap_or(rest.len());
+                let title = decode_entities(&rest[..end]);
+                if !title.trim().is_empty() {
+                    head.title = Some(title.trim().to_string());
+                }
+            }
+            "meta" => {
+                let key = attributes
+                    .get("property")
+                    .or_else(|| attributes.get("name"));
+                if let (Some(key), Some(content)) = (key, attributes.get("content")) {
+                    head.meta.push((key.to_ascii_lowercase(), content.clone()));
+                }
+            }
+            "link" => {
+                let is_oembed = attributes
+                    .get("type")
+                    .is_some_and(|kind| kind.eq_ignore_ascii_case("application/json+oembed"));
+                if is_oembed && head.oembed.is_none() {
+                    head.oembed = attributes.get("href").cloned();
+                }
+            }
+            "/head" | "body" => break,
+            _ => {}
+        }
+    }
+
+    head
+}
+
+fn parse_attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            break;
+        }
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (raw, remaining) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let body = &after[1..];
+                    let end = body.find(quote).unwrap_or(body.len());
+                    (&body[..end], body.get(end + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            value = decode_entities(raw);
+            rest = remaining;
+        }
+        if !name.is_empty() {
+            attributes.entry(name).or_insert(value);
+        }
+    }
+
+    attributes
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
@@ -0,0 +1,464 @@
+use crate::blobs::{BlobStore, put_file_compressed};
+use crate::errors::UploadError;
+use crate::structs::blob::BlobRef;
+use crate::structs::upload::UploadSession;
+use crate::utils::{gen_message_id, gen_upload_id, sha256_hex, to_hex};
+use chrono::{DateTime, Utc};
+use ring::digest;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Header carrying the hex SHA-256 of a chunk's body.
+pub const CHUNK_CHECKSUM_HEADER: &str = "X-Chunk-Sha256";
+
+const SESSION_FILE: &str = "session.json";
+const ASSEMBLED_FILE: &str = "assembled";
+
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    pub max_chunk_size: u64,
+    /// How many chunks an upload may be split into, which keeps tiny chunk
+    /// sizes from making sessions huge to track.
+    pub max_chunks: u32,
+    pub max_upload_size: u64,
+    /// How long a session may sit without receiving a chunk before it is
+    /// thrown away.
+    pub ttl: Duration,
+    /// How many unfinished sessions one user may have open at once.
+    pub max_sessions_per_user: usize,
+    /// How many bytes one user's unfinished sessions may add up to, so no
+    /// one can fill the upload disk.
+    pub max_bytes_per_user: u64,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            max_chunk_size: 8 * 1024 * 1024,
+            max_chunks: 10_000,
+            max_upload_size: 2 * 1024 * 1024 * 1024,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            max_sessions_per_user: 8,
+            max_bytes_per_user: 4 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Chunked, resumable uploads. Each session is a directory under `root`
+/// holding the session description and one file per received chunk, so only
+/// one chunk is ever held in memory and a client can pick up where it left
+/// off after a disconnect.
+#[derive(Debug, Clone)]
+pub struct UploadManager {
+    root: PathBuf,
+    limits: UploadLimits,
+    /// Held while a session is started, so racing starts can't both slip
+    /// under a user's caps.
+    starting: Arc<Mutex<()>>,
+    /// The sessions being assembled right now, so two requests can't both
+    /// write the same assembled file.
+    completing: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl UploadManager {
+    pub fn new(root: impl Into<PathBuf>, limits: UploadLimits) -> Self {
+        Self {
+            root: root.into(),
+            limits,
+            starting: Arc::new(Mutex::new(())),
+            completing: Arc::new(std::sync::Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn limits(&self) -> &UploadLimits {
+        &self.limits
+    }
+
+    pub async fn start(
+        &self,
+        owner_id: u32,
+        conversation_id: String,
+        filename: String,
+        total_size: u64,
+        chunk_size: u64,
+    ) -> Result<UploadSession, UploadError> {
+        if total_size == 0 || total_size > self.limits.max_upload_size {
+            return Err(UploadError::InvalidSize {
+                size: total_size,
+                max: self.limits.max_upload_size,
+            });
+        }
+        if chunk_size == 0 || chunk_size > self.limits.max_chunk_size {
+            return Err(UploadError::InvalidChunkSize {
+                size: chunk_size,
+                max: self.limits.max_chunk_size,
+            });
+        }
+        let chunks = total_size.div_ceil(chunk_size);
+        if chunks > u64::from(self.limits.max_chunks) {
+            return Err(UploadError::TooManyChunks {
+                chunks,
+                max: self.limits.max_chunks,
+            });
+        }
+
+        let _starting = self.starting.lock().await;
+        let open = self.open_sessions(owner_id).await?;
+        if open.len() >= self.limits.max_sessions_per_user {
+            return Err(UploadError::TooManySessions {
+                max: self.limits.max_sessions_per_user,
+            });
+        }
+        let reserved: u64 = open.iter().map(UploadSession::total_size).sum();
+        if reserved + total_size > self.limits.max_bytes_per_user {
+            return Err(UploadError::QuotaExceeded {
+                reserved,
+                max: self.limits.max_bytes_per_user,
+            });
+        }
+
+        let session = UploadSession::new(
+            gen_upload_id(),
+            owner_id,
+            conversation_id,
+            filename,
+            total_size,
+            chunk_size,
+            Utc::now() + self.limits.ttl,
+        );
+        let directory = self.root.join(session.upload_id());
+        tokio::fs::create_dir_all(&directory).await?;
+        let json = serde_json::to_vec(&session).map_err(std::io::Error::other)?;
+        tokio::fs::write(directory.join(SESSION_FILE), json).await?;
+
+        Ok(session)
+    }
+
+    /// The session with the chunks received so far. Sessions belonging to
+    /// someone else are reported as missing.
+    pub async fn status(
+        &self,
+        upload_id: &str,
+        owner_id: u32,
+    ) -> Result<UploadSession, UploadError> {
+        let directory = self.session_dir(upload_id)?;
+        let mut session = match read_session(&directory).await? {
+            Some(session) if session.owner_id() == owner_id => session,
+            _ => return Err(UploadError::NotFound(upload_id.to_string())),
+        };
+
+        let (received_chunks, last_activity) = self.progress(&directory).await?;
+        let expires_at = DateTime::<Utc>::from(last_activity) + self.limits.ttl;
+        if expires_at <= Utc::now() {
+            tokio::fs::remove_dir_all(&directory).await?;
+            return Err(UploadError::NotFound(upload_id.to_string()));
+        }
+        session.set_progress(received_chunks, expires_at);
+
+        Ok(session)
+    }
+
+    /// Stores chunk `index` after checking its length and its SHA-256.
+    /// Sending a chunk again replaces it.
+    pub async fn put_chunk(
+        &self,
+        upload_id: &str,
+        owner_id: u32,
+        index: u32,
+        checksum: &str,
+        data: &[u8],
+    ) -> Result<UploadSession, UploadError> {
+        let session = self.status(upload_id, owner_id).await?;
+        if index >= session.total_chunks() {
+            return Err(UploadError::ChunkOutOfRange {
+                index,
+                total: session.total_chunks(),
+            });
+        }
+        let expected = session.expected_chunk_size(index);
+        if data.len() as u64 != expected {
+            return Err(UploadError::ChunkLength {
+                index,
+                expected,
+                actual: data.len() as u64,
+            });
+        }
+        if !sha256_hex(data).eq_ignore_ascii_case(checksum.trim()) {
+            return Err(UploadError::ChecksumMismatch(index));
+        }
+
+        let directory = self.session_dir(upload_id)?;
+        let temporary = directory.join(format!(".chunk-{}.{}", index, gen_message_id()));
+        tokio::fs::write(&temporary, data).await?;
+        tokio::fs::rename(&temporary, directory.join(chunk_file(index))).await?;
+
+        self.status(upload_id, owner_id).await
+    }
+
+    /// Joins the chunks of a fully received upload into one file, which
+    /// can be inspected before `finish` moves it into the blob store. The
+    /// file is hashed on the way, since the blob it ends up in may be
+    /// compressed. Only one request at a time may assemble a session.
+    pub async fn assemble(
+        &self,
+        upload_id: &str,
+        owner_id: u32,
+    ) -> Result<AssembledUpload, UploadError> {
+        let session = self.status(upload_id, owner_id).await?;
+        let completing = self.claim_completion(upload_id)?;
+        let missing = session.missing_chunks();
+        if let Some(&first) = missing.first() {
+            return Err(UploadError::Incomplete {
+                missing: missing.len(),
+                first,
+            });
+        }
+
+        let directory = self.session_dir(upload_id)?;
+        let path = directory.join(ASSEMBLED_FILE);
+        let mut assembled = tokio::fs::File::create(&path).await?;
+        let mut context = digest::Context::new(&digest::SHA256);
+        let mut size = 0;
+        for index in 0..session.total_chunks() {
+            let chunk = tokio::fs::read(directory.join(chunk_file(index))).await?;
+            context.update(&chunk);
+            size += chunk.len() as u64;
+            assembled.write_all(&chunk).await?;
+        }
+        assembled.sync_all().await?;
+
+        Ok(AssembledUpload {
+            session,
+            path,
+            sha256: to_hex(context.finish().as_ref()),
+            size,
+            _completing: completing,
+        })
+    }
+
+    fn claim_completion(&self, upload_id: &str) -> Result<Completion, UploadError> {
+        if !self
+            .completing
+            .lock()
+            .unwrap()
+            .insert(upload_id.to_string())
+        {
+            return Err(UploadError::Completing(upload_id.to_string()));
+        }
+
+        Ok(Completion {
+            upload_id: upload_id.to_string(),
+            completing: self.completing.clone(),
+        })
+    }
+
+    /// Streams an assembled upload into `blobs`, compressed when that saves
+    /// space, and ends the session.
+    pub async fn finish(
+        &self,
+        upload: AssembledUpload,
+        blobs: &dyn BlobStore,
+    ) -> Result<(UploadSession, BlobRef), UploadError> {
+        let blob = put_file_compressed(blobs, &upload.path).await?;
+        tokio::fs::remove_dir_all(self.session_dir(upload.session.upload_id())?).await?;
+
+        Ok((upload.session, blob))
+    }
+
+    /// `assemble` and `finish` in one go.
+    pub async fn complete(
+        &self,
+        upload_id: &str,
+        owner_id: u32,
+        blobs: &dyn BlobStore,
+    ) -> Result<(UploadSession, BlobRef), UploadError> {
+        let upload = self.assemble(upload_id, owner_id).await?;
+        self.finish(upload, blobs).await
+    }
+
+    pub async fn cancel(&self, upload_id: &str, owner_id: u32) -> Result<(), UploadError> {
+        self.status(upload_id, owner_id).await?;
+        tokio::fs::remove_dir_all(self.session_dir(upload_id)?).await?;
+
+        Ok(())
+    }
+
+    /// Deletes every session that has been idle for longer than the TTL and
+    /// returns how many there were.
+    pub async fn remove_expired(&self) -> Result<usize, UploadError> {
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            // A session completed or cancelled meanwhile is already gone.
+            let last_activity = match self.progress(&entry.path()).await {
+                Ok((_, last_activity)) => last_activity,
+                Err(UploadError::Io(e)) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if self.is_idle(last_activity) {
+                match tokio::fs::remove_dir_all(entry.path()).await {
+                    Ok(()) => removed += 1,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// The sessions of `owner_id` that are still accepting chunks.
+    async fn open_sessions(&self, owner_id: u32) -> Result<Vec<UploadSession>, UploadError> {
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut sessions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let session = match read_session(&entry.path()).await {
+                Ok(Some(session)) if session.owner_id() == owner_id => session,
+                Ok(_) => continue,
+                Err(UploadError::Io(e)) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            match self.progress(&entry.path()).await {
+                Ok((_, last_activity)) if !self.is_idle(last_activity) => sessions.push(session),
+                Ok(_) => {}
+                Err(UploadError::Io(e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    fn is_idle(&self, last_activity: SystemTime) -> bool {
+        let idle = SystemTime::now()
+            .duration_since(last_activity)
+            .unwrap_or_default();
+        idle >= self.limits.ttl
+    }
+
+    /// Removes expired sessions every `interval`, forever.
+    pub async fn run_sweeper(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.remove_expired().await {
+                Ok(0) => {}
+                Ok(removed) => println!("Removed {} expired uploads", removed),
+                Err(e) => eprintln!("Could not remove expired uploads: {}", e),
+            }
+        }
+    }
+
+    /// Upload ids are generated hex strings; anything else could point
+    /// outside `root` and can't be a session.
+    fn session_dir(&self, upload_id: &str) -> Result<PathBuf, UploadError> {
+        if upload_id.is_empty() || !upload_id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(UploadError::NotFound(upload_id.to_string()));
+        }
+
+        Ok(self.root.join(upload_id))
+    }
+
+    /// The chunks present in a session directory, and when anything in it
+    /// last changed.
+    async fn progress(&self, directory: &Path) -> Result<(Vec<u32>, SystemTime), UploadError> {
+        let mut received = Vec::new();
+        let mut last_activity = SystemTime::UNIX_EPOCH;
+        let mut entries = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            last_activity = last_activity.max(entry.metadata().await?.modified()?);
+            let name = entry.file_name();
+            if let Some(index) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("chunk-"))
+                .and_then(|index| index.parse().ok())
+            {
+                received.push(index);
+            }
+        }
+
+        Ok((received, last_activity))
+    }
+}
+
+/// The session described in `directory`, if there is one.
+async fn read_session(directory: &Path) -> Result<Option<UploadSession>, UploadError> {
+    let json = match tokio::fs::read(directory.join(SESSION_FILE)).await {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(
+        serde_json::from_slice(&json).map_err(std::io::Error::other)?,
+    ))
+}
+
+/// Holds a session's place in `UploadManager::completing` until dropped.
+#[derive(Debug)]
+struct Completion {
+    upload_id: String,
+    completing: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.completing.lock().unwrap().remove(&self.upload_id);
+    }
+}
+
+/// An upload whose chunks have been joined into a single file. The session
+/// can't be completed by anyone else while this is around.
+#[derive(Debug)]
+pub struct AssembledUpload {
+    session: UploadSession,
+    path: PathBuf,
+    sha256: String,
+    size: u64,
+    _completing: Completion,
+}
+
+impl AssembledUpload {
+    pub fn session(&self) -> &UploadSession {
+        &self.session
+    }
+
+    /// Hex SHA-256 of the file as uploaded.
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Opens the joined file for reading from the start.
+    pub async fn open(&self) -> Result<tokio::fs::File, UploadError> {
+        Ok(tokio::fs::File::open(&self.path).await?)
+    }
+}
+
+fn chunk_file(index: u32) -> String {
+    format!("chunk-{}", index)
+}
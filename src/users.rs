@@ -0,0 +1,52 @@
+use crate::db::get_collection;
+use crate::structs::user::User;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{Document, doc, from_document, to_document};
+
+pub async fn insert_user(user: &User) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("users").await?;
+    collection.insert_one(to_document(user)?).await?;
+
+    Ok(())
+}
+
+pub async fn get_users() -> Result<Vec<User>, mongodb::error::Error> {
+    let collection = get_collection("users").await?;
+    let documents: Vec<Document> = collection.find(doc! {}).await?.try_collect().await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+pub async fn find_user_by_id(sender_id: u32) -> Result<Option<User>, mongodb::error::Error> {
+    let collection = get_collection("users").await?;
+    let document = collection.find_one(doc! { "sender_id": sender_id }).await?;
+
+    Ok(document.map(from_document).transpose()?)
+}
+
+/// The users with any of `handles`, which must already be lowercase.
+pub async fn find_users_by_handle(handles: &[String]) -> Result<Vec<User>, mongodb::error::Error> {
+    let collection = get_collection("users").await?;
+    let documents: Vec<Document> = collection
+        .find(doc! { "handle": { "$in": handles } })
+        .await?
+        .try_collect()
+        .await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+pub async fn delete_user(sender_id: u32) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("users").await?;
+    let delete_result = collection
+        .delete_one(doc! { "sender_id": sender_id })
+        .await?;
+
+    Ok(delete_result.deleted_count > 0)
+}
@@ -1,3 +1,4 @@
+use ring::digest;
 use ring::rand::{SecureRandom, SystemRandom};
 
 pub fn gen_message_id() -> u32 {
@@ -17,3 +18,49 @@ pub fn gen_sender_id() -> u32 {
         .expect("Failed to generate random bytes");
     u32::from_be_bytes(bytes)
 }
+
+/// 128 random bits as hex, unguessable enough to name an upload session.
+pub fn gen_upload_id() -> String {
+    let range = SystemRandom::new();
+    let mut bytes = [0u8; 16];
+    range
+        .fill(&mut bytes)
+        .expect("Failed to generate random bytes");
+    to_hex(&bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+/// Timestamps kept as RFC 3339 strings with all nine fractional digits, so
+/// they all have the same width and compare as strings in time order, which
+/// is how MongoDB sorts and filters them. Chrono's own serialization drops
+/// trailing zeros, which puts "12:00:05.5Z" before "12:00:05Z". Reading
+/// accepts any RFC 3339 timestamp. Used as
+/// `#[serde(with = "crate::utils::sortable_timestamp")]`.
+pub mod sortable_timestamp {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn format(timestamp: &DateTime<Utc>) -> String {
+        timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    pub fn serialize<S: Serializer>(
+        timestamp: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(timestamp))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        DateTime::deserialize(deserializer)
+    }
+}
@@ -1,6 +1,13 @@
-use crate::structs::envvars::EnvVars;
+use crate::enums::InfectedAction;
+use crate::files::FileOptions;
+use crate::structs::envvars::{EnvVars, ServerEnvVars};
+use crate::validation::{ContentRules, ValidationOptions};
 use dotenv::dotenv;
 use std::env;
+use std::time::Duration;
+
+/// Shorter secrets make client tokens guessable.
+const MIN_AUTH_SECRET_LENGTH: usize = 32;
 
 pub fn validate_and_get_env_vars() -> EnvVars {
     dotenv().ok();
@@ -57,10 +64,197 @@ pub fn validate_and_get_env_vars() -> EnvVars {
         );
     }
 
-    return EnvVars {
+    EnvVars {
         send_text_messages,
         send_file_messages,
         send_image_messages,
         send_crypto_transfer_messages,
+    }
+}
+
+pub fn validate_and_get_server_env_vars() -> ServerEnvVars {
+    dotenv().ok();
+
+    // ======= WEBSOCKET_ADDR =======
+    let websocket_addr = env::var("WEBSOCKET_ADDR").unwrap_or_else(|_| {
+        println!("WEBSOCKET_ADDR is not set, defaulting to 127.0.0.1:9001");
+        "127.0.0.1:9001".to_string()
+    });
+
+    // ======= HTTP_ADDR =======
+    let http_addr = env::var("HTTP_ADDR").unwrap_or_else(|_| {
+        println!("HTTP_ADDR is not set, defaulting to 127.0.0.1:8080");
+        "127.0.0.1:8080".to_string()
+    });
+
+    // ======= AUTH_SECRET =======
+    let auth_secret = env::var("AUTH_SECRET").unwrap_or_default();
+    if auth_secret.len() < MIN_AUTH_SECRET_LENGTH {
+        panic!(
+            "The AUTH_SECRET environment variable must be set to a random value of at least {} characters. It is used to sign client tokens; see .env.example.",
+            MIN_AUTH_SECRET_LENGTH
+        );
+    }
+
+    // ======= BLOB_DIR =======
+    let blob_dir = env::var("BLOB_DIR").ok().filter(|dir| !dir.is_empty());
+
+    // ======= UPLOAD_DIR =======
+    let upload_dir = env::var("UPLOAD_DIR").ok().filter(|dir| !dir.is_empty());
+
+    // ======= CLAMD_ADDRESS =======
+    let clamd_address = env::var("CLAMD_ADDRESS")
+        .ok()
+        .filter(|address| !address.is_empty());
+
+    // ======= CLAMD_STREAM_MAX_LENGTH =======
+    let clamd_stream_max = env::var("CLAMD_STREAM_MAX_LENGTH")
+        .ok()
+        .filter(|max| !max.is_empty())
+        .map(|max| {
+            max.parse().unwrap_or_else(|_| {
+                panic!(
+                    "CLAMD_STREAM_MAX_LENGTH must be a number of bytes, not {:?}",
+                    max
+                )
+            })
+        });
+
+    // ======= ON_INFECTED_ATTACHMENT =======
+    let on_infected = match env::var("ON_INFECTED_ATTACHMENT").as_deref() {
+        Err(_) | Ok("") | Ok("reject") => InfectedAction::Reject,
+        Ok("quarantine") => InfectedAction::Quarantine,
+        Ok(other) => panic!(
+            "ON_INFECTED_ATTACHMENT must be reject or quarantine, not {:?}",
+            other
+        ),
     };
+
+    // ======= MESSAGE_EDIT_WINDOW_SECS =======
+    let edit_window = env::var("MESSAGE_EDIT_WINDOW_SECS")
+        .ok()
+        .filter(|secs| !secs.is_empty())
+        .map(|secs| {
+            Duration::from_secs(secs.parse().unwrap_or_else(|_| {
+                panic!(
+                    "MESSAGE_EDIT_WINDOW_SECS must be a number of seconds, not {:?}",
+                    secs
+                )
+            }))
+        });
+
+    // ======= LINK_PREVIEWS =======
+    let link_previews = match env::var("LINK_PREVIEWS").as_deref() {
+        Err(_) | Ok("") | Ok("false") => false,
+        Ok("true") => true,
+        Ok(other) => panic!("LINK_PREVIEWS must be true or false, not {:?}", other),
+    };
+
+    // ======= SOLANA_RPC_URL =======
+    let solana_rpc_url = env::var("SOLANA_RPC_URL")
+        .ok()
+        .filter(|url| !url.is_empty());
+
+    ServerEnvVars {
+        websocket_addr,
+        http_addr,
+        auth_secret,
+        blob_dir,
+        upload_dir,
+        clamd_address,
+        clamd_stream_max,
+        on_infected,
+        edit_window,
+        link_previews,
+        solana_rpc_url,
+    }
+}
+
+/// File limits for this deployment. Each variable falls back to the matching
+/// `FileOptions` default when unset.
+pub fn validate_and_get_file_options() -> FileOptions {
+    dotenv().ok();
+    let mut options = FileOptions::default();
+
+    // ======= MAX_FILE_SIZE =======
+    if let Ok(max_size) = env::var("MAX_FILE_SIZE") {
+        options.max_size = max_size.parse().unwrap_or_else(|_| {
+            panic!(
+                "MAX_FILE_SIZE must be a number of bytes, not {:?}",
+                max_size
+            )
+        });
+    }
+
+    // ======= ALLOWED_FILE_TYPES =======
+    if let Ok(allowed_types) = env::var("ALLOWED_FILE_TYPES") {
+        options.allowed_types = mime_type_list(&allowed_types);
+    }
+
+    // ======= DENIED_FILE_TYPES =======
+    if let Ok(denied_types) = env::var("DENIED_FILE_TYPES") {
+        options.denied_types = mime_type_list(&denied_types);
+    }
+
+    options
+}
+
+/// Length limits for message text and file names, in characters and in
+/// bytes. Each variable falls back to the matching `ValidationOptions`
+/// default when unset, except that a byte limit left unset grows or shrinks
+/// with its character limit.
+pub fn validate_and_get_validation_options() -> ValidationOptions {
+    dotenv().ok();
+    let mut options = ValidationOptions::default();
+
+    // ======= MAX_TEXT_LENGTH, MAX_TEXT_BYTES =======
+    apply_limits(&mut options.text, "MAX_TEXT_LENGTH", "MAX_TEXT_BYTES");
+
+    // ======= MAX_MARKDOWN_LENGTH, MAX_MARKDOWN_BYTES =======
+    apply_limits(
+        &mut options.markdown,
+        "MAX_MARKDOWN_LENGTH",
+        "MAX_MARKDOWN_BYTES",
+    );
+
+    // ======= MAX_FILENAME_LENGTH, MAX_FILENAME_BYTES =======
+    apply_limits(
+        &mut options.file_name,
+        "MAX_FILENAME_LENGTH",
+        "MAX_FILENAME_BYTES",
+    );
+
+    options
+}
+
+/// Sets the character limit named `characters` and the byte limit named
+/// `bytes`. Without a byte limit of its own, text keeps the bytes per
+/// character the defaults allow.
+fn apply_limits(rules: &mut ContentRules, characters: &str, bytes: &str) {
+    if let Some(max) = limit(characters, "characters") {
+        let bytes_per_character = (rules.max_bytes / rules.max_graphemes.max(1)).max(1);
+        rules.max_bytes = max.saturating_mul(bytes_per_character);
+        rules.max_graphemes = max;
+    }
+    if let Some(max) = limit(bytes, "bytes") {
+        rules.max_bytes = max;
+    }
+}
+
+fn limit(name: &str, unit: &str) -> Option<usize> {
+    let value = env::var(name).ok().filter(|value| !value.is_empty())?;
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of {}, not {:?}", name, unit, value)),
+    )
+}
+
+/// A comma separated list such as `image/*, application/pdf`.
+fn mime_type_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|mime_type| !mime_type.is_empty())
+        .map(str::to_string)
+        .collect()
 }
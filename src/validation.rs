@@ -0,0 +1,128 @@
+use crate::enums::TextFormat;
+use crate::errors::ValidationError;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The limits one kind of user-written text is held to. Lengths are counted
+/// in grapheme clusters, what a reader sees as one character, so an emoji or
+/// a CJK character counts once however many bytes it takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentRules {
+    pub max_graphemes: usize,
+    /// Bounds the size of text whose graphemes are unusually large, such as
+    /// one character buried under hundreds of combining marks.
+    pub max_bytes: usize,
+    /// Whether `\n` and `\r` are allowed. Tabs always are.
+    pub allow_line_breaks: bool,
+}
+
+impl ContentRules {
+    pub fn new(max_graphemes: usize, max_bytes: usize, allow_line_breaks: bool) -> Self {
+        Self {
+            max_graphemes,
+            max_bytes,
+            allow_line_breaks,
+        }
+    }
+
+    /// Runs every check in turn and returns the first that fails: blank
+    /// text, control characters, unbalanced bidirectional formatting, then
+    /// size and length.
+    pub fn check(&self, text: &str) -> Result<(), ValidationError> {
+        if is_blank(text) {
+            return Err(ValidationError::Blank);
+        }
+        if let Some(control) = text.chars().find(|c| !self.allows_control(*c)) {
+            return Err(ValidationError::ControlCharacter(control));
+        }
+        if !has_balanced_bidi(text) {
+            return Err(ValidationError::UnbalancedBidi);
+        }
+        if text.len() > self.max_bytes {
+            return Err(ValidationError::TooLarge {
+                size: text.len(),
+                max: self.max_bytes,
+            });
+        }
+        let length = text.graphemes(true).count();
+        if length > self.max_graphemes {
+            return Err(ValidationError::TooLong {
+                length,
+                max: self.max_graphemes,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn allows_control(&self, c: char) -> bool {
+        match c {
+            '\t' => true,
+            '\n' | '\r' => self.allow_line_breaks,
+            _ => !c.is_control(),
+        }
+    }
+}
+
+/// The rules for each kind of text a message carries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationOptions {
+    pub text: ContentRules,
+    /// Markdown gets more room, since its syntax takes up some.
+    pub markdown: ContentRules,
+    pub file_name: ContentRules,
+}
+
+impl ValidationOptions {
+    /// The rules for the text of a message written in `format`.
+    pub fn text_rules(&self, format: TextFormat) -> &ContentRules {
+        match format {
+            TextFormat::Plain => &self.text,
+            TextFormat::Markdown => &self.markdown,
+        }
+    }
+}
+
+impl Default for ValidationOptions {
+    fn default() -> Self {
+        Self {
+            text: ContentRules::new(512, 16 * 1024, true),
+            markdown: ContentRules::new(1024, 32 * 1024, true),
+            file_name: ContentRules::new(255, 255, false),
+        }
+    }
+}
+
+/// Whether `text` shows nothing at all: it is empty or made only of
+/// whitespace and the invisible characters often used to get around a
+/// whitespace check.
+pub fn is_blank(text: &str) -> bool {
+    text.chars().all(|c| {
+        c.is_whitespace()
+            || matches!(
+                c,
+                '\u{180E}' | '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}'
+            )
+    })
+}
+
+/// Whether every bidirectional embedding, override and isolate in `text` is
+/// closed, in order, before the end of its line. One left open can make the
+/// rest of the line read in a different order than it is stored, which is
+/// how text is made to look like something it isn't.
+pub fn has_balanced_bidi(text: &str) -> bool {
+    const PDF: char = '\u{202C}';
+    const PDI: char = '\u{2069}';
+
+    text.split(['\n', '\r', '\u{2029}']).all(|line| {
+        let mut open = Vec::new();
+        for c in line.chars() {
+            match c {
+                '\u{202A}' | '\u{202B}' | '\u{202D}' | '\u{202E}' => open.push(PDF),
+                '\u{2066}' | '\u{2067}' | '\u{2068}' => open.push(PDI),
+                PDF | PDI if open.pop() != Some(c) => return false,
+                _ => {}
+            }
+        }
+        open.is_empty()
+    })
+}
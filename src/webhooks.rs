@@ -0,0 +1,246 @@
+use crate::db::get_collection;
+use crate::enums::{ServerFrame, WebhookEvent};
+use crate::store::MessageStore;
+use crate::structs::webhook::{WebhookDeadLetter, WebhookSubscription};
+use crate::unfurl::resolve_public;
+use crate::utils::to_hex;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{Document, doc, from_document, to_document};
+use reqwest::Url;
+use reqwest::redirect::Policy;
+use ring::hmac;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+
+pub async fn insert_webhook(
+    subscription: &WebhookSubscription,
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("webhook_subscriptions").await?;
+    collection.insert_one(to_document(subscription)?).await?;
+
+    Ok(())
+}
+
+pub async fn get_webhooks() -> Result<Vec<WebhookSubscription>, mongodb::error::Error> {
+    let collection = get_collection("webhook_subscriptions").await?;
+    let documents: Vec<Document> = collection.find(doc! {}).await?.try_collect().await?;
+
+    documents
+        .into_iter()
+        .map(|document| from_document(document).map_err(Into::into))
+        .collect()
+}
+
+pub async fn delete_webhook(
+    subscription_id: u32,
+    owner_id: u32,
+) -> Result<bool, mongodb::error::Error> {
+    let collection = get_collection("webhook_subscriptions").await?;
+    let delete_result = collection
+        .delete_one(doc! { "subscription_id": subscription_id, "owner_id": owner_id })
+        .await?;
+
+    Ok(delete_result.deleted_count > 0)
+}
+
+pub async fn insert_dead_letter(
+    dead_letter: &WebhookDeadLetter,
+) -> Result<(), mongodb::error::Error> {
+    let collection = get_collection("webhook_dead_letters").await?;
+    collection.insert_one(to_document(dead_letter)?).await?;
+
+    Ok(())
+}
+
+/// `sha256=<hex>` HMAC of the request body, keyed with the subscription secret.
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", to_hex(hmac::sign(&key, body).as_ref()))
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub request_timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (1-based), doubling every time.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Listens to the hub and POSTs matching events to the subscribers who are
+/// members of the conversation they happened in. Like link previews, each
+/// delivery goes to an address that was checked to be public, without
+/// following redirects or going through a proxy.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    store: Arc<dyn MessageStore>,
+    retry: RetryPolicy,
+    allow_loopback: bool,
+}
+
+impl WebhookDispatcher {
+    pub fn new(store: Arc<dyn MessageStore>, retry: RetryPolicy) -> Self {
+        Self {
+            store,
+            retry,
+            allow_loopback: false,
+        }
+    }
+
+    /// Lets deliveries to this machine through, for tests and local
+    /// development.
+    pub fn allow_loopback(mut self) -> Self {
+        self.allow_loopback = true;
+        self
+    }
+
+    pub async fn run(self, mut frames: broadcast::Receiver<ServerFrame>) {
+        loop {
+            match frames.recv().await {
+                Ok(frame) => self.dispatch(&frame).await,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Webhook dispatcher lagged, skipped {} frames", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    async fn dispatch(&self, frame: &ServerFrame) {
+        let events = WebhookEvent::from_frame(frame);
+        if events.is_empty() {
+            return;
+        }
+
+        let subscriptions = match self.store.webhooks().await {
+            Ok(subscriptions) => subscriptions,
+            Err(e) => {
+                eprintln!("Could not load webhook subscriptions: {}", e);
+                return;
+            }
+        };
+
+        for event in events {
+            let Some(audience) = self.audience(&event).await else {
+                continue;
+            };
+            for subscription in &subscriptions {
+                if subscription.wants(&event.event_type())
+                    && audience.contains(&subscription.owner_id())
+                {
+                    let dispatcher = self.clone();
+                    let subscription = subscription.clone();
+                    let event = event.clone();
+                    tokio::spawn(async move { dispatcher.deliver(subscription, event).await });
+                }
+            }
+        }
+    }
+
+    /// Whose subscriptions may be told about `event`: its recipient's when it
+    /// is meant for one user, otherwise those of the conversation's members.
+    async fn audience(&self, event: &WebhookEvent) -> Option<Vec<u32>> {
+        if let Some(recipient) = event.recipient() {
+            return Some(vec![recipient]);
+        }
+        let conversation_id = event.conversation_id()?;
+        match self.store.members(conversation_id).await {
+            Ok(members) => Some(members),
+            Err(e) => {
+                eprintln!("Could not load the members of {}: {}", conversation_id, e);
+                None
+            }
+        }
+    }
+
+    /// Delivers one event, retrying with exponential backoff and dead-lettering
+    /// it once every attempt has failed.
+    pub async fn deliver(&self, subscription: WebhookSubscription, event: WebhookEvent) {
+        let body = serde_json::to_vec(&event).expect("WebhookEvent always serializes to JSON");
+        let signature = sign_payload(subscription.secret(), &body);
+        let event_name = serde_json::to_value(event.event_type())
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        let mut last_error = String::new();
+        for attempt in 1..=self.retry.max_attempts {
+            match self
+                .post(subscription.url(), &body, &signature, &event_name)
+                .await
+            {
+                Ok(response) if response.status().is_success() => return,
+                Ok(response) => last_error = format!("Subscriber responded {}", response.status()),
+                Err(e) => last_error = e,
+            }
+
+            if attempt < self.retry.max_attempts {
+                tokio::time::sleep(self.retry.backoff(attempt)).await;
+            }
+        }
+
+        let dead_letter =
+            WebhookDeadLetter::new(&subscription, event, self.retry.max_attempts, last_error);
+        if let Err(e) = self.store.insert_dead_letter(&dead_letter).await {
+            eprintln!("Could not dead-letter webhook delivery: {}", e);
+        }
+    }
+
+    /// One attempt at a delivery. The URL is resolved and checked every time,
+    /// so a subscriber can't later point its name at an internal address.
+    async fn post(
+        &self,
+        url: &str,
+        body: &[u8],
+        signature: &str,
+        event_name: &str,
+    ) -> Result<reqwest::Response, String> {
+        let url = Url::parse(url).map_err(|e| e.to_string())?;
+        let address = resolve_public(&url, self.allow_loopback)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut client = reqwest::Client::builder()
+            .timeout(self.retry.request_timeout)
+            .redirect(Policy::none())
+            .no_proxy();
+        if let Some(domain) = url.domain() {
+            client = client.resolve(domain, address);
+        }
+
+        client
+            .build()
+            .map_err(|e| e.to_string())?
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .header(EVENT_HEADER, event_name)
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,188 @@
+use messaging::blobs::{BlobStore, FsBlobStore, load_blob, open_blob, put_file_compressed};
+use messaging::enums::{BlobCodec, Message, MessageFromType};
+use messaging::errors::BlobError;
+use messaging::structs::messages::file::FileMessagePackage;
+use messaging::utils::sha256_hex;
+use mongodb::bson::{from_document, to_document};
+use tokio::io::AsyncReadExt;
+
+#[tokio::test]
+async fn stores_blobs_under_their_digest() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+
+    let blob = blobs.put(b"some attachment").await.unwrap();
+    let again = blobs.put(b"some attachment").await.unwrap();
+
+    assert_eq!(blob, again);
+    assert_eq!(blob.sha256(), sha256_hex(b"some attachment"));
+    assert_eq!(blob.size(), 15);
+    assert_eq!(
+        blobs.get(blob.sha256()).await.unwrap().unwrap(),
+        b"some attachment"
+    );
+}
+
+#[tokio::test]
+async fn keeps_blobs_until_every_reference_is_released() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+    let blob = blobs.put(b"shared attachment").await.unwrap();
+    blobs.put(b"shared attachment").await.unwrap();
+
+    assert!(!blobs.release(blob.sha256()).await.unwrap());
+    assert!(blobs.get(blob.sha256()).await.unwrap().is_some());
+    assert!(blobs.release(blob.sha256()).await.unwrap());
+    assert!(blobs.get(blob.sha256()).await.unwrap().is_none());
+    assert!(!blobs.release(blob.sha256()).await.unwrap());
+
+    // Stored again from scratch once it is gone.
+    blobs.put(b"shared attachment").await.unwrap();
+    assert!(blobs.release(blob.sha256()).await.unwrap());
+}
+
+#[tokio::test]
+async fn keeps_blobs_stored_before_references_were_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+    let blob = blobs.put(b"old attachment").await.unwrap();
+    let path = dir.path().join(&blob.sha256()[..2]).join(blob.sha256());
+    std::fs::remove_file(path.with_extension("refs")).unwrap();
+
+    blobs.put(b"old attachment").await.unwrap();
+    assert!(!blobs.release(blob.sha256()).await.unwrap());
+    assert!(!blobs.release(blob.sha256()).await.unwrap());
+    assert!(blobs.get(blob.sha256()).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn refuses_paths_that_are_not_digests() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+
+    let error = blobs.get("../../etc/passwd").await.unwrap_err();
+
+    assert!(matches!(error, BlobError::InvalidDigest(_)));
+}
+
+#[tokio::test]
+async fn detects_corrupted_blobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+    let blob = blobs.put(b"original").await.unwrap();
+
+    let path = dir.path().join(&blob.sha256()[..2]).join(blob.sha256());
+    std::fs::write(path, b"tampered").unwrap();
+
+    let error = blobs.get(blob.sha256()).await.unwrap_err();
+    assert!(matches!(error, BlobError::Corrupt(_)));
+}
+
+#[tokio::test]
+async fn messages_keep_only_references_once_stored() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+    let file = FileMessagePackage::new(
+        1,
+        MessageFromType::User,
+        "server.log",
+        b"log line\n".repeat(10),
+    )
+    .unwrap();
+    let mut message = Message::File(file);
+
+    message.store_attachments(&blobs).await.unwrap();
+
+    let document = to_document(&message).unwrap();
+    assert!(!document.contains_key("file_data"));
+    let blob = document.get_document("file_blob").unwrap();
+    assert_eq!(blob.get_str("codec"), Ok("zstd"));
+    assert!(blob.get_i64("size").unwrap() < 90);
+
+    match message {
+        Message::File(file) => assert_eq!(
+            file.file_data(&blobs).await.unwrap(),
+            b"log line\n".repeat(10)
+        ),
+        message => panic!("expected a file message, got {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn stored_files_read_back_as_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+    let file = FileMessagePackage::new(1, MessageFromType::User, "notes.txt", b"notes\n".to_vec())
+        .unwrap();
+    let mut message = Message::File(file);
+    message.store_attachments(&blobs).await.unwrap();
+
+    // Without its bytes a file has every field an image needs.
+    let document = to_document(&message).unwrap();
+    assert!(!document.contains_key("file_data"));
+    let stored: Message = from_document(document).unwrap();
+    assert!(matches!(stored, Message::File(_)), "got {:?}", stored);
+    let sent: Message = serde_json::from_value(serde_json::to_value(&message).unwrap()).unwrap();
+    assert!(matches!(sent, Message::File(_)), "got {:?}", sent);
+}
+
+/// Bytes zstd can't shrink.
+fn noise(size: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut block = b"seed".to_vec();
+    while data.len() < size {
+        block = sha256_hex(&block).into_bytes();
+        data.extend(
+            block
+                .chunks(2)
+                .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()),
+        );
+    }
+    data.truncate(size);
+    data
+}
+
+#[tokio::test]
+async fn incompressible_files_are_stored_as_is() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path());
+    let data = noise(4096);
+    let file =
+        FileMessagePackage::new(1, MessageFromType::User, "archive.bin", data.clone()).unwrap();
+    let mut message = Message::File(file);
+
+    message.store_attachments(&blobs).await.unwrap();
+
+    let Message::File(file) = &message else {
+        panic!("expected a file message");
+    };
+    let blob = file.file_blob().unwrap();
+    assert_eq!(blob.codec(), BlobCodec::Identity);
+    assert_eq!(blob.sha256(), sha256_hex(&data));
+    assert!(!to_document(blob).unwrap().contains_key("codec"));
+    assert_eq!(load_blob(&blobs, blob).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn large_files_are_compressed_and_streamed_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(dir.path().join("blobs"));
+    let data = b"2024-01-01 INFO request served in 3ms\n".repeat(50_000);
+    let path = dir.path().join("upload");
+    std::fs::write(&path, &data).unwrap();
+
+    let blob = put_file_compressed(&blobs, &path).await.unwrap();
+
+    assert_eq!(blob.codec(), BlobCodec::Zstd);
+    assert!(blob.size() < data.len() as u64 / 10);
+    let mut streamed = Vec::new();
+    open_blob(&blobs, &blob)
+        .await
+        .unwrap()
+        .read_to_end(&mut streamed)
+        .await
+        .unwrap();
+    assert_eq!(streamed, data);
+    // Only the original is left behind.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+}
@@ -0,0 +1,134 @@
+//! Fixtures shared by the integration tests that drive the HTTP API.
+// Each test binary uses its own subset of these.
+#![allow(dead_code)]
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header, request};
+use http_body_util::BodyExt;
+use messaging::auth::{auth_key, issue_token};
+use messaging::blobs::FsBlobStore;
+use messaging::enums::{Message, MessageFromType};
+use messaging::server::{AppState, http};
+use messaging::store::{MemoryMessageStore, MessageStore};
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::user::User;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+pub const SECRET: &[u8] = b"integration-test-secret";
+
+/// The API over an in-memory store, with blobs and uploads kept in a
+/// temporary directory that lives as long as the app.
+pub struct TestApp {
+    pub router: Router,
+    pub state: AppState,
+    pub store: Arc<MemoryMessageStore>,
+    pub blobs: Arc<FsBlobStore>,
+    pub directory: TempDir,
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        Self::with(|state, _| state)
+    }
+
+    /// `configure` gets the default state and the app's directory, and
+    /// returns the state the router is built from.
+    pub fn with(configure: impl FnOnce(AppState, &Path) -> AppState) -> Self {
+        let directory = tempfile::tempdir().unwrap();
+        let blobs = Arc::new(FsBlobStore::new(directory.path().join("blobs")));
+        let store = Arc::new(MemoryMessageStore::new());
+        let state = configure(
+            AppState::new(store.clone(), blobs.clone(), SECRET),
+            directory.path(),
+        );
+        Self {
+            router: http::router(state.clone()),
+            state,
+            store,
+            blobs,
+            directory,
+        }
+    }
+
+    /// Registers `users`, whose roles the API then goes by.
+    pub async fn register(&self, users: &[&User]) {
+        for user in users {
+            self.store.insert_user(user).await.unwrap();
+        }
+    }
+
+    /// Makes `users` members of the conversation.
+    pub async fn join(&self, conversation_id: &str, users: &[&User]) {
+        for user in users {
+            self.store
+                .add_member(conversation_id, *user.sender_id())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+pub fn bearer(user: &User) -> String {
+    format!("Bearer {}", issue_token(&auth_key(SECRET), user))
+}
+
+/// A request to `uri` made as `user`.
+pub fn request(method: &str, uri: &str, user: &User) -> request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, bearer(user))
+}
+
+pub fn get(uri: &str, user: &User) -> Request<Body> {
+    request("GET", uri, user).body(Body::empty()).unwrap()
+}
+
+pub fn json_request(method: &str, uri: &str, user: &User, body: &impl Serialize) -> Request<Body> {
+    request(method, uri, user)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap()
+}
+
+pub async fn call(app: &Router, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, body.to_vec())
+}
+
+pub fn json<T: DeserializeOwned>(body: &[u8]) -> T {
+    serde_json::from_slice(body).unwrap()
+}
+
+/// A text message as a client would send it. The server fills in the
+/// sender from the token.
+pub fn text_message(text: &str) -> Message<'static> {
+    Message::Text(
+        TextMessagePackage::new(User::new(MessageFromType::User), text.to_string()).unwrap(),
+    )
+}
+
+pub async fn create_text(
+    app: &Router,
+    user: &User,
+    conversation_id: &str,
+    text: &str,
+) -> Message<'static> {
+    let uri = format!("/conversations/{}/messages", conversation_id);
+    let (status, body) = call(app, json_request("POST", &uri, user, &text_message(text))).await;
+    assert_eq!(
+        status,
+        StatusCode::CREATED,
+        "{}",
+        String::from_utf8_lossy(&body)
+    );
+    json(&body)
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::StatusCode;
+use common::{TestApp, call, create_text, get, json, json_request, request, text_message};
+use messaging::blobs::BlobStore;
+use messaging::enums::{Message, MessageFromType, Role};
+use messaging::store::MessageStore;
+use messaging::structs::history::HistoryPage;
+use messaging::structs::messages::file::FileMessagePackage;
+use messaging::structs::revision::MessageRevision;
+use messaging::structs::user::User;
+
+async fn upload_file(app: &TestApp, user: &User, data: &[u8]) -> Message<'static> {
+    let request = request(
+        "POST",
+        "/conversations/support/attachments?filename=notes.txt",
+        user,
+    )
+    .body(Body::from(data.to_vec()))
+    .unwrap();
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+    json(&body)
+}
+
+async fn delete(app: &TestApp, user: &User, message_id: u32, scope: &str) -> StatusCode {
+    let uri = format!("/messages/{}?scope={}", message_id, scope);
+    let request = request("DELETE", &uri, user).body(Body::empty()).unwrap();
+    call(&app.router, request).await.0
+}
+
+async fn history(app: &TestApp, user: &User) -> Vec<Message<'static>> {
+    let (status, body) = call(&app.router, get("/conversations/support/messages", user)).await;
+    assert_eq!(status, StatusCode::OK);
+    json::<HistoryPage>(&body).messages
+}
+
+#[tokio::test]
+async fn deleting_for_me_hides_the_message_from_me_only() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    app.join("support", &[&agent, &user]).await;
+    let message_id = create_text(&app.router, &agent, "support", "how can I help?")
+        .await
+        .message_id();
+
+    // Anyone may tidy up their own view.
+    assert_eq!(
+        delete(&app, &user, message_id, "me").await,
+        StatusCode::NO_CONTENT
+    );
+
+    assert!(history(&app, &user).await.is_empty());
+    let theirs = history(&app, &agent).await;
+    assert_eq!(theirs.len(), 1);
+    assert!(!theirs[0].is_deleted());
+}
+
+#[tokio::test]
+async fn deleting_for_everyone_leaves_a_tombstone() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let other = User::new(MessageFromType::User);
+    app.join("support", &[&user, &other]).await;
+    let message_id = create_text(&app.router, &user, "support", "my password is hunter2")
+        .await
+        .message_id();
+    let uri = format!("/messages/{}", message_id);
+    let request = json_request("PUT", &uri, &user, &text_message("oops"));
+    assert_eq!(call(&app.router, request).await.0, StatusCode::OK);
+
+    assert_eq!(
+        delete(&app, &other, message_id, "everyone").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        delete(&app, &user, message_id, "everyone").await,
+        StatusCode::NO_CONTENT
+    );
+
+    let messages = history(&app, &other).await;
+    assert_eq!(messages.len(), 1);
+    let Message::Deleted(tombstone) = &messages[0] else {
+        panic!("expected a tombstone, got {:?}", messages[0]);
+    };
+    assert_eq!(tombstone.message_id(), message_id);
+    assert_eq!(tombstone.sender_id(), *user.sender_id());
+    assert_eq!(tombstone.deleted_by(), *user.sender_id());
+    assert_eq!(tombstone.conversation_id(), Some("support"));
+    let stored = serde_json::to_string(&messages[0]).unwrap();
+    assert!(!stored.contains("hunter2") && !stored.contains("oops"));
+
+    let uri = format!("/messages/{}/revisions", message_id);
+    let (status, body) = call(&app.router, get(&uri, &user)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(json::<Vec<MessageRevision>>(&body).is_empty());
+}
+
+#[tokio::test]
+async fn moderators_may_delete_for_everyone() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let mut moderator = User::new(MessageFromType::Agent);
+    moderator.set_role(Role::Moderator);
+    app.register(&[&moderator]).await;
+    let message_id = create_text(&app.router, &user, "support", "spam")
+        .await
+        .message_id();
+
+    assert_eq!(
+        delete(&app, &moderator, message_id, "everyone").await,
+        StatusCode::NO_CONTENT
+    );
+
+    let Some(Message::Deleted(tombstone)) = app.store.find_message(message_id).await.unwrap()
+    else {
+        panic!("expected a tombstone");
+    };
+    assert_eq!(tombstone.deleted_by(), *moderator.sender_id());
+}
+
+#[tokio::test]
+async fn attachments_are_released_once_nothing_references_them() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let first = upload_file(&app, &user, b"shared notes\n").await;
+    let second = upload_file(&app, &user, b"shared notes\n").await;
+    let sha256 = first.blobs()[0].sha256().to_string();
+    assert_eq!(second.blobs()[0].sha256(), sha256);
+
+    // The second message still needs the blob.
+    assert_eq!(
+        delete(&app, &user, first.message_id(), "everyone").await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(app.blobs.get(&sha256).await.unwrap().is_some());
+
+    assert_eq!(
+        delete(&app, &user, second.message_id(), "everyone").await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(app.blobs.get(&sha256).await.unwrap().is_none());
+
+    let uri = format!("/messages/{}/attachment", second.message_id());
+    assert_eq!(
+        call(&app.router, get(&uri, &user)).await.0,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn attachments_can_not_be_replaced() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let message = upload_file(&app, &user, b"first draft\n").await;
+    let sha256 = message.blobs()[0].sha256().to_string();
+
+    let update = Message::File(
+        FileMessagePackage::new(
+            *user.sender_id(),
+            MessageFromType::User,
+            "notes.txt",
+            b"final notes\n".to_vec(),
+        )
+        .unwrap(),
+    );
+    let uri = format!("/messages/{}", message.message_id());
+    let request = json_request("PUT", &uri, &user, &update);
+    assert_eq!(call(&app.router, request).await.0, StatusCode::BAD_REQUEST);
+    assert!(app.blobs.get(&sha256).await.unwrap().is_some());
+    let stored = app.store.messages();
+    assert_eq!(stored[0].blobs()[0].sha256(), sha256);
+}
+
+#[tokio::test]
+async fn only_admins_may_purge() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let mut moderator = User::new(MessageFromType::Agent);
+    moderator.set_role(Role::Moderator);
+    let mut admin = User::new(MessageFromType::Agent);
+    admin.set_role(Role::Admin);
+    app.register(&[&moderator, &admin]).await;
+    let message_id = create_text(&app.router, &user, "support", "illegal content")
+        .await
+        .message_id();
+
+    assert_eq!(
+        delete(&app, &user, message_id, "purge").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        delete(&app, &moderator, message_id, "purge").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        delete(&app, &admin, message_id, "purge").await,
+        StatusCode::NO_CONTENT
+    );
+
+    assert!(app.store.messages().is_empty());
+    assert_eq!(
+        delete(&app, &admin, message_id, "purge").await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn roles_come_from_the_users_store_not_the_token() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let mut moderator = User::new(MessageFromType::Agent);
+    moderator.set_role(Role::Moderator);
+    app.join("support", &[&user, &moderator]).await;
+    let message_id = create_text(&app.router, &user, "support", "spam")
+        .await
+        .message_id();
+
+    // The token claims a role nobody gave them.
+    assert_eq!(
+        delete(&app, &moderator, message_id, "everyone").await,
+        StatusCode::FORBIDDEN
+    );
+
+    // Demoted after the token was issued.
+    let mut demoted = moderator.clone();
+    demoted.set_role(Role::default());
+    app.register(&[&demoted]).await;
+    assert_eq!(
+        delete(&app, &moderator, message_id, "everyone").await,
+        StatusCode::FORBIDDEN
+    );
+}
@@ -0,0 +1,282 @@
+mod common;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{TestApp, call, json_request};
+use messaging::enums::{Message, MessageFromType, ServerFrame};
+use messaging::structs::draft::{Draft, MAX_DRAFT_ATTACHMENTS};
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::upload::UploadSession;
+use messaging::structs::user::User;
+use messaging::uploads::{UploadLimits, UploadManager};
+use serde_json::json;
+use tokio::sync::broadcast;
+
+fn app() -> (TestApp, broadcast::Receiver<ServerFrame>) {
+    let app = TestApp::with(|state, directory| {
+        state.with_uploads(UploadManager::new(
+            directory.join("uploads"),
+            UploadLimits::default(),
+        ))
+    });
+    let frames = app.state.hub.subscribe();
+    (app, frames)
+}
+
+async fn send(
+    app: &TestApp,
+    method: &str,
+    uri: &str,
+    user: &User,
+    body: serde_json::Value,
+) -> (StatusCode, serde_json::Value) {
+    let (status, body) = call(&app.router, json_request(method, uri, user, &body)).await;
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+async fn save(
+    app: &TestApp,
+    user: &User,
+    text: &str,
+    updated_at: DateTime<Utc>,
+) -> (StatusCode, serde_json::Value) {
+    let body = json!({ "text": text, "updated_at": updated_at });
+    send(app, "PUT", "/conversations/support/draft", user, body).await
+}
+
+fn draft_frame(frames: &mut broadcast::Receiver<ServerFrame>) -> Option<Draft> {
+    while let Ok(frame) = frames.try_recv() {
+        if let ServerFrame::DraftUpdated { draft } = frame {
+            return Some(draft);
+        }
+    }
+    None
+}
+
+#[tokio::test]
+async fn the_latest_save_wins_across_devices() {
+    let (app, mut frames) = app();
+    let user = User::new(MessageFromType::User);
+    let now = Utc::now();
+
+    // The phone saves, then the laptop's older edit arrives late.
+    let (status, body) = save(&app, &user, "from the phone", now).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["text"], "from the phone");
+    let pushed = draft_frame(&mut frames).unwrap();
+    assert_eq!(pushed.text(), "from the phone");
+    assert_eq!(pushed.sender_id(), *user.sender_id());
+
+    let (status, body) = save(&app, &user, "from the laptop", now - TimeDelta::seconds(5)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["text"], "from the phone");
+    assert!(draft_frame(&mut frames).is_none());
+
+    let (_, body) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(body["text"], "from the phone");
+    let (_, body) = send(&app, "GET", "/drafts", &user, json!(null)).await;
+    assert_eq!(body.as_array().unwrap().len(), 1);
+
+    // A clock running ahead doesn't get to win over everything after it.
+    let (_, body) = save(&app, &user, "fast clock", now + TimeDelta::days(1)).await;
+    let saved: Draft = serde_json::from_value(body).unwrap();
+    assert!(saved.updated_at() <= Utc::now());
+    let (_, body) = save(&app, &user, "a moment later", Utc::now()).await;
+    assert_eq!(body["text"], "a moment later");
+
+    let other = User::new(MessageFromType::User);
+    let (status, _) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &other,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = send(&app, "GET", "/drafts", &other, json!(null)).await;
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn a_cleared_draft_is_not_brought_back_by_an_older_save() {
+    let (app, _frames) = app();
+    let user = User::new(MessageFromType::User);
+    let before = Utc::now() - TimeDelta::seconds(1);
+    save(&app, &user, "never mind", before).await;
+
+    let (status, _) = send(
+        &app,
+        "DELETE",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, body) = save(&app, &user, "never mind", before).await;
+    assert_eq!(body["text"], "");
+    let (status, _) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = send(&app, "GET", "/drafts", &user, json!(null)).await;
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn sending_a_message_clears_the_draft() {
+    let (app, mut frames) = app();
+    let user = User::new(MessageFromType::User);
+    save(&app, &user, "see you at 9", Utc::now()).await;
+    draft_frame(&mut frames);
+
+    let message = Message::Text(TextMessagePackage::new(user.clone(), "see you at 9").unwrap());
+    let (status, _) = send(
+        &app,
+        "POST",
+        "/conversations/support/messages",
+        &user,
+        serde_json::to_value(message).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, _) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let cleared = draft_frame(&mut frames).unwrap();
+    assert!(cleared.is_empty());
+    assert_eq!(cleared.conversation_id(), "support");
+
+    // Sending with nothing drafted has nothing to tell the other devices.
+    let message = Message::Text(TextMessagePackage::new(user.clone(), "again").unwrap());
+    send(
+        &app,
+        "POST",
+        "/conversations/support/messages",
+        &user,
+        serde_json::to_value(message).unwrap(),
+    )
+    .await;
+    assert!(draft_frame(&mut frames).is_none());
+}
+
+async fn start_upload(app: &TestApp, user: &User, conversation_id: &str) -> UploadSession {
+    let body = json!({ "filename": "notes.txt", "total_size": 10, "chunk_size": 10 });
+    let uri = format!("/conversations/{}/uploads", conversation_id);
+    let (status, body) = send(app, "POST", &uri, user, body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    serde_json::from_value(body).unwrap()
+}
+
+#[tokio::test]
+async fn drafts_hold_the_users_own_uploads() {
+    let (app, _frames) = app();
+    let user = User::new(MessageFromType::User);
+    let upload = start_upload(&app, &user, "support").await;
+
+    let body = json!({
+        "upload_ids": [upload.upload_id(), upload.upload_id()],
+        "updated_at": Utc::now(),
+    });
+    let (status, body) = send(&app, "PUT", "/conversations/support/draft", &user, body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["upload_ids"], json!([upload.upload_id()]));
+    assert_eq!(body["text"], "");
+    let (status, _) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let elsewhere = start_upload(&app, &user, "billing").await;
+    let body = json!({ "upload_ids": [elsewhere.upload_id()], "updated_at": Utc::now() });
+    let (status, _) = send(&app, "PUT", "/conversations/support/draft", &user, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let other = User::new(MessageFromType::User);
+    let body = json!({ "upload_ids": [upload.upload_id()], "updated_at": Utc::now() });
+    let (status, _) = send(&app, "PUT", "/conversations/support/draft", &other, body).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let too_many: Vec<String> = (0..=MAX_DRAFT_ATTACHMENTS)
+        .map(|i| format!("upload-{}", i))
+        .collect();
+    let body = json!({ "upload_ids": too_many, "updated_at": Utc::now() });
+    let (status, _) = send(&app, "PUT", "/conversations/support/draft", &user, body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (status, body) = save(&app, &user, &"x".repeat(17 * 1024), Utc::now()).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"], "text_too_long");
+}
+
+#[tokio::test]
+async fn expired_uploads_drop_out_of_drafts() {
+    let (app, _frames) = app();
+    let user = User::new(MessageFromType::User);
+    let kept = start_upload(&app, &user, "support").await;
+    let expired = start_upload(&app, &user, "support").await;
+    let body = json!({
+        "text": "see attached",
+        "upload_ids": [kept.upload_id(), expired.upload_id()],
+        "updated_at": Utc::now(),
+    });
+    let (status, _) = send(&app, "PUT", "/conversations/support/draft", &user, body).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Gone the way the sweeper removes idle sessions.
+    let uri = format!("/uploads/{}", expired.upload_id());
+    let (status, _) = send(&app, "DELETE", &uri, &user, json!(null)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, body) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["upload_ids"], json!([kept.upload_id()]));
+    assert_eq!(body["text"], "see attached");
+
+    // A draft that only held expired uploads is as good as none.
+    let body = json!({ "upload_ids": [kept.upload_id()], "updated_at": Utc::now() });
+    send(&app, "PUT", "/conversations/support/draft", &user, body).await;
+    let uri = format!("/uploads/{}", kept.upload_id());
+    send(&app, "DELETE", &uri, &user, json!(null)).await;
+    let (status, _) = send(
+        &app,
+        "GET",
+        "/conversations/support/draft",
+        &user,
+        json!(null),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
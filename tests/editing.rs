@@ -0,0 +1,113 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, call, create_text, get, json, json_request, text_message};
+use messaging::enums::{Message, MessageFromType, Role};
+use messaging::structs::history::HistoryPage;
+use messaging::structs::revision::MessageRevision;
+use messaging::structs::user::User;
+use std::time::Duration;
+
+async fn edit(app: &TestApp, user: &User, message_id: u32, text: &str) -> (StatusCode, Vec<u8>) {
+    let uri = format!("/messages/{}", message_id);
+    call(
+        &app.router,
+        json_request("PUT", &uri, user, &text_message(text)),
+    )
+    .await
+}
+
+async fn fetch(app: &TestApp, user: &User, uri: &str) -> Vec<u8> {
+    let (status, body) = call(&app.router, get(uri, user)).await;
+    assert_eq!(status, StatusCode::OK);
+    body
+}
+
+async fn send(app: &TestApp, user: &User, text: &str) -> u32 {
+    create_text(&app.router, user, "support", text)
+        .await
+        .message_id()
+}
+
+#[tokio::test]
+async fn edits_keep_earlier_versions() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let message_id = send(&app, &user, "helo").await;
+
+    assert_eq!(
+        edit(&app, &user, message_id, "hello").await.0,
+        StatusCode::OK
+    );
+    let (status, body) = edit(&app, &user, message_id, "hello there").await;
+    assert_eq!(status, StatusCode::OK);
+    let Message::Text(edited) = json::<Message>(&body) else {
+        panic!("expected a text message");
+    };
+    assert_eq!(edited.message(), "hello there");
+    assert!(edited.is_edited());
+
+    let page: HistoryPage = json(&fetch(&app, &user, "/conversations/support/messages").await);
+    let Message::Text(listed) = &page.messages[0] else {
+        panic!("expected a text message");
+    };
+    assert_eq!(listed.edited_at(), edited.edited_at());
+
+    let revisions: Vec<MessageRevision> =
+        json(&fetch(&app, &user, &format!("/messages/{}/revisions", message_id)).await);
+    let texts: Vec<&str> = revisions.iter().map(MessageRevision::message).collect();
+    assert_eq!(texts, ["helo", "hello"]);
+    assert!(
+        revisions
+            .iter()
+            .all(|revision| revision.edited_by() == *user.sender_id())
+    );
+    assert_eq!(revisions[1].edited_at(), edited.edited_at().unwrap());
+}
+
+#[tokio::test]
+async fn only_the_sender_or_a_moderator_may_edit() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let other = User::new(MessageFromType::User);
+    let mut moderator = User::new(MessageFromType::Agent);
+    moderator.set_role(Role::Moderator);
+    app.register(&[&moderator]).await;
+    let message_id = send(&app, &user, "call me on 555-0100").await;
+
+    assert_eq!(
+        edit(&app, &other, message_id, "hijacked").await.0,
+        StatusCode::FORBIDDEN
+    );
+
+    let (status, body) = edit(&app, &moderator, message_id, "[number removed]").await;
+    assert_eq!(status, StatusCode::OK);
+    let Message::Text(edited) = json::<Message>(&body) else {
+        panic!("expected a text message");
+    };
+    assert_eq!(edited.message(), "[number removed]");
+    // The message is still the sender's.
+    assert_eq!(edited.sender().sender_id(), user.sender_id());
+
+    let revisions: Vec<MessageRevision> =
+        json(&fetch(&app, &user, &format!("/messages/{}/revisions", message_id)).await);
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0].edited_by(), *moderator.sender_id());
+}
+
+#[tokio::test]
+async fn edits_are_refused_once_the_window_closes() {
+    let app = TestApp::with(|state, _| state.with_edit_window(Duration::ZERO));
+    let user = User::new(MessageFromType::User);
+    let message_id = send(&app, &user, "too late").await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    let (status, body) = edit(&app, &user, message_id, "fixed").await;
+
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let error: serde_json::Value = json(&body);
+    assert_eq!(error["error"], "edit_window_closed");
+    let revisions: Vec<MessageRevision> =
+        json(&fetch(&app, &user, &format!("/messages/{}/revisions", message_id)).await);
+    assert!(revisions.is_empty());
+}
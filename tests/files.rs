@@ -0,0 +1,114 @@
+use messaging::enums::{Message, MessageFromType};
+use messaging::errors::FileError;
+use messaging::files::{FileOptions, content_disposition, sanitize_filename, sniff_mime_type};
+use messaging::structs::messages::crypto::CryptoTransferMessagePackage;
+use messaging::structs::messages::file::FileMessagePackage;
+use messaging::utils::sha256_hex;
+use std::io::Cursor;
+
+fn png() -> Vec<u8> {
+    let mut png = Vec::new();
+    image::DynamicImage::new_rgb8(4, 4)
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+#[test]
+fn sniffs_types_from_content() {
+    assert_eq!(sniff_mime_type(&png()), "image/png");
+    assert_eq!(sniff_mime_type(b"%PDF-1.7\n..."), "application/pdf");
+    assert_eq!(sniff_mime_type("grüße\n".as_bytes()), "text/plain");
+    assert_eq!(
+        sniff_mime_type(&[0x00, 0x9f, 0x92, 0x96]),
+        "application/octet-stream"
+    );
+    let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+    elf.resize(64, 0);
+    assert_eq!(sniff_mime_type(&elf), "application/x-executable");
+}
+
+#[test]
+fn records_file_metadata() {
+    let data = b"%PDF-1.7\nnot really a pdf".to_vec();
+    let file = FileMessagePackage::new(
+        1,
+        MessageFromType::User,
+        "C:\\Users\\me\\invoice.pdf",
+        data.clone(),
+    )
+    .unwrap();
+
+    let metadata = file.metadata();
+    assert_eq!(metadata.filename(), "invoice.pdf");
+    assert_eq!(metadata.mime_type(), "application/pdf");
+    assert_eq!(metadata.size(), data.len() as u64);
+    assert_eq!(metadata.sha256(), sha256_hex(&data));
+}
+
+#[test]
+fn enforces_size_and_type_policy() {
+    let options = FileOptions {
+        max_size: 16,
+        allowed_types: vec!["image/*".into(), "text/plain".into()],
+        denied_types: vec!["image/gif".into()],
+    };
+    let build = |filename: &str, data: &[u8]| {
+        FileMessagePackage::with_options(
+            1,
+            MessageFromType::User,
+            filename,
+            data.to_vec(),
+            &options,
+        )
+    };
+
+    assert!(build("notes.txt", b"short note").is_ok());
+    assert!(matches!(
+        build("notes.txt", b"a note that is far too long"),
+        Err(FileError::TooLarge { size: 27, max: 16 })
+    ));
+    assert!(matches!(
+        build("doc.txt", b"%PDF-1.7\n"),
+        Err(FileError::TypeNotAllowed(mime_type)) if mime_type == "application/pdf"
+    ));
+    assert!(matches!(
+        build("anim.gif", b"GIF89a\x01\x00\x01\x00"),
+        Err(FileError::TypeNotAllowed(_))
+    ));
+}
+
+#[test]
+fn rejects_unusable_filenames() {
+    assert_eq!(sanitize_filename("../../etc/passwd").unwrap(), "passwd");
+    for filename in ["", "   ", "uploads/", "..", "bad\nname.txt"] {
+        assert!(
+            matches!(
+                sanitize_filename(filename),
+                Err(FileError::InvalidFilename(_))
+            ),
+            "{:?} was accepted",
+            filename
+        );
+    }
+    assert_eq!(
+        content_disposition("résumé 1.pdf"),
+        "attachment; filename*=UTF-8''r%C3%A9sum%C3%A9%201.pdf"
+    );
+}
+
+#[test]
+fn other_message_types_do_not_read_as_files() {
+    let transfer = CryptoTransferMessagePackage::new(
+        3,
+        MessageFromType::Agent,
+        "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+        1.5,
+        "SOL",
+    )
+    .unwrap();
+    let json = serde_json::to_vec(&Message::CryptoTransfer(transfer)).unwrap();
+
+    let message: Message = serde_json::from_slice(&json).unwrap();
+    assert!(matches!(message, Message::CryptoTransfer(_)));
+}
@@ -0,0 +1,458 @@
+mod common;
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use chrono::{TimeDelta, TimeZone, Utc};
+use common::{TestApp, bearer, call, create_text, json, json_request, text_message};
+use http_body_util::BodyExt;
+use messaging::enums::{Message, MessageFromType, Role};
+use messaging::structs::history::HistoryPage;
+use messaging::structs::messages::image::SimilarImage;
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::user::User;
+use std::io::Cursor;
+use tower::ServiceExt;
+
+fn gradient_png(rising: bool) -> Vec<u8> {
+    let image = image::RgbImage::from_fn(64, 32, |x, _| {
+        let level = (x * 4) as u8;
+        let level = if rising { level } else { 255 - level };
+        image::Rgb([level, level, level])
+    });
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgb8(image)
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+async fn upload_image(app: &Router, user: &User, conversation_id: &str, png: Vec<u8>) -> u32 {
+    let request = Request::post(format!("/conversations/{}/attachments", conversation_id))
+        .header(header::AUTHORIZATION, bearer(user))
+        .header(header::CONTENT_TYPE, "image/png")
+        .body(Body::from(png))
+        .unwrap();
+    let (status, body) = call(app, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+    json::<Message>(&body).message_id()
+}
+
+#[tokio::test]
+async fn rejects_requests_without_a_token() {
+    let app = TestApp::new();
+    let request = Request::get("/messages/1").body(Body::empty()).unwrap();
+    let (status, body) = call(&app.router, request).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(json::<serde_json::Value>(&body)["error"], "unauthorized");
+}
+
+#[tokio::test]
+async fn creates_and_fetches_a_message_as_the_authenticated_sender() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let message_id = create_text(&app.router, &user, "support", "hello")
+        .await
+        .message_id();
+
+    let request = Request::get(format!("/messages/{}", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (status, body) = call(&app.router, request).await;
+
+    assert_eq!(status, StatusCode::OK);
+    let message: Message = json(&body);
+    assert_eq!(message.sender_id(), *user.sender_id());
+    assert_eq!(message.conversation_id(), Some("support"));
+}
+
+#[tokio::test]
+async fn pages_through_history_with_a_cursor() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    for text in ["one", "two", "three"] {
+        create_text(&app.router, &user, "support", text).await;
+    }
+    create_text(&app.router, &user, "elsewhere", "other").await;
+
+    let request = Request::get("/conversations/support/messages?limit=2")
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (_, body) = call(&app.router, request).await;
+    let first: HistoryPage = json(&body);
+    assert_eq!(first.messages.len(), 2);
+    let cursor = first.next_cursor.expect("a full page has a next cursor");
+
+    let request = Request::get(format!(
+        "/conversations/support/messages?limit=2&cursor={}",
+        cursor
+    ))
+    .header(header::AUTHORIZATION, bearer(&user))
+    .body(Body::empty())
+    .unwrap();
+    let (_, body) = call(&app.router, request).await;
+    let second: HistoryPage = json(&body);
+
+    assert_eq!(second.messages.len(), 1);
+    assert!(second.next_cursor.is_none());
+    match &second.messages[0] {
+        Message::Text(text) => assert_eq!(text.message(), "one"),
+        message => panic!("expected a text message, got {:?}", message),
+    }
+}
+
+#[test]
+fn stored_timestamps_sort_in_time_order() {
+    let stamp = |created_at| {
+        let mut message = TextMessagePackage::new(User::new(MessageFromType::User), "x").unwrap();
+        message.set_created_at(created_at);
+        let stored = serde_json::to_value(Message::Text(message)).unwrap();
+        stored["timestamp"].as_str().unwrap().to_string()
+    };
+    let whole_second = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 5).unwrap();
+
+    // "…:05Z" would sort after "…:05.5Z".
+    let (earlier, later) = (
+        stamp(whole_second),
+        stamp(whole_second + TimeDelta::milliseconds(500)),
+    );
+    assert_eq!(earlier.len(), later.len());
+    assert!(earlier < later, "{} < {}", earlier, later);
+}
+
+#[tokio::test]
+async fn only_members_read_a_conversation() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let stranger = User::new(MessageFromType::User);
+    let message_id = create_text(&app.router, &user, "support", "my address is...")
+        .await
+        .message_id();
+
+    let request = Request::get("/conversations/support/messages")
+        .header(header::AUTHORIZATION, bearer(&stranger))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::FORBIDDEN);
+    for uri in ["", "/thread", "/revisions", "/reactions", "/html"] {
+        let request = Request::get(format!("/messages/{}{}", message_id, uri))
+            .header(header::AUTHORIZATION, bearer(&stranger))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            call(&app.router, request).await.0,
+            StatusCode::NOT_FOUND,
+            "{}",
+            uri
+        );
+    }
+}
+
+#[tokio::test]
+async fn only_the_sender_can_update_or_delete() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let other = User::new(MessageFromType::User);
+    let message_id = create_text(&app.router, &user, "support", "first draft")
+        .await
+        .message_id();
+
+    let request = json_request(
+        "PUT",
+        &format!("/messages/{}", message_id),
+        &other,
+        &text_message("hijacked"),
+    );
+    assert_eq!(call(&app.router, request).await.0, StatusCode::FORBIDDEN);
+
+    let request = json_request(
+        "PUT",
+        &format!("/messages/{}", message_id),
+        &user,
+        &text_message("second draft"),
+    );
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    match json::<Message>(&body) {
+        Message::Text(text) => {
+            assert_eq!(text.message_id(), message_id);
+            assert_eq!(text.message(), "second draft");
+        }
+        message => panic!("expected a text message, got {:?}", message),
+    }
+
+    let request = Request::delete(format!("/messages/{}", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::NO_CONTENT);
+
+    // Deleting leaves a tombstone behind.
+    let request = Request::get(format!("/messages/{}", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(json::<Message>(&body).is_deleted());
+}
+
+#[tokio::test]
+async fn serves_thumbnails_of_uploaded_images() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgb8(image::RgbImage::new(600, 300))
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let message_id = upload_image(&app.router, &user, "support", png).await;
+
+    let request = Request::get(format!("/messages/{}/thumbnails/256", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let thumbnail = image::load_from_memory(&body).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+    let request = Request::get(format!("/messages/{}/thumbnails/100", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn only_agents_change_conversation_settings() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    let update = r#"{"image_metadata":"Keep"}"#;
+
+    let request = Request::put("/conversations/support/settings")
+        .header(header::AUTHORIZATION, bearer(&user))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(update))
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::FORBIDDEN);
+
+    let request = Request::put("/conversations/support/settings")
+        .header(header::AUTHORIZATION, bearer(&agent))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(update))
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::OK);
+
+    let request = Request::put(format!(
+        "/conversations/support/members/{}",
+        user.sender_id()
+    ))
+    .header(header::AUTHORIZATION, bearer(&agent))
+    .body(Body::empty())
+    .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::NO_CONTENT);
+    let request = Request::get("/conversations/support/settings")
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (_, body) = call(&app.router, request).await;
+    assert_eq!(json::<serde_json::Value>(&body)["image_metadata"], "Keep");
+
+    // Only agents in the conversation.
+    let stranger = User::new(MessageFromType::Agent);
+    let request = Request::put("/conversations/support/settings")
+        .header(header::AUTHORIZATION, bearer(&stranger))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"image_metadata":"Strip"}"#))
+        .unwrap();
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(json::<serde_json::Value>(&body)["error"], "not_member");
+}
+
+#[tokio::test]
+async fn only_members_see_or_change_conversation_preferences() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let stranger = User::new(MessageFromType::Agent);
+    create_text(&app.router, &user, "support", "hello").await;
+
+    for uri in ["settings", "notifications"] {
+        let request = Request::get(format!("/conversations/support/{}", uri))
+            .header(header::AUTHORIZATION, bearer(&stranger))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            call(&app.router, request).await.0,
+            StatusCode::FORBIDDEN,
+            "{}",
+            uri
+        );
+    }
+    let request = Request::put("/conversations/support/notifications")
+        .header(header::AUTHORIZATION, bearer(&stranger))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"level":"mentions"}"#))
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::FORBIDDEN);
+
+    let request = Request::get("/conversations/support/notifications")
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn finds_similar_images_in_the_conversation() {
+    let app = TestApp::new();
+    let mut moderator = User::new(MessageFromType::Agent);
+    moderator.set_role(Role::Moderator);
+    app.register(&[&moderator]).await;
+    let user = User::new(MessageFromType::User);
+    let original = upload_image(&app.router, &user, "support", gradient_png(true)).await;
+    let resent = upload_image(&app.router, &user, "support", gradient_png(true)).await;
+    upload_image(&app.router, &user, "support", gradient_png(false)).await;
+    upload_image(&app.router, &user, "elsewhere", gradient_png(true)).await;
+
+    let request = Request::get(format!("/messages/{}/similar", original))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    let similar: Vec<SimilarImage> = json(&body);
+    assert_eq!(similar.len(), 1);
+    assert_eq!(similar[0].message.message_id(), resent);
+    assert_eq!(similar[0].distance, 0);
+
+    let request = Request::get(format!(
+        "/messages/{}/similar?all_conversations=true",
+        original
+    ))
+    .header(header::AUTHORIZATION, bearer(&user))
+    .body(Body::empty())
+    .unwrap();
+    // Other conversations are only searched by moderators.
+    assert_eq!(call(&app.router, request).await.0, StatusCode::FORBIDDEN);
+
+    let request = Request::get(format!(
+        "/messages/{}/similar?all_conversations=true",
+        original
+    ))
+    .header(header::AUTHORIZATION, bearer(&moderator))
+    .body(Body::empty())
+    .unwrap();
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json::<Vec<SimilarImage>>(&body).len(), 2);
+}
+
+#[tokio::test]
+async fn stripped_images_are_served_as_their_normalized_type() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let mut bmp = Vec::new();
+    image::DynamicImage::ImageRgb8(image::RgbImage::new(16, 16))
+        .write_to(&mut Cursor::new(&mut bmp), image::ImageFormat::Bmp)
+        .unwrap();
+    let message_id = upload_image(&app.router, &user, "support", bmp).await;
+
+    let request = Request::get(format!("/messages/{}/attachment", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(image::guess_format(&body).unwrap(), image::ImageFormat::Png);
+}
+
+#[tokio::test]
+async fn stores_attachments_as_blob_references() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let png = gradient_png(true);
+    let message_id = upload_image(&app.router, &user, "support", png.clone()).await;
+    upload_image(&app.router, &user, "support", png).await;
+
+    let request = Request::get(format!("/messages/{}", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (_, body) = call(&app.router, request).await;
+    let document: serde_json::Value = json(&body);
+    assert!(document.get("image_data").is_none());
+    let sha256 = document["image_blob"]["sha256"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let request = Request::get(format!("/messages/{}/attachment", message_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(messaging::utils::sha256_hex(&body), sha256);
+
+    // Both uploads share the image's blob. The image is smaller than every
+    // thumbnail size, so its three thumbnails are identical and share one too.
+    let blobs = std::fs::read_dir(app.directory.path().join("blobs"))
+        .unwrap()
+        .flat_map(|shard| std::fs::read_dir(shard.unwrap().path()).unwrap())
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_none())
+        .count();
+    assert_eq!(blobs, 2);
+}
+
+#[tokio::test]
+async fn describes_files_by_their_content() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let upload = |uri: &str| {
+        Request::post(uri)
+            .header(header::AUTHORIZATION, bearer(&user))
+            .header(header::CONTENT_TYPE, "image/png")
+            .body(Body::from("meeting notes\n"))
+            .unwrap()
+    };
+
+    let (status, _) = call(&app.router, upload("/conversations/support/attachments")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = call(
+        &app.router,
+        upload("/conversations/support/attachments?filename=notes%20v2.txt"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let Message::File(file) = json::<Message>(&body) else {
+        panic!("a text body is not an image, whatever its Content-Type says");
+    };
+    assert_eq!(file.metadata().filename(), "notes v2.txt");
+    assert_eq!(file.metadata().mime_type(), "text/plain");
+    assert_eq!(file.metadata().size(), 14);
+
+    let request = Request::get(format!("/messages/{}/attachment", file.message_id()))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+    assert_eq!(
+        response.headers()[header::CONTENT_DISPOSITION],
+        "attachment; filename*=UTF-8''notes%20v2.txt"
+    );
+    assert_eq!(
+        response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+        "nosniff"
+    );
+}
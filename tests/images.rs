@@ -0,0 +1,254 @@
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use messaging::enums::{MessageFromType, MetadataPolicy};
+use messaging::errors::ImageProcessingError;
+use messaging::images::{ImageProcessingOptions, hash_distance, process_image};
+use messaging::structs::messages::image::ImageMessagePackage;
+use std::io::Cursor;
+
+/// Inserts an EXIF segment holding just an orientation tag after the JPEG's
+/// start-of-image marker.
+fn with_exif_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+    let mut tiff = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+    tiff.extend_from_slice(&orientation.to_le_bytes());
+    tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    let mut segment = b"Exif\0\0".to_vec();
+    segment.extend_from_slice(&tiff);
+
+    let mut data = jpeg[..2].to_vec();
+    data.extend_from_slice(&[0xFF, 0xE1]);
+    data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+    data.extend_from_slice(&segment);
+    data.extend_from_slice(&jpeg[2..]);
+    data
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    image.write_to(&mut Cursor::new(&mut data), format).unwrap();
+    data
+}
+
+#[test]
+fn records_metadata_and_normalizes_jpeg_as_jpeg() {
+    let data = encode(
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 30, Rgb([200, 10, 10]))),
+        ImageFormat::Jpeg,
+    );
+
+    let image = ImageMessagePackage::new(1, MessageFromType::User, data.clone()).unwrap();
+
+    let metadata = image.metadata();
+    assert_eq!((metadata.width(), metadata.height()), (40, 30));
+    assert_eq!(metadata.format(), "jpeg");
+    assert_eq!(metadata.mime_type(), "image/jpeg");
+    assert_eq!(metadata.normalized_mime_type(), "image/jpeg");
+    assert_eq!(image.image_data(), image.normalized_data());
+    assert_eq!(
+        image::guess_format(image.normalized_data()).unwrap(),
+        ImageFormat::Jpeg
+    );
+}
+
+#[test]
+fn normalizes_images_with_alpha_to_png() {
+    let data = encode(
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([0, 0, 255, 128]))),
+        ImageFormat::Png,
+    );
+
+    let processed = process_image(&data, &ImageProcessingOptions::default()).unwrap();
+
+    assert_eq!(processed.metadata.normalized_mime_type(), "image/png");
+    let normalized = image::load_from_memory(&processed.normalized_data).unwrap();
+    assert_eq!(
+        *normalized.to_rgba8().get_pixel(0, 0),
+        Rgba([0, 0, 255, 128])
+    );
+}
+
+#[test]
+fn rejects_data_that_is_not_an_image() {
+    let error =
+        ImageMessagePackage::new(1, MessageFromType::User, b"hello there".to_vec()).unwrap_err();
+
+    assert!(matches!(error, ImageProcessingError::UnsupportedFormat));
+}
+
+#[test]
+fn rejects_corrupt_images() {
+    let mut data = encode(
+        DynamicImage::ImageRgb8(RgbImage::new(16, 16)),
+        ImageFormat::Png,
+    );
+    data.truncate(40);
+
+    let error = process_image(&data, &ImageProcessingOptions::default()).unwrap_err();
+
+    assert!(matches!(error, ImageProcessingError::Corrupt(_)));
+}
+
+#[test]
+fn rejects_images_over_the_pixel_limit_before_decoding() {
+    let data = encode(
+        DynamicImage::ImageRgb8(RgbImage::new(100, 100)),
+        ImageFormat::Png,
+    );
+    let options = ImageProcessingOptions {
+        max_pixels: 5_000,
+        ..ImageProcessingOptions::default()
+    };
+
+    let error = process_image(&data, &options).unwrap_err();
+
+    assert!(matches!(
+        error,
+        ImageProcessingError::TooManyPixels {
+            width: 100,
+            height: 100,
+            max_pixels: 5_000,
+        }
+    ));
+}
+
+#[test]
+fn generates_thumbnails_without_upscaling() {
+    let data = encode(
+        DynamicImage::ImageRgb8(RgbImage::new(500, 200)),
+        ImageFormat::Png,
+    );
+
+    let image = ImageMessagePackage::new(1, MessageFromType::User, data).unwrap();
+
+    let sizes: Vec<_> = image
+        .thumbnails()
+        .iter()
+        .map(|thumbnail| (thumbnail.size(), thumbnail.width(), thumbnail.height()))
+        .collect();
+    assert_eq!(sizes, [(64, 64, 26), (256, 256, 102), (1024, 500, 200)]);
+    let thumbnail = image.thumbnail(256).unwrap();
+    assert_eq!(thumbnail.mime_type(), "image/jpeg");
+    assert_eq!(
+        image::guess_format(thumbnail.data()).unwrap(),
+        ImageFormat::Jpeg
+    );
+}
+
+#[test]
+fn thumbnails_keep_transparency_as_webp() {
+    let data = encode(
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(300, 300, Rgba([0, 0, 0, 0]))),
+        ImageFormat::Png,
+    );
+
+    let processed = process_image(&data, &ImageProcessingOptions::default()).unwrap();
+
+    assert!(
+        processed
+            .thumbnails
+            .iter()
+            .all(|thumbnail| thumbnail.mime_type() == "image/webp")
+    );
+}
+
+#[test]
+fn strips_exif_and_bakes_in_the_orientation() {
+    let jpeg = encode(
+        DynamicImage::ImageRgb8(RgbImage::new(40, 20)),
+        ImageFormat::Jpeg,
+    );
+    let data = with_exif_orientation(&jpeg, 6);
+
+    let image = ImageMessagePackage::new(1, MessageFromType::User, data).unwrap();
+
+    let metadata = image.metadata();
+    assert_eq!((metadata.width(), metadata.height()), (20, 40));
+    assert_eq!(metadata.metadata_policy(), MetadataPolicy::Strip);
+    assert!(!contains(&image.image_data(), b"Exif"));
+    let stored = image::load_from_memory(&image.image_data()).unwrap();
+    assert_eq!((stored.width(), stored.height()), (20, 40));
+}
+
+#[test]
+fn keeps_the_original_when_the_policy_says_so() {
+    let jpeg = encode(
+        DynamicImage::ImageRgb8(RgbImage::new(40, 20)),
+        ImageFormat::Jpeg,
+    );
+    let data = with_exif_orientation(&jpeg, 6);
+    let options = ImageProcessingOptions {
+        metadata: MetadataPolicy::Keep,
+        ..ImageProcessingOptions::default()
+    };
+
+    let image = ImageMessagePackage::with_options(1, MessageFromType::User, data.clone(), &options)
+        .unwrap();
+
+    assert_eq!(image.image_data(), data);
+    assert!(!contains(image.normalized_data(), b"Exif"));
+    assert!(
+        image
+            .thumbnails()
+            .iter()
+            .all(|thumbnail| !contains(thumbnail.data(), b"Exif"))
+    );
+}
+
+#[test]
+fn records_a_blurhash_and_dominant_colour() {
+    let mut pixels = RgbImage::from_pixel(60, 40, Rgb([30, 120, 200]));
+    for x in 0..10 {
+        pixels.put_pixel(x, 0, Rgb([255, 255, 0]));
+    }
+    let data = encode(DynamicImage::ImageRgb8(pixels), ImageFormat::Png);
+
+    let image = ImageMessagePackage::new(1, MessageFromType::User, data).unwrap();
+
+    let metadata = image.metadata();
+    assert_eq!(metadata.dominant_color(), Some("#1e78c8"));
+    let blurhash = metadata.blurhash().unwrap();
+    // Four components across and three down for a landscape image.
+    assert_eq!(blurhash.len(), 4 + 2 * 4 * 3);
+    assert!(blurhash.starts_with("L"));
+}
+
+fn gradient(width: u32, height: u32, rising: bool) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, _| {
+        let level = (x * 255 / (width - 1)) as u8;
+        let level = if rising { level } else { 255 - level };
+        Rgb([level, level, level])
+    }))
+}
+
+#[test]
+fn perceptual_hashes_match_across_formats_and_sizes() {
+    let png = encode(gradient(200, 100, true), ImageFormat::Png);
+    let jpeg = encode(gradient(90, 45, true), ImageFormat::Jpeg);
+    let mirrored = encode(gradient(200, 100, false), ImageFormat::Png);
+
+    let hash = |data: Vec<u8>| {
+        let image = ImageMessagePackage::new(1, MessageFromType::User, data).unwrap();
+        image.metadata().perceptual_hash().unwrap().to_string()
+    };
+    let (png, jpeg, mirrored) = (hash(png), hash(jpeg), hash(mirrored));
+
+    assert!(hash_distance(&png, &jpeg).unwrap() <= 4);
+    assert!(hash_distance(&png, &mirrored).unwrap() > 32);
+}
+
+#[test]
+fn byte_identical_images_have_the_same_content_hash() {
+    let data = encode(gradient(50, 50, true), ImageFormat::Png);
+
+    let first = ImageMessagePackage::new(1, MessageFromType::User, data.clone()).unwrap();
+    let second = ImageMessagePackage::new(2, MessageFromType::Agent, data).unwrap();
+
+    let content_hash = first.metadata().content_hash().unwrap();
+    assert_eq!(content_hash.len(), 64);
+    assert_eq!(Some(content_hash), second.metadata().content_hash());
+}
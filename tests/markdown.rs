@@ -0,0 +1,191 @@
+mod common;
+
+use axum::http::{StatusCode, header};
+use common::{TestApp, call, get, json_request};
+use http_body_util::BodyExt;
+use messaging::enums::{Message, MessageFromType, TextFormat};
+use messaging::errors::MarkdownError;
+use messaging::markdown::{MAX_NESTING, parse, render_html};
+use messaging::store::MessageStore;
+use messaging::structs::markdown::{Block, Inline};
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::user::User;
+use tower::ServiceExt;
+
+fn text(text: &str) -> Inline {
+    Inline::Text { text: text.into() }
+}
+
+async fn post_markdown(app: &TestApp, user: &User, markdown: &str) -> (StatusCode, String) {
+    let message =
+        TextMessagePackage::with_format(user.clone(), markdown, TextFormat::Plain).unwrap();
+    let mut body = serde_json::to_value(Message::Text(message)).unwrap();
+    body["format"] = "markdown".into();
+    let (status, body) = call(
+        &app.router,
+        json_request("POST", "/conversations/support/messages", user, &body),
+    )
+    .await;
+    (status, String::from_utf8(body).unwrap())
+}
+
+#[test]
+fn the_supported_subset_is_parsed_into_blocks() {
+    let blocks = parse(
+        "Run **this** _now_, see [the docs](https://example.com/a?b=1):\n\n\
+         ```Rust extra\nfn main() {}\n```\n\n\
+         3. first `x`\n4. second\n   - nested\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        blocks,
+        vec![
+            Block::Paragraph {
+                content: vec![
+                    text("Run "),
+                    Inline::Strong {
+                        content: vec![text("this")]
+                    },
+                    text(" "),
+                    Inline::Emphasis {
+                        content: vec![text("now")]
+                    },
+                    text(", see "),
+                    Inline::Link {
+                        url: "https://example.com/a?b=1".into(),
+                        content: vec![text("the docs")],
+                    },
+                    text(":"),
+                ],
+            },
+            Block::CodeBlock {
+                language: Some("rust".into()),
+                code: "fn main() {}\n".into(),
+            },
+            Block::List {
+                start: Some(3),
+                items: vec![
+                    vec![Block::Paragraph {
+                        content: vec![text("first "), Inline::Code { code: "x".into() }],
+                    }],
+                    vec![
+                        Block::Paragraph {
+                            content: vec![text("second")],
+                        },
+                        Block::List {
+                            start: None,
+                            items: vec![vec![Block::Paragraph {
+                                content: vec![text("nested")],
+                            }]],
+                        },
+                    ],
+                ],
+            },
+        ]
+    );
+}
+
+#[test]
+fn unsafe_links_and_deep_nesting_are_refused() {
+    assert_eq!(
+        parse("[click](javascript:alert(1))"),
+        Err(MarkdownError::UnsafeLink("javascript:alert(1)".into()))
+    );
+    assert!(matches!(
+        parse("![x](data:image/png;base64,AAAA)"),
+        Err(MarkdownError::UnsafeLink(_))
+    ));
+    assert!(matches!(
+        parse("[relative](/admin)"),
+        Err(MarkdownError::UnsafeLink(_))
+    ));
+    assert!(parse("<someone@example.com>").is_ok());
+
+    let nested: String = (0..=MAX_NESTING)
+        .map(|depth| format!("{}- level\n", "  ".repeat(depth)))
+        .collect();
+    assert_eq!(parse(&nested), Err(MarkdownError::TooDeep(MAX_NESTING)));
+}
+
+#[test]
+fn rendered_html_escapes_everything_it_did_not_produce() {
+    let blocks = parse(
+        "# Title <img src=x onerror=alert(1)>\n\n<script>alert(1)</script>\n\n\
+         ```html\" onclick=\"x\n<b>&</b>\n```\n\n[a](https://x.test/\"><script>)",
+    )
+    .unwrap();
+    let html = render_html(&blocks);
+
+    assert!(!html.contains("<script"), "{}", html);
+    assert!(!html.contains("<img"), "{}", html);
+    assert!(!html.contains("onclick"), "{}", html);
+    assert!(html.contains("<p>Title &lt;img src=x onerror=alert(1)&gt;</p>"));
+    assert!(html.contains("<pre><code>&lt;b&gt;&amp;&lt;/b&gt;\n</code></pre>"));
+    assert!(
+        html.contains("href=\"https://x.test/&quot;&gt;&lt;script&gt;\""),
+        "{}",
+        html
+    );
+
+    // Stored ASTs are not trusted either.
+    let forged = vec![Block::Paragraph {
+        content: vec![Inline::Link {
+            url: "javascript:alert(1)".into(),
+            content: vec![text("hi")],
+        }],
+    }];
+    assert_eq!(render_html(&forged), "<p>hi</p>");
+}
+
+#[tokio::test]
+async fn markdown_messages_are_stored_with_their_ast_and_rendered() {
+    let app = TestApp::new();
+    let store = &app.store;
+    let mut agent = User::new(MessageFromType::Agent);
+    agent.set_handle("agent_7").unwrap();
+    store.insert_user(&agent).await.unwrap();
+    let user = User::new(MessageFromType::User);
+
+    let (status, body) =
+        post_markdown(&app, &user, "Try:\n```python\n@agent_7\ndef f(): pass\n```").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let message: Message = serde_json::from_str(&body).unwrap();
+    let Message::Text(text) = &message else {
+        panic!("expected a text message");
+    };
+    assert_eq!(text.format(), TextFormat::Markdown);
+    assert_eq!(
+        text.message(),
+        "Try:\n```python\n@agent_7\ndef f(): pass\n```"
+    );
+    assert!(matches!(
+        text.markdown().unwrap()[1],
+        Block::CodeBlock { .. }
+    ));
+    // Decorators in code don't mention anyone.
+    assert!(text.mentions().is_empty());
+
+    let uri = format!("/messages/{}/html", message.message_id());
+    let response = app.router.clone().oneshot(get(&uri, &user)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html; charset=utf-8");
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    let html = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(
+        String::from_utf8(html.to_vec()).unwrap(),
+        "<p>Try:</p><pre><code class=\"language-python\">@agent_7\ndef f(): pass\n</code></pre>"
+    );
+
+    let (status, body) = post_markdown(&app, &user, "[x](javascript:void(0))").await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+}
+
+#[test]
+fn plain_text_renders_escaped() {
+    let message =
+        TextMessagePackage::new(User::new(MessageFromType::User), "a < b\n**not bold**").unwrap();
+    assert!(message.markdown().is_none());
+    assert_eq!(message.html(), "<p>a &lt; b<br>**not bold**</p>");
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::StatusCode;
+use common::{TestApp, call, create_text, get, json, json_request, request, text_message};
+use messaging::enums::MessageFromType;
+use messaging::structs::user::User;
+
+async fn post(app: &TestApp, user: &User, text: &str) -> (StatusCode, serde_json::Value) {
+    let (status, body) = call(
+        &app.router,
+        json_request(
+            "POST",
+            "/conversations/support/messages",
+            user,
+            &text_message(text),
+        ),
+    )
+    .await;
+    (status, json(&body))
+}
+
+async fn members(app: &TestApp, user: &User) -> (StatusCode, Vec<u8>) {
+    call(&app.router, get("/conversations/support/members", user)).await
+}
+
+async fn change(app: &TestApp, method: &str, user: &User, member: &User) -> StatusCode {
+    let uri = format!("/conversations/support/members/{}", member.sender_id());
+    let request = request(method, &uri, user).body(Body::empty()).unwrap();
+    call(&app.router, request).await.0
+}
+
+#[tokio::test]
+async fn conversations_belong_to_their_members() {
+    let app = TestApp::new();
+    let owner = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    let stranger = User::new(MessageFromType::User);
+
+    // Whoever uses a conversation first becomes its member.
+    create_text(&app.router, &owner, "support", "hello?").await;
+    let (status, body) = post(&app, &stranger, "let me in").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "not_member");
+    assert_eq!(members(&app, &stranger).await.0, StatusCode::FORBIDDEN);
+
+    assert_eq!(
+        change(&app, "PUT", &owner, &agent).await,
+        StatusCode::NO_CONTENT
+    );
+    create_text(&app.router, &agent, "support", "how can I help?").await;
+    let (status, body) = members(&app, &agent).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        json::<Vec<u32>>(&body),
+        [*owner.sender_id(), *agent.sender_id()]
+    );
+
+    assert_eq!(
+        change(&app, "DELETE", &agent, &owner).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        change(&app, "DELETE", &agent, &agent).await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        post(&app, &agent, "back again").await.0,
+        StatusCode::FORBIDDEN
+    );
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::StatusCode;
+use common::{SECRET, TestApp, call, create_text, get, json, json_request, request, text_message};
+use futures::{SinkExt, StreamExt};
+use messaging::auth::{auth_key, issue_token};
+use messaging::enums::{ClientFrame, Message, MessageFromType, NotifyLevel, ServerFrame};
+use messaging::mentions::parse_handles;
+use messaging::server::websocket;
+use messaging::store::{MemoryMessageStore, MessageStore};
+use messaging::structs::conversation::NotificationSettings;
+use messaging::structs::mention::InboxMention;
+use messaging::structs::user::User;
+use serde_json::json;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn register(store: &MemoryMessageStore, sender_type: MessageFromType, handle: &str) -> User {
+    let mut user = User::new(sender_type);
+    user.set_handle(handle).unwrap();
+    store.insert_user(&user).await.unwrap();
+    user
+}
+
+async fn inbox(app: &TestApp, user: &User, unread: bool) -> Vec<InboxMention> {
+    let uri = format!("/mentions?unread={}", unread);
+    let (status, body) = call(&app.router, get(&uri, user)).await;
+    assert_eq!(status, StatusCode::OK);
+    json(&body)
+}
+
+#[test]
+fn handles_are_parsed_outside_email_addresses() {
+    let handles = parse_handles("@Alice, ask @bob_2 (not bob@example.com or @x) @@carol");
+    let names: Vec<&str> = handles
+        .iter()
+        .map(|(_, _, handle)| handle.as_str())
+        .collect();
+    assert_eq!(names, vec!["alice", "bob_2"]);
+    assert_eq!((handles[0].0, handles[0].1), (0, 6));
+}
+
+#[tokio::test]
+async fn mentions_resolve_to_users_and_fill_their_inbox() {
+    let app = TestApp::new();
+    let sender = register(&app.store, MessageFromType::User, "sam").await;
+    let alice = register(&app.store, MessageFromType::Agent, "alice").await;
+    let bob = register(&app.store, MessageFromType::Agent, "bob").await;
+
+    let message = create_text(
+        &app.router,
+        &sender,
+        "support",
+        "@ALICE and @nobody, @bob will know. Thanks @alice! - @sam",
+    )
+    .await;
+    let Message::Text(text) = &message else {
+        panic!("expected a text message");
+    };
+    let mentioned: Vec<(u32, &str)> = text
+        .mentions()
+        .iter()
+        .map(|mention| (mention.sender_id(), mention.handle()))
+        .collect();
+    assert_eq!(
+        mentioned,
+        vec![
+            (*alice.sender_id(), "alice"),
+            (*bob.sender_id(), "bob"),
+            (*alice.sender_id(), "alice"),
+            (*sender.sender_id(), "sam"),
+        ]
+    );
+    let first = &text.mentions()[0];
+    assert_eq!(&text.message()[first.start()..first.end()], "@ALICE");
+    // Agents can tell they are addressed directly.
+    assert!(text.mentions_user(*alice.sender_id()));
+
+    let alice_inbox = inbox(&app, &alice, true).await;
+    assert_eq!(alice_inbox.len(), 1);
+    assert_eq!(alice_inbox[0].message_id(), message.message_id());
+    assert_eq!(alice_inbox[0].mentioned_by(), *sender.sender_id());
+    assert_eq!(alice_inbox[0].conversation_id(), "support");
+    assert_eq!(inbox(&app, &bob, true).await.len(), 1);
+    // Mentioning yourself isn't news.
+    assert!(inbox(&app, &sender, false).await.is_empty());
+
+    let uri = format!("/mentions/{}/read", message.message_id());
+    let request = request("PUT", &uri, &alice).body(Body::empty()).unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::NO_CONTENT);
+    assert!(inbox(&app, &alice, true).await.is_empty());
+    assert!(inbox(&app, &alice, false).await[0].is_read());
+
+    // Editing the mention away takes it out of the inbox.
+    let uri = format!("/messages/{}", message.message_id());
+    let request = json_request("PUT", &uri, &sender, &text_message("only @alice now"));
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json::<Message>(&body).mentions().len(), 1);
+    assert!(inbox(&app, &bob, false).await.is_empty());
+    assert!(inbox(&app, &alice, false).await[0].is_read());
+}
+
+async fn send(client: &mut Client, frame: &ClientFrame) {
+    let json = serde_json::to_string(frame).unwrap();
+    client.send(WsMessage::text(json)).await.unwrap();
+}
+
+async fn recv(client: &mut Client) -> Option<ServerFrame> {
+    loop {
+        let message = timeout(Duration::from_millis(500), client.next())
+            .await
+            .ok()?
+            .expect("connection closed")
+            .unwrap();
+        if let WsMessage::Text(text) = message {
+            return Some(serde_json::from_str(&text).unwrap());
+        }
+    }
+}
+
+async fn connect(app: &TestApp, user: &User) -> Client {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(websocket::serve(listener, app.state.clone()));
+    let (mut client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+    let token = issue_token(&auth_key(SECRET), user);
+    send(&mut client, &ClientFrame::Authenticate { token }).await;
+    assert!(matches!(
+        recv(&mut client).await,
+        Some(ServerFrame::Authenticated { .. })
+    ));
+    client
+}
+
+#[tokio::test]
+async fn mentions_only_conversations_push_just_the_mentions() {
+    let app = TestApp::new();
+    let sender = register(&app.store, MessageFromType::User, "sam").await;
+    let agent = register(&app.store, MessageFromType::Agent, "agent_7").await;
+    app.join("support", &[&sender, &agent]).await;
+    let request = json_request(
+        "PUT",
+        "/conversations/support/notifications",
+        &agent,
+        &json!({ "level": "mentions" }),
+    );
+    let (status, body) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        json::<NotificationSettings>(&body).level,
+        NotifyLevel::Mentions
+    );
+
+    let mut agent_client = connect(&app, &agent).await;
+    send(
+        &mut agent_client,
+        &ClientFrame::Subscribe {
+            conversation_id: "support".into(),
+        },
+    )
+    .await;
+    assert!(matches!(
+        recv(&mut agent_client).await,
+        Some(ServerFrame::Subscribed { .. })
+    ));
+
+    create_text(&app.router, &sender, "support", "anyone there?").await;
+    let message = create_text(&app.router, &sender, "support", "@agent_7 are you there?").await;
+
+    let Some(ServerFrame::Message {
+        message: pushed, ..
+    }) = recv(&mut agent_client).await
+    else {
+        panic!("expected the message that mentions the agent");
+    };
+    assert_eq!(pushed.message_id(), message.message_id());
+    let Some(ServerFrame::Mentioned { mention }) = recv(&mut agent_client).await else {
+        panic!("expected a mention");
+    };
+    assert_eq!(mention.sender_id(), *agent.sender_id());
+    assert_eq!(mention.message_id(), message.message_id());
+    assert!(recv(&mut agent_client).await.is_none());
+}
+
+#[tokio::test]
+async fn mentions_reach_users_who_are_not_subscribed() {
+    let app = TestApp::new();
+    let sender = register(&app.store, MessageFromType::User, "sam").await;
+    let agent = register(&app.store, MessageFromType::Agent, "agent_7").await;
+    let bystander = register(&app.store, MessageFromType::Agent, "agent_8").await;
+    let mut agent_client = connect(&app, &agent).await;
+    let mut bystander_client = connect(&app, &bystander).await;
+
+    let message = create_text(&app.router, &sender, "support", "paging @agent_7").await;
+
+    let Some(ServerFrame::Mentioned { mention }) = recv(&mut agent_client).await else {
+        panic!("expected a mention");
+    };
+    assert_eq!(mention.message_id(), message.message_id());
+    assert!(recv(&mut bystander_client).await.is_none());
+}
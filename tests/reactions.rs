@@ -0,0 +1,227 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::StatusCode;
+use common::{TestApp, call, create_text, get, json, request};
+use messaging::enums::{MessageFromType, ServerFrame};
+use messaging::structs::history::HistoryPage;
+use messaging::structs::reaction::{MAX_REACTIONS_PER_USER, Reaction, ReactionGroup};
+use messaging::structs::user::User;
+
+/// URL-encoded 👍 and 🎉.
+const THUMBS_UP: &str = "%F0%9F%91%8D";
+const PARTY: &str = "%F0%9F%8E%89";
+
+async fn react(app: &TestApp, user: &User, message_id: u32, emoji: &str) -> StatusCode {
+    let uri = format!("/messages/{}/reactions/{}", message_id, emoji);
+    let request = request("PUT", &uri, user).body(Body::empty()).unwrap();
+    call(&app.router, request).await.0
+}
+
+async fn unreact(app: &TestApp, user: &User, message_id: u32, emoji: &str) -> StatusCode {
+    let uri = format!("/messages/{}/reactions/{}", message_id, emoji);
+    let request = request("DELETE", &uri, user).body(Body::empty()).unwrap();
+    call(&app.router, request).await.0
+}
+
+async fn reactions(app: &TestApp, user: &User, message_id: u32) -> Vec<ReactionGroup> {
+    let uri = format!("/messages/{}/reactions", message_id);
+    let (status, body) = call(&app.router, get(&uri, user)).await;
+    assert_eq!(status, StatusCode::OK);
+    json(&body)
+}
+
+#[tokio::test]
+async fn reactions_are_grouped_by_emoji() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    app.join("support", &[&user, &agent]).await;
+    let message_id = create_text(&app.router, &user, "support", "it works now")
+        .await
+        .message_id();
+
+    assert_eq!(
+        react(&app, &user, message_id, PARTY).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        react(&app, &agent, message_id, THUMBS_UP).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        react(&app, &user, message_id, THUMBS_UP).await,
+        StatusCode::CREATED
+    );
+    // Reacting twice with the same emoji counts once.
+    assert_eq!(
+        react(&app, &user, message_id, THUMBS_UP).await,
+        StatusCode::NO_CONTENT
+    );
+
+    let groups = reactions(&app, &agent, message_id).await;
+    assert_eq!(
+        groups,
+        vec![
+            ReactionGroup {
+                emoji: "👍".into(),
+                count: 2,
+                sender_ids: vec![*agent.sender_id(), *user.sender_id()],
+            },
+            ReactionGroup {
+                emoji: "🎉".into(),
+                count: 1,
+                sender_ids: vec![*user.sender_id()],
+            },
+        ]
+    );
+
+    let (status, body) = call(&app.router, get("/conversations/support/messages", &agent)).await;
+    assert_eq!(status, StatusCode::OK);
+    let page: HistoryPage = json(&body);
+    // Reactions are not messages of their own.
+    assert_eq!(page.messages.len(), 1);
+    assert_eq!(page.reactions[&message_id]["👍"], 2);
+    assert_eq!(page.reactions[&message_id]["🎉"], 1);
+
+    assert_eq!(
+        unreact(&app, &user, message_id, THUMBS_UP).await,
+        StatusCode::NO_CONTENT
+    );
+    let groups = reactions(&app, &agent, message_id).await;
+    let thumbs_up = groups.iter().find(|group| group.emoji == "👍").unwrap();
+    assert_eq!(thumbs_up.sender_ids, vec![*agent.sender_id()]);
+    assert_eq!(app.store.messages().len(), 1);
+}
+
+#[tokio::test]
+async fn reaction_changes_are_published_to_the_conversation() {
+    let app = TestApp::new();
+    let mut frames = app.state.hub.subscribe();
+    let user = User::new(MessageFromType::User);
+    let message_id = create_text(&app.router, &user, "support", "shipped")
+        .await
+        .message_id();
+    assert!(matches!(
+        frames.recv().await.unwrap(),
+        ServerFrame::Message { .. }
+    ));
+
+    react(&app, &user, message_id, PARTY).await;
+    // Repeats and removing a reaction that isn't there are not news.
+    react(&app, &user, message_id, PARTY).await;
+    unreact(&app, &user, message_id, THUMBS_UP).await;
+    unreact(&app, &user, message_id, PARTY).await;
+
+    let ServerFrame::ReactionAdded { reaction } = frames.recv().await.unwrap() else {
+        panic!("expected a reaction");
+    };
+    assert_eq!(reaction.message_id(), message_id);
+    assert_eq!(reaction.sender_id(), *user.sender_id());
+    assert_eq!(reaction.emoji(), "🎉");
+    let frame = frames.recv().await.unwrap();
+    assert_eq!(frame.conversation_id(), Some("support"));
+    let ServerFrame::ReactionRemoved {
+        message_id: removed_from,
+        emoji,
+        ..
+    } = frame
+    else {
+        panic!("expected a removed reaction, got {:?}", frame);
+    };
+    assert_eq!(removed_from, message_id);
+    assert_eq!(emoji, "🎉");
+    assert!(frames.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn deleted_messages_and_non_emoji_are_refused() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let message_id = create_text(&app.router, &user, "support", "never mind")
+        .await
+        .message_id();
+
+    assert_eq!(
+        react(&app, &user, message_id, "lol").await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(
+        react(&app, &user, 4_000_000_000, THUMBS_UP).await,
+        StatusCode::NOT_FOUND
+    );
+
+    react(&app, &user, message_id, THUMBS_UP).await;
+    let uri = format!("/messages/{}?scope=everyone", message_id);
+    let request = request("DELETE", &uri, &user).body(Body::empty()).unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::NO_CONTENT);
+
+    assert!(reactions(&app, &user, message_id).await.is_empty());
+    assert_eq!(
+        react(&app, &user, message_id, PARTY).await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+}
+
+#[test]
+fn only_emoji_are_reactions() {
+    let react = |emoji: &str| Reaction::new(1, "support".into(), 2, emoji.into());
+
+    for emoji in ["👍", "❤️", "🇫🇷", "👩🏽‍💻", "1️⃣", "#⃣", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "‼️"]
+    {
+        assert!(react(emoji).is_ok(), "{} was refused", emoji);
+    }
+    for text in [
+        "!!!",
+        "123",
+        "你好",
+        "lol",
+        "👍 ",
+        "a👍",
+        "\u{200D}",
+        "1\u{FE0F}",
+    ] {
+        assert!(react(text).is_err(), "{:?} was accepted", text);
+    }
+}
+
+#[tokio::test]
+async fn each_user_may_only_use_so_many_emoji_on_a_message() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let other = User::new(MessageFromType::User);
+    app.join("support", &[&user, &other]).await;
+    let message_id = create_text(&app.router, &user, "support", "vote!")
+        .await
+        .message_id();
+
+    // Consecutive emoji from the emoticons block, URL-encoded.
+    let emoji = |i: usize| {
+        let emoji = char::from_u32(0x1F600 + i as u32).unwrap().to_string();
+        emoji
+            .bytes()
+            .map(|byte| format!("%{:02X}", byte))
+            .collect::<String>()
+    };
+    for i in 0..MAX_REACTIONS_PER_USER {
+        assert_eq!(
+            react(&app, &user, message_id, &emoji(i)).await,
+            StatusCode::CREATED
+        );
+    }
+
+    let next = emoji(MAX_REACTIONS_PER_USER);
+    assert_eq!(
+        react(&app, &user, message_id, &next).await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    // Repeating one they already used is still fine, as is someone else.
+    assert_eq!(
+        react(&app, &user, message_id, &emoji(0)).await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        react(&app, &other, message_id, &next).await,
+        StatusCode::CREATED
+    );
+}
@@ -0,0 +1,293 @@
+mod common;
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use chrono::{TimeDelta, Utc};
+use common::{TestApp, bearer, call, json};
+use messaging::enums::{InfectedAction, Message, MessageFromType, ScanStatus};
+use messaging::errors::ScanError;
+use messaging::scanning::{
+    ClamdAddress, ClamdScanner, DEFAULT_CLAMD_STREAM_MAX, ScanPolicy, Scanner,
+};
+use messaging::store::MessageStore;
+use messaging::structs::messages::file::FileMessagePackage;
+use messaging::structs::scheduled::ScheduledMessage;
+use messaging::structs::user::User;
+use messaging::uploads::{CHUNK_CHECKSUM_HEADER, UploadLimits, UploadManager};
+use messaging::utils::sha256_hex;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const EICAR: &[u8] = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+/// Answers one INSTREAM command the way clamd does, flagging anything that
+/// contains the EICAR test string.
+async fn answer_instream<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+    let mut command = [0u8; 10];
+    stream.read_exact(&mut command).await.unwrap();
+    assert_eq!(&command, b"zINSTREAM\0");
+
+    let mut data = Vec::new();
+    loop {
+        let length = stream.read_u32().await.unwrap() as usize;
+        if length == 0 {
+            break;
+        }
+        let start = data.len();
+        data.resize(start + length, 0);
+        stream.read_exact(&mut data[start..]).await.unwrap();
+    }
+
+    let reply: &[u8] = if data.windows(EICAR.len()).any(|window| window == EICAR) {
+        b"stream: Eicar-Test-Signature FOUND\0"
+    } else {
+        b"stream: OK\0"
+    };
+    stream.write_all(reply).await.unwrap();
+}
+
+async fn start_stub_clamd() -> ClamdAddress {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(answer_instream(stream));
+        }
+    });
+    ClamdAddress::parse(&format!("tcp:{}", address))
+}
+
+fn scanner(address: ClamdAddress) -> Arc<ClamdScanner> {
+    Arc::new(ClamdScanner::new(address, Duration::from_secs(5)))
+}
+
+async fn app(on_infected: InfectedAction) -> TestApp {
+    let scanning = ScanPolicy::new(scanner(start_stub_clamd().await), on_infected);
+    TestApp::with(|state, directory| {
+        state
+            .with_scanning(scanning)
+            .with_uploads(UploadManager::new(
+                directory.join("uploads"),
+                UploadLimits::default(),
+            ))
+    })
+}
+
+async fn upload_file(app: &Router, user: &User, data: &[u8]) -> (StatusCode, Vec<u8>) {
+    let request = Request::post("/conversations/support/attachments?filename=eicar.txt")
+        .header(header::AUTHORIZATION, bearer(user))
+        .body(Body::from(data.to_vec()))
+        .unwrap();
+    call(app, request).await
+}
+
+#[tokio::test]
+async fn speaks_instream_over_tcp() {
+    let scanner = scanner(start_stub_clamd().await);
+
+    assert_eq!(scanner.scan(&mut &b"hello"[..]).await.unwrap(), None);
+    assert_eq!(
+        scanner.scan(&mut &EICAR[..]).await.unwrap().as_deref(),
+        Some("Eicar-Test-Signature")
+    );
+}
+
+#[tokio::test]
+async fn speaks_instream_over_a_unix_socket() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("clamd.sock");
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        answer_instream(stream).await;
+    });
+
+    let scanner = scanner(ClamdAddress::parse(path.to_str().unwrap()));
+    assert!(scanner.scan(&mut &EICAR[..]).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn reports_unusable_scanners() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0u8; 18];
+        stream.read_exact(&mut request).await.unwrap();
+        stream
+            .write_all(b"INSTREAM size limit exceeded. ERROR\0")
+            .await
+            .unwrap();
+    });
+
+    // A daemon whose StreamMaxLength is lower than we were told.
+    let error = scanner(ClamdAddress::Tcp(address.clone()))
+        .scan(&mut &b"data"[..])
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        ScanError::TooLarge {
+            max: DEFAULT_CLAMD_STREAM_MAX
+        }
+    ));
+
+    // Nothing listens there any more.
+    let error = scanner(ClamdAddress::Tcp(address))
+        .scan(&mut &b"data"[..])
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ScanError::Io(_)));
+}
+
+#[tokio::test]
+async fn refuses_attachments_over_the_stream_limit() {
+    let scanner =
+        ClamdScanner::new(start_stub_clamd().await, Duration::from_secs(5)).with_max_stream_size(4);
+    let error = scanner.scan(&mut &b"hello"[..]).await.unwrap_err();
+    assert!(matches!(error, ScanError::TooLarge { max: 4 }));
+
+    // The policy knows the size up front and doesn't read the data at all.
+    let policy = ScanPolicy::new(Arc::new(scanner), InfectedAction::Reject);
+    let mut data = &b"hello"[..];
+    let error = policy.check(&mut data, 5).await.unwrap_err();
+    assert!(matches!(error, ScanError::TooLarge { max: 4 }));
+    assert_eq!(data, b"hello");
+    let verdict = policy.check(&mut &b"hi"[..], 2).await.unwrap();
+    assert_eq!(verdict.status(), ScanStatus::Clean);
+}
+
+#[tokio::test]
+async fn rejects_infected_attachments() {
+    let app = app(InfectedAction::Reject).await;
+    let user = User::new(MessageFromType::User);
+
+    let (status, body) = upload_file(&app.router, &user, b"quarterly figures").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let message: Message = json(&body);
+    assert_eq!(message.scan().unwrap().status(), ScanStatus::Clean);
+    assert_eq!(message.scan().unwrap().scanner(), "clamd");
+
+    let (status, _) = upload_file(&app.router, &user, EICAR).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let history = app
+        .store
+        .conversation_history("support", None, None, 10)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 1);
+}
+
+#[tokio::test]
+async fn scans_scheduled_attachments() {
+    let app = app(InfectedAction::Reject).await;
+    let user = User::new(MessageFromType::User);
+    let schedule = |data: &[u8]| {
+        let file = FileMessagePackage::new(
+            *user.sender_id(),
+            MessageFromType::User,
+            "eicar.txt",
+            data.to_vec(),
+        )
+        .unwrap();
+        let body = json!({
+            "message": Message::File(file),
+            "send_at": Utc::now() + TimeDelta::hours(1),
+        });
+        Request::post("/conversations/support/scheduled")
+            .header(header::AUTHORIZATION, bearer(&user))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let (status, _) = call(&app.router, schedule(EICAR)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(
+        app.store
+            .scheduled_messages(*user.sender_id())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let (status, body) = call(&app.router, schedule(b"quarterly figures")).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let scheduled: ScheduledMessage = json(&body);
+    assert_eq!(
+        scheduled.message().scan().unwrap().status(),
+        ScanStatus::Clean
+    );
+}
+
+#[tokio::test]
+async fn quarantines_infected_attachments() {
+    let app = app(InfectedAction::Quarantine).await;
+    let user = User::new(MessageFromType::User);
+
+    let (status, body) = upload_file(&app.router, &user, EICAR).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let message: Message = json(&body);
+    assert!(message.is_quarantined());
+    assert_eq!(
+        message.scan().unwrap().signature(),
+        Some("Eicar-Test-Signature")
+    );
+
+    let request = Request::get(format!("/messages/{}/attachment", message.message_id()))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (status, _) = call(&app.router, request).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn scans_chunked_uploads_before_storing_them() {
+    let app = app(InfectedAction::Reject).await;
+    let user = User::new(MessageFromType::User);
+
+    let request = Request::post("/conversations/support/uploads")
+        .header(header::AUTHORIZATION, bearer(&user))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            json!({ "filename": "eicar.txt", "total_size": EICAR.len(), "chunk_size": 32 })
+                .to_string(),
+        ))
+        .unwrap();
+    let (_, body) = call(&app.router, request).await;
+    let upload_id = json::<serde_json::Value>(&body)["upload_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    for (index, chunk) in EICAR.chunks(32).enumerate() {
+        let request = Request::put(format!("/uploads/{}/chunks/{}", upload_id, index))
+            .header(header::AUTHORIZATION, bearer(&user))
+            .header(CHUNK_CHECKSUM_HEADER, sha256_hex(chunk))
+            .body(Body::from(chunk.to_vec()))
+            .unwrap();
+        assert_eq!(call(&app.router, request).await.0, StatusCode::OK);
+    }
+
+    let request = Request::post(format!("/uploads/{}/complete", upload_id))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        call(&app.router, request).await.0,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert!(!app.directory.path().join("blobs").exists());
+    assert!(
+        !app.directory
+            .path()
+            .join("uploads")
+            .join(&upload_id)
+            .exists()
+    );
+}
@@ -0,0 +1,364 @@
+mod common;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{TestApp, call, get, json, json_request, text_message};
+use messaging::blobs::BlobStore;
+use messaging::enums::{Message, MessageFromType, ServerFrame};
+use messaging::server::{AppState, scheduler};
+use messaging::store::MessageStore;
+use messaging::structs::messages::file::FileMessagePackage;
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::scheduled::ScheduledMessage;
+use messaging::structs::user::User;
+use serde_json::json;
+
+async fn schedule(
+    app: &TestApp,
+    user: &User,
+    text: &str,
+    send_at: DateTime<Utc>,
+) -> (StatusCode, Vec<u8>) {
+    let body = json!({ "message": text_message(text), "send_at": send_at });
+    call(
+        &app.router,
+        json_request("POST", "/conversations/support/scheduled", user, &body),
+    )
+    .await
+}
+
+async fn list(app: &TestApp, user: &User) -> Vec<ScheduledMessage> {
+    let (status, body) = call(&app.router, get("/scheduled", user)).await;
+    assert_eq!(status, StatusCode::OK);
+    json(&body)
+}
+
+async fn put(app: &TestApp, uri: &str, user: &User, body: serde_json::Value) -> StatusCode {
+    call(&app.router, json_request("PUT", uri, user, &body))
+        .await
+        .0
+}
+
+async fn delete(app: &TestApp, uri: &str, user: &User) -> StatusCode {
+    call(&app.router, json_request("DELETE", uri, user, &json!(null)))
+        .await
+        .0
+}
+
+#[tokio::test]
+async fn schedules_reschedules_and_cancels() {
+    let app = TestApp::new();
+    let agent = User::new(MessageFromType::Agent);
+    let other = User::new(MessageFromType::Agent);
+
+    let tomorrow = Utc::now() + TimeDelta::days(1);
+    let (status, body) = schedule(&app, &agent, "remind me at 9", tomorrow).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let scheduled: ScheduledMessage = json(&body);
+    assert_eq!(scheduled.send_at(), tomorrow);
+    assert_eq!(scheduled.conversation_id(), "support");
+    schedule(&app, &agent, "sooner", tomorrow - TimeDelta::hours(1)).await;
+
+    let pending = list(&app, &agent).await;
+    // Soonest first.
+    let ids: Vec<u32> = pending.iter().map(|s| s.message_id()).collect();
+    assert_eq!(pending.len(), 2);
+    assert_eq!(ids[1], scheduled.message_id());
+    assert!(list(&app, &other).await.is_empty());
+
+    let (status, _) = schedule(&app, &agent, "too late", Utc::now()).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let uri = format!("/scheduled/{}", scheduled.message_id());
+    let later = tomorrow + TimeDelta::days(1);
+    assert_eq!(
+        put(&app, &uri, &other, json!({ "send_at": later })).await,
+        StatusCode::FORBIDDEN
+    );
+    let (status, body) = call(
+        &app.router,
+        json_request("PUT", &uri, &agent, &json!({ "send_at": later })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json::<ScheduledMessage>(&body).send_at(), later);
+    let past = Utc::now() - TimeDelta::hours(1);
+    assert_eq!(
+        put(&app, &uri, &agent, json!({ "send_at": past })).await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+
+    assert_eq!(delete(&app, &uri, &other).await, StatusCode::FORBIDDEN);
+    assert_eq!(delete(&app, &uri, &agent).await, StatusCode::NO_CONTENT);
+    assert_eq!(delete(&app, &uri, &agent).await, StatusCode::NOT_FOUND);
+    assert_eq!(list(&app, &agent).await.len(), 1);
+}
+
+#[tokio::test]
+async fn delivers_due_messages_after_a_restart() {
+    let app = TestApp::new();
+    let store = app.store.clone();
+    let agent = User::new(MessageFromType::Agent);
+
+    let send_at = Utc::now() + TimeDelta::hours(1);
+    let (_, body) = schedule(&app, &agent, "good morning", send_at).await;
+    let message_id = json::<ScheduledMessage>(&body).message_id();
+
+    // A server started later picks up what the last one scheduled.
+    let state = AppState::new(store.clone(), app.blobs.clone(), common::SECRET);
+    let mut frames = state.hub.subscribe();
+    assert_eq!(scheduler::deliver_due(&state, Utc::now()).await, 0);
+    assert!(store.messages().is_empty());
+
+    let before = Utc::now();
+    assert_eq!(
+        scheduler::deliver_due(&state, send_at + TimeDelta::seconds(1)).await,
+        1
+    );
+    let delivered = store.find_message(message_id).await.unwrap().unwrap();
+    assert!(delivered.created_at() >= before);
+    assert!(
+        store
+            .scheduled_messages(*agent.sender_id())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    match frames.try_recv().unwrap() {
+        ServerFrame::Message {
+            conversation_id,
+            message,
+        } => {
+            assert_eq!(conversation_id, "support");
+            assert_eq!(message.message_id(), message_id);
+        }
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    assert_eq!(
+        scheduler::deliver_due(&state, send_at + TimeDelta::days(1)).await,
+        0
+    );
+    assert_eq!(store.messages().len(), 1);
+}
+
+#[tokio::test]
+async fn does_not_send_a_message_twice() {
+    let app = TestApp::new();
+    let (store, state) = (&app.store, &app.state);
+
+    // As if the server stopped between sending it and crossing it off.
+    let mut message =
+        TextMessagePackage::new(User::new(MessageFromType::Agent), "only once").unwrap();
+    message.set_conversation_id("support".into());
+    let message = Message::Text(message);
+    let send_at = Utc::now() + TimeDelta::minutes(5);
+    let scheduled = ScheduledMessage::new(message.clone(), send_at).unwrap();
+    store.insert_scheduled(&scheduled).await.unwrap();
+    store.insert_message(&message).await.unwrap();
+
+    assert_eq!(
+        scheduler::deliver_due(state, send_at + TimeDelta::minutes(1)).await,
+        0
+    );
+    assert_eq!(store.messages().len(), 1);
+    assert!(
+        store
+            .find_scheduled(message.message_id())
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn drops_replies_whose_parent_was_deleted() {
+    let app = TestApp::new();
+    let (store, state) = (&app.store, &app.state);
+    let agent = User::new(MessageFromType::Agent);
+
+    let mut parent = TextMessagePackage::new(agent.clone(), "question").unwrap();
+    parent.set_conversation_id("support".into());
+    let parent = Message::Text(parent);
+    store.insert_message(&parent).await.unwrap();
+
+    let mut reply = TextMessagePackage::new(agent.clone(), "follow-up").unwrap();
+    reply.set_reply_to(parent.message_id());
+    let send_at = Utc::now() + TimeDelta::hours(1);
+    let body = json!({ "message": Message::Text(reply), "send_at": send_at });
+    let (status, body) = call(
+        &app.router,
+        json_request("POST", "/conversations/support/scheduled", &agent, &body),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let reply_id = json::<ScheduledMessage>(&body).message_id();
+
+    store.delete_message(parent.message_id()).await.unwrap();
+    assert_eq!(
+        scheduler::deliver_due(state, send_at + TimeDelta::seconds(1)).await,
+        0
+    );
+    assert!(store.find_message(reply_id).await.unwrap().is_none());
+    assert!(store.find_scheduled(reply_id).await.unwrap().is_none());
+}
+
+fn file_message(user: &User, data: &[u8]) -> Message<'static> {
+    Message::File(
+        FileMessagePackage::new(
+            *user.sender_id(),
+            MessageFromType::Agent,
+            "notes.txt",
+            data.to_vec(),
+        )
+        .unwrap(),
+    )
+}
+
+async fn schedule_file(app: &TestApp, user: &User, data: &[u8]) -> ScheduledMessage {
+    let send_at = Utc::now() + TimeDelta::hours(1);
+    let body = json!({ "message": file_message(user, data), "send_at": send_at });
+    let (status, body) = call(
+        &app.router,
+        json_request("POST", "/conversations/support/scheduled", user, &body),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    json(&body)
+}
+
+#[tokio::test]
+async fn scheduled_attachments_wait_in_the_blob_store() {
+    let app = TestApp::new();
+    let agent = User::new(MessageFromType::Agent);
+    let scheduled = schedule_file(&app, &agent, b"agenda\n").await;
+
+    let stored = app
+        .store
+        .find_scheduled(scheduled.message_id())
+        .await
+        .unwrap()
+        .unwrap();
+    let Message::File(file) = stored.message() else {
+        panic!("expected a file message");
+    };
+    assert!(file.inline_data().is_empty());
+    assert_eq!(file.file_data(&*app.blobs).await.unwrap(), b"agenda\n");
+    let sha256 = file.file_blob().unwrap().sha256().to_string();
+    assert!(app.blobs.get(&sha256).await.unwrap().is_some());
+
+    let send_at = scheduled.send_at() + TimeDelta::seconds(1);
+    assert_eq!(scheduler::deliver_due(&app.state, send_at).await, 1);
+    let uri = format!("/messages/{}/attachment", scheduled.message_id());
+    let (status, body) = call(&app.router, get(&uri, &agent)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"agenda\n");
+
+    // Only the delivered message holds the blob now.
+    let uri = format!("/messages/{}?scope=everyone", scheduled.message_id());
+    assert_eq!(delete(&app, &uri, &agent).await, StatusCode::NO_CONTENT);
+    assert!(app.blobs.get(&sha256).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn cancelling_releases_scheduled_attachments() {
+    let app = TestApp::new();
+    let agent = User::new(MessageFromType::Agent);
+    let scheduled = schedule_file(&app, &agent, b"draft minutes\n").await;
+    let sha256 = scheduled.message().blobs()[0].sha256().to_string();
+
+    let uri = format!("/scheduled/{}", scheduled.message_id());
+    assert_eq!(delete(&app, &uri, &agent).await, StatusCode::NO_CONTENT);
+    assert!(app.blobs.get(&sha256).await.unwrap().is_none());
+
+    let send_at = scheduled.send_at() + TimeDelta::seconds(1);
+    assert_eq!(scheduler::deliver_due(&app.state, send_at).await, 0);
+}
+
+#[tokio::test]
+async fn a_due_message_is_claimed_by_one_delivery() {
+    let app = TestApp::new();
+    let agent = User::new(MessageFromType::Agent);
+    let send_at = Utc::now() + TimeDelta::hours(1);
+    let (_, body) = schedule(&app, &agent, "standup", send_at).await;
+    let message_id = json::<ScheduledMessage>(&body).message_id();
+
+    // Not yet due, so it stays for a later poll.
+    assert!(
+        app.store
+            .claim_scheduled(message_id, Utc::now())
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let due = send_at + TimeDelta::seconds(1);
+    assert!(
+        app.store
+            .claim_scheduled(message_id, due)
+            .await
+            .unwrap()
+            .is_some()
+    );
+    assert!(
+        app.store
+            .claim_scheduled(message_id, due)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(scheduler::deliver_due(&app.state, due).await, 0);
+    assert!(app.store.messages().is_empty());
+}
+
+#[tokio::test]
+async fn a_rescheduled_message_waits_for_its_new_time() {
+    let app = TestApp::new();
+    let agent = User::new(MessageFromType::Agent);
+    let send_at = Utc::now() + TimeDelta::hours(1);
+    let (_, body) = schedule(&app, &agent, "lunch?", send_at).await;
+    let message_id = json::<ScheduledMessage>(&body).message_id();
+
+    let uri = format!("/scheduled/{}", message_id);
+    let later = send_at + TimeDelta::hours(2);
+    assert_eq!(
+        put(&app, &uri, &agent, json!({ "send_at": later })).await,
+        StatusCode::OK
+    );
+    let due = send_at + TimeDelta::seconds(1);
+    assert_eq!(scheduler::deliver_due(&app.state, due).await, 0);
+    assert!(app.store.messages().is_empty());
+
+    assert_eq!(
+        scheduler::deliver_due(&app.state, later + TimeDelta::seconds(1)).await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn drops_messages_from_senders_who_left() {
+    let app = TestApp::new();
+    let agent = User::new(MessageFromType::Agent);
+    let other = User::new(MessageFromType::Agent);
+    let scheduled = schedule_file(&app, &agent, b"handover notes\n").await;
+    let sha256 = scheduled.message().blobs()[0].sha256().to_string();
+
+    app.join("support", &[&other]).await;
+    assert!(
+        app.store
+            .remove_member("support", *agent.sender_id())
+            .await
+            .unwrap()
+    );
+
+    let send_at = scheduled.send_at() + TimeDelta::seconds(1);
+    assert_eq!(scheduler::deliver_due(&app.state, send_at).await, 0);
+    assert!(app.store.messages().is_empty());
+    assert!(
+        app.store
+            .find_scheduled(scheduled.message_id())
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(app.blobs.get(&sha256).await.unwrap().is_none());
+}
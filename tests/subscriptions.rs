@@ -0,0 +1,193 @@
+mod common;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use common::text_message;
+use futures::StreamExt;
+use messaging::enums::{Message, MessageFromType, MessageType};
+use messaging::subscriptions::{Checkpoint, SubscriptionFilter, SubscriptionSource, poll_messages};
+use mongodb::bson::{Bson, doc, from_document, to_document};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Serves `messages` in (timestamp, message_id) order, as `message_history`
+/// does, and keeps checkpoints in memory.
+#[derive(Default)]
+struct MemorySource {
+    messages: Vec<Message<'static>>,
+    checkpoints: Mutex<BTreeMap<String, Checkpoint>>,
+}
+
+#[async_trait]
+impl SubscriptionSource for MemorySource {
+    async fn load_checkpoint(&self, name: &str) -> Result<Checkpoint, mongodb::error::Error> {
+        let checkpoints = self.checkpoints.lock().unwrap();
+        Ok(checkpoints
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Checkpoint::new(name)))
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), mongodb::error::Error> {
+        // Stored the way the Mongo source stores it.
+        let stored = from_document(to_document(checkpoint)?)?;
+        self.checkpoints
+            .lock()
+            .unwrap()
+            .insert(checkpoint.name().to_string(), stored);
+        Ok(())
+    }
+
+    async fn messages_after(
+        &self,
+        _filter: &SubscriptionFilter,
+        timestamp: DateTime<Utc>,
+        message_id: u32,
+        limit: i64,
+    ) -> Result<Vec<Message<'static>>, mongodb::error::Error> {
+        let mut messages: Vec<_> = self
+            .messages
+            .iter()
+            .filter(|message| {
+                (message.created_at(), message.message_id()) > (timestamp, message_id)
+            })
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| (message.created_at(), message.message_id()));
+        messages.truncate(limit as usize);
+        Ok(messages)
+    }
+}
+
+/// `count` messages, three to a timestamp, starting a minute ago.
+fn messages(count: usize) -> Vec<Message<'static>> {
+    let start = Utc::now() - TimeDelta::minutes(1);
+    (0..count)
+        .map(|i| {
+            let mut message = text_message(&format!("message {}", i));
+            message.set_created_at(start + TimeDelta::milliseconds((i / 3) as i64));
+            message
+        })
+        .collect()
+}
+
+fn ids(messages: &[Message]) -> Vec<u32> {
+    messages.iter().map(Message::message_id).collect()
+}
+
+fn started_before(messages: &[Message]) -> Checkpoint {
+    let mut checkpoint = Checkpoint::new("tail");
+    let mut before = messages[0].clone();
+    before.set_created_at(before.created_at() - TimeDelta::seconds(1));
+    checkpoint.advance(&before);
+    checkpoint
+}
+
+#[test]
+fn filters_match_the_stored_message_fields() {
+    assert!(
+        SubscriptionFilter::default()
+            .conditions("")
+            .unwrap()
+            .is_empty()
+    );
+
+    let filter = SubscriptionFilter {
+        conversation_id: Some("support".into()),
+        message_type: Some(MessageType::Image),
+        from: Some(MessageFromType::Agent),
+    };
+    let from = mongodb::bson::to_bson(&MessageFromType::Agent).unwrap();
+    assert_eq!(
+        filter.conditions("fullDocument.").unwrap(),
+        vec![
+            doc! { "fullDocument.conversation_id": "support" },
+            doc! { "fullDocument.type": mongodb::bson::to_bson(&MessageType::Image).unwrap() },
+            doc! { "$or": [
+                { "fullDocument.from": from.clone() },
+                { "fullDocument.sender.sender_type": from },
+            ] },
+        ]
+    );
+}
+
+#[test]
+fn checkpoints_survive_being_stored() {
+    let mut checkpoint: Checkpoint = from_document(doc! {
+        "name": "tail",
+        "resume_token": { "_data": "8263F0A1B2000000012B022C0100296E5A1004" },
+        "last_timestamp": Bson::Null,
+        "last_message_id": Bson::Null,
+    })
+    .unwrap();
+    assert!(checkpoint.resume_token().is_some());
+    checkpoint.advance(&messages(1)[0]);
+
+    let stored = to_document(&checkpoint).unwrap();
+    assert_eq!(from_document::<Checkpoint>(stored).unwrap(), checkpoint);
+}
+
+#[tokio::test]
+async fn polling_yields_every_message_in_order() {
+    // More than one batch, with timestamps shared across batch boundaries.
+    let messages = messages(250);
+    let source = Arc::new(MemorySource {
+        messages: messages.clone(),
+        ..MemorySource::default()
+    });
+
+    let polled: Vec<_> = poll_messages(
+        source,
+        SubscriptionFilter::default(),
+        Some(started_before(&messages)),
+        Duration::from_millis(10),
+    )
+    .take(messages.len())
+    .collect()
+    .await;
+
+    let mut expected = messages;
+    expected.sort_by_key(|message| (message.created_at(), message.message_id()));
+    assert_eq!(ids(&polled), ids(&expected));
+}
+
+#[tokio::test]
+async fn checkpoints_only_cover_messages_the_consumer_finished() {
+    let messages = messages(5);
+    let source = Arc::new(MemorySource {
+        messages: messages.clone(),
+        ..MemorySource::default()
+    });
+    let mut expected = messages.clone();
+    expected.sort_by_key(|message| (message.created_at(), message.message_id()));
+    let filter = SubscriptionFilter::default();
+    let interval = Duration::from_millis(10);
+
+    let mut stream = poll_messages(
+        source.clone(),
+        filter.clone(),
+        Some(started_before(&messages)),
+        interval,
+    );
+    assert_eq!(
+        stream.next().await.unwrap().message_id(),
+        expected[0].message_id()
+    );
+    assert_eq!(
+        stream.next().await.unwrap().message_id(),
+        expected[1].message_id()
+    );
+    // Stopped while handling the second message.
+    drop(stream);
+
+    let checkpoint = source.load_checkpoint("tail").await.unwrap();
+    assert_eq!(checkpoint.last_message_id(), Some(expected[0].message_id()));
+    assert_eq!(checkpoint.last_timestamp(), Some(expected[0].created_at()));
+
+    let resumed: Vec<_> = poll_messages(source, filter, Some(checkpoint), interval)
+        .take(4)
+        .collect()
+        .await;
+    assert_eq!(ids(&resumed), ids(&expected[1..]));
+}
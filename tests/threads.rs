@@ -0,0 +1,105 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::StatusCode;
+use common::{TestApp, call, get, json, json_request, request, text_message};
+use messaging::enums::{Message, MessageFromType, MessageType};
+use messaging::structs::history::HistoryPage;
+use messaging::structs::thread::ThreadPage;
+use messaging::structs::user::User;
+use serde::de::DeserializeOwned;
+
+async fn send(
+    app: &TestApp,
+    user: &User,
+    conversation_id: &str,
+    text: &str,
+    reply_to: Option<u32>,
+) -> (StatusCode, Vec<u8>) {
+    let mut message = text_message(text);
+    if let Some(reply_to) = reply_to {
+        message.set_reply_to(reply_to);
+    }
+    let uri = format!("/conversations/{}/messages", conversation_id);
+    call(&app.router, json_request("POST", &uri, user, &message)).await
+}
+
+async fn reply(app: &TestApp, user: &User, text: &str, reply_to: Option<u32>) -> Message<'static> {
+    let (status, body) = send(app, user, "support", text, reply_to).await;
+    assert_eq!(status, StatusCode::CREATED);
+    json(&body)
+}
+
+async fn fetch<T: DeserializeOwned>(app: &TestApp, user: &User, uri: &str) -> T {
+    let (status, body) = call(&app.router, get(uri, user)).await;
+    assert_eq!(status, StatusCode::OK);
+    json(&body)
+}
+
+#[tokio::test]
+async fn replies_join_the_thread_of_their_parent() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    app.join("support", &[&user, &agent]).await;
+    let root = reply(&app, &user, "my order never arrived", None).await;
+    let first = reply(&app, &agent, "which order?", Some(root.message_id())).await;
+    let second = reply(&app, &user, "#1234", Some(first.message_id())).await;
+    reply(&app, &user, "unrelated question", None).await;
+
+    assert_eq!(first.thread_root(), Some(root.message_id()));
+    // Replies to replies stay in the same thread.
+    assert_eq!(second.reply_to(), Some(first.message_id()));
+    assert_eq!(second.thread_root(), Some(root.message_id()));
+
+    for message_id in [root.message_id(), second.message_id()] {
+        let thread: ThreadPage =
+            fetch(&app, &user, &format!("/messages/{}/thread", message_id)).await;
+        assert_eq!(thread.root.message_id(), root.message_id());
+        let replies: Vec<u32> = thread.replies.iter().map(Message::message_id).collect();
+        assert_eq!(replies, [first.message_id(), second.message_id()]);
+        assert_eq!(thread.quoted[&first.message_id()].preview(), "which order?");
+    }
+}
+
+#[tokio::test]
+async fn history_counts_replies_and_quotes_parents() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let long = "a".repeat(300);
+    let root = reply(&app, &user, &long, None).await;
+    let answer = reply(&app, &user, "short answer", Some(root.message_id())).await;
+    reply(&app, &user, "another", Some(answer.message_id())).await;
+
+    let page: HistoryPage = fetch(&app, &user, "/conversations/support/messages").await;
+
+    assert_eq!(page.reply_counts.len(), 1);
+    assert_eq!(page.reply_counts[&root.message_id()], 2);
+    let quote = &page.quoted[&root.message_id()];
+    assert_eq!(quote.sender_id(), *user.sender_id());
+    assert_eq!(quote.r#type(), MessageType::Text);
+    assert_eq!(quote.preview(), format!("{}…", "a".repeat(100)));
+    assert_eq!(page.quoted[&answer.message_id()].preview(), "short answer");
+}
+
+#[tokio::test]
+async fn replies_need_a_live_parent_in_the_same_conversation() {
+    let app = TestApp::new();
+    let user = User::new(MessageFromType::User);
+    let elsewhere = {
+        let (status, body) = send(&app, &user, "sales", "hello", None).await;
+        assert_eq!(status, StatusCode::CREATED);
+        json::<Message>(&body).message_id()
+    };
+    let deleted = reply(&app, &user, "typo", None).await.message_id();
+    let uri = format!("/messages/{}", deleted);
+    let request = request("DELETE", &uri, &user).body(Body::empty()).unwrap();
+    assert_eq!(call(&app.router, request).await.0, StatusCode::NO_CONTENT);
+
+    for parent in [elsewhere, deleted, 42] {
+        let (status, body) = send(&app, &user, "support", "re:", Some(parent)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let error: serde_json::Value = json(&body);
+        assert_eq!(error["error"], "invalid_reply");
+    }
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use common::{TestApp, call, json, json_request};
+use messaging::enums::{Message, MessageFromType, ServerFrame, WebhookEvent, WebhookEventType};
+use messaging::errors::TransferError;
+use messaging::structs::messages::crypto::CryptoTransferMessagePackage;
+use messaging::structs::user::User;
+use messaging::transfers::TransferVerifier;
+use serde_json::json;
+use std::sync::Arc;
+
+const ON_CHAIN: &str = "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb";
+const PENDING: &str = "4hXTCkRzt9WyecNzV1XPgCDfGAZzQKNxLXgynz5QDuWW";
+
+/// Knows of one confirmed transaction and nothing else.
+struct StubChain;
+
+#[async_trait]
+impl TransferVerifier for StubChain {
+    async fn is_confirmed(
+        &self,
+        _transfer: &CryptoTransferMessagePackage<'_>,
+        signature: &str,
+    ) -> Result<bool, TransferError> {
+        Ok(signature == ON_CHAIN)
+    }
+}
+
+fn transfer(signature: Option<&str>) -> Message<'static> {
+    let mut transfer = CryptoTransferMessagePackage::new(
+        0,
+        MessageFromType::User,
+        "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+        1.5,
+        "SOL",
+    )
+    .unwrap();
+    if let Some(signature) = signature {
+        transfer.set_transaction_signature(signature.into());
+    }
+    Message::CryptoTransfer(transfer)
+}
+
+async fn send(app: &TestApp, user: &User, message: &Message<'static>) -> Message<'static> {
+    let (status, body) = call(
+        &app.router,
+        json_request("POST", "/conversations/support/messages", user, message),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    json(&body)
+}
+
+async fn confirm(app: &TestApp, user: &User, message_id: u32, signature: &str) -> StatusCode {
+    let uri = format!("/messages/{}/confirmation", message_id);
+    let body = json!({ "transaction_signature": signature });
+    call(&app.router, json_request("POST", &uri, user, &body))
+        .await
+        .0
+}
+
+fn is_confirmed(message: &Message<'static>) -> bool {
+    matches!(message, Message::CryptoTransfer(transfer) if transfer.is_confirmed())
+}
+
+fn transfer_events(frame: &ServerFrame) -> usize {
+    WebhookEvent::from_frame(frame)
+        .iter()
+        .filter(|event| event.event_type() == WebhookEventType::TransferConfirmed)
+        .count()
+}
+
+#[tokio::test]
+async fn a_client_can_not_claim_a_transfer_is_confirmed() {
+    let app = TestApp::new();
+    let sender = User::new(MessageFromType::User);
+    let mut claimed = serde_json::to_value(transfer(Some(ON_CHAIN))).unwrap();
+    claimed["confirmed"] = json!(true);
+
+    let (status, body) = call(
+        &app.router,
+        json_request("POST", "/conversations/support/messages", &sender, &claimed),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    // Nothing here can check the chain.
+    assert!(!is_confirmed(&json(&body)));
+
+    let message_id = json::<Message<'static>>(&body).message_id();
+    assert_eq!(
+        confirm(&app, &sender, message_id, ON_CHAIN).await,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn transfers_are_confirmed_on_chain_when_sent() {
+    let app = TestApp::with(|state, _| state.with_transfer_verifier(Arc::new(StubChain)));
+    let sender = User::new(MessageFromType::User);
+    let mut frames = app.state.hub.subscribe();
+
+    let sent = send(&app, &sender, &transfer(Some(ON_CHAIN))).await;
+    assert!(is_confirmed(&sent));
+    assert_eq!(transfer_events(&frames.try_recv().unwrap()), 1);
+
+    let pending = send(&app, &sender, &transfer(Some(PENDING))).await;
+    assert!(!is_confirmed(&pending));
+    assert_eq!(transfer_events(&frames.try_recv().unwrap()), 0);
+}
+
+#[tokio::test]
+async fn a_transfer_is_announced_the_first_time_it_is_confirmed() {
+    let app = TestApp::with(|state, _| state.with_transfer_verifier(Arc::new(StubChain)));
+    let sender = User::new(MessageFromType::User);
+    let other = User::new(MessageFromType::User);
+    let sent = send(&app, &sender, &transfer(None)).await;
+    let message_id = sent.message_id();
+    let mut frames = app.state.hub.subscribe();
+
+    assert_eq!(
+        confirm(&app, &other, message_id, ON_CHAIN).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        confirm(&app, &sender, message_id, PENDING).await,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(
+        confirm(&app, &sender, message_id, ON_CHAIN).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        confirm(&app, &sender, message_id, ON_CHAIN).await,
+        StatusCode::OK
+    );
+
+    let frame = frames.try_recv().unwrap();
+    assert!(matches!(frame, ServerFrame::TransferConfirmed { .. }));
+    assert_eq!(transfer_events(&frame), 1);
+    assert!(frames.try_recv().is_err());
+    let stored = app.store.messages();
+    assert!(is_confirmed(&stored[0]));
+}
+
+#[tokio::test]
+async fn transfers_can_not_be_edited() {
+    let app = TestApp::with(|state, _| state.with_transfer_verifier(Arc::new(StubChain)));
+    let sender = User::new(MessageFromType::User);
+    let sent = send(&app, &sender, &transfer(None)).await;
+
+    let uri = format!("/messages/{}", sent.message_id());
+    let (status, _) = call(
+        &app.router,
+        json_request("PUT", &uri, &sender, &transfer(Some(ON_CHAIN))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(!is_confirmed(&app.store.messages()[0]));
+}
+
+#[test]
+fn updates_to_a_confirmed_transfer_are_not_announced_again() {
+    let mut confirmed = transfer(Some(ON_CHAIN));
+    if let Message::CryptoTransfer(transfer) = &mut confirmed {
+        transfer.set_confirmed();
+    }
+    let frame = ServerFrame::MessageUpdated {
+        conversation_id: "support".into(),
+        message: confirmed,
+    };
+    assert_eq!(transfer_events(&frame), 0);
+}
@@ -0,0 +1,282 @@
+use axum::Router;
+use axum::extract::State;
+use axum::http::header::LOCATION;
+use axum::http::{StatusCode, header::CONTENT_TYPE};
+use axum::response::{Html, IntoResponse};
+use axum::routing::get;
+use messaging::enums::{Message, MessageFromType, ServerFrame};
+use messaging::errors::UnfurlError;
+use messaging::server::hub::Hub;
+use messaging::store::{MemoryMessageStore, MessageStore};
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::user::User;
+use messaging::unfurl::{LinkPreviewer, UnfurlOptions, Unfurler, extract_urls, is_public};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const ARTICLE: &str = r#"<!doctype html>
+<html><head>
+<title>Ignored when there are OpenGraph tags</title>
+<meta property="og:title" content="Rust &amp; WebSockets">
+<meta property="og:description" content="How the messaging server fans frames out.">
+<meta property='og:image' content='/images/cover.png'>
+<meta property="og:site_name" content="Example Blog">
+</head><body><meta property="og:title" content="Not in the head"></body></html>"#;
+
+const VIDEO: &str = r#"<html><head>
+<title>A video</title>
+<link rel="alternate" type="application/json+oembed" href="/oembed?format=json">
+</head></html>"#;
+
+const OEMBED: &str = r#"{"type": "video", "version": "1.0", "title": "Launch stream",
+    "provider_name": "Example Video", "thumbnail_url": "http://127.0.0.1/thumb.jpg"}"#;
+
+/// Starts the pages the tests preview and returns their base URL and how
+/// often `/counted` was fetched.
+async fn start_site() -> (String, Arc<AtomicUsize>) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+        .route("/article", get(|| async { Html(ARTICLE) }))
+        .route("/video", get(|| async { Html(VIDEO) }))
+        .route(
+            "/oembed",
+            get(|| async { ([(CONTENT_TYPE, "application/json")], OEMBED) }),
+        )
+        .route(
+            "/counted",
+            get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                Html("<title>Counted</title>")
+            }),
+        )
+        .route(
+            "/to-internal",
+            get(|| async {
+                (StatusCode::FOUND, [(LOCATION, "http://10.0.0.1/admin")]).into_response()
+            }),
+        )
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Html("<title>Too late</title>")
+            }),
+        )
+        .route(
+            "/padded",
+            get(|| async {
+                Html(format!(
+                    "<!--{}--><title>Past the cap</title>",
+                    "x".repeat(64 * 1024)
+                ))
+            }),
+        )
+        .route(
+            "/image.png",
+            get(|| async { ([(CONTENT_TYPE, "image/png")], vec![0u8; 16]) }),
+        )
+        .with_state(hits.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, hits)
+}
+
+fn local_options() -> UnfurlOptions {
+    UnfurlOptions {
+        timeout: Duration::from_millis(500),
+        max_bytes: 16 * 1024,
+        allow_loopback: true,
+        ..UnfurlOptions::default()
+    }
+}
+
+#[test]
+fn extracts_links_without_trailing_punctuation() {
+    let text = "See https://example.com/a?b=1, (and http://example.org/x). \
+        Again: https://example.com/a?b=1! Not ftp://example.net or https://";
+    assert_eq!(
+        extract_urls(text, 5),
+        ["https://example.com/a?b=1", "http://example.org/x"]
+    );
+    assert_eq!(extract_urls(text, 1), ["https://example.com/a?b=1"]);
+}
+
+#[tokio::test]
+async fn reads_opengraph_tags() {
+    let (site, _) = start_site().await;
+    let unfurler = Unfurler::new(local_options());
+
+    let url = format!("{}/article", site);
+    let preview = unfurler.unfurl(&url).await.unwrap();
+    assert_eq!(preview.url, url);
+    assert_eq!(preview.title.as_deref(), Some("Rust & WebSockets"));
+    assert_eq!(
+        preview.description.as_deref(),
+        Some("How the messaging server fans frames out.")
+    );
+    assert_eq!(preview.image, Some(format!("{}/images/cover.png", site)));
+    assert_eq!(preview.site_name.as_deref(), Some("Example Blog"));
+}
+
+#[tokio::test]
+async fn falls_back_to_oembed() {
+    let (site, _) = start_site().await;
+    let unfurler = Unfurler::new(local_options());
+
+    let preview = unfurler.unfurl(&format!("{}/video", site)).await.unwrap();
+    assert_eq!(preview.title.as_deref(), Some("Launch stream"));
+    assert_eq!(preview.site_name.as_deref(), Some("Example Video"));
+    assert_eq!(preview.image.as_deref(), Some("http://127.0.0.1/thumb.jpg"));
+}
+
+#[tokio::test]
+async fn caches_previews() {
+    let (site, hits) = start_site().await;
+    let unfurler = Unfurler::new(local_options());
+
+    let url = format!("{}/counted", site);
+    let first = unfurler.cached_unfurl(&url).await;
+    let second = unfurler.cached_unfurl(&url).await;
+    assert_eq!(first.as_ref().unwrap().title.as_deref(), Some("Counted"));
+    assert_eq!(first, second);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    // Once the TTL has run out, the link is fetched again.
+    let unfurler = Unfurler::new(UnfurlOptions {
+        cache_ttl: Duration::ZERO,
+        ..local_options()
+    });
+    unfurler.cached_unfurl(&url).await;
+    unfurler.cached_unfurl(&url).await;
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn refuses_internal_addresses() {
+    let unfurler = Unfurler::new(UnfurlOptions::default());
+    for url in [
+        "http://127.0.0.1/",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://[::1]/",
+        "http://[::ffff:10.0.0.1]/",
+        "http://localhost/",
+    ] {
+        assert!(
+            matches!(unfurler.unfurl(url).await, Err(UnfurlError::Blocked(_))),
+            "{} was not blocked",
+            url
+        );
+    }
+    assert!(matches!(
+        unfurler.unfurl("file:///etc/passwd").await,
+        Err(UnfurlError::UnsupportedUrl(_))
+    ));
+
+    for ip in [
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "100.64.0.1",
+        "0.0.0.0",
+        "224.0.0.1",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:10.0.0.1",
+        "::127.0.0.1",
+        "::169.254.169.254",
+        "2002:7f00:1::1",
+        "2002:a9fe:a9fe::",
+        "2002:c0a8:101:1::1",
+    ] {
+        assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+    }
+    assert!(is_public("93.184.216.34".parse().unwrap()));
+    assert!(is_public("2606:4700::1111".parse().unwrap()));
+    assert!(is_public("::ffff:93.184.216.34".parse().unwrap()));
+    assert!(is_public("2002:5db8:d822::1".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn checks_every_redirect() {
+    let (site, _) = start_site().await;
+    let unfurler = Unfurler::new(local_options());
+
+    let result = unfurler.unfurl(&format!("{}/to-internal", site)).await;
+    assert!(matches!(result, Err(UnfurlError::Blocked(host)) if host == "10.0.0.1"));
+}
+
+#[tokio::test]
+async fn gives_up_on_slow_and_oversized_pages() {
+    let (site, _) = start_site().await;
+    let unfurler = Unfurler::new(local_options());
+
+    assert!(matches!(
+        unfurler.unfurl(&format!("{}/slow", site)).await,
+        Err(UnfurlError::Timeout)
+    ));
+    // Only the first `max_bytes` are read, and the title comes after them.
+    assert!(matches!(
+        unfurler.unfurl(&format!("{}/padded", site)).await,
+        Err(UnfurlError::NoMetadata)
+    ));
+    assert!(matches!(
+        unfurler.unfurl(&format!("{}/image.png", site)).await,
+        Err(UnfurlError::NotHtml(_))
+    ));
+}
+
+#[tokio::test]
+async fn attaches_previews_to_new_messages() {
+    let (site, _) = start_site().await;
+    let store = Arc::new(MemoryMessageStore::new());
+    let hub = Hub::new();
+    let previewer = LinkPreviewer::new(store.clone(), hub.clone(), Unfurler::new(local_options()));
+    tokio::spawn(previewer.run(hub.subscribe()));
+    let mut frames = hub.subscribe();
+
+    let text = format!("Read {}/article and {}/nothing-here", site, site);
+    let mut message = TextMessagePackage::new(User::new(MessageFromType::Agent), text).unwrap();
+    message.set_conversation_id("support".into());
+    let message_id = message.message_id();
+    let message = Message::Text(message);
+    store.insert_message(&message).await.unwrap();
+    hub.publish(ServerFrame::Message {
+        conversation_id: "support".into(),
+        message,
+    });
+
+    let updated = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(ServerFrame::MessageUpdated { message, .. }) = frames.recv().await {
+                return message;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    let Message::Text(updated) = updated else {
+        panic!("expected a text message");
+    };
+    assert_eq!(updated.message_id(), message_id);
+    assert_eq!(updated.previews().len(), 1);
+    assert_eq!(
+        updated.previews()[0].title.as_deref(),
+        Some("Rust & WebSockets")
+    );
+
+    let Some(Message::Text(stored)) = store.find_message(message_id).await.unwrap() else {
+        panic!("expected the stored text message");
+    };
+    assert_eq!(stored.previews(), updated.previews());
+
+    // Previews of text that has been edited away are not attached.
+    let attached = store
+        .attach_previews(message_id, "older text", updated.previews())
+        .await
+        .unwrap();
+    assert!(attached.is_none());
+}
@@ -0,0 +1,413 @@
+mod common;
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use common::{TestApp, bearer, call, json};
+use messaging::blobs::{BlobStore, FsBlobStore};
+use messaging::enums::{BlobCodec, Message, MessageFromType};
+use messaging::errors::UploadError;
+use messaging::files::FileOptions;
+use messaging::structs::upload::UploadSession;
+use messaging::structs::user::User;
+use messaging::uploads::{CHUNK_CHECKSUM_HEADER, UploadLimits, UploadManager};
+use messaging::utils::sha256_hex;
+use serde_json::json;
+use std::time::Duration;
+
+const CHUNK_SIZE: usize = 1024;
+
+fn limits() -> UploadLimits {
+    UploadLimits {
+        max_chunk_size: 4096,
+        max_chunks: 1024,
+        max_upload_size: 1024 * 1024,
+        ttl: Duration::from_secs(60),
+        max_sessions_per_user: 3,
+        max_bytes_per_user: 2 * 1024 * 1024,
+    }
+}
+
+fn app() -> TestApp {
+    app_with(FileOptions::default())
+}
+
+fn app_with(files: FileOptions) -> TestApp {
+    TestApp::with(|state, directory| {
+        state
+            .with_file_options(files)
+            .with_uploads(UploadManager::new(directory.join("uploads"), limits()))
+    })
+}
+
+fn file_bytes(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+async fn start(app: &Router, user: &User, total_size: usize) -> UploadSession {
+    let request = Request::post("/conversations/support/uploads")
+        .header(header::AUTHORIZATION, bearer(user))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            json!({ "filename": "report.bin", "total_size": total_size, "chunk_size": CHUNK_SIZE })
+                .to_string(),
+        ))
+        .unwrap();
+    let (status, body) = call(app, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+    json(&body)
+}
+
+async fn put_chunk(
+    app: &Router,
+    user: &User,
+    upload_id: &str,
+    index: usize,
+    checksum: &str,
+    data: &[u8],
+) -> StatusCode {
+    let request = Request::put(format!("/uploads/{}/chunks/{}", upload_id, index))
+        .header(header::AUTHORIZATION, bearer(user))
+        .header(CHUNK_CHECKSUM_HEADER, checksum)
+        .body(Body::from(data.to_vec()))
+        .unwrap();
+    call(app, request).await.0
+}
+
+async fn status(app: &Router, user: &User, upload_id: &str) -> (StatusCode, Vec<u8>) {
+    let request = Request::get(format!("/uploads/{}", upload_id))
+        .header(header::AUTHORIZATION, bearer(user))
+        .body(Body::empty())
+        .unwrap();
+    call(app, request).await
+}
+
+async fn complete(app: &Router, user: &User, upload_id: &str) -> (StatusCode, Vec<u8>) {
+    let request = Request::post(format!("/uploads/{}/complete", upload_id))
+        .header(header::AUTHORIZATION, bearer(user))
+        .body(Body::empty())
+        .unwrap();
+    call(app, request).await
+}
+
+#[tokio::test]
+async fn resumes_and_completes_chunked_uploads() {
+    let app = app();
+    let user = User::new(MessageFromType::User);
+    let data = file_bytes(CHUNK_SIZE * 2 + 100);
+    let chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+
+    let session = start(&app.router, &user, data.len()).await;
+    assert_eq!(session.total_chunks(), 3);
+    let upload_id = session.upload_id();
+
+    // The connection drops after the first and last chunks.
+    for index in [0, 2] {
+        let status = put_chunk(
+            &app.router,
+            &user,
+            upload_id,
+            index,
+            &sha256_hex(chunks[index]),
+            chunks[index],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (code, body) = status(&app.router, &user, upload_id).await;
+    assert_eq!(code, StatusCode::OK);
+    let session: UploadSession = json(&body);
+    assert_eq!(session.received_chunks(), [0, 2]);
+    assert_eq!(session.missing_chunks(), [1]);
+
+    let (code, body) = complete(&app.router, &user, upload_id).await;
+    assert_eq!(code, StatusCode::CONFLICT);
+    let error: serde_json::Value = json(&body);
+    assert_eq!(
+        error["message"],
+        "upload is missing 1 chunks, starting with chunk 1"
+    );
+
+    let status = put_chunk(
+        &app.router,
+        &user,
+        upload_id,
+        1,
+        &sha256_hex(chunks[1]),
+        chunks[1],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (code, body) = complete(&app.router, &user, upload_id).await;
+    assert_eq!(code, StatusCode::CREATED);
+    let Message::File(file) = json::<Message>(&body) else {
+        panic!("expected a file message");
+    };
+    assert_eq!(file.conversation_id(), Some("support"));
+    // The repeating test pattern compresses well.
+    assert_eq!(file.file_blob().unwrap().codec(), BlobCodec::Zstd);
+    assert!(file.file_blob().unwrap().size() < data.len() as u64);
+    assert_eq!(file.metadata().filename(), "report.bin");
+    assert_eq!(file.metadata().size(), data.len() as u64);
+    assert_eq!(file.metadata().sha256(), sha256_hex(&data));
+
+    let request = Request::get(format!("/messages/{}/attachment", file.message_id()))
+        .header(header::AUTHORIZATION, bearer(&user))
+        .body(Body::empty())
+        .unwrap();
+    let (code, body) = call(&app.router, request).await;
+    assert_eq!(code, StatusCode::OK);
+    assert_eq!(body, data);
+
+    // A completed upload is gone.
+    assert_eq!(
+        status_code(&app.router, &user, upload_id).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+async fn status_code(app: &Router, user: &User, upload_id: &str) -> StatusCode {
+    status(app, user, upload_id).await.0
+}
+
+#[tokio::test]
+async fn rejects_corrupt_chunks() {
+    let app = app();
+    let user = User::new(MessageFromType::User);
+    let data = file_bytes(CHUNK_SIZE + 10);
+    let session = start(&app.router, &user, data.len()).await;
+    let upload_id = session.upload_id();
+
+    let checksum = sha256_hex(&data[..CHUNK_SIZE]);
+    let mut corrupted = data[..CHUNK_SIZE].to_vec();
+    corrupted[3] ^= 0xff;
+    assert_eq!(
+        put_chunk(&app.router, &user, upload_id, 0, &checksum, &corrupted).await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(
+        put_chunk(
+            &app.router,
+            &user,
+            upload_id,
+            1,
+            &checksum,
+            &data[..CHUNK_SIZE]
+        )
+        .await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(
+        put_chunk(
+            &app.router,
+            &user,
+            upload_id,
+            2,
+            &checksum,
+            &data[..CHUNK_SIZE]
+        )
+        .await,
+        StatusCode::BAD_REQUEST
+    );
+
+    let (_, body) = status(&app.router, &user, upload_id).await;
+    let session: UploadSession = json(&body);
+    assert!(session.received_chunks().is_empty());
+}
+
+#[tokio::test]
+async fn uploads_belong_to_their_owner() {
+    let app = app();
+    let owner = User::new(MessageFromType::User);
+    let other = User::new(MessageFromType::User);
+    let session = start(&app.router, &owner, 10).await;
+
+    assert_eq!(
+        status_code(&app.router, &other, session.upload_id()).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        put_chunk(
+            &app.router,
+            &other,
+            session.upload_id(),
+            0,
+            &sha256_hex(b"0123456789"),
+            b"0123456789"
+        )
+        .await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        status_code(&app.router, &owner, "../../etc").await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn applies_the_file_policy() {
+    let app = app_with(FileOptions {
+        max_size: 2048,
+        allowed_types: vec!["application/pdf".into()],
+        denied_types: Vec::new(),
+    });
+    let user = User::new(MessageFromType::User);
+
+    let request = Request::post("/conversations/support/uploads")
+        .header(header::AUTHORIZATION, bearer(&user))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            json!({ "filename": "big.pdf", "total_size": 4096, "chunk_size": CHUNK_SIZE })
+                .to_string(),
+        ))
+        .unwrap();
+    assert_eq!(
+        call(&app.router, request).await.0,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    let data = b"plain text, not a pdf";
+    let session = start(&app.router, &user, data.len()).await;
+    let upload_id = session.upload_id();
+    let status = put_chunk(&app.router, &user, upload_id, 0, &sha256_hex(data), data).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (code, _) = complete(&app.router, &user, upload_id).await;
+    assert_eq!(code, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(
+        status_code(&app.router, &user, upload_id).await,
+        StatusCode::NOT_FOUND
+    );
+    assert!(!app.directory.path().join("blobs").exists());
+}
+
+#[tokio::test]
+async fn expired_uploads_are_removed() {
+    let directory = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(directory.path().join("blobs"));
+    let uploads = UploadManager::new(
+        directory.path().join("uploads"),
+        UploadLimits {
+            ttl: Duration::from_millis(50),
+            ..limits()
+        },
+    );
+
+    let stale = uploads
+        .start(1, "support".into(), "notes.txt".into(), 10, 10)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let fresh = uploads
+        .start(1, "support".into(), "notes.txt".into(), 10, 10)
+        .await
+        .unwrap();
+
+    assert_eq!(uploads.remove_expired().await.unwrap(), 1);
+    assert!(matches!(
+        uploads.status(stale.upload_id(), 1).await,
+        Err(UploadError::NotFound(_))
+    ));
+    uploads
+        .put_chunk(
+            fresh.upload_id(),
+            1,
+            0,
+            &sha256_hex(b"0123456789"),
+            b"0123456789",
+        )
+        .await
+        .unwrap();
+    let (_, blob) = uploads
+        .complete(fresh.upload_id(), 1, &blobs)
+        .await
+        .unwrap();
+    assert_eq!(
+        blobs.get(blob.sha256()).await.unwrap().unwrap(),
+        b"0123456789"
+    );
+}
+
+#[tokio::test]
+async fn caps_what_one_user_may_have_open() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = UploadManager::new(directory.path().join("uploads"), limits());
+    let start = |owner_id: u32, total_size: u64| {
+        uploads.start(
+            owner_id,
+            "support".into(),
+            "notes.txt".into(),
+            total_size,
+            4096,
+        )
+    };
+
+    let first = start(1, 1024 * 1024).await.unwrap();
+    start(1, 1024 * 1024).await.unwrap();
+    assert!(matches!(
+        start(1, 1).await,
+        Err(UploadError::QuotaExceeded { .. })
+    ));
+    // Someone else's uploads don't count.
+    start(2, 1024 * 1024).await.unwrap();
+
+    uploads.cancel(first.upload_id(), 1).await.unwrap();
+    start(1, 10).await.unwrap();
+    start(1, 10).await.unwrap();
+    assert!(matches!(
+        start(1, 10).await,
+        Err(UploadError::TooManySessions { max: 3 })
+    ));
+}
+
+#[tokio::test]
+async fn caps_how_many_chunks_an_upload_has() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = UploadManager::new(directory.path().join("uploads"), limits());
+
+    assert!(matches!(
+        uploads
+            .start(1, "support".into(), "notes.txt".into(), 1024 * 1024, 1)
+            .await,
+        Err(UploadError::TooManyChunks {
+            chunks: 1048576,
+            max: 1024
+        })
+    ));
+    let session = uploads
+        .start(1, "support".into(), "notes.txt".into(), 1024 * 1024, 1024)
+        .await
+        .unwrap();
+    assert_eq!(session.total_chunks(), 1024);
+}
+
+#[tokio::test]
+async fn one_request_at_a_time_completes_an_upload() {
+    let directory = tempfile::tempdir().unwrap();
+    let blobs = FsBlobStore::new(directory.path().join("blobs"));
+    let uploads = UploadManager::new(directory.path().join("uploads"), limits());
+    let session = uploads
+        .start(1, "support".into(), "notes.txt".into(), 10, 10)
+        .await
+        .unwrap();
+    let upload_id = session.upload_id();
+    uploads
+        .put_chunk(upload_id, 1, 0, &sha256_hex(b"0123456789"), b"0123456789")
+        .await
+        .unwrap();
+
+    let assembled = uploads.assemble(upload_id, 1).await.unwrap();
+    assert!(matches!(
+        uploads.complete(upload_id, 1, &blobs).await,
+        Err(UploadError::Completing(_))
+    ));
+    drop(assembled);
+
+    // Giving up on the assembled file lets another request finish it.
+    let assembled = uploads.assemble(upload_id, 1).await.unwrap();
+    uploads.finish(assembled, &blobs).await.unwrap();
+    assert!(matches!(
+        uploads.complete(upload_id, 1, &blobs).await,
+        Err(UploadError::NotFound(_))
+    ));
+}
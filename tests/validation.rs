@@ -0,0 +1,184 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, call, json, json_request};
+use messaging::enums::{Message, MessageFromType, TextFormat};
+use messaging::errors::ValidationError;
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::user::User;
+use messaging::validate_and_get_env_vars::validate_and_get_validation_options;
+use messaging::validation::{ContentRules, ValidationOptions, has_balanced_bidi, is_blank};
+
+fn user() -> User {
+    User::new(MessageFromType::User)
+}
+
+#[test]
+fn counts_characters_not_bytes() {
+    // 512 of them take 2048 bytes, four times the old byte limit.
+    let emoji = "😀".repeat(512);
+    assert!(TextMessagePackage::new(user(), emoji.as_str()).is_ok());
+    let cjk = "漢".repeat(512);
+    assert!(TextMessagePackage::new(user(), cjk.as_str()).is_ok());
+    // A family is several code points joined into one character.
+    let families = "👨‍👩‍👧‍👦".repeat(512);
+    assert!(TextMessagePackage::new(user(), families.as_str()).is_ok());
+
+    let error = TextMessagePackage::new(user(), "😀".repeat(513)).unwrap_err();
+    assert_eq!(
+        error,
+        ValidationError::TooLong {
+            length: 513,
+            max: 512
+        }
+    );
+}
+
+#[test]
+fn caps_the_size_of_piled_up_combining_marks() {
+    let zalgo = format!("a{}", "\u{0301}".repeat(10_000));
+    assert!(matches!(
+        TextMessagePackage::new(user(), zalgo).unwrap_err(),
+        ValidationError::TooLarge { .. }
+    ));
+}
+
+#[test]
+fn rejects_blank_text() {
+    for blank in ["", "   ", "\n\t ", "\u{200B}\u{FEFF}", "\u{3000}"] {
+        assert!(is_blank(blank), "{:?}", blank);
+        assert_eq!(
+            TextMessagePackage::new(user(), blank).unwrap_err(),
+            ValidationError::Blank
+        );
+    }
+    assert!(!is_blank(" hi "));
+}
+
+#[test]
+fn rejects_control_characters_but_keeps_line_breaks() {
+    assert!(TextMessagePackage::new(user(), "line one\r\nline two\tindented").is_ok());
+    assert_eq!(
+        TextMessagePackage::new(user(), "ring \u{7} the bell").unwrap_err(),
+        ValidationError::ControlCharacter('\u{7}')
+    );
+    assert_eq!(
+        TextMessagePackage::new(user(), "null\0byte")
+            .unwrap_err()
+            .to_string(),
+        "text contains the control character U+0000"
+    );
+
+    let file_name = ValidationOptions::default().file_name;
+    assert_eq!(
+        file_name.check("report\n.pdf"),
+        Err(ValidationError::ControlCharacter('\n'))
+    );
+}
+
+#[test]
+fn rejects_bidirectional_overrides_left_open() {
+    // Closed in order, overrides and isolates are fine.
+    assert!(has_balanced_bidi("abc \u{202E}olleh\u{202C} def"));
+    assert!(has_balanced_bidi("\u{2067}\u{202B}x\u{202C}\u{2069}"));
+
+    // What "Trojan Source" relies on: an override that runs to the end of
+    // the line, or one closed by the wrong terminator.
+    for text in [
+        "access = \"user\u{202E} \u{2066}// admin\u{2069}\"",
+        "\u{2067}x\u{202C}",
+        "\u{202C}",
+        "\u{202E}one line\ncloses on the next\u{202C}",
+    ] {
+        assert!(!has_balanced_bidi(text), "{:?}", text);
+        assert_eq!(
+            TextMessagePackage::new(user(), text).unwrap_err(),
+            ValidationError::UnbalancedBidi
+        );
+    }
+}
+
+#[test]
+fn applies_the_limits_of_each_kind_of_text() {
+    let options = ValidationOptions {
+        text: ContentRules::new(5, 1024, true),
+        markdown: ContentRules::new(10, 1024, true),
+        file_name: ContentRules::new(8, 255, false),
+    };
+
+    let text = |text: &str, format| {
+        TextMessagePackage::with_options(user(), text.to_string(), format, &options)
+    };
+    assert!(text("hello", TextFormat::Plain).is_ok());
+    assert!(text("hello!", TextFormat::Plain).is_err());
+    assert!(text("**hello!**", TextFormat::Markdown).is_ok());
+    assert!(matches!(
+        text("**hello!!!**", TextFormat::Markdown),
+        Err(ValidationError::TooLong { .. })
+    ));
+    assert!(matches!(
+        text("[x](ftp:a)", TextFormat::Markdown),
+        Err(ValidationError::Markdown(_))
+    ));
+    assert!(options.file_name.check("a.pdf").is_ok());
+    assert!(options.file_name.check("report.pdf").is_err());
+}
+
+async fn post_text(app: &TestApp, user: &User, text: &str) -> (StatusCode, serde_json::Value) {
+    let mut body = serde_json::to_value(Message::Text(
+        TextMessagePackage::new(user.clone(), "x").unwrap(),
+    ))
+    .unwrap();
+    body["message"] = text.into();
+    let (status, body) = call(
+        &app.router,
+        json_request("POST", "/conversations/support/messages", user, &body),
+    )
+    .await;
+    (status, json(&body))
+}
+
+#[tokio::test]
+async fn the_api_reports_which_rule_failed() {
+    let app = TestApp::with(|state, _| {
+        state.with_validation(ValidationOptions {
+            text: ContentRules::new(1000, 16 * 1024, true),
+            ..ValidationOptions::default()
+        })
+    });
+
+    let user = user();
+
+    let (status, _) = post_text(&app, &user, &"😀".repeat(1000)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    for (text, code) in [
+        (" \u{200B} ".to_string(), "blank_text"),
+        ("bell \u{7}".to_string(), "control_character"),
+        ("\u{202E}reversed".to_string(), "unbalanced_bidi"),
+        ("😀".repeat(1001), "text_too_long"),
+    ] {
+        let (status, body) = post_text(&app, &user, &text).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], code);
+    }
+}
+
+#[test]
+fn byte_limits_follow_configured_character_limits() {
+    // SAFETY: no other test in this binary reads these variables.
+    unsafe {
+        std::env::set_var("MAX_TEXT_LENGTH", "2000");
+        std::env::set_var("MAX_MARKDOWN_LENGTH", "4000");
+        std::env::set_var("MAX_MARKDOWN_BYTES", "50000");
+    }
+    let options = validate_and_get_validation_options();
+    let defaults = ValidationOptions::default();
+
+    assert_eq!(options.text.max_graphemes, 2000);
+    assert_eq!(options.text.max_bytes, 2000 * 32);
+    assert!(options.text.check(&"😀".repeat(2000)).is_ok());
+    assert_eq!(options.markdown.max_graphemes, 4000);
+    assert_eq!(options.markdown.max_bytes, 50000);
+    assert_eq!(options.file_name, defaults.file_name);
+}
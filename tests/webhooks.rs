@@ -0,0 +1,318 @@
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use messaging::enums::{
+    Message, MessageFromType, ReceiptStatus, ServerFrame, WebhookEvent, WebhookEventType,
+};
+use messaging::server::hub::Hub;
+use messaging::store::{MemoryMessageStore, MessageStore};
+use messaging::structs::mention::InboxMention;
+use messaging::structs::messages::crypto::CryptoTransferMessagePackage;
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::receipt::Receipt;
+use messaging::structs::user::User;
+use messaging::structs::webhook::WebhookSubscription;
+use messaging::webhooks::{
+    EVENT_HEADER, RetryPolicy, SIGNATURE_HEADER, WebhookDispatcher, sign_payload,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const SECRET: &str = "a-long-webhook-secret";
+
+#[derive(Clone, Default)]
+struct Receiver {
+    deliveries: Arc<Mutex<Vec<(HeaderMap, Bytes)>>>,
+    failures_left: Arc<AtomicUsize>,
+}
+
+async fn receive(State(receiver): State<Receiver>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    receiver.deliveries.lock().unwrap().push((headers, body));
+    let failing = receiver
+        .failures_left
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+            left.checked_sub(1)
+        })
+        .is_ok();
+    if failing {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Starts a local HTTP receiver that fails its first `failures` requests.
+async fn start_receiver(failures: usize) -> (String, Receiver) {
+    let receiver = Receiver::default();
+    receiver.failures_left.store(failures, Ordering::SeqCst);
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .with_state(receiver.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, receiver)
+}
+
+fn fast_retries(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(50),
+        request_timeout: Duration::from_secs(2),
+    }
+}
+
+async fn start_dispatcher(
+    url: &str,
+    events: Vec<WebhookEventType>,
+    retry: RetryPolicy,
+) -> (Hub, Arc<MemoryMessageStore>) {
+    let store = Arc::new(MemoryMessageStore::new());
+    let subscription = WebhookSubscription::new(1, url.to_string(), events, SECRET.into()).unwrap();
+    store.insert_webhook(&subscription).await.unwrap();
+    store.add_member("support", 1).await.unwrap();
+
+    let hub = Hub::new();
+    let dispatcher = WebhookDispatcher::new(store.clone(), retry).allow_loopback();
+    tokio::spawn(dispatcher.run(hub.subscribe()));
+    (hub, store)
+}
+
+fn text_frame() -> ServerFrame {
+    let mut message =
+        TextMessagePackage::new(User::new(MessageFromType::Agent), "hello".to_string()).unwrap();
+    message.set_conversation_id("support".into());
+    ServerFrame::Message {
+        conversation_id: "support".into(),
+        message: Message::Text(message),
+    }
+}
+
+async fn wait_for<F: Fn() -> bool>(condition: F) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition was not met in time");
+}
+
+#[tokio::test]
+async fn delivers_signed_events() {
+    let (url, receiver) = start_receiver(0).await;
+    let (hub, _) = start_dispatcher(
+        &url,
+        vec![WebhookEventType::MessageCreated],
+        fast_retries(3),
+    )
+    .await;
+
+    hub.publish(text_frame());
+    wait_for(|| !receiver.deliveries.lock().unwrap().is_empty()).await;
+
+    let (headers, body) = receiver.deliveries.lock().unwrap()[0].clone();
+    assert_eq!(
+        headers[SIGNATURE_HEADER],
+        sign_payload(SECRET, &body).as_str()
+    );
+    assert_eq!(headers[EVENT_HEADER], "message_created");
+    let event: WebhookEvent = serde_json::from_slice(&body).unwrap();
+    assert_eq!(event.event_type(), WebhookEventType::MessageCreated);
+}
+
+#[tokio::test]
+async fn only_delivers_subscribed_events() {
+    let (url, receiver) = start_receiver(0).await;
+    let (hub, _) = start_dispatcher(
+        &url,
+        vec![
+            WebhookEventType::ReceiptRead,
+            WebhookEventType::TransferConfirmed,
+        ],
+        fast_retries(3),
+    )
+    .await;
+
+    hub.publish(text_frame());
+    hub.publish(ServerFrame::Receipt {
+        receipt: Receipt::new(7, "support".into(), 2, ReceiptStatus::Delivered),
+    });
+    let mut transfer = CryptoTransferMessagePackage::new(
+        3,
+        MessageFromType::Agent,
+        "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+        1.5,
+        "SOL",
+    )
+    .unwrap();
+    transfer.set_transaction_signature("5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb".into());
+    transfer.set_confirmed();
+    transfer.set_conversation_id("support".into());
+    hub.publish(ServerFrame::Message {
+        conversation_id: "support".into(),
+        message: Message::CryptoTransfer(transfer),
+    });
+    hub.publish(ServerFrame::Receipt {
+        receipt: Receipt::new(7, "support".into(), 2, ReceiptStatus::Read),
+    });
+
+    wait_for(|| receiver.deliveries.lock().unwrap().len() >= 2).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut events: Vec<String> = receiver
+        .deliveries
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(headers, _)| headers[EVENT_HEADER].to_str().unwrap().to_string())
+        .collect();
+    events.sort();
+    assert_eq!(events, ["receipt_read", "transfer_confirmed"]);
+}
+
+#[tokio::test]
+async fn only_delivers_conversations_the_owner_is_a_member_of() {
+    let (url, receiver) = start_receiver(0).await;
+    let (hub, store) =
+        start_dispatcher(&url, vec![WebhookEventType::ReceiptRead], fast_retries(3)).await;
+    let outsider = WebhookSubscription::new(
+        2,
+        url.clone(),
+        vec![WebhookEventType::ReceiptRead],
+        SECRET.into(),
+    )
+    .unwrap();
+    store.insert_webhook(&outsider).await.unwrap();
+
+    hub.publish(ServerFrame::Receipt {
+        receipt: Receipt::new(7, "elsewhere".into(), 2, ReceiptStatus::Read),
+    });
+    hub.publish(ServerFrame::Receipt {
+        receipt: Receipt::new(8, "support".into(), 2, ReceiptStatus::Read),
+    });
+    wait_for(|| !receiver.deliveries.lock().unwrap().is_empty()).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let deliveries = receiver.deliveries.lock().unwrap().clone();
+    assert_eq!(deliveries.len(), 1);
+    let WebhookEvent::ReceiptRead { receipt } = serde_json::from_slice(&deliveries[0].1).unwrap()
+    else {
+        panic!("expected a receipt");
+    };
+    assert_eq!(receipt.conversation_id(), "support");
+}
+
+#[tokio::test]
+async fn mentions_only_go_to_the_mentioned_users_webhooks() {
+    let (url, receiver) = start_receiver(0).await;
+    let (hub, store) =
+        start_dispatcher(&url, vec![WebhookEventType::Mentioned], fast_retries(3)).await;
+    // Not a member, but it's their inbox.
+    let mentioned = WebhookSubscription::new(
+        2,
+        url.clone(),
+        vec![WebhookEventType::Mentioned],
+        SECRET.into(),
+    )
+    .unwrap();
+    store.insert_webhook(&mentioned).await.unwrap();
+
+    hub.publish(ServerFrame::Mentioned {
+        mention: InboxMention::new(7, "support".into(), 2, 3),
+    });
+    wait_for(|| !receiver.deliveries.lock().unwrap().is_empty()).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let deliveries = receiver.deliveries.lock().unwrap().clone();
+    assert_eq!(deliveries.len(), 1);
+    let WebhookEvent::Mentioned { mention } = serde_json::from_slice(&deliveries[0].1).unwrap()
+    else {
+        panic!("expected a mention");
+    };
+    assert_eq!(mention.sender_id(), 2);
+}
+
+#[tokio::test]
+async fn refuses_to_deliver_to_internal_addresses() {
+    let (url, receiver) = start_receiver(0).await;
+    let store = Arc::new(MemoryMessageStore::new());
+    let subscription = WebhookSubscription::new(
+        1,
+        url,
+        vec![WebhookEventType::MessageCreated],
+        SECRET.into(),
+    )
+    .unwrap();
+    store.insert_webhook(&subscription).await.unwrap();
+    store.add_member("support", 1).await.unwrap();
+    let hub = Hub::new();
+    tokio::spawn(WebhookDispatcher::new(store.clone(), fast_retries(2)).run(hub.subscribe()));
+
+    hub.publish(text_frame());
+    wait_for(|| !store.dead_letters().is_empty()).await;
+
+    assert!(receiver.deliveries.lock().unwrap().is_empty());
+    assert!(
+        store.dead_letters()[0]
+            .last_error()
+            .contains("may not be fetched")
+    );
+}
+
+#[tokio::test]
+async fn retries_failed_deliveries() {
+    let (url, receiver) = start_receiver(2).await;
+    let (hub, store) = start_dispatcher(
+        &url,
+        vec![WebhookEventType::MessageCreated],
+        fast_retries(5),
+    )
+    .await;
+
+    hub.publish(text_frame());
+    wait_for(|| receiver.deliveries.lock().unwrap().len() == 3).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(receiver.deliveries.lock().unwrap().len(), 3);
+    assert!(store.dead_letters().is_empty());
+}
+
+#[tokio::test]
+async fn dead_letters_after_repeated_failures() {
+    let (url, receiver) = start_receiver(usize::MAX).await;
+    let (hub, store) = start_dispatcher(
+        &url,
+        vec![WebhookEventType::MessageCreated],
+        fast_retries(3),
+    )
+    .await;
+
+    hub.publish(text_frame());
+    wait_for(|| !store.dead_letters().is_empty()).await;
+
+    assert_eq!(receiver.deliveries.lock().unwrap().len(), 3);
+    let dead_letter = &store.dead_letters()[0];
+    assert_eq!(dead_letter.attempts(), 3);
+    assert!(dead_letter.last_error().contains("500"));
+}
+
+#[test]
+fn backoff_doubles_up_to_the_cap() {
+    let retry = RetryPolicy {
+        max_attempts: 10,
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(10),
+        request_timeout: Duration::from_secs(1),
+    };
+
+    assert_eq!(retry.backoff(1), Duration::from_secs(1));
+    assert_eq!(retry.backoff(2), Duration::from_secs(2));
+    assert_eq!(retry.backoff(4), Duration::from_secs(8));
+    assert_eq!(retry.backoff(5), Duration::from_secs(10));
+}
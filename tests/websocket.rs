@@ -0,0 +1,241 @@
+use chrono::{TimeDelta, Utc};
+use futures::{SinkExt, StreamExt};
+use messaging::auth::{auth_key, issue_token, issue_token_until};
+use messaging::blobs::FsBlobStore;
+use messaging::enums::{ClientFrame, Message, MessageFromType, ReceiptStatus, ServerFrame};
+use messaging::server::{AppState, websocket};
+use messaging::store::{MemoryMessageStore, MessageStore};
+use messaging::structs::messages::text::TextMessagePackage;
+use messaging::structs::user::User;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+const SECRET: &[u8] = b"websocket-test-secret";
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn start_server() -> (SocketAddr, Arc<MemoryMessageStore>) {
+    let store = Arc::new(MemoryMessageStore::new());
+    let blob_dir = tempfile::tempdir().unwrap();
+    let blobs = Arc::new(FsBlobStore::new(blob_dir.path()));
+    let state = AppState::new(store.clone(), blobs, SECRET);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let _blob_dir = blob_dir;
+        websocket::serve(listener, state).await
+    });
+    (addr, store)
+}
+
+async fn send(client: &mut Client, frame: &ClientFrame) {
+    let json = serde_json::to_string(frame).unwrap();
+    client.send(WsMessage::text(json)).await.unwrap();
+}
+
+async fn recv(client: &mut Client) -> ServerFrame {
+    loop {
+        let message = timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for a frame")
+            .expect("connection closed")
+            .unwrap();
+        if let WsMessage::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr, user: &User) -> Client {
+    let (mut client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+    let token = issue_token(&auth_key(SECRET), user);
+    send(&mut client, &ClientFrame::Authenticate { token }).await;
+    match recv(&mut client).await {
+        ServerFrame::Authenticated { sender_id } => assert_eq!(sender_id, *user.sender_id()),
+        frame => panic!("expected authenticated, got {:?}", frame),
+    }
+    client
+}
+
+async fn join(store: &MemoryMessageStore, users: &[&User]) {
+    for user in users {
+        store
+            .add_member("support", *user.sender_id())
+            .await
+            .unwrap();
+    }
+}
+
+async fn subscribe(client: &mut Client, conversation_id: &str) {
+    send(
+        client,
+        &ClientFrame::Subscribe {
+            conversation_id: conversation_id.into(),
+        },
+    )
+    .await;
+    assert!(matches!(recv(client).await, ServerFrame::Subscribed { .. }));
+}
+
+#[tokio::test]
+async fn rejects_connections_with_an_invalid_token() {
+    let (addr, _) = start_server().await;
+    let (mut client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+
+    let user = User::new(MessageFromType::User);
+    let token = issue_token(&auth_key(b"some-other-secret"), &user);
+    send(&mut client, &ClientFrame::Authenticate { token }).await;
+
+    assert!(matches!(recv(&mut client).await, ServerFrame::Error { .. }));
+}
+
+#[tokio::test]
+async fn rejects_expired_tokens() {
+    let (addr, _) = start_server().await;
+    let (mut client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+
+    let user = User::new(MessageFromType::User);
+    let token = issue_token_until(&auth_key(SECRET), &user, Utc::now() - TimeDelta::seconds(1));
+    send(&mut client, &ClientFrame::Authenticate { token }).await;
+
+    match recv(&mut client).await {
+        ServerFrame::Error { error } => assert!(error.contains("expired"), "{}", error),
+        frame => panic!("expected an error, got {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn only_members_may_subscribe() {
+    let (addr, store) = start_server().await;
+    let user = User::new(MessageFromType::User);
+    let stranger = User::new(MessageFromType::User);
+    join(&store, &[&user]).await;
+    let mut stranger_client = connect(addr, &stranger).await;
+
+    send(
+        &mut stranger_client,
+        &ClientFrame::Subscribe {
+            conversation_id: "support".into(),
+        },
+    )
+    .await;
+    assert!(matches!(
+        recv(&mut stranger_client).await,
+        ServerFrame::Error { .. }
+    ));
+
+    // Whoever first uses a conversation nobody belongs to gets it.
+    subscribe(&mut stranger_client, "elsewhere").await;
+    assert_eq!(
+        store.members("elsewhere").await.unwrap(),
+        [*stranger.sender_id()]
+    );
+}
+
+#[tokio::test]
+async fn pushes_sent_messages_to_subscribers() {
+    let (addr, store) = start_server().await;
+    let user = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    join(&store, &[&user, &agent]).await;
+    let mut user_client = connect(addr, &user).await;
+    let mut agent_client = connect(addr, &agent).await;
+    subscribe(&mut user_client, "support").await;
+    subscribe(&mut agent_client, "support").await;
+
+    let message = TextMessagePackage::new(user.clone(), "hello agent").unwrap();
+    send(
+        &mut user_client,
+        &ClientFrame::Send {
+            conversation_id: "support".into(),
+            message: Box::new(Message::Text(message.into_owned())),
+        },
+    )
+    .await;
+
+    let message_id = match recv(&mut user_client).await {
+        ServerFrame::Sent { message_id, .. } => message_id,
+        frame => panic!("expected sent, got {:?}", frame),
+    };
+    match recv(&mut agent_client).await {
+        ServerFrame::Message {
+            conversation_id,
+            message: Message::Text(text),
+        } => {
+            assert_eq!(conversation_id, "support");
+            assert_eq!(text.message_id(), message_id);
+            assert_eq!(text.message(), "hello agent");
+            assert_eq!(text.sender().sender_id(), user.sender_id());
+        }
+        frame => panic!("expected a text message, got {:?}", frame),
+    }
+
+    let stored = store.messages();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].conversation_id(), Some("support"));
+}
+
+#[tokio::test]
+async fn pushes_receipts_and_typing_only_to_subscribed_conversations() {
+    let (addr, store) = start_server().await;
+    let user = User::new(MessageFromType::User);
+    let agent = User::new(MessageFromType::Agent);
+    join(&store, &[&user, &agent]).await;
+    let mut user_client = connect(addr, &user).await;
+    let mut agent_client = connect(addr, &agent).await;
+    subscribe(&mut user_client, "support").await;
+    subscribe(&mut agent_client, "support").await;
+
+    send(
+        &mut agent_client,
+        &ClientFrame::Typing {
+            conversation_id: "elsewhere".into(),
+            is_typing: true,
+        },
+    )
+    .await;
+    send(
+        &mut agent_client,
+        &ClientFrame::Typing {
+            conversation_id: "support".into(),
+            is_typing: true,
+        },
+    )
+    .await;
+    match recv(&mut user_client).await {
+        ServerFrame::Typing {
+            conversation_id,
+            sender_id,
+            is_typing,
+        } => {
+            assert_eq!(conversation_id, "support");
+            assert_eq!(sender_id, *agent.sender_id());
+            assert!(is_typing);
+        }
+        frame => panic!("expected typing, got {:?}", frame),
+    }
+
+    send(
+        &mut agent_client,
+        &ClientFrame::Receipt {
+            conversation_id: "support".into(),
+            message_id: 42,
+            status: ReceiptStatus::Read,
+        },
+    )
+    .await;
+    match recv(&mut user_client).await {
+        ServerFrame::Receipt { receipt } => {
+            assert_eq!(receipt.message_id(), 42);
+            assert_eq!(receipt.sender_id(), *agent.sender_id());
+            assert_eq!(receipt.status(), ReceiptStatus::Read);
+        }
+        frame => panic!("expected receipt, got {:?}", frame),
+    }
+    assert_eq!(store.receipts().len(), 1);
+}